@@ -0,0 +1,196 @@
+//! The classes and modules declared at the top level of a program, and the
+//! order in which they include each other.
+
+use crate::ast::{self, Location};
+use crate::error::{AnalysisError, AnalysisErrorType};
+use rustc_hash::FxHashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeclarationKind {
+    Class,
+    Module,
+}
+
+/// A `class` or `module` statement.
+#[derive(Debug)]
+pub struct Declaration<'a> {
+    pub kind: DeclarationKind,
+    pub name: &'a str,
+    pub location: Location,
+    /// The names in the `extends` clause and their locations, in order.
+    pub bases: Vec<(&'a str, Location)>,
+    pub body: &'a [ast::Stmt],
+}
+
+#[derive(Debug, Default)]
+pub struct Declarations<'a> {
+    declarations: Vec<Declaration<'a>>,
+    by_name: FxHashMap<&'a str, usize>,
+}
+
+impl<'a> Declarations<'a> {
+    /// Collect the classes and modules declared directly in `body`. A later
+    /// declaration replaces an earlier one with the same name.
+    pub fn collect(body: &'a [ast::Stmt]) -> Self {
+        let mut declarations = Declarations::default();
+        for stmt in body {
+            let (kind, name, bases, body) = match &stmt.node {
+                ast::StmtKind::ClassDef { name, bases, body, .. } => (DeclarationKind::Class, name, bases, body),
+                ast::StmtKind::ModuleDef { name, bases, body, .. } => (DeclarationKind::Module, name, bases, body),
+                _ => continue,
+            };
+            let bases = bases
+                .iter()
+                .filter_map(|base| match &base.node {
+                    ast::ExprKind::Name { id, .. } => Some((id.as_str(), base.location)),
+                    _ => None,
+                })
+                .collect();
+            let index = declarations.declarations.len();
+            declarations.declarations.push(Declaration {
+                kind,
+                name,
+                location: stmt.location,
+                bases,
+                body,
+            });
+            declarations.by_name.insert(name, index);
+        }
+        declarations
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Declaration<'a>> {
+        self.by_name.get(name).map(|&index| &self.declarations[index])
+    }
+
+    /// The declarations in source order, leaving out ones that were replaced.
+    pub fn iter(&self) -> impl Iterator<Item = &Declaration<'a>> {
+        self.declarations
+            .iter()
+            .enumerate()
+            .filter(|(index, declaration)| self.by_name[declaration.name] == *index)
+            .map(|(_, declaration)| declaration)
+    }
+
+    /// `name` and everything it includes, in inclusion order: the bases of a
+    /// declaration come before it, in the order of its `extends` clause, and a
+    /// module that is included more than once appears where it is first included.
+    pub fn inclusion_order(&self, name: &str) -> Result<Vec<&Declaration<'a>>, AnalysisError> {
+        let declaration = match self.get(name) {
+            Some(declaration) => declaration,
+            None => return Ok(vec![]),
+        };
+        let order = self.include(declaration, &mut vec![])?;
+        // Every name in the order was looked up to get there.
+        Ok(order.into_iter().map(|name| self.get(name).unwrap()).collect())
+    }
+
+    /// Whether `name` includes `module`, directly or through other modules.
+    pub fn includes(&self, name: &str, module: &str) -> bool {
+        self.inclusion_order(name)
+            .is_ok_and(|order| order.iter().any(|declaration| declaration.name == module))
+    }
+
+    /// The location of the base in the `extends` clause of `declaration`
+    /// through which it includes `module`.
+    pub fn inclusion_location(&self, declaration: &Declaration, module: &str) -> Location {
+        declaration
+            .bases
+            .iter()
+            .find(|(base, _)| self.includes(base, module))
+            .map_or(declaration.location, |(_, location)| *location)
+    }
+
+    fn include(&self, declaration: &Declaration<'a>, stack: &mut Vec<&'a str>) -> Result<Vec<&'a str>, AnalysisError> {
+        stack.push(declaration.name);
+        let mut base_orders = vec![];
+        for &(base, location) in &declaration.bases {
+            let error = if stack.contains(&base) {
+                AnalysisErrorType::CyclicInclusion(base.to_owned())
+            } else if let Some(base) = self.get(base) {
+                base_orders.push(self.include(base, stack)?);
+                continue;
+            } else {
+                AnalysisErrorType::UnknownBase(base.to_owned())
+            };
+            return Err(AnalysisError { error, location });
+        }
+        stack.pop();
+        Ok(merge_inclusion_orders(declaration.name, base_orders))
+    }
+}
+
+/// The inclusion order of `node` from those of its bases, in the order of its
+/// `extends` clause: what each base includes, leaving out what an earlier base
+/// already included, followed by `node` itself.
+///
+/// This is generic over what identifies a class or module, so that the
+/// interpreter, which only has the inclusion orders of classes that were
+/// already built, orders them the same way as the analyses do.
+pub fn merge_inclusion_orders<T, B>(node: T, base_orders: impl IntoIterator<Item = B>) -> Vec<T>
+where
+    T: Copy + PartialEq,
+    B: IntoIterator<Item = T>,
+{
+    let mut order = vec![];
+    for included in base_orders.into_iter().flatten() {
+        if !order.contains(&included) {
+            order.push(included);
+        }
+    }
+    order.push(node);
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emerald_lang_parser::parser::parse_program;
+
+    fn inclusion_order(source: &str, name: &str) -> Result<Vec<String>, String> {
+        let body = parse_program(source, "<test>").unwrap();
+        let declarations = Declarations::collect(&body);
+        declarations
+            .inclusion_order(name)
+            .map(|order| order.iter().map(|declaration| declaration.name.to_owned()).collect())
+            .map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn test_inclusion_order() {
+        let source = "\
+module Organism:
+    energy: int
+module Predator extends Organism:
+    pass
+module Swimmer:
+    pass
+class Shark extends Predator, Swimmer, Organism:
+    pass
+";
+        assert_eq!(
+            inclusion_order(source, "Shark").unwrap(),
+            vec!["Organism", "Predator", "Swimmer", "Shark"]
+        );
+    }
+
+    #[test]
+    fn test_inclusion_errors() {
+        let source = "\
+module A extends B:
+    pass
+module B extends A:
+    pass
+class C extends Missing:
+    pass
+";
+        assert_eq!(
+            inclusion_order(source, "A").unwrap_err(),
+            "module 'A' includes itself"
+        );
+        assert_eq!(
+            inclusion_order(source, "C").unwrap_err(),
+            "unknown module 'Missing'"
+        );
+    }
+}
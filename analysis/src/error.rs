@@ -0,0 +1,75 @@
+//! Errors found by the analyses of this crate.
+
+use crate::ast::Location;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub struct AnalysisError {
+    pub error: AnalysisErrorType,
+    pub location: Location,
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AnalysisErrorType {
+    /// An `extends` clause names something that isn't declared.
+    UnknownBase(String),
+    /// A module includes itself, directly or through other modules.
+    CyclicInclusion(String),
+    /// Two included modules declare a field with the same name and different types.
+    ConflictingField {
+        name: String,
+        owner: String,
+        first_owner: String,
+    },
+    /// A class doesn't provide a member that an included module uses on `self`.
+    MissingSelfMember {
+        class: String,
+        module: String,
+        member: String,
+    },
+    /// A `super()` call in a method of `owner` that no definition after `owner`
+    /// in the method resolution order of `class` answers.
+    UnresolvedSuper {
+        class: String,
+        owner: String,
+        method: String,
+    },
+}
+
+impl fmt::Display for AnalysisErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnalysisErrorType::UnknownBase(name) => write!(f, "unknown module '{}'", name),
+            AnalysisErrorType::CyclicInclusion(name) => write!(f, "module '{}' includes itself", name),
+            AnalysisErrorType::ConflictingField {
+                name,
+                owner,
+                first_owner,
+            } => write!(
+                f,
+                "field '{}' of '{}' conflicts with the field of the same name in '{}'",
+                name, owner, first_owner
+            ),
+            AnalysisErrorType::MissingSelfMember {
+                class,
+                module,
+                member,
+            } => write!(
+                f,
+                "class '{}' doesn't provide '{}', which module '{}' uses on self",
+                class, member, module
+            ),
+            AnalysisErrorType::UnresolvedSuper { class, owner, method } => write!(
+                f,
+                "'super().{}()' in '{}' has no method to call in class '{}'",
+                method, owner, class
+            ),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Exhaustiveness of `match` statements over enums.
+//!
+//! A `match` is over an enum when one of its cases names a variant as
+//! `Enum.Variant`, either in a class pattern `Enum.Variant(...)` or as a value
+//! pattern. A case without a guard handles a variant when every sub-pattern of
+//! the variant matches anything. The match is exhaustive when one of its cases
+//! matches anything, or when every variant is handled.
+//!
+//! Enums are looked up by name among the `enum` declarations of the module,
+//! wherever they are nested.
+
+use crate::ast::{
+    self,
+    visitor::{self, Visitor},
+    Location,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fmt;
+
+/// A variant that a `match` statement over its enum doesn't handle.
+#[derive(Debug, PartialEq)]
+pub struct MissingVariant {
+    /// The location of the `match` statement.
+    pub location: Location,
+    pub enum_name: String,
+    pub variant: String,
+    /// Where the variant is declared.
+    pub variant_location: Location,
+}
+
+impl fmt::Display for MissingVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "non-exhaustive match over '{}': variant '{}' is not handled",
+            self.enum_name, self.variant
+        )
+    }
+}
+
+/// Check every `match` statement in `body`, returning the missing variants in
+/// source order, and in declaration order for each statement.
+pub fn check_module(body: &[ast::Stmt]) -> Vec<MissingVariant> {
+    let mut collector = EnumCollector::default();
+    for stmt in body {
+        collector.visit_stmt(stmt);
+    }
+    let mut checker = MatchChecker {
+        enums: collector.enums,
+        missing: vec![],
+    };
+    for stmt in body {
+        checker.visit_stmt(stmt);
+    }
+    checker.missing
+}
+
+/// The variants of each enum, with their locations.
+type Enums = FxHashMap<String, Vec<(String, Location)>>;
+
+#[derive(Default)]
+struct EnumCollector {
+    enums: Enums,
+}
+
+impl Visitor for EnumCollector {
+    fn visit_stmt(&mut self, node: &ast::Stmt) {
+        if let ast::StmtKind::EnumDef { name, variants, .. } = &node.node {
+            let variants = variants
+                .iter()
+                .map(|variant| (variant.node.name.clone(), variant.location))
+                .collect();
+            self.enums.insert(name.clone(), variants);
+        }
+        visitor::walk_stmt(self, node);
+    }
+}
+
+struct MatchChecker {
+    enums: Enums,
+    missing: Vec<MissingVariant>,
+}
+
+impl MatchChecker {
+    fn check_match(&mut self, location: Location, cases: &[ast::MatchCase]) {
+        let enum_name = match cases.iter().find_map(|case| self.enum_of(&case.pattern)) {
+            Some(enum_name) => enum_name,
+            None => return,
+        };
+        let mut handled = FxHashSet::default();
+        for case in cases {
+            let mut variants = FxHashSet::default();
+            let irrefutable = handled_variants(&case.pattern, enum_name, &mut variants);
+            if case.guard.is_some() {
+                continue;
+            }
+            if irrefutable {
+                return;
+            }
+            handled.extend(variants);
+        }
+        let missing = self.enums[enum_name]
+            .iter()
+            .filter(|(variant, _)| !handled.contains(variant.as_str()))
+            .map(|(variant, variant_location)| MissingVariant {
+                location,
+                enum_name: enum_name.to_owned(),
+                variant: variant.clone(),
+                variant_location: *variant_location,
+            })
+            .collect::<Vec<_>>();
+        self.missing.extend(missing);
+    }
+
+    /// The first enum one of whose variants `pattern` names.
+    fn enum_of<'a>(&self, pattern: &'a ast::Pattern) -> Option<&'a str> {
+        match &pattern.node {
+            ast::PatternKind::MatchClass { cls: value, .. } | ast::PatternKind::MatchValue { value } => {
+                let (enum_name, variant) = variant_reference(value)?;
+                let variants = self.enums.get(enum_name)?;
+                variants.iter().any(|(name, _)| name == variant).then_some(enum_name)
+            }
+            ast::PatternKind::MatchAs { pattern: Some(pattern), .. } => self.enum_of(pattern),
+            ast::PatternKind::MatchOr { patterns } => {
+                patterns.iter().find_map(|pattern| self.enum_of(pattern))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Visitor for MatchChecker {
+    fn visit_stmt(&mut self, node: &ast::Stmt) {
+        if let ast::StmtKind::Match { cases, .. } = &node.node {
+            self.check_match(node.location, cases);
+        }
+        visitor::walk_stmt(self, node);
+    }
+}
+
+/// `Enum.Variant` as the enum and variant names.
+fn variant_reference(expr: &ast::Expr) -> Option<(&str, &str)> {
+    match &expr.node {
+        ast::ExprKind::Attribute { value, attr, .. } => match &value.node {
+            ast::ExprKind::Name { id, .. } => Some((id, attr)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `pattern` matches anything.
+fn is_irrefutable(pattern: &ast::Pattern) -> bool {
+    match &pattern.node {
+        ast::PatternKind::MatchAs { pattern: None, .. } => true,
+        ast::PatternKind::MatchAs { pattern: Some(pattern), .. } => is_irrefutable(pattern),
+        ast::PatternKind::MatchOr { patterns } => patterns.iter().any(is_irrefutable),
+        _ => false,
+    }
+}
+
+/// Add the variants of `enum_name` that `pattern` fully matches to `variants`,
+/// returning whether `pattern` matches anything.
+fn handled_variants<'a>(
+    pattern: &'a ast::Pattern,
+    enum_name: &str,
+    variants: &mut FxHashSet<&'a str>,
+) -> bool {
+    match &pattern.node {
+        ast::PatternKind::MatchAs { pattern: None, .. } => true,
+        ast::PatternKind::MatchAs { pattern: Some(pattern), .. } => {
+            handled_variants(pattern, enum_name, variants)
+        }
+        ast::PatternKind::MatchOr { patterns } => {
+            let mut irrefutable = false;
+            for pattern in patterns {
+                irrefutable |= handled_variants(pattern, enum_name, variants);
+            }
+            irrefutable
+        }
+        ast::PatternKind::MatchClass {
+            cls,
+            patterns,
+            kwd_patterns,
+            ..
+        } => {
+            if let Some((name, variant)) = variant_reference(cls) {
+                if name == enum_name && patterns.iter().chain(kwd_patterns).all(is_irrefutable) {
+                    variants.insert(variant);
+                }
+            }
+            false
+        }
+        ast::PatternKind::MatchValue { value } => {
+            if let Some((name, variant)) = variant_reference(value) {
+                if name == enum_name {
+                    variants.insert(variant);
+                }
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emerald_lang_parser::parser::parse_program;
+
+    fn check(source: &str) -> Vec<MissingVariant> {
+        check_module(&parse_program(source, "<test>").unwrap())
+    }
+
+    const MESSAGE: &str = "\
+enum Message:
+    Ping
+    Data(bytes, int)
+    Login(user: str, password: str)
+";
+
+    #[test]
+    fn test_missing_variants() {
+        let source = format!(
+            "{}
+def handle(message):
+    match message:
+        case Message.Data(payload, 0):
+            pass
+        case Message.Login(user=user) if user:
+            pass
+        case Message.Login(user='root'):
+            pass
+",
+            MESSAGE
+        );
+        insta::assert_debug_snapshot!(check(&source));
+    }
+
+    #[test]
+    fn test_exhaustive_matches() {
+        let source = format!(
+            "{}
+match message:
+    case Message.Ping | Message.Data(_, _):
+        pass
+    case Message.Login(user=user) as login:
+        pass
+match message:
+    case Message.Ping():
+        pass
+    case other:
+        pass
+match message:
+    case Message.Ping:
+        pass
+    case _:
+        pass
+",
+            MESSAGE
+        );
+        assert_eq!(check(&source), vec![]);
+    }
+
+    #[test]
+    fn test_match_over_something_else() {
+        let source = format!(
+            "{}
+match x:
+    case 1 | Color.RED | Message.Unknown:
+        pass
+",
+            MESSAGE
+        );
+        assert_eq!(check(&source), vec![]);
+    }
+
+    #[test]
+    fn test_nested_match_is_checked() {
+        let source = format!(
+            "{}
+class Server:
+    def handle(self, message):
+        match message:
+            case Message.Ping:
+                match message:
+                    case Message.Data(x, y):
+                        pass
+            case _:
+                pass
+",
+            MESSAGE
+        );
+        let missing: Vec<_> = check(&source).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            missing,
+            vec![
+                "non-exhaustive match over 'Message': variant 'Ping' is not handled",
+                "non-exhaustive match over 'Message': variant 'Login' is not handled",
+            ]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! The interfaces a module requires of `self` and of the parameters of its
+//! methods, inferred from attribute accesses in the method bodies.
+//!
+//! In a module like
+//!
+//! ```text
+//! module Predator:
+//!     def eat(organism):
+//!         self.energy += organism.energy
+//! ```
+//!
+//! `eat` requires `self` to have an `energy` member and its `organism` argument
+//! as well. Every class that includes the module, directly or through another
+//! module, has to provide the members its methods use on `self`, as a field or
+//! a method of anything in its inclusion order.
+
+use crate::ast::{
+    self,
+    visitor::{self, Visitor},
+    Location,
+};
+use crate::declarations::{Declaration, DeclarationKind, Declarations};
+use crate::error::{AnalysisError, AnalysisErrorType};
+use crate::layout::declared_fields;
+use rustc_hash::FxHashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemberKind {
+    Attribute,
+    /// A member that is called, like `self.energy()`.
+    Method,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub kind: MemberKind,
+    /// Where the member is first used.
+    pub location: Location,
+}
+
+/// The members used on a value, in order of first use.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Interface {
+    pub members: Vec<Member>,
+}
+
+impl Interface {
+    pub fn get(&self, name: &str) -> Option<&Member> {
+        self.members.iter().find(|member| member.name == name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Record a use. A member that is called anywhere is a method.
+    fn add(&mut self, name: &str, kind: MemberKind, location: Location) {
+        match self.members.iter_mut().find(|member| member.name == name) {
+            Some(member) => {
+                if kind == MemberKind::Method {
+                    member.kind = kind;
+                }
+            }
+            None => self.members.push(Member {
+                name: name.to_owned(),
+                kind,
+                location,
+            }),
+        }
+    }
+
+    fn extend(&mut self, other: &Interface) {
+        for member in &other.members {
+            self.add(&member.name, member.kind, member.location);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodInterface {
+    pub name: String,
+    pub location: Location,
+    pub self_interface: Interface,
+    /// The interface of every parameter other than `self`, in order.
+    pub parameters: Vec<(String, Interface)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleInterface {
+    pub name: String,
+    /// The members used on `self` by any of the methods.
+    pub self_interface: Interface,
+    pub methods: Vec<MethodInterface>,
+}
+
+/// Infer the interfaces of the methods of a class or module.
+pub fn module_interface(declaration: &Declaration) -> ModuleInterface {
+    let mut self_interface = Interface::default();
+    let methods: Vec<_> = methods(declaration)
+        .map(|(name, args, body, location)| {
+            let method = method_interface(name, args, body, location);
+            self_interface.extend(&method.self_interface);
+            method
+        })
+        .collect();
+    ModuleInterface {
+        name: declaration.name.to_owned(),
+        self_interface,
+        methods,
+    }
+}
+
+/// The methods declared directly in the body of `declaration`.
+pub fn methods<'a>(
+    declaration: &Declaration<'a>,
+) -> impl Iterator<Item = (&'a str, &'a ast::Arguments, &'a [ast::Stmt], Location)> {
+    declaration.body.iter().filter_map(|stmt| match &stmt.node {
+        ast::StmtKind::FunctionDef { name, args, body, .. }
+        | ast::StmtKind::AsyncFunctionDef { name, args, body, .. } => {
+            Some((name.as_str(), &**args, body.as_slice(), stmt.location))
+        }
+        _ => None,
+    })
+}
+
+fn method_interface(name: &str, args: &ast::Arguments, body: &[ast::Stmt], location: Location) -> MethodInterface {
+    // `self` may be spelled out as the first parameter, or left implicit.
+    let parameters = args
+        .posonlyargs
+        .iter()
+        .chain(&args.args)
+        .chain(args.vararg.as_deref())
+        .chain(&args.kwonlyargs)
+        .chain(args.kwarg.as_deref())
+        .map(|arg| arg.node.arg.as_str())
+        .filter(|&arg| arg != "self");
+    let mut collector = UseCollector {
+        self_interface: Interface::default(),
+        parameters: parameters.map(|arg| (arg.to_owned(), Interface::default())).collect(),
+    };
+    for stmt in body {
+        collector.visit_stmt(stmt);
+    }
+    MethodInterface {
+        name: name.to_owned(),
+        location,
+        self_interface: collector.self_interface,
+        parameters: collector.parameters,
+    }
+}
+
+struct UseCollector {
+    self_interface: Interface,
+    parameters: Vec<(String, Interface)>,
+}
+
+impl UseCollector {
+    /// Record `expr` if it is an attribute of `self` or of a parameter.
+    fn record(&mut self, expr: &ast::Expr, kind: MemberKind) -> bool {
+        let (id, attr) = match &expr.node {
+            ast::ExprKind::Attribute { value, attr, .. } | ast::ExprKind::OptionalAttribute { value, attr } => {
+                match &value.node {
+                    ast::ExprKind::Name { id, .. } => (id, attr),
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        };
+        let interface = if id == "self" {
+            &mut self.self_interface
+        } else {
+            match self.parameters.iter_mut().find(|(name, _)| name == id) {
+                Some((_, interface)) => interface,
+                None => return false,
+            }
+        };
+        interface.add(attr, kind, expr.location);
+        true
+    }
+}
+
+impl Visitor for UseCollector {
+    fn visit_expr(&mut self, node: &ast::Expr) {
+        if let ast::ExprKind::Call { func, args, keywords } = &node.node {
+            if self.record(func, MemberKind::Method) {
+                for arg in args {
+                    self.visit_expr(arg);
+                }
+                for keyword in keywords {
+                    self.visit_keyword(keyword);
+                }
+                return;
+            }
+        }
+        self.record(node, MemberKind::Attribute);
+        visitor::walk_expr(self, node);
+    }
+}
+
+/// The names of the fields and methods of `name` and everything it includes.
+pub fn provided_members<'a>(declarations: &Declarations<'a>, name: &str) -> Result<FxHashSet<&'a str>, AnalysisError> {
+    let mut provided = FxHashSet::default();
+    for declaration in declarations.inclusion_order(name)? {
+        provided.extend(declared_fields(declaration).map(|(field, _, _)| field));
+        provided.extend(methods(declaration).map(|(method, _, _, _)| method));
+    }
+    Ok(provided)
+}
+
+/// Check that every class in `body` provides the members that the modules it
+/// includes use on `self`. Classes whose inclusion order can't be determined
+/// are skipped; their errors are reported by `layout::module_layouts`.
+pub fn check_module(body: &[ast::Stmt]) -> Vec<AnalysisError> {
+    let declarations = Declarations::collect(body);
+    let mut errors = vec![];
+    for class in declarations.iter().filter(|declaration| declaration.kind == DeclarationKind::Class) {
+        let (order, provided) = match (
+            declarations.inclusion_order(class.name),
+            provided_members(&declarations, class.name),
+        ) {
+            (Ok(order), Ok(provided)) => (order, provided),
+            _ => continue,
+        };
+        for module in order.iter().filter(|declaration| declaration.kind == DeclarationKind::Module) {
+            let interface = module_interface(module);
+            for member in &interface.self_interface.members {
+                if provided.contains(member.name.as_str()) {
+                    continue;
+                }
+                errors.push(AnalysisError {
+                    error: AnalysisErrorType::MissingSelfMember {
+                        class: class.name.to_owned(),
+                        module: module.name.to_owned(),
+                        member: member.name.clone(),
+                    },
+                    location: declarations.inclusion_location(class, module.name),
+                });
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emerald_lang_parser::parser::parse_program;
+
+    #[test]
+    fn test_module_interface() {
+        let source = "\
+module Predator:
+    def eat(organism, *, greedy=False):
+        self.energy += organism.energy
+        organism.die()
+        if greedy or self.hungry():
+            self.digest(organism.size)
+    def digest(self, amount):
+        self.energy += amount
+        xs.map() do x:
+            self.remember(x, amount.unit)
+";
+        let body = parse_program(source, "<test>").unwrap();
+        let declarations = Declarations::collect(&body);
+        insta::assert_debug_snapshot!(module_interface(declarations.get("Predator").unwrap()));
+    }
+
+    #[test]
+    fn test_missing_self_members() {
+        let source = "\
+module Organism:
+    energy: int
+module Predator:
+    def eat(organism):
+        self.energy += organism.energy
+        self.grow(organism.size)
+module Shark extends Predator:
+    def swim(self):
+        self.fins
+class Amoeba extends Organism, Predator:
+    def grow(self, amount):
+        pass
+class Fish extends Organism, Shark:
+    pass
+";
+        let errors: Vec<_> = check_module(&parse_program(source, "<test>").unwrap())
+            .iter()
+            .map(|error| (error.to_string(), error.location.row(), error.location.column()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("class 'Fish' doesn't provide 'grow', which module 'Predator' uses on self".to_owned(), 13, 29),
+                ("class 'Fish' doesn't provide 'fins', which module 'Shark' uses on self".to_owned(), 13, 29),
+            ]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Memory layouts derived from field declarations.
+//!
+//! A field is declared by an annotation without a target expression in the
+//! body of a class or module, like `energy: int`. The layout of a class or
+//! module holds the fields of everything it includes, in inclusion order (see
+//! `Declarations::inclusion_order`), followed by its own. A field that an
+//! earlier module already declared with the same type shares its slot; one with
+//! a different type is an error.
+//!
+//! Fields are laid out in that order without reordering, each aligned to its
+//! type, so the offsets of the fields of an included module don't depend on
+//! what comes after it.
+
+use crate::ast::{self, Location};
+use crate::declarations::{Declaration, Declarations};
+use crate::error::{AnalysisError, AnalysisErrorType};
+
+/// The type of a field, as far as its storage is concerned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    /// `int`, a 64-bit integer.
+    Int,
+    /// `float`, a 64-bit float.
+    Float,
+    /// `bool`, a single byte.
+    Bool,
+    /// `str`, a reference to a string.
+    Str,
+    /// A reference to an instance of the named class.
+    Object(String),
+    /// Any other annotation: a dynamically typed value.
+    Value,
+}
+
+impl FieldType {
+    pub fn from_annotation(annotation: &ast::Expr) -> Self {
+        match &annotation.node {
+            ast::ExprKind::Name { id, .. } => match id.as_str() {
+                "int" => FieldType::Int,
+                "float" => FieldType::Float,
+                "bool" => FieldType::Bool,
+                "str" => FieldType::Str,
+                _ => FieldType::Object(id.clone()),
+            },
+            _ => FieldType::Value,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            FieldType::Bool => 1,
+            FieldType::Int | FieldType::Float | FieldType::Str | FieldType::Object(_) | FieldType::Value => 8,
+        }
+    }
+
+    pub fn align(&self) -> usize {
+        self.size()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub ty: FieldType,
+    /// The byte offset of the field from the start of the instance.
+    pub offset: usize,
+    /// The class or module that declares the field.
+    pub owner: String,
+    pub location: Location,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    pub name: String,
+    pub fields: Vec<Field>,
+    /// The size of an instance in bytes, a multiple of `align`.
+    pub size: usize,
+    pub align: usize,
+}
+
+impl Layout {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    fn add(&mut self, name: &str, ty: FieldType, owner: &str, location: Location) -> Result<(), AnalysisError> {
+        if let Some(field) = self.field(name) {
+            if field.ty == ty {
+                return Ok(());
+            }
+            return Err(AnalysisError {
+                error: AnalysisErrorType::ConflictingField {
+                    name: name.to_owned(),
+                    owner: owner.to_owned(),
+                    first_owner: field.owner.clone(),
+                },
+                location,
+            });
+        }
+        let offset = align_up(self.size, ty.align());
+        self.size = offset + ty.size();
+        self.align = self.align.max(ty.align());
+        self.fields.push(Field {
+            name: name.to_owned(),
+            ty,
+            offset,
+            owner: owner.to_owned(),
+            location,
+        });
+        Ok(())
+    }
+}
+
+/// The fields declared directly in the body of `declaration`, in order.
+pub fn declared_fields<'a>(declaration: &Declaration<'a>) -> impl Iterator<Item = (&'a str, &'a ast::Expr, Location)> {
+    declaration.body.iter().filter_map(|stmt| match &stmt.node {
+        ast::StmtKind::AnnAssign {
+            target, annotation, simple: 1, ..
+        } => match &target.node {
+            ast::ExprKind::Name { id, .. } => Some((id.as_str(), &**annotation, stmt.location)),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// The layout of `name`, or the first error that prevents building it.
+pub fn layout(declarations: &Declarations, name: &str) -> Result<Layout, AnalysisError> {
+    let mut layout = Layout {
+        name: name.to_owned(),
+        fields: vec![],
+        size: 0,
+        align: 1,
+    };
+    for declaration in declarations.inclusion_order(name)? {
+        for (field, annotation, location) in declared_fields(declaration) {
+            let ty = FieldType::from_annotation(annotation);
+            layout.add(field, ty, declaration.name, location)?;
+        }
+    }
+    layout.size = align_up(layout.size, layout.align);
+    Ok(layout)
+}
+
+/// The layouts of every class and module in `body`, in source order, and the
+/// errors that prevented building the others.
+pub fn module_layouts(body: &[ast::Stmt]) -> (Vec<Layout>, Vec<AnalysisError>) {
+    let declarations = Declarations::collect(body);
+    let mut layouts = vec![];
+    let mut errors = vec![];
+    for declaration in declarations.iter() {
+        match layout(&declarations, declaration.name) {
+            Ok(layout) => layouts.push(layout),
+            Err(error) => {
+                // An error in an included module is found again for every module including it.
+                if !errors.contains(&error) {
+                    errors.push(error);
+                }
+            }
+        }
+    }
+    (layouts, errors)
+}
+
+fn align_up(offset: usize, align: usize) -> usize {
+    offset.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emerald_lang_parser::parser::parse_program;
+
+    #[test]
+    fn test_module_layouts() {
+        let source = "\
+module Organism:
+    energy: int
+    alive: bool
+    def grow(self):
+        self.energy += 1
+
+module Predator:
+    hungry: bool
+    prey: Organism
+    energy: int
+
+class Amoeba extends Organism, Predator:
+    size: float
+    tags: list[str]
+";
+        let (layouts, errors) = module_layouts(&parse_program(source, "<test>").unwrap());
+        assert_eq!(errors, vec![]);
+        insta::assert_debug_snapshot!(layouts);
+    }
+
+    #[test]
+    fn test_conflicting_fields() {
+        let source = "\
+module Organism:
+    energy: int
+module Battery:
+    energy: float
+class Robot extends Organism, Battery:
+    pass
+";
+        let (layouts, errors) = module_layouts(&parse_program(source, "<test>").unwrap());
+        assert_eq!(layouts.len(), 2);
+        let errors: Vec<_> = errors.iter().map(|error| (error.to_string(), error.location)).collect();
+        assert_eq!(
+            errors,
+            vec![(
+                "field 'energy' of 'Battery' conflicts with the field of the same name in 'Organism'"
+                    .to_owned(),
+                Location::new(4, 4)
+            )]
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! Static analyses over a parsed Emerald module.
+//!
+//! Unlike the parser's validator, which looks at one statement at a time, these
+//! need to know about declarations elsewhere in the module.
+
+pub use emerald_lang_ast as ast;
+
+pub mod declarations;
+pub mod error;
+pub mod exhaustiveness;
+pub mod interface;
+pub mod layout;
+pub mod mro;
@@ -0,0 +1,389 @@
+//! Method resolution: which definition a method call on an instance of a class
+//! runs, and where `super()` calls continue.
+//!
+//! The method resolution order of a class is its inclusion order reversed: the
+//! class itself comes first, and of the modules in its `extends` clause the one
+//! included last comes before the ones included earlier. A module included by
+//! another module comes after it. A call runs the first definition in that
+//! order, so when two included modules define the same method, the one
+//! included last wins.
+//!
+//! Inside a method owned by a class or module, `super().name()` runs the first
+//! definition of `name` that comes after the owner in the method resolution
+//! order of the class of `self`. As with Python, what that is depends on the
+//! class the module is included in, not on the module alone.
+//!
+//! The method table of a class is plain owned data, so the interpreter, the
+//! type checker and editor tooling can compute it once and share it.
+
+use crate::ast::{
+    self,
+    visitor::{self, Visitor},
+    Location,
+};
+use crate::declarations::{merge_inclusion_orders, Declaration, DeclarationKind, Declarations};
+use crate::error::{AnalysisError, AnalysisErrorType};
+use crate::interface::methods;
+use std::fmt;
+
+/// A definition of a method in a class or module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodDefinition {
+    /// The class or module whose body defines the method.
+    pub owner: String,
+    pub location: Location,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Method {
+    pub name: String,
+    /// Every definition of the method, in method resolution order. The first
+    /// is the one that a call runs; the others are reachable through `super()`.
+    pub definitions: Vec<MethodDefinition>,
+}
+
+impl Method {
+    pub fn resolved(&self) -> &MethodDefinition {
+        &self.definitions[0]
+    }
+}
+
+/// Two modules that define the same method without either including the
+/// other, where the class doesn't define it itself. The module included last
+/// wins, but that is easily an accident.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodConflict {
+    pub class: String,
+    pub method: String,
+    /// The module whose definition is used.
+    pub winner: String,
+    /// The module whose definition is hidden.
+    pub shadowed: String,
+    /// The base in the `extends` clause of the class through which the
+    /// winning module is included.
+    pub location: Location,
+}
+
+impl fmt::Display for MethodConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "method '{}' of '{}' hides the method of the same name in '{}' in class '{}'",
+            self.method, self.winner, self.shadowed, self.class
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodTable {
+    pub class: String,
+    /// The class and everything it includes, in method resolution order.
+    pub mro: Vec<String>,
+    /// The methods, ordered by where their resolved definition comes in method
+    /// resolution order.
+    pub methods: Vec<Method>,
+    pub conflicts: Vec<MethodConflict>,
+}
+
+impl MethodTable {
+    pub fn method(&self, name: &str) -> Option<&Method> {
+        self.methods.iter().find(|method| method.name == name)
+    }
+
+    /// The definition that a call to `name` on an instance of the class runs.
+    pub fn lookup(&self, name: &str) -> Option<&MethodDefinition> {
+        self.method(name).map(Method::resolved)
+    }
+
+    /// The definition that `super().name()` runs when called from a method
+    /// owned by `owner`: the first one after `owner` in method resolution order.
+    pub fn resolve_super(&self, owner: &str, name: &str) -> Option<&MethodDefinition> {
+        let after = super_order(&self.mro, owner)?;
+        self.method(name)?
+            .definitions
+            .iter()
+            .find(|definition| after.contains(&definition.owner))
+    }
+}
+
+/// The method resolution order of `class` from those of its bases, in the
+/// order of its `extends` clause: its inclusion order reversed.
+pub fn resolution_order<T, B>(class: T, base_mros: impl IntoIterator<Item = B>) -> Vec<T>
+where
+    T: Copy + PartialEq,
+    B: IntoIterator<Item = T>,
+    B::IntoIter: DoubleEndedIterator,
+{
+    let base_orders = base_mros.into_iter().map(|mro| mro.into_iter().rev());
+    let mut mro = merge_inclusion_orders(class, base_orders);
+    mro.reverse();
+    mro
+}
+
+/// Where `super()` continues looking in `mro` from a method owned by `owner`:
+/// everything after it, or nothing when `owner` isn't in it.
+pub fn super_order<'m, T: PartialEq<O>, O: ?Sized>(mro: &'m [T], owner: &O) -> Option<&'m [T]> {
+    let position = mro.iter().position(|included| included == owner)?;
+    Some(&mro[position + 1..])
+}
+
+/// The method table of `name`, or the error that prevents determining its
+/// method resolution order.
+pub fn method_table(declarations: &Declarations, name: &str) -> Result<MethodTable, AnalysisError> {
+    let order = declarations.inclusion_order(name)?;
+    let mut methods: Vec<Method> = vec![];
+    for declaration in order.iter().rev() {
+        for (method, _, _, location) in methods_of(declaration) {
+            let definition = MethodDefinition {
+                owner: declaration.name.to_owned(),
+                location,
+            };
+            match methods.iter_mut().find(|existing| existing.name == method) {
+                Some(existing) => existing.definitions.push(definition),
+                None => methods.push(Method {
+                    name: method.to_owned(),
+                    definitions: vec![definition],
+                }),
+            }
+        }
+    }
+
+    let mut conflicts = vec![];
+    if let Some(class) = declarations.get(name) {
+        for method in &methods {
+            let winner = &method.resolved().owner;
+            if winner == name {
+                continue;
+            }
+            for shadowed in &method.definitions[1..] {
+                if declarations.includes(winner, &shadowed.owner) {
+                    continue;
+                }
+                conflicts.push(MethodConflict {
+                    class: name.to_owned(),
+                    method: method.name.clone(),
+                    winner: winner.clone(),
+                    shadowed: shadowed.owner.clone(),
+                    location: declarations.inclusion_location(class, winner),
+                });
+            }
+        }
+    }
+
+    Ok(MethodTable {
+        class: name.to_owned(),
+        mro: order.iter().rev().map(|declaration| declaration.name.to_owned()).collect(),
+        methods,
+        conflicts,
+    })
+}
+
+/// The methods of `declaration` in source order, with a later definition of
+/// a name replacing an earlier one.
+fn methods_of<'a>(
+    declaration: &Declaration<'a>,
+) -> Vec<(&'a str, &'a ast::Arguments, &'a [ast::Stmt], Location)> {
+    let mut defined: Vec<(&str, _, _, _)> = vec![];
+    for method in methods(declaration) {
+        match defined.iter_mut().find(|(name, _, _, _)| *name == method.0) {
+            Some(existing) => *existing = method,
+            None => defined.push(method),
+        }
+    }
+    defined
+}
+
+/// The method tables of every class in `body`, in source order, and the
+/// `super()` calls that don't resolve in one of them. Classes whose inclusion
+/// order can't be determined are skipped; their errors are reported by
+/// `layout::module_layouts`.
+pub fn check_module(body: &[ast::Stmt]) -> (Vec<MethodTable>, Vec<AnalysisError>) {
+    let declarations = Declarations::collect(body);
+    let mut tables = vec![];
+    let mut errors = vec![];
+    for class in declarations.iter().filter(|declaration| declaration.kind == DeclarationKind::Class) {
+        let table = match method_table(&declarations, class.name) {
+            Ok(table) => table,
+            Err(_) => continue,
+        };
+        for owner in &table.mro {
+            let declaration = match declarations.get(owner) {
+                Some(declaration) => declaration,
+                None => continue,
+            };
+            for (_, _, body, _) in methods_of(declaration) {
+                let mut calls = SuperCalls::default();
+                for stmt in body {
+                    calls.visit_stmt(stmt);
+                }
+                for (method, location) in calls.calls {
+                    if table.resolve_super(owner, &method).is_some() {
+                        continue;
+                    }
+                    let error = AnalysisError {
+                        error: AnalysisErrorType::UnresolvedSuper {
+                            class: class.name.to_owned(),
+                            owner: owner.clone(),
+                            method,
+                        },
+                        location,
+                    };
+                    if !errors.contains(&error) {
+                        errors.push(error);
+                    }
+                }
+            }
+        }
+        tables.push(table);
+    }
+    (tables, errors)
+}
+
+/// The methods called on `super()`, like `eat` in `super().eat(prey)`.
+#[derive(Default)]
+struct SuperCalls {
+    calls: Vec<(String, Location)>,
+}
+
+impl Visitor for SuperCalls {
+    fn visit_expr(&mut self, node: &ast::Expr) {
+        if let ast::ExprKind::Attribute { value, attr, .. } = &node.node {
+            if let ast::ExprKind::Call { func, args, keywords } = &value.node {
+                if args.is_empty() && keywords.is_empty() {
+                    if let ast::ExprKind::Name { id, .. } = &func.node {
+                        if id == "super" {
+                            self.calls.push((attr.clone(), node.location));
+                        }
+                    }
+                }
+            }
+        }
+        visitor::walk_expr(self, node);
+    }
+
+    // A nested function or class has methods of its own.
+    fn visit_stmt(&mut self, node: &ast::Stmt) {
+        if !matches!(
+            node.node,
+            ast::StmtKind::FunctionDef { .. }
+                | ast::StmtKind::AsyncFunctionDef { .. }
+                | ast::StmtKind::ClassDef { .. }
+                | ast::StmtKind::ModuleDef { .. }
+        ) {
+            visitor::walk_stmt(self, node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emerald_lang_parser::parser::parse_program;
+
+    const SOURCE: &str = "\
+module Organism:
+    def eat(self, food):
+        pass
+    def grow(self):
+        pass
+module Predator extends Organism:
+    def eat(self, prey):
+        super().eat(prey)
+module Swimmer:
+    def move(self):
+        pass
+    def grow(self):
+        pass
+module Walker:
+    def move(self):
+        pass
+class Shark extends Predator, Walker, Swimmer:
+    def grow(self):
+        super().grow()
+";
+
+    #[test]
+    fn test_method_table() {
+        let body = parse_program(SOURCE, "<test>").unwrap();
+        let declarations = Declarations::collect(&body);
+        insta::assert_debug_snapshot!(method_table(&declarations, "Shark").unwrap());
+    }
+
+    #[test]
+    fn test_resolve_super() {
+        let body = parse_program(SOURCE, "<test>").unwrap();
+        let declarations = Declarations::collect(&body);
+        let table = method_table(&declarations, "Shark").unwrap();
+        let owner = |definition: Option<&MethodDefinition>| definition.map(|definition| definition.owner.clone());
+        assert_eq!(owner(table.lookup("eat")), Some("Predator".to_owned()));
+        assert_eq!(owner(table.lookup("move")), Some("Swimmer".to_owned()));
+        assert_eq!(owner(table.resolve_super("Shark", "grow")), Some("Swimmer".to_owned()));
+        assert_eq!(owner(table.resolve_super("Swimmer", "grow")), Some("Organism".to_owned()));
+        assert_eq!(owner(table.resolve_super("Predator", "eat")), Some("Organism".to_owned()));
+        assert_eq!(owner(table.resolve_super("Organism", "eat")), None);
+        assert_eq!(owner(table.resolve_super("Walker", "move")), None);
+    }
+
+    #[test]
+    fn test_resolution_order_from_base_mros() {
+        let body = parse_program(SOURCE, "<test>").unwrap();
+        let declarations = Declarations::collect(&body);
+        let table = method_table(&declarations, "Shark").unwrap();
+        let organism = resolution_order("Organism", Vec::<Vec<&str>>::new());
+        let predator = resolution_order("Predator", [organism]);
+        let walker = resolution_order("Walker", Vec::<Vec<&str>>::new());
+        let swimmer = resolution_order("Swimmer", Vec::<Vec<&str>>::new());
+        assert_eq!(resolution_order("Shark", [predator, walker, swimmer]), table.mro);
+        assert_eq!(super_order(&table.mro, "Swimmer"), Some(&table.mro[2..]));
+        assert_eq!(super_order(&table.mro, "Fish"), None);
+    }
+
+    #[test]
+    fn test_method_conflicts_and_unresolved_super() {
+        let source = "\
+module Walker:
+    def move(self):
+        pass
+module Swimmer:
+    def move(self):
+        super().move()
+        super().dive()
+class Duck extends Walker, Swimmer:
+    pass
+class Penguin extends Swimmer, Walker:
+    def move(self):
+        pass
+";
+        let (tables, errors) = check_module(&parse_program(source, "<test>").unwrap());
+        let conflicts: Vec<_> = tables
+            .iter()
+            .flat_map(|table| &table.conflicts)
+            .map(|conflict| (conflict.to_string(), conflict.location))
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![(
+                "method 'move' of 'Swimmer' hides the method of the same name in 'Walker' in class 'Duck'".to_owned(),
+                Location::new(8, 27)
+            )]
+        );
+        let errors: Vec<_> = errors.iter().map(|error| (error.to_string(), error.location)).collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "'super().dive()' in 'Swimmer' has no method to call in class 'Duck'".to_owned(),
+                    Location::new(7, 8)
+                ),
+                (
+                    "'super().move()' in 'Swimmer' has no method to call in class 'Penguin'".to_owned(),
+                    Location::new(6, 8)
+                ),
+                (
+                    "'super().dive()' in 'Swimmer' has no method to call in class 'Penguin'".to_owned(),
+                    Location::new(7, 8)
+                ),
+            ]
+        );
+    }
+}
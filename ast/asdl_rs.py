@@ -340,10 +340,12 @@ class FoldImplVisitor(TypeInfoEmitVisitor):
             enumname += "Kind"
         self.emit("match node {", depth + 1)
         for cons in sum.types:
-            fields_pattern = self.make_pattern(cons.fields)
-            self.emit(
-                f"{enumname}::{cons.name} {{ {fields_pattern} }} => {{", depth + 2
-            )
+            if cons.fields:
+                fields_pattern = self.make_pattern(cons.fields)
+                pattern = f"{enumname}::{cons.name} {{ {fields_pattern} }}"
+            else:
+                pattern = f"{enumname}::{cons.name}"
+            self.emit(f"{pattern} => {{", depth + 2)
             self.gen_construction(f"{enumname}::{cons.name}", cons.fields, depth + 3)
             self.emit("}", depth + 2)
         self.emit("}", depth + 1)
@@ -386,6 +388,9 @@ class FoldImplVisitor(TypeInfoEmitVisitor):
         return ",".join(rust_field(f.name) for f in fields)
 
     def gen_construction(self, cons_path, fields, depth):
+        if not fields:
+            self.emit(f"Ok({cons_path})", depth)
+            return
         self.emit(f"Ok({cons_path} {{", depth)
         for field in fields:
             name = rust_field(field.name)
@@ -405,6 +410,117 @@ class FoldModuleVisitor(TypeInfoEmitVisitor):
         self.emit("}", depth)
 
 
+class VisitorTraitDefVisitor(TypeInfoEmitVisitor):
+    def visitModule(self, mod, depth):
+        self.emit("pub trait Visitor<U = ()> {", depth)
+        for dfn in mod.dfns:
+            self.visit(dfn, depth + 1)
+        self.emit("}", depth)
+
+    def visitType(self, type, depth):
+        name = type.name
+        (apply_u,) = self.get_generics(name, "U")
+        typename = get_rust_type(name)
+        self.emit(f"fn visit_{name}(&mut self, node: &{typename}{apply_u}) {{", depth)
+        self.emit(f"walk_{name}(self, node)", depth + 1)
+        self.emit("}", depth)
+
+
+class VisitorWalkVisitor(TypeInfoEmitVisitor):
+    def visitModule(self, mod, depth):
+        for dfn in mod.dfns:
+            self.visit(dfn, depth)
+
+    def visitType(self, type, depth=0):
+        self.visit(type.value, type.name, depth)
+
+    def visitable_fields(self, fields):
+        return [f for f in fields if f.type not in asdl.builtin_types]
+
+    def emit_walk_header(self, name, depth):
+        (apply_u,) = self.get_generics(name, "U")
+        typename = get_rust_type(name)
+        self.emit(
+            f"pub fn walk_{name}<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &{typename}{apply_u}) {{",
+            depth,
+        )
+
+    def visitSum(self, sum, name, depth):
+        self.emit_walk_header(name, depth)
+        if is_simple(sum):
+            self.emit("}", depth)
+            return
+        enumname = get_rust_type(name)
+        subject = "node"
+        if sum.attributes:
+            enumname += "Kind"
+            subject = "&node.node"
+        self.emit(f"match {subject} {{", depth + 1)
+        for cons in sum.types:
+            fields = self.visitable_fields(cons.fields)
+            if not cons.fields:
+                pattern = f"{enumname}::{cons.name}"
+            elif not fields:
+                pattern = f"{enumname}::{cons.name} {{ .. }}"
+            else:
+                rest = ", .." if len(fields) < len(cons.fields) else ""
+                pattern = f"{enumname}::{cons.name} {{ {self.make_pattern(fields)}{rest} }}"
+            self.emit(f"{pattern} => {{", depth + 2)
+            self.gen_walk_fields(fields, depth + 3)
+            self.emit("}", depth + 2)
+        self.emit("}", depth + 1)
+        self.emit("}", depth)
+
+    def visitProduct(self, product, name, depth):
+        self.emit_walk_header(name, depth)
+        structname = get_rust_type(name)
+        subject = "node"
+        if product.attributes:
+            structname += "Data"
+            subject = "&node.node"
+        fields = self.visitable_fields(product.fields)
+        if fields:
+            rest = ", .." if len(fields) < len(product.fields) else ""
+            self.emit(
+                f"let {structname} {{ {self.make_pattern(fields)}{rest} }} = {subject};",
+                depth + 1,
+            )
+            self.gen_walk_fields(fields, depth + 1)
+        self.emit("}", depth)
+
+    def make_pattern(self, fields):
+        return ", ".join(rust_field(f.name) for f in fields)
+
+    def gen_walk_fields(self, fields, depth):
+        for field in fields:
+            name = rust_field(field.name)
+            if field.seq:
+                self.emit(f"for node in {name} {{", depth)
+                self.gen_walk_value(field, "node", depth + 1)
+                self.emit("}", depth)
+            else:
+                self.gen_walk_value(field, name, depth)
+
+    def gen_walk_value(self, field, value, depth):
+        if field.opt:
+            self.emit(f"if let Some(node) = {value} {{", depth)
+            self.emit(f"visitor.visit_{field.type}(node);", depth + 1)
+            self.emit("}", depth)
+        else:
+            self.emit(f"visitor.visit_{field.type}({value});", depth)
+
+
+class VisitorModuleVisitor(TypeInfoEmitVisitor):
+    def visitModule(self, mod):
+        depth = 0
+        self.emit('#[cfg(feature = "visitor")]', depth)
+        self.emit("pub mod visitor {", depth)
+        self.emit("use super::*;", depth + 1)
+        VisitorTraitDefVisitor(self.file, self.typeinfo).visit(mod, depth + 1)
+        VisitorWalkVisitor(self.file, self.typeinfo).visit(mod, depth + 1)
+        self.emit("}", depth)
+
+
 class ClassDefVisitor(EmitVisitor):
     def visitModule(self, mod):
         for dfn in mod.dfns:
@@ -670,7 +786,11 @@ def write_ast_def(mod, typeinfo, f):
         )
     )
 
-    c = ChainOfVisitors(StructVisitor(f, typeinfo), FoldModuleVisitor(f, typeinfo))
+    c = ChainOfVisitors(
+        StructVisitor(f, typeinfo),
+        FoldModuleVisitor(f, typeinfo),
+        VisitorModuleVisitor(f, typeinfo),
+    )
     c.visit(mod)
 
 
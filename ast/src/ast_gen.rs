@@ -1,4 +1,4 @@
-// File automatically generated by asdl_rs.py.
+// File automatically generated by ast/asdl_rs.py.
 
 #![allow(clippy::derive_partial_eq_without_eq)]
 
@@ -66,6 +66,17 @@ pub enum StmtKind<U = ()> {
         body: Vec<Stmt<U>>,
         decorator_list: Vec<Expr<U>>,
     },
+    ModuleDef {
+        name: Ident,
+        bases: Vec<Expr<U>>,
+        body: Vec<Stmt<U>>,
+        decorator_list: Vec<Expr<U>>,
+    },
+    EnumDef {
+        name: Ident,
+        variants: Vec<Variant<U>>,
+        decorator_list: Vec<Expr<U>>,
+    },
     Return {
         value: Option<Box<Expr<U>>>,
     },
@@ -189,7 +200,9 @@ pub enum ExprKind<U = ()> {
     DoBlock {
         args: Box<Arguments<U>>,
         body: Vec<Stmt<U>>,
+        returns: Option<Box<Expr<U>>>,
         chain: Option<Box<Expr<U>>>,
+        is_async: usize,
     },
     EndOfBlockMarker,
     IfExp {
@@ -252,6 +265,23 @@ pub enum ExprKind<U = ()> {
         value: Constant,
         kind: Option<String>,
     },
+    OptionalAttribute {
+        value: Box<Expr<U>>,
+        attr: Ident,
+    },
+    OptionalSubscript {
+        value: Box<Expr<U>>,
+        slice: Box<Expr<U>>,
+    },
+    Range {
+        lower: Box<Expr<U>>,
+        upper: Box<Expr<U>>,
+        inclusive: usize,
+    },
+    Pipe {
+        value: Box<Expr<U>>,
+        func: Box<Expr<U>>,
+    },
     Attribute {
         value: Box<Expr<U>>,
         attr: Ident,
@@ -297,6 +327,7 @@ pub enum ExprContext {
 pub enum Boolop {
     And,
     Or,
+    Coalesce,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -395,6 +426,14 @@ pub struct Withitem<U = ()> {
     pub optional_vars: Option<Box<Expr<U>>>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariantData<U = ()> {
+    pub name: Ident,
+    pub fields: Vec<Expr<U>>,
+    pub field_names: Vec<Ident>,
+}
+pub type Variant<U = ()> = Located<VariantData<U>, U>;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct MatchCase<U = ()> {
     pub pattern: Box<Pattern<U>>,
@@ -499,6 +538,9 @@ pub mod fold {
             fn fold_withitem(&mut self, node: Withitem<U>) -> Result<Withitem<Self::TargetU>, Self::Error> {
                 fold_withitem(self, node)
             }
+            fn fold_variant(&mut self, node: Variant<U>) -> Result<Variant<Self::TargetU>, Self::Error> {
+                fold_variant(self, node)
+            }
             fn fold_match_case(&mut self, node: MatchCase<U>) -> Result<MatchCase<Self::TargetU>, Self::Error> {
                 fold_match_case(self, node)
             }
@@ -582,6 +624,21 @@ pub mod fold {
                     decorator_list: Foldable::fold(decorator_list, folder)?,
                 })
             }
+            StmtKind::ModuleDef { name,bases,body,decorator_list } => {
+                Ok(StmtKind::ModuleDef {
+                    name: Foldable::fold(name, folder)?,
+                    bases: Foldable::fold(bases, folder)?,
+                    body: Foldable::fold(body, folder)?,
+                    decorator_list: Foldable::fold(decorator_list, folder)?,
+                })
+            }
+            StmtKind::EnumDef { name,variants,decorator_list } => {
+                Ok(StmtKind::EnumDef {
+                    name: Foldable::fold(name, folder)?,
+                    variants: Foldable::fold(variants, folder)?,
+                    decorator_list: Foldable::fold(decorator_list, folder)?,
+                })
+            }
             StmtKind::Return { value } => {
                 Ok(StmtKind::Return {
                     value: Foldable::fold(value, folder)?,
@@ -713,17 +770,14 @@ pub mod fold {
                     value: Foldable::fold(value, folder)?,
                 })
             }
-            StmtKind::Pass {  } => {
-                Ok(StmtKind::Pass {
-                })
+            StmtKind::Pass => {
+                Ok(StmtKind::Pass)
             }
-            StmtKind::Break {  } => {
-                Ok(StmtKind::Break {
-                })
+            StmtKind::Break => {
+                Ok(StmtKind::Break)
             }
-            StmtKind::Continue {  } => {
-                Ok(StmtKind::Continue {
-                })
+            StmtKind::Continue => {
+                Ok(StmtKind::Continue)
             }
         }
     })
@@ -768,16 +822,17 @@ pub mod fold {
                     body: Foldable::fold(body, folder)?,
                 })
             }
-            ExprKind::DoBlock { args,body,chain } => {
+            ExprKind::DoBlock { args,body,returns,chain,is_async } => {
                 Ok(ExprKind::DoBlock {
                     args: Foldable::fold(args, folder)?,
                     body: Foldable::fold(body, folder)?,
+                    returns: Foldable::fold(returns, folder)?,
                     chain: Foldable::fold(chain, folder)?,
+                    is_async: Foldable::fold(is_async, folder)?,
                 })
             }
-            ExprKind::EndOfBlockMarker {  } => {
-                Ok(ExprKind::EndOfBlockMarker {
-                })
+            ExprKind::EndOfBlockMarker => {
+                Ok(ExprKind::EndOfBlockMarker)
             }
             ExprKind::IfExp { test,body,orelse } => {
                 Ok(ExprKind::IfExp {
@@ -869,6 +924,31 @@ pub mod fold {
                     kind: Foldable::fold(kind, folder)?,
                 })
             }
+            ExprKind::OptionalAttribute { value,attr } => {
+                Ok(ExprKind::OptionalAttribute {
+                    value: Foldable::fold(value, folder)?,
+                    attr: Foldable::fold(attr, folder)?,
+                })
+            }
+            ExprKind::OptionalSubscript { value,slice } => {
+                Ok(ExprKind::OptionalSubscript {
+                    value: Foldable::fold(value, folder)?,
+                    slice: Foldable::fold(slice, folder)?,
+                })
+            }
+            ExprKind::Range { lower,upper,inclusive } => {
+                Ok(ExprKind::Range {
+                    lower: Foldable::fold(lower, folder)?,
+                    upper: Foldable::fold(upper, folder)?,
+                    inclusive: Foldable::fold(inclusive, folder)?,
+                })
+            }
+            ExprKind::Pipe { value,func } => {
+                Ok(ExprKind::Pipe {
+                    value: Foldable::fold(value, folder)?,
+                    func: Foldable::fold(func, folder)?,
+                })
+            }
             ExprKind::Attribute { value,attr,ctx } => {
                 Ok(ExprKind::Attribute {
                     value: Foldable::fold(value, folder)?,
@@ -925,17 +1005,14 @@ pub mod fold {
     }
     pub fn fold_expr_context<U, F: Fold<U> + ?Sized>(#[allow(unused)] folder: &mut F, node: ExprContext) -> Result<ExprContext, F::Error> {
         match node {
-            ExprContext::Load {  } => {
-                Ok(ExprContext::Load {
-                })
+            ExprContext::Load => {
+                Ok(ExprContext::Load)
             }
-            ExprContext::Store {  } => {
-                Ok(ExprContext::Store {
-                })
+            ExprContext::Store => {
+                Ok(ExprContext::Store)
             }
-            ExprContext::Del {  } => {
-                Ok(ExprContext::Del {
-                })
+            ExprContext::Del => {
+                Ok(ExprContext::Del)
             }
         }
     }
@@ -947,13 +1024,14 @@ pub mod fold {
     }
     pub fn fold_boolop<U, F: Fold<U> + ?Sized>(#[allow(unused)] folder: &mut F, node: Boolop) -> Result<Boolop, F::Error> {
         match node {
-            Boolop::And {  } => {
-                Ok(Boolop::And {
-                })
+            Boolop::And => {
+                Ok(Boolop::And)
             }
-            Boolop::Or {  } => {
-                Ok(Boolop::Or {
-                })
+            Boolop::Or => {
+                Ok(Boolop::Or)
+            }
+            Boolop::Coalesce => {
+                Ok(Boolop::Coalesce)
             }
         }
     }
@@ -965,57 +1043,44 @@ pub mod fold {
     }
     pub fn fold_operator<U, F: Fold<U> + ?Sized>(#[allow(unused)] folder: &mut F, node: Operator) -> Result<Operator, F::Error> {
         match node {
-            Operator::Add {  } => {
-                Ok(Operator::Add {
-                })
+            Operator::Add => {
+                Ok(Operator::Add)
             }
-            Operator::Sub {  } => {
-                Ok(Operator::Sub {
-                })
+            Operator::Sub => {
+                Ok(Operator::Sub)
             }
-            Operator::Mult {  } => {
-                Ok(Operator::Mult {
-                })
+            Operator::Mult => {
+                Ok(Operator::Mult)
             }
-            Operator::MatMult {  } => {
-                Ok(Operator::MatMult {
-                })
+            Operator::MatMult => {
+                Ok(Operator::MatMult)
             }
-            Operator::Div {  } => {
-                Ok(Operator::Div {
-                })
+            Operator::Div => {
+                Ok(Operator::Div)
             }
-            Operator::Mod {  } => {
-                Ok(Operator::Mod {
-                })
+            Operator::Mod => {
+                Ok(Operator::Mod)
             }
-            Operator::Pow {  } => {
-                Ok(Operator::Pow {
-                })
+            Operator::Pow => {
+                Ok(Operator::Pow)
             }
-            Operator::LShift {  } => {
-                Ok(Operator::LShift {
-                })
+            Operator::LShift => {
+                Ok(Operator::LShift)
             }
-            Operator::RShift {  } => {
-                Ok(Operator::RShift {
-                })
+            Operator::RShift => {
+                Ok(Operator::RShift)
             }
-            Operator::BitOr {  } => {
-                Ok(Operator::BitOr {
-                })
+            Operator::BitOr => {
+                Ok(Operator::BitOr)
             }
-            Operator::BitXor {  } => {
-                Ok(Operator::BitXor {
-                })
+            Operator::BitXor => {
+                Ok(Operator::BitXor)
             }
-            Operator::BitAnd {  } => {
-                Ok(Operator::BitAnd {
-                })
+            Operator::BitAnd => {
+                Ok(Operator::BitAnd)
             }
-            Operator::FloorDiv {  } => {
-                Ok(Operator::FloorDiv {
-                })
+            Operator::FloorDiv => {
+                Ok(Operator::FloorDiv)
             }
         }
     }
@@ -1027,21 +1092,17 @@ pub mod fold {
     }
     pub fn fold_unaryop<U, F: Fold<U> + ?Sized>(#[allow(unused)] folder: &mut F, node: Unaryop) -> Result<Unaryop, F::Error> {
         match node {
-            Unaryop::Invert {  } => {
-                Ok(Unaryop::Invert {
-                })
+            Unaryop::Invert => {
+                Ok(Unaryop::Invert)
             }
-            Unaryop::Not {  } => {
-                Ok(Unaryop::Not {
-                })
+            Unaryop::Not => {
+                Ok(Unaryop::Not)
             }
-            Unaryop::UAdd {  } => {
-                Ok(Unaryop::UAdd {
-                })
+            Unaryop::UAdd => {
+                Ok(Unaryop::UAdd)
             }
-            Unaryop::USub {  } => {
-                Ok(Unaryop::USub {
-                })
+            Unaryop::USub => {
+                Ok(Unaryop::USub)
             }
         }
     }
@@ -1053,45 +1114,35 @@ pub mod fold {
     }
     pub fn fold_cmpop<U, F: Fold<U> + ?Sized>(#[allow(unused)] folder: &mut F, node: Cmpop) -> Result<Cmpop, F::Error> {
         match node {
-            Cmpop::Eq {  } => {
-                Ok(Cmpop::Eq {
-                })
+            Cmpop::Eq => {
+                Ok(Cmpop::Eq)
             }
-            Cmpop::NotEq {  } => {
-                Ok(Cmpop::NotEq {
-                })
+            Cmpop::NotEq => {
+                Ok(Cmpop::NotEq)
             }
-            Cmpop::Lt {  } => {
-                Ok(Cmpop::Lt {
-                })
+            Cmpop::Lt => {
+                Ok(Cmpop::Lt)
             }
-            Cmpop::LtE {  } => {
-                Ok(Cmpop::LtE {
-                })
+            Cmpop::LtE => {
+                Ok(Cmpop::LtE)
             }
-            Cmpop::Gt {  } => {
-                Ok(Cmpop::Gt {
-                })
+            Cmpop::Gt => {
+                Ok(Cmpop::Gt)
             }
-            Cmpop::GtE {  } => {
-                Ok(Cmpop::GtE {
-                })
+            Cmpop::GtE => {
+                Ok(Cmpop::GtE)
             }
-            Cmpop::Is {  } => {
-                Ok(Cmpop::Is {
-                })
+            Cmpop::Is => {
+                Ok(Cmpop::Is)
             }
-            Cmpop::IsNot {  } => {
-                Ok(Cmpop::IsNot {
-                })
+            Cmpop::IsNot => {
+                Ok(Cmpop::IsNot)
             }
-            Cmpop::In {  } => {
-                Ok(Cmpop::In {
-                })
+            Cmpop::In => {
+                Ok(Cmpop::In)
             }
-            Cmpop::NotIn {  } => {
-                Ok(Cmpop::NotIn {
-                })
+            Cmpop::NotIn => {
+                Ok(Cmpop::NotIn)
             }
         }
     }
@@ -1206,6 +1257,22 @@ pub mod fold {
             optional_vars: Foldable::fold(optional_vars, folder)?,
         })
     }
+    impl<T, U> Foldable<T, U> for Variant<T> {
+        type Mapped = Variant<U>;
+        fn fold<F: Fold<T, TargetU = U> + ?Sized>(self, folder: &mut F) -> Result<Self::Mapped, F::Error> {
+            folder.fold_variant(self)
+        }
+    }
+    pub fn fold_variant<U, F: Fold<U> + ?Sized>(#[allow(unused)] folder: &mut F, node: Variant<U>) -> Result<Variant<F::TargetU>, F::Error> {
+    fold_located(folder, node, |folder, node| {
+        let VariantData { name,fields,field_names } = node;
+        Ok(VariantData {
+            name: Foldable::fold(name, folder)?,
+            fields: Foldable::fold(fields, folder)?,
+            field_names: Foldable::fold(field_names, folder)?,
+        })
+    })
+    }
     impl<T, U> Foldable<T, U> for MatchCase<T> {
         type Mapped = MatchCase<U>;
         fn fold<F: Fold<T, TargetU = U> + ?Sized>(self, folder: &mut F) -> Result<Self::Mapped, F::Error> {
@@ -1296,3 +1363,611 @@ pub mod fold {
     }
 }
 
+#[cfg(feature = "visitor")]
+pub mod visitor {
+    use super::*;
+    pub trait Visitor<U = ()> {
+        fn visit_mod(&mut self, node: &Mod<U>) {
+            walk_mod(self, node)
+        }
+        fn visit_stmt(&mut self, node: &Stmt<U>) {
+            walk_stmt(self, node)
+        }
+        fn visit_expr(&mut self, node: &Expr<U>) {
+            walk_expr(self, node)
+        }
+        fn visit_expr_context(&mut self, node: &ExprContext) {
+            walk_expr_context(self, node)
+        }
+        fn visit_boolop(&mut self, node: &Boolop) {
+            walk_boolop(self, node)
+        }
+        fn visit_operator(&mut self, node: &Operator) {
+            walk_operator(self, node)
+        }
+        fn visit_unaryop(&mut self, node: &Unaryop) {
+            walk_unaryop(self, node)
+        }
+        fn visit_cmpop(&mut self, node: &Cmpop) {
+            walk_cmpop(self, node)
+        }
+        fn visit_comprehension(&mut self, node: &Comprehension<U>) {
+            walk_comprehension(self, node)
+        }
+        fn visit_excepthandler(&mut self, node: &Excepthandler<U>) {
+            walk_excepthandler(self, node)
+        }
+        fn visit_arguments(&mut self, node: &Arguments<U>) {
+            walk_arguments(self, node)
+        }
+        fn visit_arg(&mut self, node: &Arg<U>) {
+            walk_arg(self, node)
+        }
+        fn visit_keyword(&mut self, node: &Keyword<U>) {
+            walk_keyword(self, node)
+        }
+        fn visit_alias(&mut self, node: &Alias<U>) {
+            walk_alias(self, node)
+        }
+        fn visit_withitem(&mut self, node: &Withitem<U>) {
+            walk_withitem(self, node)
+        }
+        fn visit_variant(&mut self, node: &Variant<U>) {
+            walk_variant(self, node)
+        }
+        fn visit_match_case(&mut self, node: &MatchCase<U>) {
+            walk_match_case(self, node)
+        }
+        fn visit_pattern(&mut self, node: &Pattern<U>) {
+            walk_pattern(self, node)
+        }
+        fn visit_type_ignore(&mut self, node: &TypeIgnore) {
+            walk_type_ignore(self, node)
+        }
+    }
+    pub fn walk_mod<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Mod<U>) {
+        match node {
+            Mod::Module { body, type_ignores } => {
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in type_ignores {
+                    visitor.visit_type_ignore(node);
+                }
+            }
+            Mod::Interactive { body } => {
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+            }
+            Mod::Expression { body } => {
+                visitor.visit_expr(body);
+            }
+            Mod::FunctionType { argtypes, returns } => {
+                for node in argtypes {
+                    visitor.visit_expr(node);
+                }
+                visitor.visit_expr(returns);
+            }
+        }
+    }
+    pub fn walk_stmt<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Stmt<U>) {
+        match &node.node {
+            StmtKind::FunctionDef { args, body, decorator_list, returns, .. } => {
+                visitor.visit_arguments(args);
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in decorator_list {
+                    visitor.visit_expr(node);
+                }
+                if let Some(node) = returns {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::AsyncFunctionDef { args, body, decorator_list, returns, .. } => {
+                visitor.visit_arguments(args);
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in decorator_list {
+                    visitor.visit_expr(node);
+                }
+                if let Some(node) = returns {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::ClassDef { bases, keywords, body, decorator_list, .. } => {
+                for node in bases {
+                    visitor.visit_expr(node);
+                }
+                for node in keywords {
+                    visitor.visit_keyword(node);
+                }
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in decorator_list {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::ModuleDef { bases, body, decorator_list, .. } => {
+                for node in bases {
+                    visitor.visit_expr(node);
+                }
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in decorator_list {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::EnumDef { variants, decorator_list, .. } => {
+                for node in variants {
+                    visitor.visit_variant(node);
+                }
+                for node in decorator_list {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::Return { value } => {
+                if let Some(node) = value {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::Delete { targets } => {
+                for node in targets {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::Assign { targets, value, .. } => {
+                for node in targets {
+                    visitor.visit_expr(node);
+                }
+                visitor.visit_expr(value);
+            }
+            StmtKind::AugAssign { target, op, value } => {
+                visitor.visit_expr(target);
+                visitor.visit_operator(op);
+                visitor.visit_expr(value);
+            }
+            StmtKind::AnnAssign { target, annotation, value, .. } => {
+                visitor.visit_expr(target);
+                visitor.visit_expr(annotation);
+                if let Some(node) = value {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::For { target, iter, body, orelse, .. } => {
+                visitor.visit_expr(target);
+                visitor.visit_expr(iter);
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in orelse {
+                    visitor.visit_stmt(node);
+                }
+            }
+            StmtKind::AsyncFor { target, iter, body, orelse, .. } => {
+                visitor.visit_expr(target);
+                visitor.visit_expr(iter);
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in orelse {
+                    visitor.visit_stmt(node);
+                }
+            }
+            StmtKind::While { test, body, orelse } => {
+                visitor.visit_expr(test);
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in orelse {
+                    visitor.visit_stmt(node);
+                }
+            }
+            StmtKind::If { test, body, orelse } => {
+                visitor.visit_expr(test);
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in orelse {
+                    visitor.visit_stmt(node);
+                }
+            }
+            StmtKind::With { items, body, .. } => {
+                for node in items {
+                    visitor.visit_withitem(node);
+                }
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+            }
+            StmtKind::AsyncWith { items, body, .. } => {
+                for node in items {
+                    visitor.visit_withitem(node);
+                }
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+            }
+            StmtKind::Match { subject, cases } => {
+                visitor.visit_expr(subject);
+                for node in cases {
+                    visitor.visit_match_case(node);
+                }
+            }
+            StmtKind::Raise { exc, cause } => {
+                if let Some(node) = exc {
+                    visitor.visit_expr(node);
+                }
+                if let Some(node) = cause {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::Try { body, handlers, orelse, finalbody } => {
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                for node in handlers {
+                    visitor.visit_excepthandler(node);
+                }
+                for node in orelse {
+                    visitor.visit_stmt(node);
+                }
+                for node in finalbody {
+                    visitor.visit_stmt(node);
+                }
+            }
+            StmtKind::Assert { test, msg } => {
+                visitor.visit_expr(test);
+                if let Some(node) = msg {
+                    visitor.visit_expr(node);
+                }
+            }
+            StmtKind::Import { names } => {
+                for node in names {
+                    visitor.visit_alias(node);
+                }
+            }
+            StmtKind::ImportFrom { names, .. } => {
+                for node in names {
+                    visitor.visit_alias(node);
+                }
+            }
+            StmtKind::Global { .. } => {
+            }
+            StmtKind::Nonlocal { .. } => {
+            }
+            StmtKind::Expr { value } => {
+                visitor.visit_expr(value);
+            }
+            StmtKind::Pass => {
+            }
+            StmtKind::Break => {
+            }
+            StmtKind::Continue => {
+            }
+        }
+    }
+    pub fn walk_expr<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Expr<U>) {
+        match &node.node {
+            ExprKind::BoolOp { op, values } => {
+                visitor.visit_boolop(op);
+                for node in values {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::NamedExpr { target, value } => {
+                visitor.visit_expr(target);
+                visitor.visit_expr(value);
+            }
+            ExprKind::BinOp { left, op, right } => {
+                visitor.visit_expr(left);
+                visitor.visit_operator(op);
+                visitor.visit_expr(right);
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                visitor.visit_unaryop(op);
+                visitor.visit_expr(operand);
+            }
+            ExprKind::Lambda { args, body } => {
+                visitor.visit_arguments(args);
+                visitor.visit_expr(body);
+            }
+            ExprKind::DoBlock { args, body, returns, chain, .. } => {
+                visitor.visit_arguments(args);
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+                if let Some(node) = returns {
+                    visitor.visit_expr(node);
+                }
+                if let Some(node) = chain {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::EndOfBlockMarker => {
+            }
+            ExprKind::IfExp { test, body, orelse } => {
+                visitor.visit_expr(test);
+                visitor.visit_expr(body);
+                visitor.visit_expr(orelse);
+            }
+            ExprKind::Dict { keys, values } => {
+                for node in keys {
+                    visitor.visit_expr(node);
+                }
+                for node in values {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::Set { elts } => {
+                for node in elts {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::ListComp { elt, generators } => {
+                visitor.visit_expr(elt);
+                for node in generators {
+                    visitor.visit_comprehension(node);
+                }
+            }
+            ExprKind::SetComp { elt, generators } => {
+                visitor.visit_expr(elt);
+                for node in generators {
+                    visitor.visit_comprehension(node);
+                }
+            }
+            ExprKind::DictComp { key, value, generators } => {
+                visitor.visit_expr(key);
+                visitor.visit_expr(value);
+                for node in generators {
+                    visitor.visit_comprehension(node);
+                }
+            }
+            ExprKind::GeneratorExp { elt, generators } => {
+                visitor.visit_expr(elt);
+                for node in generators {
+                    visitor.visit_comprehension(node);
+                }
+            }
+            ExprKind::Await { value } => {
+                visitor.visit_expr(value);
+            }
+            ExprKind::Yield { value } => {
+                if let Some(node) = value {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::YieldFrom { value } => {
+                visitor.visit_expr(value);
+            }
+            ExprKind::Compare { left, ops, comparators } => {
+                visitor.visit_expr(left);
+                for node in ops {
+                    visitor.visit_cmpop(node);
+                }
+                for node in comparators {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::Call { func, args, keywords } => {
+                visitor.visit_expr(func);
+                for node in args {
+                    visitor.visit_expr(node);
+                }
+                for node in keywords {
+                    visitor.visit_keyword(node);
+                }
+            }
+            ExprKind::FormattedValue { value, format_spec, .. } => {
+                visitor.visit_expr(value);
+                if let Some(node) = format_spec {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::JoinedStr { values } => {
+                for node in values {
+                    visitor.visit_expr(node);
+                }
+            }
+            ExprKind::Constant { .. } => {
+            }
+            ExprKind::OptionalAttribute { value, .. } => {
+                visitor.visit_expr(value);
+            }
+            ExprKind::OptionalSubscript { value, slice } => {
+                visitor.visit_expr(value);
+                visitor.visit_expr(slice);
+            }
+            ExprKind::Range { lower, upper, .. } => {
+                visitor.visit_expr(lower);
+                visitor.visit_expr(upper);
+            }
+            ExprKind::Pipe { value, func } => {
+                visitor.visit_expr(value);
+                visitor.visit_expr(func);
+            }
+            ExprKind::Attribute { value, ctx, .. } => {
+                visitor.visit_expr(value);
+                visitor.visit_expr_context(ctx);
+            }
+            ExprKind::Subscript { value, slice, ctx } => {
+                visitor.visit_expr(value);
+                visitor.visit_expr(slice);
+                visitor.visit_expr_context(ctx);
+            }
+            ExprKind::Starred { value, ctx } => {
+                visitor.visit_expr(value);
+                visitor.visit_expr_context(ctx);
+            }
+            ExprKind::Name { ctx, .. } => {
+                visitor.visit_expr_context(ctx);
+            }
+            ExprKind::List { elts, ctx } => {
+                for node in elts {
+                    visitor.visit_expr(node);
+                }
+                visitor.visit_expr_context(ctx);
+            }
+            ExprKind::Tuple { elts, ctx } => {
+                for node in elts {
+                    visitor.visit_expr(node);
+                }
+                visitor.visit_expr_context(ctx);
+            }
+            ExprKind::Slice { lower, upper, step } => {
+                if let Some(node) = lower {
+                    visitor.visit_expr(node);
+                }
+                if let Some(node) = upper {
+                    visitor.visit_expr(node);
+                }
+                if let Some(node) = step {
+                    visitor.visit_expr(node);
+                }
+            }
+        }
+    }
+    pub fn walk_expr_context<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &ExprContext) {
+    }
+    pub fn walk_boolop<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Boolop) {
+    }
+    pub fn walk_operator<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Operator) {
+    }
+    pub fn walk_unaryop<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Unaryop) {
+    }
+    pub fn walk_cmpop<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Cmpop) {
+    }
+    pub fn walk_comprehension<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Comprehension<U>) {
+        let Comprehension { target, iter, ifs, .. } = node;
+        visitor.visit_expr(target);
+        visitor.visit_expr(iter);
+        for node in ifs {
+            visitor.visit_expr(node);
+        }
+    }
+    pub fn walk_excepthandler<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Excepthandler<U>) {
+        match &node.node {
+            ExcepthandlerKind::ExceptHandler { type_, body, .. } => {
+                if let Some(node) = type_ {
+                    visitor.visit_expr(node);
+                }
+                for node in body {
+                    visitor.visit_stmt(node);
+                }
+            }
+        }
+    }
+    pub fn walk_arguments<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Arguments<U>) {
+        let Arguments { posonlyargs, args, vararg, kwonlyargs, kw_defaults, kwarg, defaults } = node;
+        for node in posonlyargs {
+            visitor.visit_arg(node);
+        }
+        for node in args {
+            visitor.visit_arg(node);
+        }
+        if let Some(node) = vararg {
+            visitor.visit_arg(node);
+        }
+        for node in kwonlyargs {
+            visitor.visit_arg(node);
+        }
+        for node in kw_defaults {
+            visitor.visit_expr(node);
+        }
+        if let Some(node) = kwarg {
+            visitor.visit_arg(node);
+        }
+        for node in defaults {
+            visitor.visit_expr(node);
+        }
+    }
+    pub fn walk_arg<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Arg<U>) {
+        let ArgData { annotation, .. } = &node.node;
+        if let Some(node) = annotation {
+            visitor.visit_expr(node);
+        }
+    }
+    pub fn walk_keyword<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Keyword<U>) {
+        let KeywordData { value, .. } = &node.node;
+        visitor.visit_expr(value);
+    }
+    pub fn walk_alias<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Alias<U>) {
+    }
+    pub fn walk_withitem<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Withitem<U>) {
+        let Withitem { context_expr, optional_vars } = node;
+        visitor.visit_expr(context_expr);
+        if let Some(node) = optional_vars {
+            visitor.visit_expr(node);
+        }
+    }
+    pub fn walk_variant<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Variant<U>) {
+        let VariantData { fields, .. } = &node.node;
+        for node in fields {
+            visitor.visit_expr(node);
+        }
+    }
+    pub fn walk_match_case<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &MatchCase<U>) {
+        let MatchCase { pattern, guard, body } = node;
+        visitor.visit_pattern(pattern);
+        if let Some(node) = guard {
+            visitor.visit_expr(node);
+        }
+        for node in body {
+            visitor.visit_stmt(node);
+        }
+    }
+    pub fn walk_pattern<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &Pattern<U>) {
+        match &node.node {
+            PatternKind::MatchValue { value } => {
+                visitor.visit_expr(value);
+            }
+            PatternKind::MatchSingleton { .. } => {
+            }
+            PatternKind::MatchSequence { patterns } => {
+                for node in patterns {
+                    visitor.visit_pattern(node);
+                }
+            }
+            PatternKind::MatchMapping { keys, patterns, .. } => {
+                for node in keys {
+                    visitor.visit_expr(node);
+                }
+                for node in patterns {
+                    visitor.visit_pattern(node);
+                }
+            }
+            PatternKind::MatchClass { cls, patterns, kwd_patterns, .. } => {
+                visitor.visit_expr(cls);
+                for node in patterns {
+                    visitor.visit_pattern(node);
+                }
+                for node in kwd_patterns {
+                    visitor.visit_pattern(node);
+                }
+            }
+            PatternKind::MatchStar { .. } => {
+            }
+            PatternKind::MatchAs { pattern, .. } => {
+                if let Some(node) = pattern {
+                    visitor.visit_pattern(node);
+                }
+            }
+            PatternKind::MatchOr { patterns } => {
+                for node in patterns {
+                    visitor.visit_pattern(node);
+                }
+            }
+        }
+    }
+    pub fn walk_type_ignore<U, V: Visitor<U> + ?Sized>(#[allow(unused)] visitor: &mut V, #[allow(unused)] node: &TypeIgnore) {
+        match node {
+            TypeIgnore::TypeIgnore { .. } => {
+            }
+        }
+    }
+}
+
@@ -11,6 +11,8 @@ pub enum Constant {
     Float(f64),
     Complex { real: f64, imag: f64 },
     Ellipsis,
+    /// A `%r{pattern}flags` literal. The pattern is kept as written in the source.
+    Regex { pattern: String, flags: String },
 }
 
 impl From<String> for Constant {
@@ -34,14 +36,14 @@ impl From<BigInt> for Constant {
     }
 }
 
-#[cfg(feature = "rustpython-common")]
+#[cfg(feature = "unparse")]
 impl std::fmt::Display for Constant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Constant::None => f.pad("None"),
             Constant::Bool(b) => f.pad(if *b { "True" } else { "False" }),
-            Constant::Str(s) => rustpython_common::str::repr(s).fmt(f),
-            Constant::Bytes(b) => f.pad(&rustpython_common::bytes::repr(b)),
+            Constant::Str(s) => f.pad(&crate::unparse::repr::str(s)),
+            Constant::Bytes(b) => f.pad(&crate::unparse::repr::bytes(b)),
             Constant::Int(i) => i.fmt(f),
             Constant::Tuple(tup) => {
                 if let [elt] = &**tup {
@@ -57,7 +59,7 @@ impl std::fmt::Display for Constant {
                     f.write_str(")")
                 }
             }
-            Constant::Float(fp) => f.pad(&rustpython_common::float_ops::to_string(*fp)),
+            Constant::Float(fp) => f.pad(&crate::unparse::repr::float(*fp)),
             Constant::Complex { real, imag } => {
                 if *real == 0.0 {
                     write!(f, "{}j", imag)
@@ -66,6 +68,7 @@ impl std::fmt::Display for Constant {
                 }
             }
             Constant::Ellipsis => f.pad("..."),
+            Constant::Regex { pattern, flags } => write!(f, "%r{{{}}}{}", pattern, flags),
         }
     }
 }
@@ -8,9 +8,14 @@ impl<U> ExprKind<U> {
                 "operator"
             }
             ExprKind::Subscript { .. } => "subscript",
+            ExprKind::OptionalAttribute { .. } | ExprKind::OptionalSubscript { .. } => {
+                "optional chain"
+            }
             ExprKind::Await { .. } => "await expression",
             ExprKind::Yield { .. } | ExprKind::YieldFrom { .. } => "yield expression",
             ExprKind::Compare { .. } => "comparison",
+            ExprKind::Range { .. } => "range",
+            ExprKind::Pipe { .. } => "pipeline",
             ExprKind::Attribute { .. } => "attribute",
             ExprKind::Call { .. } => "function call",
             ExprKind::Constant { value, .. } => match value {
@@ -18,7 +23,8 @@ impl<U> ExprKind<U> {
                 | Constant::Int(_)
                 | Constant::Float(_)
                 | Constant::Complex { .. }
-                | Constant::Bytes(_) => "literal",
+                | Constant::Bytes(_)
+                | Constant::Regex { .. } => "literal",
                 Constant::Tuple(_) => "tuple",
                 Constant::Bool(b) => {
                     if *b {
@@ -47,6 +47,16 @@ pub enum DoMode {
     Sync,
 }
 
+impl DoMode {
+    /// The `is_async` of the `DoBlock` this mode was written for.
+    pub fn is_async(&self) -> usize {
+        match self {
+            DoMode::Async => 1,
+            DoMode::Sync => 0,
+        }
+    }
+}
+
 pub fn modify_rightmost_expr_of_statement(statement: &mut StmtKind, mut f: impl FnMut(&mut Expr) -> bool) -> bool {
     match statement {
         StmtKind::Expr { value } => { f(value) },
@@ -57,6 +67,25 @@ pub fn modify_rightmost_expr_of_statement(statement: &mut StmtKind, mut f: impl
             false
         },
         StmtKind::Assign { value, .. } => { f(value) },
+        StmtKind::AugAssign { value, .. } => { f(value) },
+        StmtKind::AnnAssign { value, .. } => {
+            if let Some(value) = value {
+                return f(value);
+            }
+            false
+        },
+        StmtKind::Raise { exc, cause } => {
+            if let Some(value) = cause.as_mut().or(exc.as_mut()) {
+                return f(value);
+            }
+            false
+        },
+        StmtKind::Assert { test, msg } => {
+            match msg {
+                Some(msg) => f(msg),
+                None => f(test),
+            }
+        },
         _ => false
     }
 }
@@ -69,6 +98,8 @@ pub fn modify_rightmost_expr(expr: &mut Expr, mut f: impl FnMut(&mut Expr) -> bo
             modify_rightmost_expr(&mut values[len - 1], f)
         },
         ExprKind::UnaryOp { ref mut operand, .. } => { modify_rightmost_expr(operand, f) },
+        ExprKind::Range { ref mut upper, .. } => { modify_rightmost_expr(upper, f) },
+        ExprKind::Pipe { ref mut func, .. } => { modify_rightmost_expr(func, f) },
         _ => f(expr)
     }
 }
@@ -83,10 +114,12 @@ pub fn modify_leftmost_of_multi_expr(expr: &mut Expr, mut f: impl FnMut(&mut Exp
         },
         ExprKind::NamedExpr { .. } => f(expr),
         ExprKind::BinOp { ref mut left , .. } => { modify_leftmost_of_multi_expr(left, f) },
+        ExprKind::Range { ref mut lower , .. } => { modify_leftmost_of_multi_expr(lower, f) },
+        ExprKind::Pipe { ref mut value , .. } => { modify_leftmost_of_multi_expr(value, f) },
         ExprKind::UnaryOp { .. } => f(expr),
         ExprKind::Lambda { .. } => f(expr),
         ExprKind::DoBlock { .. } => f(expr),
-        ExprKind::EndOfBlockMarker { .. } => f(expr),
+        ExprKind::EndOfBlockMarker => f(expr),
         ExprKind::IfExp { .. } => f(expr),
         ExprKind::Dict { .. } => f(expr),
         ExprKind::Set { .. } => f(expr),
@@ -104,6 +137,8 @@ pub fn modify_leftmost_of_multi_expr(expr: &mut Expr, mut f: impl FnMut(&mut Exp
         ExprKind::Constant { .. } => f(expr),
         ExprKind::Attribute { ref mut value , .. } => modify_leftmost_of_multi_expr(value, f),
         ExprKind::Subscript{ ref mut value , .. } => modify_leftmost_of_multi_expr(value, f),
+        ExprKind::OptionalAttribute { ref mut value , .. } => modify_leftmost_of_multi_expr(value, f),
+        ExprKind::OptionalSubscript { ref mut value , .. } => modify_leftmost_of_multi_expr(value, f),
         ExprKind::Starred { .. } => f(expr),
         ExprKind::Name { .. } => f(expr),
         ExprKind::List { .. } => f(expr),
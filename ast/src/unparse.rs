@@ -1,6 +1,6 @@
 use crate::{
     Arg, Arguments, Boolop, Cmpop, Comprehension, Constant, ConversionFlag, Expr, ExprKind,
-    Operator,
+    Operator, Stmt, StmtKind,
 };
 use std::fmt;
 
@@ -16,7 +16,7 @@ mod precedence {
         (@$i:expr,) => {};
     }
     precedence!(
-        TUPLE, TEST, OR, AND, NOT, CMP, // "EXPR" =
+        TUPLE, TEST, PIPE, COALESCE, OR, AND, NOT, CMP, RANGE, // "EXPR" =
         BOR, BXOR, BAND, SHIFT, ARITH, TERM, FACTOR, POWER, AWAIT, ATOM,
     );
     pub const EXPR: u8 = BOR;
@@ -72,7 +72,14 @@ impl<'a> Unparser<'a> {
         }
         match &ast.node {
             ExprKind::BoolOp { op, values } => {
-                let (op, prec) = opprec!(bin, op, Boolop, And("and", AND), Or("or", OR));
+                let (op, prec) = opprec!(
+                    bin,
+                    op,
+                    Boolop,
+                    And("and", AND),
+                    Or("or", OR),
+                    Coalesce("??", COALESCE),
+                );
                 group_if!(prec, {
                     let mut first = true;
                     for val in values {
@@ -114,6 +121,24 @@ impl<'a> Unparser<'a> {
                     self.unparse_expr(right, prec + !rassoc as u8)?;
                 })
             }
+            ExprKind::Range {
+                lower,
+                upper,
+                inclusive,
+            } => {
+                group_if!(precedence::RANGE, {
+                    self.unparse_expr(lower, precedence::RANGE + 1)?;
+                    self.p(if *inclusive != 0 { ".." } else { "..." })?;
+                    self.unparse_expr(upper, precedence::RANGE + 1)?;
+                })
+            }
+            ExprKind::Pipe { value, func } => {
+                group_if!(precedence::PIPE, {
+                    self.unparse_expr(value, precedence::PIPE)?;
+                    self.p(" |> ")?;
+                    self.unparse_expr(func, precedence::PIPE + 1)?;
+                })
+            }
             ExprKind::UnaryOp { op, operand } => {
                 let (op, prec) = opprec!(
                     un,
@@ -137,6 +162,11 @@ impl<'a> Unparser<'a> {
                     write!(self, ": {}", **body)?;
                 })
             }
+            ExprKind::DoBlock { .. } => {
+                // Inline do blocks are only valid in parentheses or argument lists.
+                group_if!(precedence::TUPLE, { self.unparse_do_block(ast)? })
+            }
+            ExprKind::EndOfBlockMarker => self.p("|")?,
             ExprKind::IfExp { test, body, orelse } => {
                 group_if!(precedence::TEST, {
                     self.unparse_expr(body, precedence::TEST + 1)?;
@@ -263,7 +293,7 @@ impl<'a> Unparser<'a> {
                     let mut first = true;
                     for arg in args {
                         self.p_delim(&mut first, ", ")?;
-                        self.unparse_expr(arg, precedence::TEST)?;
+                        self.unparse_call_arg(arg)?;
                     }
                     for kw in keywords {
                         self.p_delim(&mut first, ", ")?;
@@ -273,7 +303,7 @@ impl<'a> Unparser<'a> {
                         } else {
                             self.p("**")?;
                         }
-                        self.unparse_expr(&kw.node.value, precedence::TEST)?;
+                        self.unparse_call_arg(&kw.node.value)?;
                     }
                 }
                 self.p(")")?;
@@ -288,7 +318,7 @@ impl<'a> Unparser<'a> {
                 if let Some(kind) = kind {
                     self.p(kind)?;
                 }
-                assert_eq!(f64::MAX_10_EXP, 308);
+                // One past `f64::MAX_10_EXP`, so it reads back as infinity.
                 let inf_str = "1e309";
                 match value {
                     Constant::Float(f) if f.is_infinite() => self.p(inf_str)?,
@@ -329,6 +359,17 @@ impl<'a> Unparser<'a> {
                 self.unparse_expr(slice, lvl)?;
                 self.p("]")?;
             }
+            ExprKind::OptionalAttribute { value, attr } => {
+                self.unparse_expr(value, precedence::ATOM)?;
+                self.p("?.")?;
+                self.p(attr)?;
+            }
+            ExprKind::OptionalSubscript { value, slice } => {
+                self.unparse_expr(value, precedence::ATOM)?;
+                self.p("?[")?;
+                self.unparse_expr(slice, precedence::TUPLE)?;
+                self.p("]")?;
+            }
             ExprKind::Starred { value, .. } => {
                 self.p("*")?;
                 self.unparse_expr(value, precedence::EXPR)?;
@@ -374,6 +415,64 @@ impl<'a> Unparser<'a> {
         Ok(())
     }
 
+    fn unparse_call_arg<U>(&mut self, arg: &Expr<U>) -> fmt::Result {
+        // An argument list is the one place an inline do block doesn't need parentheses.
+        let level = match arg.node {
+            ExprKind::DoBlock { .. } => precedence::TUPLE,
+            _ => precedence::TEST,
+        };
+        self.unparse_expr(arg, level)
+    }
+
+    /// Write a do block in its inline form. Only a block whose body is a single expression
+    /// has one: a block of statements is written as part of the statement it is attached to.
+    fn unparse_do_block<U>(&mut self, block: &Expr<U>) -> fmt::Result {
+        let (args, body, returns, chain, is_async) = match &block.node {
+            ExprKind::DoBlock {
+                args,
+                body,
+                returns,
+                chain,
+                is_async,
+            } => (args, body, returns, chain, is_async),
+            _ => unreachable!(),
+        };
+        let value = match (&**body, chain) {
+            (
+                [Stmt {
+                    node: StmtKind::Expr { value },
+                    ..
+                }],
+                None,
+            ) => value,
+            _ => return Err(fmt::Error),
+        };
+        self.p(if *is_async > 0 { "async do" } else { "do" })?;
+        let mut params = args
+            .posonlyargs
+            .iter()
+            .chain(&args.args)
+            .chain(args.vararg.as_deref())
+            .chain(&args.kwonlyargs)
+            .chain(args.kwarg.as_deref())
+            .peekable();
+        let has_params = params.peek().is_some();
+        // Annotations and a return type need the parenthesized parameter list.
+        if returns.is_some() || params.any(|arg| arg.node.annotation.is_some()) {
+            self.p(" (")?;
+            self.unparse_args(args)?;
+            self.p(")")?;
+            if let Some(returns) = returns {
+                write!(self, " -> {}", **returns)?;
+            }
+        } else if has_params {
+            self.p(" ")?;
+            self.unparse_args(args)?;
+        }
+        self.p(": ")?;
+        self.unparse_expr(value, precedence::TEST)
+    }
+
     fn unparse_args<U>(&mut self, args: &Arguments<U>) -> fmt::Result {
         let mut first = true;
         let defaults_start = args.posonlyargs.len() + args.args.len() - args.defaults.len();
@@ -507,7 +606,7 @@ impl<'a> Unparser<'a> {
         } else {
             self.p("f")?;
             let body = to_string_fmt(|f| Unparser::new(f).unparse_fstring_body(values, is_spec));
-            fmt::Display::fmt("TODO extract me from rustpython_common::str::repr")
+            self.p(&repr::str(&body))
         }
     }
 }
@@ -528,3 +627,66 @@ fn to_string_fmt(f: impl FnOnce(&mut fmt::Formatter) -> fmt::Result) -> String {
     }
     Fmt(Cell::new(Some(f))).to_string()
 }
+
+/// Python-compatible `repr` of literals, so unparsed code reads back to the same constant.
+pub(crate) mod repr {
+    use std::fmt::Write;
+
+    pub fn str(s: &str) -> String {
+        let quote = if s.contains('\'') && !s.contains('"') { '"' } else { '\'' };
+        let mut out = String::with_capacity(s.len() + 2);
+        out.push(quote);
+        for c in s.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c == quote => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                    write!(out, "\\x{:02x}", c as u32).unwrap();
+                }
+                c => out.push(c),
+            }
+        }
+        out.push(quote);
+        out
+    }
+
+    pub fn bytes(b: &[u8]) -> String {
+        let quote = if b.contains(&b'\'') && !b.contains(&b'"') { b'"' } else { b'\'' };
+        let mut out = String::with_capacity(b.len() + 3);
+        out.push('b');
+        out.push(quote as char);
+        for &c in b {
+            match c {
+                b'\\' => out.push_str("\\\\"),
+                b'\n' => out.push_str("\\n"),
+                b'\r' => out.push_str("\\r"),
+                b'\t' => out.push_str("\\t"),
+                c if c == quote => {
+                    out.push('\\');
+                    out.push(c as char);
+                }
+                0x20..=0x7e => out.push(c as char),
+                c => write!(out, "\\x{:02x}", c).unwrap(),
+            }
+        }
+        out.push(quote as char);
+        out
+    }
+
+    pub fn float(f: f64) -> String {
+        if f.is_nan() {
+            "nan".to_owned()
+        } else if f.is_infinite() {
+            if f > 0.0 { "inf" } else { "-inf" }.to_owned()
+        } else {
+            // Debug keeps a trailing `.0` on whole numbers, so the result still lexes as a float.
+            format!("{:?}", f)
+        }
+    }
+}
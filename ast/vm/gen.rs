@@ -1,4 +1,4 @@
-// File automatically generated by asdl_rs.py.
+// File automatically generated by ast/asdl_rs.py.
 
 #![allow(clippy::all)]
 
@@ -83,6 +83,26 @@ impl NodeClassDef {
         class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
     }
 }
+#[pyclass(module = "_ast", name = "ModuleDef", base = "NodeKindStmt")]
+struct NodeModuleDef;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodeModuleDef {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("name")).into(),ctx.new_str(ascii!("bases")).into(),ctx.new_str(ascii!("body")).into(),ctx.new_str(ascii!("decorator_list")).into()]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
+    }
+}
+#[pyclass(module = "_ast", name = "EnumDef", base = "NodeKindStmt")]
+struct NodeEnumDef;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodeEnumDef {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("name")).into(),ctx.new_str(ascii!("variants")).into(),ctx.new_str(ascii!("decorator_list")).into()]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
+    }
+}
 #[pyclass(module = "_ast", name = "Return", base = "NodeKindStmt")]
 struct NodeReturn;
 #[pyclass(flags(HAS_DICT, BASETYPE))]
@@ -373,7 +393,7 @@ struct NodeDoBlock;
 impl NodeDoBlock {
     #[extend_class]
     fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
-        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("args")).into(),ctx.new_str(ascii!("body")).into(),ctx.new_str(ascii!("chain")).into()]).into());
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("args")).into(),ctx.new_str(ascii!("body")).into(),ctx.new_str(ascii!("returns")).into(),ctx.new_str(ascii!("chain")).into(),ctx.new_str(ascii!("is_async")).into()]).into());
         class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
     }
 }
@@ -537,6 +557,46 @@ impl NodeConstant {
         class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
     }
 }
+#[pyclass(module = "_ast", name = "OptionalAttribute", base = "NodeKindExpr")]
+struct NodeOptionalAttribute;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodeOptionalAttribute {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("value")).into(),ctx.new_str(ascii!("attr")).into()]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
+    }
+}
+#[pyclass(module = "_ast", name = "OptionalSubscript", base = "NodeKindExpr")]
+struct NodeOptionalSubscript;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodeOptionalSubscript {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("value")).into(),ctx.new_str(ascii!("slice")).into()]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
+    }
+}
+#[pyclass(module = "_ast", name = "Range", base = "NodeKindExpr")]
+struct NodeRange;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodeRange {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("lower")).into(),ctx.new_str(ascii!("upper")).into(),ctx.new_str(ascii!("inclusive")).into()]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
+    }
+}
+#[pyclass(module = "_ast", name = "Pipe", base = "NodeKindExpr")]
+struct NodePipe;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodePipe {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("value")).into(),ctx.new_str(ascii!("func")).into()]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
+    }
+}
 #[pyclass(module = "_ast", name = "Attribute", base = "NodeKindExpr")]
 struct NodeAttribute;
 #[pyclass(flags(HAS_DICT, BASETYPE))]
@@ -665,6 +725,16 @@ impl NodeOr {
         class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![]).into());
     }
 }
+#[pyclass(module = "_ast", name = "Coalesce", base = "NodeKindBoolop")]
+struct NodeCoalesce;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodeCoalesce {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![]).into());
+    }
+}
 #[pyclass(module = "_ast", name = "operator", base = "AstNode")]
 struct NodeKindOperator;
 #[pyclass(flags(HAS_DICT, BASETYPE))]
@@ -1021,6 +1091,16 @@ impl NodeWithitem {
         class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![]).into());
     }
 }
+#[pyclass(module = "_ast", name = "variant", base = "AstNode")]
+struct NodeVariant;
+#[pyclass(flags(HAS_DICT, BASETYPE))]
+impl NodeVariant {
+    #[extend_class]
+    fn extend_class_with_fields(ctx: &Context, class: &'static Py<PyType>) {
+        class.set_attr(identifier!(ctx, _fields), ctx.new_tuple(vec![ctx.new_str(ascii!("name")).into(),ctx.new_str(ascii!("fields")).into(),ctx.new_str(ascii!("field_names")).into()]).into());
+        class.set_attr(identifier!(ctx, _attributes), ctx.new_list(vec![ctx.new_str(ascii!("lineno")).into(),ctx.new_str(ascii!("col_offset")).into(),ctx.new_str(ascii!("end_lineno")).into(),ctx.new_str(ascii!("end_col_offset")).into()]).into());
+    }
+}
 #[pyclass(module = "_ast", name = "match_case", base = "AstNode")]
 struct NodeMatchCase;
 #[pyclass(flags(HAS_DICT, BASETYPE))]
@@ -1232,6 +1312,23 @@ impl Node for ast::StmtKind {
                 _dict.set_item("decorator_list", decorator_list.ast_to_object(_vm), _vm).unwrap();
                 _node.into()
             }
+            ast::StmtKind::ModuleDef { name,bases,body,decorator_list } => {
+                let _node = AstNode.into_ref_with_type(_vm, NodeModuleDef::static_type().to_owned()).unwrap();
+                let _dict = _node.as_object().dict().unwrap();
+                _dict.set_item("name", name.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("bases", bases.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("body", body.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("decorator_list", decorator_list.ast_to_object(_vm), _vm).unwrap();
+                _node.into()
+            }
+            ast::StmtKind::EnumDef { name,variants,decorator_list } => {
+                let _node = AstNode.into_ref_with_type(_vm, NodeEnumDef::static_type().to_owned()).unwrap();
+                let _dict = _node.as_object().dict().unwrap();
+                _dict.set_item("name", name.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("variants", variants.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("decorator_list", decorator_list.ast_to_object(_vm), _vm).unwrap();
+                _node.into()
+            }
             ast::StmtKind::Return { value } => {
                 let _node = AstNode.into_ref_with_type(_vm, NodeReturn::static_type().to_owned()).unwrap();
                 let _dict = _node.as_object().dict().unwrap();
@@ -1430,6 +1527,21 @@ impl Node for ast::StmtKind {
                 decorator_list: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "decorator_list", "stmt")?)?,
             }
         } else
+        if _cls.is(NodeModuleDef::static_type()) {
+            ast::StmtKind::ModuleDef {
+                name: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "name", "stmt")?)?,
+                bases: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "bases", "stmt")?)?,
+                body: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "body", "stmt")?)?,
+                decorator_list: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "decorator_list", "stmt")?)?,
+            }
+        } else
+        if _cls.is(NodeEnumDef::static_type()) {
+            ast::StmtKind::EnumDef {
+                name: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "name", "stmt")?)?,
+                variants: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "variants", "stmt")?)?,
+                decorator_list: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "decorator_list", "stmt")?)?,
+            }
+        } else
         if _cls.is(NodeReturn::static_type()) {
             ast::StmtKind::Return {
                 value: get_node_field_opt(_vm, &_object, "value")?.map(|obj| Node::ast_from_object(_vm, obj)).transpose()?,
@@ -1620,12 +1732,14 @@ impl Node for ast::ExprKind {
                 _dict.set_item("body", body.ast_to_object(_vm), _vm).unwrap();
                 _node.into()
             }
-            ast::ExprKind::DoBlock { args,body,chain } => {
+            ast::ExprKind::DoBlock { args,body,returns,chain,is_async } => {
                 let _node = AstNode.into_ref_with_type(_vm, NodeDoBlock::static_type().to_owned()).unwrap();
                 let _dict = _node.as_object().dict().unwrap();
                 _dict.set_item("args", args.ast_to_object(_vm), _vm).unwrap();
                 _dict.set_item("body", body.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("returns", returns.ast_to_object(_vm), _vm).unwrap();
                 _dict.set_item("chain", chain.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("is_async", is_async.ast_to_object(_vm), _vm).unwrap();
                 _node.into()
             }
             ast::ExprKind::EndOfBlockMarker {  } => {
@@ -1737,6 +1851,35 @@ impl Node for ast::ExprKind {
                 _dict.set_item("kind", kind.ast_to_object(_vm), _vm).unwrap();
                 _node.into()
             }
+            ast::ExprKind::OptionalAttribute { value,attr } => {
+                let _node = AstNode.into_ref_with_type(_vm, NodeOptionalAttribute::static_type().to_owned()).unwrap();
+                let _dict = _node.as_object().dict().unwrap();
+                _dict.set_item("value", value.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("attr", attr.ast_to_object(_vm), _vm).unwrap();
+                _node.into()
+            }
+            ast::ExprKind::OptionalSubscript { value,slice } => {
+                let _node = AstNode.into_ref_with_type(_vm, NodeOptionalSubscript::static_type().to_owned()).unwrap();
+                let _dict = _node.as_object().dict().unwrap();
+                _dict.set_item("value", value.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("slice", slice.ast_to_object(_vm), _vm).unwrap();
+                _node.into()
+            }
+            ast::ExprKind::Range { lower,upper,inclusive } => {
+                let _node = AstNode.into_ref_with_type(_vm, NodeRange::static_type().to_owned()).unwrap();
+                let _dict = _node.as_object().dict().unwrap();
+                _dict.set_item("lower", lower.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("upper", upper.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("inclusive", inclusive.ast_to_object(_vm), _vm).unwrap();
+                _node.into()
+            }
+            ast::ExprKind::Pipe { value,func } => {
+                let _node = AstNode.into_ref_with_type(_vm, NodePipe::static_type().to_owned()).unwrap();
+                let _dict = _node.as_object().dict().unwrap();
+                _dict.set_item("value", value.ast_to_object(_vm), _vm).unwrap();
+                _dict.set_item("func", func.ast_to_object(_vm), _vm).unwrap();
+                _node.into()
+            }
             ast::ExprKind::Attribute { value,attr,ctx } => {
                 let _node = AstNode.into_ref_with_type(_vm, NodeAttribute::static_type().to_owned()).unwrap();
                 let _dict = _node.as_object().dict().unwrap();
@@ -1830,7 +1973,9 @@ impl Node for ast::ExprKind {
             ast::ExprKind::DoBlock {
                 args: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "args", "expr")?)?,
                 body: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "body", "expr")?)?,
+                returns: get_node_field_opt(_vm, &_object, "returns")?.map(|obj| Node::ast_from_object(_vm, obj)).transpose()?,
                 chain: get_node_field_opt(_vm, &_object, "chain")?.map(|obj| Node::ast_from_object(_vm, obj)).transpose()?,
+                is_async: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "is_async", "expr")?)?,
             }
         } else
         if _cls.is(NodeEndOfBlockMarker::static_type()) {
@@ -1927,6 +2072,31 @@ impl Node for ast::ExprKind {
                 kind: get_node_field_opt(_vm, &_object, "kind")?.map(|obj| Node::ast_from_object(_vm, obj)).transpose()?,
             }
         } else
+        if _cls.is(NodeOptionalAttribute::static_type()) {
+            ast::ExprKind::OptionalAttribute {
+                value: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "value", "expr")?)?,
+                attr: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "attr", "expr")?)?,
+            }
+        } else
+        if _cls.is(NodeOptionalSubscript::static_type()) {
+            ast::ExprKind::OptionalSubscript {
+                value: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "value", "expr")?)?,
+                slice: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "slice", "expr")?)?,
+            }
+        } else
+        if _cls.is(NodeRange::static_type()) {
+            ast::ExprKind::Range {
+                lower: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "lower", "expr")?)?,
+                upper: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "upper", "expr")?)?,
+                inclusive: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "inclusive", "expr")?)?,
+            }
+        } else
+        if _cls.is(NodePipe::static_type()) {
+            ast::ExprKind::Pipe {
+                value: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "value", "expr")?)?,
+                func: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "func", "expr")?)?,
+            }
+        } else
         if _cls.is(NodeAttribute::static_type()) {
             ast::ExprKind::Attribute {
                 value: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "value", "expr")?)?,
@@ -2031,6 +2201,10 @@ impl Node for ast::Boolop {
                 let _node = AstNode.into_ref_with_type(_vm, NodeOr::static_type().to_owned()).unwrap();
                 _node.into()
             }
+            ast::Boolop::Coalesce {  } => {
+                let _node = AstNode.into_ref_with_type(_vm, NodeCoalesce::static_type().to_owned()).unwrap();
+                _node.into()
+            }
         }
     }
     fn ast_from_object(_vm: &VirtualMachine, _object: PyObjectRef) -> PyResult<Self> {
@@ -2044,6 +2218,10 @@ impl Node for ast::Boolop {
             ast::Boolop::Or {
             }
         } else
+        if _cls.is(NodeCoalesce::static_type()) {
+            ast::Boolop::Coalesce {
+            }
+        } else
         {
             return Err(_vm.new_type_error(format!("expected some sort of boolop, but got {}",_object.repr(_vm)?)));
         })
@@ -2490,6 +2668,30 @@ impl Node for ast::Withitem {
         )
     }
 }
+impl NamedNode for ast::VariantData {
+    const NAME: &'static str = "variant";
+}
+impl Node for ast::VariantData {
+    fn ast_to_object(self, _vm: &VirtualMachine) -> PyObjectRef {
+        let ast::VariantData { name,fields,field_names } = self;
+        let _node = AstNode.into_ref_with_type(_vm, NodeVariant::static_type().to_owned()).unwrap();
+        let _dict = _node.as_object().dict().unwrap();
+        _dict.set_item("name", name.ast_to_object(_vm), _vm).unwrap();
+        _dict.set_item("fields", fields.ast_to_object(_vm), _vm).unwrap();
+        _dict.set_item("field_names", field_names.ast_to_object(_vm), _vm).unwrap();
+        _node.into()
+    }
+    fn ast_from_object(_vm: &VirtualMachine, _object: PyObjectRef) -> PyResult<Self> {
+        let _location = ast::Location::new(Node::ast_from_object(_vm, get_node_field(_vm, &_object, "lineno", "variant")?)?, Node::ast_from_object(_vm, get_node_field(_vm, &_object, "col_offset", "variant")?)?);
+        Ok(
+            ast::VariantData {
+                name: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "name", "variant")?)?,
+                fields: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "fields", "variant")?)?,
+                field_names: Node::ast_from_object(_vm, get_node_field(_vm, &_object, "field_names", "variant")?)?,
+            }
+        )
+    }
+}
 impl NamedNode for ast::MatchCase {
     const NAME: &'static str = "match_case";
 }
@@ -2671,6 +2873,8 @@ pub fn extend_module_nodes(vm: &VirtualMachine, module: &PyObject) {
         "FunctionDef" => NodeFunctionDef::make_class(&vm.ctx),
         "AsyncFunctionDef" => NodeAsyncFunctionDef::make_class(&vm.ctx),
         "ClassDef" => NodeClassDef::make_class(&vm.ctx),
+        "ModuleDef" => NodeModuleDef::make_class(&vm.ctx),
+        "EnumDef" => NodeEnumDef::make_class(&vm.ctx),
         "Return" => NodeReturn::make_class(&vm.ctx),
         "Delete" => NodeDelete::make_class(&vm.ctx),
         "Assign" => NodeAssign::make_class(&vm.ctx),
@@ -2717,6 +2921,10 @@ pub fn extend_module_nodes(vm: &VirtualMachine, module: &PyObject) {
         "FormattedValue" => NodeFormattedValue::make_class(&vm.ctx),
         "JoinedStr" => NodeJoinedStr::make_class(&vm.ctx),
         "Constant" => NodeConstant::make_class(&vm.ctx),
+        "OptionalAttribute" => NodeOptionalAttribute::make_class(&vm.ctx),
+        "OptionalSubscript" => NodeOptionalSubscript::make_class(&vm.ctx),
+        "Range" => NodeRange::make_class(&vm.ctx),
+        "Pipe" => NodePipe::make_class(&vm.ctx),
         "Attribute" => NodeAttribute::make_class(&vm.ctx),
         "Subscript" => NodeSubscript::make_class(&vm.ctx),
         "Starred" => NodeStarred::make_class(&vm.ctx),
@@ -2731,6 +2939,7 @@ pub fn extend_module_nodes(vm: &VirtualMachine, module: &PyObject) {
         "boolop" => NodeKindBoolop::make_class(&vm.ctx),
         "And" => NodeAnd::make_class(&vm.ctx),
         "Or" => NodeOr::make_class(&vm.ctx),
+        "Coalesce" => NodeCoalesce::make_class(&vm.ctx),
         "operator" => NodeKindOperator::make_class(&vm.ctx),
         "Add" => NodeAdd::make_class(&vm.ctx),
         "Sub" => NodeSub::make_class(&vm.ctx),
@@ -2769,6 +2978,7 @@ pub fn extend_module_nodes(vm: &VirtualMachine, module: &PyObject) {
         "keyword" => NodeKeyword::make_class(&vm.ctx),
         "alias" => NodeAlias::make_class(&vm.ctx),
         "withitem" => NodeWithitem::make_class(&vm.ctx),
+        "variant" => NodeVariant::make_class(&vm.ctx),
         "match_case" => NodeMatchCase::make_class(&vm.ctx),
         "pattern" => NodeKindPattern::make_class(&vm.ctx),
         "MatchValue" => NodeMatchValue::make_class(&vm.ctx),
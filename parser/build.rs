@@ -112,7 +112,6 @@ fn gen_phf() {
         .entry("as", "Tok::As")
         .entry("assert", "Tok::Assert")
         .entry("async", "Tok::Async")
-        .entry("async do", "Tok::AsyncDo")
         .entry("await", "Tok::Await")
         .entry("break", "Tok::Break")
         .entry("class", "Tok::Class")
@@ -140,6 +139,7 @@ fn gen_phf() {
         .entry("raise", "Tok::Raise")
         .entry("return", "Tok::Return")
         .entry("try", "Tok::Try")
+        .entry("unless", "Tok::Unless")
         .entry("while", "Tok::While")
         .entry("with", "Tok::With")
         .entry("yield", "Tok::Yield")
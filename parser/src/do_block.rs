@@ -1,6 +1,50 @@
-use crate::ast;
+use crate::{
+    ast,
+    error::{LexicalError, LexicalErrorType},
+};
 
 pub enum StatementsOrDoBlock {
     Statements(ast::Suite),
     DoBlock(ast::Expr),
-}
\ No newline at end of file
+}
+
+/// Pass `do_block` as the last argument of the rightmost call in `expr`.
+pub fn attach_to_expr(expr: &mut ast::Expr, do_block: &ast::Expr) -> Result<(), LexicalError> {
+    let modified = ast::modify_rightmost_expr(expr, |expr| match &mut expr.node {
+        ast::ExprKind::Call { args, .. } => {
+            args.push(do_block.clone());
+            true
+        }
+        _ => false,
+    });
+    if modified {
+        Ok(())
+    } else {
+        Err(not_a_call(do_block))
+    }
+}
+
+/// Pass `do_block` as the last argument of the rightmost call in `statement`.
+pub fn attach_to_statement(statement: &mut ast::Stmt, do_block: &ast::Expr) -> Result<(), LexicalError> {
+    let mut result = Err(not_a_call(do_block));
+    ast::modify_rightmost_expr_of_statement(&mut statement.node, |expr| {
+        result = attach_to_expr(expr, do_block);
+        result.is_ok()
+    });
+    result
+}
+
+/// Pass `do_block` to the last context manager of a `with` header, which can't be followed by `as`.
+pub fn attach_to_withitems(items: &mut [ast::Withitem], do_block: &ast::Expr) -> Result<(), LexicalError> {
+    match items.last_mut() {
+        Some(ast::Withitem { context_expr, optional_vars: None }) => attach_to_expr(context_expr, do_block),
+        _ => Err(not_a_call(do_block)),
+    }
+}
+
+fn not_a_call(do_block: &ast::Expr) -> LexicalError {
+    LexicalError {
+        error: LexicalErrorType::OtherError("last expression prior to do block must be a function call".to_string()),
+        location: do_block.location,
+    }
+}
@@ -27,6 +27,7 @@ pub enum LexicalErrorType {
     UnrecognizedToken { tok: char },
     FStringError(FStringErrorType),
     LineContinuationError,
+    RegexError(String),
     Eof,
     OtherError(String),
 }
@@ -62,6 +63,7 @@ impl fmt::Display for LexicalErrorType {
             LexicalErrorType::LineContinuationError => {
                 write!(f, "unexpected character after line continuation character")
             }
+            LexicalErrorType::RegexError(error) => write!(f, "invalid regular expression: {}", error),
             LexicalErrorType::Eof => write!(f, "unexpected EOF while parsing"),
             LexicalErrorType::OtherError(msg) => write!(f, "{}", msg),
         }
@@ -202,6 +204,88 @@ pub enum ParseErrorType {
     UnexpectedToken(Tok, Option<String>),
     /// Maps to `User` type from `lalrpop-util`
     Lexical(LexicalErrorType),
+    /// Found by the validator after the grammar accepted the input
+    Semantic(SemanticErrorType),
+}
+
+/// Errors the grammar accepts but which are invalid Emerald, found by walking the AST.
+#[derive(Debug, PartialEq)]
+pub enum SemanticErrorType {
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    ReturnOutsideFunction,
+    YieldOutsideFunction,
+    AwaitOutsideFunction,
+    AwaitOutsideAsyncFunction,
+    /// Holds `async for` or `async with`.
+    AsyncOutsideAsyncFunction(&'static str),
+    DuplicateArgument(String),
+    NonlocalAtModuleLevel,
+    /// Holds the short name of the offending node, see `ExprKind::name`.
+    InvalidAssignTarget(&'static str),
+    InvalidDeleteTarget(&'static str),
+    InvalidAugAssignTarget(&'static str),
+    InvalidAnnotatedTarget,
+    AnnotatedTupleTarget,
+    StarredAssignTarget,
+    MultipleStarredTargets,
+    MultipleStarredPatterns,
+    DuplicateVariant(String),
+    DuplicateVariantField(String),
+    /// An `if` expression without `else` that isn't a statement modifier.
+    ExpectedElse,
+    /// A `|` that doesn't start the chain after a do block.
+    MisplacedBlockMarker,
+}
+
+impl fmt::Display for SemanticErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SemanticErrorType::BreakOutsideLoop => write!(f, "'break' outside loop"),
+            SemanticErrorType::ContinueOutsideLoop => write!(f, "'continue' not properly in loop"),
+            SemanticErrorType::ReturnOutsideFunction => write!(f, "'return' outside function"),
+            SemanticErrorType::YieldOutsideFunction => write!(f, "'yield' outside function"),
+            SemanticErrorType::AwaitOutsideFunction => write!(f, "'await' outside function"),
+            SemanticErrorType::AwaitOutsideAsyncFunction => {
+                write!(f, "'await' outside async function")
+            }
+            SemanticErrorType::AsyncOutsideAsyncFunction(name) => {
+                write!(f, "'{}' outside async function", name)
+            }
+            SemanticErrorType::DuplicateArgument(name) => {
+                write!(f, "duplicate argument '{}' in function definition", name)
+            }
+            SemanticErrorType::NonlocalAtModuleLevel => {
+                write!(f, "nonlocal declaration not allowed at module level")
+            }
+            SemanticErrorType::InvalidAssignTarget(name) => write!(f, "cannot assign to {}", name),
+            SemanticErrorType::InvalidDeleteTarget(name) => write!(f, "cannot delete {}", name),
+            SemanticErrorType::InvalidAugAssignTarget(name) => {
+                write!(f, "'{}' is an illegal expression for augmented assignment", name)
+            }
+            SemanticErrorType::InvalidAnnotatedTarget => write!(f, "illegal target for annotation"),
+            SemanticErrorType::AnnotatedTupleTarget => {
+                write!(f, "only single target (not tuple) can be annotated")
+            }
+            SemanticErrorType::StarredAssignTarget => {
+                write!(f, "starred assignment target must be in a list or tuple")
+            }
+            SemanticErrorType::MultipleStarredTargets => {
+                write!(f, "multiple starred expressions in assignment")
+            }
+            SemanticErrorType::MultipleStarredPatterns => {
+                write!(f, "multiple starred names in sequence pattern")
+            }
+            SemanticErrorType::DuplicateVariant(name) => {
+                write!(f, "duplicate variant '{}' in enum definition", name)
+            }
+            SemanticErrorType::DuplicateVariantField(name) => {
+                write!(f, "duplicate field '{}' in enum variant", name)
+            }
+            SemanticErrorType::ExpectedElse => write!(f, "expected 'else' after 'if' expression"),
+            SemanticErrorType::MisplacedBlockMarker => write!(f, "'|' only continues the chain after a do block"),
+        }
+    }
 }
 
 /// Convert `lalrpop_util::ParseError` to our internal type
@@ -262,6 +346,7 @@ impl fmt::Display for ParseErrorType {
                 }
             }
             ParseErrorType::Lexical(ref error) => write!(f, "{}", error),
+            ParseErrorType::Semantic(ref error) => write!(f, "{}", error),
         }
     }
 }
@@ -1,377 +1,83 @@
-use self::FStringErrorType::*;
+//! Build the AST of f-strings. The lexer splits an f-string into `FStringStart`,
+//! `FStringMiddle` and `FStringEnd` tokens around the tokens of its replacement
+//! fields, so the expressions inside are parsed in place like any other.
+
 use crate::{
-    ast::{Constant, Expr, ExprKind, Location, ConversionFlag},
-    error::{FStringError, FStringErrorType, ParseError},
-    parser::parse_expression,
+    ast::{Constant, ConversionFlag, Expr, ExprKind, Location},
+    error::{FStringErrorType, LexicalError, LexicalErrorType},
 };
-use std::{iter, mem, str};
-
-struct FStringParser {
-    str_start: Location,
-    str_end: Location,
-}
-
-impl FStringParser {
-    fn new(str_start: Location, str_end: Location) -> Self {
-        Self { str_start, str_end }
-    }
-
-    #[inline]
-    fn expr(&self, node: ExprKind) -> Expr {
-        Expr::new(self.str_start, self.str_end, node)
-    }
-
-    fn parse_formatted_value<'a>(
-        &mut self,
-        mut chars: iter::Peekable<str::Chars<'a>>,
-        nested: u8,
-    ) -> Result<(Vec<Expr>, iter::Peekable<str::Chars<'a>>), FStringErrorType> {
-        let mut expression = String::from("{");
-        let mut spec = None;
-        let mut delims = Vec::new();
-        let mut conversion = ConversionFlag::None;
-        let mut self_documenting = false;
-        let mut trailing_seq = String::new();
-
-        while let Some(ch) = chars.next() {
-            match ch {
-                // can be integrated better with the remainign code, but as a starting point ok
-                // in general I would do here a tokenizing of the fstrings to omit this peeking.
-                '!' if chars.peek() == Some(&'=') => {
-                    expression.push_str("!=");
-                    chars.next();
-                }
-
-                '=' if chars.peek() == Some(&'=') => {
-                    expression.push_str("==");
-                    chars.next();
-                }
-
-                '>' if chars.peek() == Some(&'=') => {
-                    expression.push_str(">=");
-                    chars.next();
-                }
-
-                '<' if chars.peek() == Some(&'=') => {
-                    expression.push_str("<=");
-                    chars.next();
-                }
-
-                '!' if delims.is_empty() && chars.peek() != Some(&'=') => {
-                    if expression.trim().is_empty() {
-                        return Err(EmptyExpression);
-                    }
-
-                    conversion = match chars.next() {
-                        Some('s') => ConversionFlag::Str,
-                        Some('a') => ConversionFlag::Ascii,
-                        Some('r') => ConversionFlag::Repr,
-                        Some(_) => {
-                            return Err(if expression[1..].trim().is_empty() {
-                                EmptyExpression
-                            } else {
-                                InvalidConversionFlag
-                            });
-                        }
-                        None => {
-                            return Err(if expression[1..].trim().is_empty() {
-                                EmptyExpression
-                            } else {
-                                UnclosedLbrace
-                            });
-                        }
-                    };
-
-                    if let Some(&peek) = chars.peek() {
-                        if peek != '}' && peek != ':' {
-                            return Err(if expression[1..].trim().is_empty() {
-                                EmptyExpression
-                            } else {
-                                UnclosedLbrace
-                            });
-                        }
-                    } else {
-                        return Err(if expression[1..].trim().is_empty() {
-                            EmptyExpression
-                        } else {
-                            UnclosedLbrace
-                        });
-                    }
-                }
-
-                // match a python 3.8 style self documenting expression
-                // format '{' PYTHON_EXPRESSION '=' FORMAT_SPECIFIER? '}'
-                '=' if chars.peek() != Some(&'=') && delims.is_empty() => {
-                    self_documenting = true;
-                }
-
-                ':' if delims.is_empty() => {
-                    let (parsed_spec, remaining_chars) = self.parse_spec(chars, nested)?;
-
-                    spec = Some(Box::new(self.expr(ExprKind::JoinedStr {
-                        values: parsed_spec,
-                    })));
-                    chars = remaining_chars;
-                }
-                '(' | '{' | '[' => {
-                    expression.push(ch);
-                    delims.push(ch);
-                }
-                ')' => {
-                    let last_delim = delims.pop();
-                    match last_delim {
-                        Some('(') => {
-                            expression.push(ch);
-                        }
-                        Some(c) => {
-                            return Err(MismatchedDelimiter(c, ')'));
-                        }
-                        None => {
-                            return Err(Unmatched(')'));
-                        }
-                    }
-                }
-                ']' => {
-                    let last_delim = delims.pop();
-                    match last_delim {
-                        Some('[') => {
-                            expression.push(ch);
-                        }
-                        Some(c) => {
-                            return Err(MismatchedDelimiter(c, ']'));
-                        }
-                        None => {
-                            return Err(Unmatched(']'));
-                        }
-                    }
-                }
-                '}' if !delims.is_empty() => {
-                    let last_delim = delims.pop();
-                    match last_delim {
-                        Some('{') => {
-                            expression.push(ch);
-                        }
-                        Some(c) => return Err(MismatchedDelimiter(c, '}')),
-                        None => {}
-                    }
-                }
-                '}' => {
-                    if expression[1..].trim().is_empty() {
-                        return Err(EmptyExpression);
-                    }
-                    expression.push(ch);
-
-                    let ret = if !self_documenting {
-                        vec![self.expr(ExprKind::FormattedValue {
-                            value: Box::new(
-                                parse_fstring_expr(&expression[1..expression.len() - 1])
-                                    .map_err(|e| InvalidExpression(Box::new(e.error)))?,
-                            ),
-                            conversion: conversion as _,
-                            format_spec: spec,
-                        })]
-                    } else {
-                        vec![
-                            self.expr(ExprKind::Constant {
-                                value: Constant::Str(
-                                    expression[1..expression.len() - 1].to_owned() + "=",
-                                ),
-                                kind: None,
-                            }),
-                            self.expr(ExprKind::Constant {
-                                value: trailing_seq.into(),
-                                kind: None,
-                            }),
-                            self.expr(ExprKind::FormattedValue {
-                                value: Box::new(
-                                    parse_fstring_expr(&expression[1..expression.len() - 1])
-                                        .map_err(|e| InvalidExpression(Box::new(e.error)))?,
-                                ),
-                                conversion: (if conversion == ConversionFlag::None && spec.is_none()
-                                {
-                                    ConversionFlag::Repr
-                                } else {
-                                    conversion
-                                }) as _,
-                                format_spec: spec,
-                            }),
-                        ]
-                    };
-                    return Ok((ret, chars));
-                }
-                '"' | '\'' => {
-                    expression.push(ch);
-                    let mut string_ended = false;
-                    for next in &mut chars {
-                        expression.push(next);
-                        if next == ch {
-                            string_ended = true;
-                            break;
-                        }
-                    }
-                    if !string_ended {
-                        return Err(UnterminatedString);
-                    }
-                }
-                ' ' if self_documenting => {
-                    trailing_seq.push(ch);
-                }
-                '\\' => return Err(ExpressionCannotInclude('\\')),
-                _ => {
-                    if self_documenting {
-                        return Err(UnclosedLbrace);
-                    }
-
-                    expression.push(ch);
-                }
-            }
-        }
-        Err(if expression[1..].trim().is_empty() {
-            EmptyExpression
-        } else {
-            UnclosedLbrace
-        })
-    }
 
-    fn parse_spec<'a>(
-        &mut self,
-        mut chars: iter::Peekable<str::Chars<'a>>,
-        nested: u8,
-    ) -> Result<(Vec<Expr>, iter::Peekable<str::Chars<'a>>), FStringErrorType> {
-        let mut spec_constructor = Vec::new();
-        let mut constant_piece = String::new();
-        while let Some(&next) = chars.peek() {
-            match next {
-                '{' => {
-                    if !constant_piece.is_empty() {
-                        spec_constructor.push(self.expr(ExprKind::Constant {
-                            value: constant_piece.to_owned().into(),
-                            kind: None,
-                        }));
-                        constant_piece.clear();
-                    }
-                    let (parsed_expr, remaining_chars) = self.parse(chars, nested + 1)?;
-                    spec_constructor.extend(parsed_expr);
-                    chars = remaining_chars;
-                    continue;
-                }
-                '}' => {
-                    break;
-                }
-                _ => {
-                    constant_piece.push(next);
-                }
+/// A `{value!conversion:spec}` field. With a `=` after the value, `debug` holds
+/// the source text up to and including it and where that text ends.
+pub fn replacement_field(
+    location: Location,
+    end_location: Location,
+    text_location: Location,
+    value: Expr,
+    debug: Option<(String, Location)>,
+    conversion: Option<(Location, String)>,
+    format_spec: Option<Expr>,
+) -> Result<Vec<Expr>, LexicalError> {
+    let conversion = match conversion {
+        None if debug.is_some() && format_spec.is_none() => ConversionFlag::Repr,
+        None => ConversionFlag::None,
+        Some((location, name)) => match name.as_str() {
+            "s" => ConversionFlag::Str,
+            "r" => ConversionFlag::Repr,
+            "a" => ConversionFlag::Ascii,
+            _ => {
+                return Err(LexicalError {
+                    error: LexicalErrorType::FStringError(FStringErrorType::InvalidConversionFlag),
+                    location,
+                })
             }
-            chars.next();
-        }
-        if !constant_piece.is_empty() {
-            spec_constructor.push(self.expr(ExprKind::Constant {
-                value: constant_piece.to_owned().into(),
+        },
+    };
+
+    let mut values = vec![];
+    if let Some((text, text_end_location)) = debug {
+        values.push(Expr::new(
+            text_location,
+            text_end_location,
+            ExprKind::Constant {
+                value: Constant::Str(text),
                 kind: None,
-            }));
-            constant_piece.clear();
-        }
-        Ok((spec_constructor, chars))
-    }
-
-    fn parse<'a>(
-        &mut self,
-        mut chars: iter::Peekable<str::Chars<'a>>,
-        nested: u8,
-    ) -> Result<(Vec<Expr>, iter::Peekable<str::Chars<'a>>), FStringErrorType> {
-        if nested >= 2 {
-            return Err(ExpressionNestedTooDeeply);
-        }
-
-        let mut content = String::new();
-        let mut values = vec![];
-
-        while let Some(&ch) = chars.peek() {
-            match ch {
-                '{' => {
-                    chars.next();
-                    if nested == 0 {
-                        match chars.peek() {
-                            Some('{') => {
-                                chars.next();
-                                content.push('{');
-                                continue;
-                            }
-                            None => return Err(UnclosedLbrace),
-                            _ => {}
-                        }
-                    }
-                    if !content.is_empty() {
-                        values.push(self.expr(ExprKind::Constant {
-                            value: mem::take(&mut content).into(),
-                            kind: None,
-                        }));
-                    }
-
-                    let (parsed_values, remaining_chars) =
-                        self.parse_formatted_value(chars, nested)?;
-                    values.extend(parsed_values);
-                    chars = remaining_chars;
-                }
-                '}' => {
-                    if nested > 0 {
-                        break;
-                    }
-                    chars.next();
-                    if let Some('}') = chars.peek() {
-                        chars.next();
-                        content.push('}');
-                    } else {
-                        return Err(SingleRbrace);
-                    }
-                }
-                _ => {
-                    content.push(ch);
-                    chars.next();
-                }
-            }
-        }
-
-        if !content.is_empty() {
-            values.push(self.expr(ExprKind::Constant {
-                value: content.into(),
-                kind: None,
-            }))
-        }
-
-        Ok((values, chars))
-    }
-}
-
-fn parse_fstring_expr(source: &str) -> Result<Expr, ParseError> {
-    let fstring_body = format!("({})", source);
-    parse_expression(&fstring_body, "<fstring>")
-}
-
-/// Parse an fstring from a string, located at a certain position in the sourcecode.
-/// In case of errors, we will get the location and the error returned.
-pub fn parse_located_fstring(
-    source: &str,
-    start: Location,
-    end: Location,
-) -> Result<Vec<Expr>, FStringError> {
-    FStringParser::new(start, end)
-        .parse(source.chars().peekable(), 0)
-        .map(|(e, _)| e)
-        .map_err(|error| FStringError {
-            error,
-            location: start,
-        })
+            },
+        ));
+    }
+    values.push(Expr::new(
+        location,
+        end_location,
+        ExprKind::FormattedValue {
+            value: Box::new(value),
+            conversion: conversion as _,
+            format_spec: format_spec.map(Box::new),
+        },
+    ));
+    Ok(values)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use crate::{
+        ast::{Expr, ExprKind},
+        error::{FStringErrorType::*, LexicalErrorType, ParseErrorType},
+        parser::{parse_expression, parse_program},
+    };
+
+    fn parse_fstring(source: &str) -> Result<Vec<Expr>, ParseErrorType> {
+        let source = format!("f\"{}\"", source);
+        match parse_expression(&source, "<test>").map_err(|e| e.error)?.node {
+            ExprKind::JoinedStr { values } => Ok(values),
+            node => panic!("not an f-string: {:?}", node),
+        }
+    }
 
-    fn parse_fstring(source: &str) -> Result<Vec<Expr>, FStringErrorType> {
-        FStringParser::new(Location::default(), Location::default())
-            .parse(source.chars().peekable(), 0)
-            .map(|(e, _)| e)
+    fn fstring_error(source: &str) -> Option<crate::error::FStringErrorType> {
+        match parse_fstring(source) {
+            Err(ParseErrorType::Lexical(LexicalErrorType::FStringError(error))) => Some(error),
+            _ => None,
+        }
     }
 
     #[test]
@@ -429,26 +135,24 @@ mod tests {
 
     #[test]
     fn test_parse_invalid_fstring() {
-        assert_eq!(parse_fstring("{5!a"), Err(UnclosedLbrace));
-        assert_eq!(parse_fstring("{5!a1}"), Err(UnclosedLbrace));
-        assert_eq!(parse_fstring("{5!"), Err(UnclosedLbrace));
-        assert_eq!(parse_fstring("abc{!a 'cat'}"), Err(EmptyExpression));
-        assert_eq!(parse_fstring("{!a"), Err(EmptyExpression));
-        assert_eq!(parse_fstring("{ !a}"), Err(EmptyExpression));
+        assert!(parse_fstring("{5!a").is_err());
+        assert!(parse_fstring("{5!a1}").is_err());
+        assert!(parse_fstring("{5!").is_err());
+        assert_eq!(fstring_error("abc{!a 'cat'}"), Some(EmptyExpression));
+        assert_eq!(fstring_error("{!a"), Some(EmptyExpression));
+        assert_eq!(fstring_error("{ !a}"), Some(EmptyExpression));
 
-        assert_eq!(parse_fstring("{5!}"), Err(InvalidConversionFlag));
-        assert_eq!(parse_fstring("{5!x}"), Err(InvalidConversionFlag));
+        assert!(parse_fstring("{5!}").is_err());
+        assert_eq!(fstring_error("{5!x}"), Some(InvalidConversionFlag));
 
-        assert_eq!(parse_fstring("{a:{a:{b}}}"), Err(ExpressionNestedTooDeeply));
+        assert_eq!(fstring_error("{a:b}}"), Some(SingleRbrace));
+        assert_eq!(fstring_error("}"), Some(SingleRbrace));
+        assert!(parse_fstring("{a:{b}").is_err());
+        assert!(parse_fstring("{").is_err());
+        assert_eq!(fstring_error("{a)}"), Some(Unmatched(')')));
 
-        assert_eq!(parse_fstring("{a:b}}"), Err(SingleRbrace));
-        assert_eq!(parse_fstring("}"), Err(SingleRbrace));
-        assert_eq!(parse_fstring("{a:{b}"), Err(UnclosedLbrace));
-        assert_eq!(parse_fstring("{"), Err(UnclosedLbrace));
+        assert_eq!(fstring_error("{}"), Some(EmptyExpression));
 
-        assert_eq!(parse_fstring("{}"), Err(EmptyExpression));
-
-        // TODO: check for InvalidExpression enum?
         assert!(parse_fstring("{class}").is_err());
     }
 
@@ -482,8 +186,30 @@ mod tests {
 
     #[test]
     fn test_parse_fstring_yield_expr() {
-        let source = "{yield}";
+        let source = "def f():\n    return f\"{yield}\"";
+        let parse_ast = parse_program(source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_parse_fstring_same_quotes_and_backslashes() {
+        let source = r#"{", ".join(["a\n", 'b'])!r:>{width}} {f"{x}"}"#;
         let parse_ast = parse_fstring(source).unwrap();
         insta::assert_debug_snapshot!(parse_ast);
     }
+
+    #[test]
+    fn test_parse_fstring_multiline_field_with_comment() {
+        let source = "f'''{\n    x  # the value\n    + 1\n}'''";
+        let parse_ast = parse_expression(source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_fstring_error_location() {
+        let error = parse_expression("f'abc {x!z}'", "<test>").unwrap_err();
+        assert_eq!((error.location.row(), error.location.column()), (1, 9));
+        let error = parse_expression("f'''\n  {a +}'''", "<test>").unwrap_err();
+        assert_eq!((error.location.row(), error.location.column()), (2, 7));
+    }
 }
@@ -5,7 +5,8 @@
 use super::token::StringKind;
 pub use super::token::Tok;
 use crate::ast::Location;
-use crate::error::{LexicalError, LexicalErrorType};
+use crate::error::{FStringErrorType, LexicalError, LexicalErrorType};
+use crate::regex;
 use num_bigint::BigInt;
 use num_traits::identities::Zero;
 use num_traits::Num;
@@ -143,6 +144,8 @@ pub struct Lexer<T: Iterator<Item = char>> {
 
     pending: Vec<Spanned>,
     location: Location,
+    /// The f-strings being lexed, innermost last.
+    fstrings: Vec<FStringFrame>,
 }
 
 // generated in build.rs, in gen_phf()
@@ -232,6 +235,7 @@ where
             pending: Vec::new(),
             location: start,
             window: CharWindow::new(input),
+            fstrings: Vec::new(),
         };
         lxr.window.slide();
         lxr.window.slide();
@@ -246,16 +250,17 @@ where
         let mut name = String::new();
         let start_pos = self.get_pos();
 
-        // Detect potential string like rb'' b'' f'' u'' r''
+        // Detect potential string like rb'' b'' f'' u'' r'' d''
         let mut saw_b = false;
         let mut saw_r = false;
         let mut saw_u = false;
         let mut saw_f = false;
+        let mut saw_d = false;
         loop {
-            // Detect r"", f"", b"" and u""
+            // Detect r"", f"", b"", u"" and d""
             if !(saw_b || saw_u || saw_f) && matches!(self.window[0], Some('b' | 'B')) {
                 saw_b = true;
-            } else if !(saw_b || saw_r || saw_u || saw_f)
+            } else if !(saw_b || saw_r || saw_u || saw_f || saw_d)
                 && matches!(self.window[0], Some('u' | 'U'))
             {
                 saw_u = true;
@@ -263,6 +268,8 @@ where
                 saw_r = true;
             } else if !(saw_b || saw_u || saw_f) && matches!(self.window[0], Some('f' | 'F')) {
                 saw_f = true;
+            } else if !(saw_u || saw_d) && matches!(self.window[0], Some('d' | 'D')) {
+                saw_d = true;
             } else {
                 break;
             }
@@ -272,9 +279,12 @@ where
 
             // Check if we have a string:
             if matches!(self.window[0], Some('"' | '\'')) {
-                return self
-                    .lex_string(saw_b, saw_r, saw_u, saw_f)
-                    .map(|(_, tok, end_pos)| (start_pos, tok, end_pos));
+                let string = if saw_f {
+                    self.lex_fstring_start(saw_r, saw_d)
+                } else {
+                    self.lex_string(saw_b, saw_r, saw_u, saw_d)
+                };
+                return string.map(|(_, tok, end_pos)| (start_pos, tok, end_pos));
             }
         }
 
@@ -334,8 +344,8 @@ where
         // Normal number:
         let mut value_text = self.radix_run(10);
 
-        // If float:
-        if self.window[0] == Some('.') || self.at_exponent() {
+        // If float, where `1..2` is a range rather than `1.` followed by `.2`:
+        if (self.window[0] == Some('.') && self.window[1] != Some('.')) || self.at_exponent() {
             // Take '.':
             if self.window[0] == Some('.') {
                 if self.window[1] == Some('_') {
@@ -556,33 +566,19 @@ where
         is_bytes: bool,
         is_raw: bool,
         is_unicode: bool,
-        is_fstring: bool,
+        is_dedent: bool,
     ) -> LexResult {
         let start_pos = self.get_pos();
-        let quote_char = self.next_char().unwrap();
-        let mut string_content = String::new();
-
-        // If the next two characters are also the quote character, then we have a triple-quoted
-        // string; consume those two characters and ensure that we require a triple-quote to close
-        let triple_quoted =
-            if self.window[0] == Some(quote_char) && self.window[1] == Some(quote_char) {
-                self.next_char();
-                self.next_char();
-                true
-            } else {
-                false
-            };
+        let (quote_char, triple_quoted) = self.lex_opening_quote(is_dedent)?;
+        let mut text = StringText::new();
 
         loop {
             match self.next_char() {
                 Some('\\') => {
-                    if self.window[0] == Some(quote_char) && !is_raw {
-                        string_content.push(quote_char);
-                        self.next_char();
-                    } else if is_raw {
-                        string_content.push('\\');
+                    if is_raw {
+                        text.push('\\');
                         if let Some(c) = self.next_char() {
-                            string_content.push(c)
+                            text.push_source(c);
                         } else {
                             return Err(LexicalError {
                                 error: LexicalErrorType::StringError,
@@ -590,44 +586,7 @@ where
                             });
                         }
                     } else {
-                        match self.next_char() {
-                            Some('\\') => {
-                                string_content.push('\\');
-                            }
-                            Some('\'') => string_content.push('\''),
-                            Some('\"') => string_content.push('\"'),
-                            Some('\n') => {
-                                // Ignore Unix EOL character
-                            }
-                            Some('a') => string_content.push('\x07'),
-                            Some('b') => string_content.push('\x08'),
-                            Some('f') => string_content.push('\x0c'),
-                            Some('n') => {
-                                string_content.push('\n');
-                            }
-                            Some('r') => string_content.push('\r'),
-                            Some('t') => {
-                                string_content.push('\t');
-                            }
-                            Some('v') => string_content.push('\x0b'),
-                            Some(o @ '0'..='7') => string_content.push(self.parse_octet(o)),
-                            Some('x') => string_content.push(self.unicode_literal(2)?),
-                            Some('u') if !is_bytes => string_content.push(self.unicode_literal(4)?),
-                            Some('U') if !is_bytes => string_content.push(self.unicode_literal(8)?),
-                            Some('N') if !is_bytes => {
-                                string_content.push(self.parse_unicode_name()?)
-                            }
-                            Some(c) => {
-                                string_content.push('\\');
-                                string_content.push(c);
-                            }
-                            None => {
-                                return Err(LexicalError {
-                                    error: LexicalErrorType::StringError,
-                                    location: self.get_pos(),
-                                });
-                            }
-                        }
+                        self.lex_escape(is_bytes, &mut text)?;
                     }
                 }
                 Some(c) => {
@@ -643,7 +602,7 @@ where
                                 self.next_char();
                                 break;
                             }
-                            string_content.push(c);
+                            text.push(c);
                         } else {
                             break;
                         }
@@ -654,7 +613,7 @@ where
                                 location: self.get_pos(),
                             });
                         }
-                        string_content.push(c);
+                        text.push_source(c);
                     }
                 }
                 None => {
@@ -671,14 +630,23 @@ where
         }
         let end_pos = self.get_pos();
 
+        let string_content = if is_dedent {
+            let piece = DedentPiece {
+                text: &text,
+                at_start: true,
+                at_end: true,
+            };
+            dedent(&[piece]).pop().unwrap()
+        } else {
+            text.value
+        };
+
         let tok = if is_bytes {
             Tok::Bytes {
                 value: string_content.chars().map(|c| c as u8).collect(),
             }
         } else {
-            let kind = if is_fstring {
-                StringKind::F
-            } else if is_unicode {
+            let kind = if is_unicode {
                 StringKind::U
             } else {
                 StringKind::Normal
@@ -692,6 +660,395 @@ where
         Ok((start_pos, tok, end_pos))
     }
 
+    /// Lex a `%r{pattern}flags` regex literal. Braces in the pattern only need
+    /// escaping when they don't balance, so quantifiers like `{2,3}` can be
+    /// written as is; inside a character class braces don't count.
+    fn lex_regex(&mut self) -> LexResult {
+        let start_pos = self.get_pos();
+        // Skip over `%r{`.
+        self.next_char();
+        self.next_char();
+        self.next_char();
+        let pattern_pos = self.get_pos();
+        let mut pattern = String::new();
+        let mut depth = 0;
+        let mut class_depth = 0;
+        loop {
+            let c = match self.next_char() {
+                Some(c) => c,
+                None => {
+                    return Err(LexicalError {
+                        error: LexicalErrorType::Eof,
+                        location: self.get_pos(),
+                    });
+                }
+            };
+            match c {
+                '}' if class_depth == 0 && depth == 0 => break,
+                '}' if class_depth == 0 => depth -= 1,
+                '{' if class_depth == 0 => depth += 1,
+                '\\' => {
+                    pattern.push(c);
+                    match self.next_char() {
+                        Some(c) => pattern.push(c),
+                        None => {
+                            return Err(LexicalError {
+                                error: LexicalErrorType::Eof,
+                                location: self.get_pos(),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                '[' => {
+                    class_depth += 1;
+                    pattern.push(c);
+                    // A `]` right after the opening bracket is a literal.
+                    if self.window[0] == Some('^') {
+                        pattern.push(self.next_char().unwrap());
+                    }
+                    if self.window[0] == Some(']') {
+                        pattern.push(self.next_char().unwrap());
+                    }
+                    continue;
+                }
+                ']' if class_depth > 0 => class_depth -= 1,
+                _ => {}
+            }
+            pattern.push(c);
+        }
+
+        let mut flags = String::new();
+        while let Some(c) = self.window[0].filter(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            if !regex::FLAGS.contains(c) {
+                return Err(LexicalError {
+                    error: LexicalErrorType::RegexError(format!("unknown flag '{}'", c)),
+                    location: self.get_pos(),
+                });
+            }
+            self.next_char();
+            flags.push(c);
+        }
+        regex::validate(&pattern, &flags, pattern_pos)?;
+        let end_pos = self.get_pos();
+        Ok((start_pos, Tok::Regex { pattern, flags }, end_pos))
+    }
+
+    /// Consume the opening quote or triple quote of a string, returning the quote
+    /// character and whether it was tripled.
+    fn lex_opening_quote(&mut self, is_dedent: bool) -> Result<(char, bool), LexicalError> {
+        let start_pos = self.get_pos();
+        let quote_char = self.next_char().unwrap();
+
+        // If the next two characters are also the quote character, then we have a triple-quoted
+        // string; consume those two characters and ensure that we require a triple-quote to close
+        let triple_quoted =
+            if self.window[0] == Some(quote_char) && self.window[1] == Some(quote_char) {
+                self.next_char();
+                self.next_char();
+                true
+            } else {
+                false
+            };
+
+        if is_dedent && !triple_quoted {
+            return Err(LexicalError {
+                error: LexicalErrorType::OtherError(
+                    "dedented string literal must be triple-quoted".to_owned(),
+                ),
+                location: start_pos,
+            });
+        }
+        Ok((quote_char, triple_quoted))
+    }
+
+    /// Lex the escape sequence after a backslash in a string that isn't raw.
+    fn lex_escape(&mut self, is_bytes: bool, text: &mut StringText) -> Result<(), LexicalError> {
+        match self.next_char() {
+            Some('\\') => {
+                text.push('\\');
+            }
+            Some('\'') => text.push('\''),
+            Some('\"') => text.push('\"'),
+            Some('\n') => {
+                // Ignore Unix EOL character
+                text.start_line();
+            }
+            Some('a') => text.push('\x07'),
+            Some('b') => text.push('\x08'),
+            Some('f') => text.push('\x0c'),
+            Some('n') => {
+                text.push('\n');
+            }
+            Some('r') => text.push('\r'),
+            Some('t') => {
+                text.push('\t');
+            }
+            Some('v') => text.push('\x0b'),
+            Some(o @ '0'..='7') => text.push(self.parse_octet(o)),
+            Some('x') => text.push(self.unicode_literal(2)?),
+            Some('u') if !is_bytes => text.push(self.unicode_literal(4)?),
+            Some('U') if !is_bytes => text.push(self.unicode_literal(8)?),
+            Some('N') if !is_bytes => text.push(self.parse_unicode_name()?),
+            Some(c) => {
+                text.push('\\');
+                text.push_source(c);
+            }
+            None => {
+                return Err(LexicalError {
+                    error: LexicalErrorType::StringError,
+                    location: self.get_pos(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Lex the prefix and opening quote of an f-string. The rest of it is lexed in
+    /// `lex_fstring_middle` and `lex_field_delimiter`.
+    fn lex_fstring_start(&mut self, is_raw: bool, is_dedent: bool) -> LexResult {
+        let start_pos = self.get_pos();
+        let (quote_char, triple_quoted) = self.lex_opening_quote(is_dedent)?;
+        let end_pos = self.get_pos();
+        self.fstrings.push(FStringFrame::Text(FStringContext {
+            quote_char,
+            triple_quoted,
+            is_raw,
+            dedent: is_dedent.then(Vec::new),
+            after_start: true,
+        }));
+        Ok((start_pos, Tok::FStringStart, end_pos))
+    }
+
+    /// Lex literal text of the f-string, or of a format spec, up to the next
+    /// replacement field or the closing quote.
+    fn lex_fstring_middle(&mut self) -> Result<(), LexicalError> {
+        let in_spec = matches!(self.fstrings.last(), Some(FStringFrame::Spec));
+        let context = self.fstring_context();
+        let (quote_char, triple_quoted, is_raw) =
+            (context.quote_char, context.triple_quoted, context.is_raw);
+        let fstring_error = |error, location| LexicalError {
+            error: LexicalErrorType::FStringError(error),
+            location,
+        };
+
+        let start_pos = self.get_pos();
+        let mut text = StringText::new();
+        loop {
+            match self.window[0] {
+                Some(c) if c == quote_char => {
+                    let closing = !triple_quoted
+                        || (self.window[1] == Some(quote_char) && self.window[2] == Some(quote_char));
+                    if !closing {
+                        self.next_char();
+                        text.push_source(c);
+                        continue;
+                    }
+                    if in_spec {
+                        return Err(fstring_error(FStringErrorType::UnclosedLbrace, self.get_pos()));
+                    }
+                    self.emit_fstring_middle(start_pos, text, false);
+                    let end_start = self.get_pos();
+                    for _ in 0..if triple_quoted { 3 } else { 1 } {
+                        self.next_char();
+                    }
+                    let end_pos = self.get_pos();
+                    self.emit((end_start, Tok::FStringEnd, end_pos));
+                    self.finish_fstring();
+                    return Ok(());
+                }
+                Some('{') if !in_spec && self.window[1] == Some('{') => {
+                    self.next_char();
+                    self.next_char();
+                    text.push('{');
+                }
+                Some('{') => {
+                    self.emit_fstring_middle(start_pos, text, true);
+                    self.eat_single_char(Tok::Lbrace);
+                    self.nesting += 1;
+                    self.fstrings.push(FStringFrame::Field {
+                        nesting: self.nesting,
+                        text: String::new(),
+                    });
+                    return Ok(());
+                }
+                Some('}') if in_spec => {
+                    self.emit_fstring_middle(start_pos, text, true);
+                    self.fstrings.pop();
+                    return Ok(());
+                }
+                Some('}') if self.window[1] == Some('}') => {
+                    self.next_char();
+                    self.next_char();
+                    text.push('}');
+                }
+                Some('}') => {
+                    return Err(fstring_error(FStringErrorType::SingleRbrace, self.get_pos()));
+                }
+                // A backslash in front of a replacement field stays a backslash.
+                Some('\\') if matches!(self.window[1], Some('{' | '}')) => {
+                    self.next_char();
+                    text.push('\\');
+                }
+                Some('\\') => {
+                    self.next_char();
+                    if is_raw {
+                        text.push('\\');
+                        match self.next_char() {
+                            Some(c) => text.push_source(c),
+                            None => {
+                                return Err(LexicalError {
+                                    error: LexicalErrorType::StringError,
+                                    location: self.get_pos(),
+                                })
+                            }
+                        }
+                    } else {
+                        self.lex_escape(false, &mut text)?;
+                    }
+                }
+                Some('\n') if !triple_quoted => {
+                    return Err(LexicalError {
+                        error: LexicalErrorType::Eof,
+                        location: self.get_pos(),
+                    });
+                }
+                Some(c) => {
+                    self.next_char();
+                    text.push_source(c);
+                }
+                None => {
+                    return Err(LexicalError {
+                        error: if triple_quoted {
+                            LexicalErrorType::Eof
+                        } else {
+                            LexicalErrorType::StringError
+                        },
+                        location: self.get_pos(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Emit the literal text lexed so far, if any. `before_field` tells whether a
+    /// replacement field follows rather than the closing quote.
+    fn emit_fstring_middle(&mut self, start_pos: Location, text: StringText, before_field: bool) {
+        let end_pos = self.get_pos();
+        let index = self.pending.len();
+        let in_text = matches!(self.fstrings.last(), Some(FStringFrame::Text(_)));
+        let context = self.fstring_context();
+        let after_start = std::mem::replace(&mut context.after_start, false);
+        if text.value.is_empty() {
+            return;
+        }
+        let value = text.value.clone();
+        // Text in a format spec isn't dedented, only the body of the f-string is.
+        if let (Some(middles), true) = (&mut context.dedent, in_text) {
+            middles.push(DedentMiddle {
+                index,
+                text,
+                at_start: after_start,
+                at_end: !before_field,
+            });
+        }
+        self.emit((start_pos, Tok::FStringMiddle { value }, end_pos));
+    }
+
+    /// Pop the f-string whose `FStringEnd` was just emitted, dedenting its text for `fd"""`.
+    fn finish_fstring(&mut self) {
+        let middles = match self.fstrings.pop() {
+            Some(FStringFrame::Text(FStringContext { dedent: Some(middles), .. })) => middles,
+            _ => return,
+        };
+        let pieces: Vec<_> = middles
+            .iter()
+            .map(|middle| DedentPiece {
+                text: &middle.text,
+                at_start: middle.at_start,
+                at_end: middle.at_end,
+            })
+            .collect();
+        let values = dedent(&pieces);
+        // Back to front, so that removing an emptied middle doesn't move the others.
+        for (middle, value) in middles.iter().zip(values).rev() {
+            if value.is_empty() {
+                self.pending.remove(middle.index);
+            } else {
+                self.pending[middle.index].1 = Tok::FStringMiddle { value };
+            }
+        }
+    }
+
+    /// Handle `}`, `:`, `!` and `=` at the top level of a replacement field. Returns
+    /// whether `c` was one of them, otherwise it is lexed as usual.
+    fn lex_field_delimiter(&mut self, c: char) -> Result<bool, LexicalError> {
+        let is_delimiter = match c {
+            ')' | ']' => {
+                return Err(LexicalError {
+                    error: LexicalErrorType::FStringError(FStringErrorType::Unmatched(c)),
+                    location: self.get_pos(),
+                })
+            }
+            '}' | ':' => true,
+            '!' | '=' => self.window[1] != Some('='),
+            _ => false,
+        };
+        if !is_delimiter {
+            return Ok(false);
+        }
+        if self.field_text().trim().is_empty() {
+            return Err(LexicalError {
+                error: LexicalErrorType::FStringError(FStringErrorType::EmptyExpression),
+                location: self.get_pos(),
+            });
+        }
+        match c {
+            '}' => {
+                self.eat_single_char(Tok::Rbrace);
+                self.nesting -= 1;
+                self.fstrings.pop();
+            }
+            ':' => {
+                self.eat_single_char(Tok::Colon);
+                self.fstrings.push(FStringFrame::Spec);
+            }
+            '!' => self.eat_single_char(Tok::Exclamation),
+            _ => {
+                // `{x = }` prints the expression's source text including the whitespace around `=`.
+                let tok_start = self.get_pos();
+                self.next_char();
+                while let Some(' ' | '\t') = self.window[0] {
+                    self.next_char();
+                }
+                let tok_end = self.get_pos();
+                let text = self.field_text().to_owned();
+                self.emit((tok_start, Tok::FStringDebug { text }, tok_end));
+            }
+        }
+        Ok(true)
+    }
+
+    /// The innermost f-string, whose text we are in or whose replacement field we are in.
+    fn fstring_context(&mut self) -> &mut FStringContext {
+        self.fstrings
+            .iter_mut()
+            .rev()
+            .find_map(|frame| match frame {
+                FStringFrame::Text(context) => Some(context),
+                _ => None,
+            })
+            .expect("not inside an f-string")
+    }
+
+    /// The source text of the innermost replacement field so far.
+    fn field_text(&self) -> &str {
+        match self.fstrings.last() {
+            Some(FStringFrame::Field { text, .. }) => text,
+            _ => unreachable!("not inside a replacement field"),
+        }
+    }
+
     fn is_identifier_start(&self, c: char) -> bool {
         c == '_' || is_xid_start(c)
     }
@@ -708,12 +1065,18 @@ where
     /// This function is used by the iterator implementation.
     fn inner_next(&mut self) -> LexResult {
         // top loop, keep on processing, until we have something pending.
-        while self.pending.is_empty() {
-            // Detect indentation levels
+        loop {
+            // Detect indentation levels. This happens before a pending newline is handed out,
+            // because a leading-dot continuation on the next line takes that newline back.
             if self.at_begin_of_line {
                 self.handle_indentations()?;
             }
 
+            // The text of a dedented f-string is only known once it ends, so hold on to its tokens until then.
+            if !self.pending.is_empty() && !self.in_dedented_fstring() {
+                break;
+            }
+
             self.consume_normal()?;
         }
 
@@ -799,6 +1162,15 @@ where
         // Determine indent or dedent:
         let current_indentation = self.indentations.current();
         let ordering = indentation_level.compare_strict(current_indentation, self.get_pos())?;
+
+        // An indented line starting with `.method` continues the expression on the line before.
+        if ordering == Ordering::Greater && self.is_leading_dot() {
+            if let Some(newline) = self.pending.iter().rposition(|(_, tok, _)| *tok == Tok::Newline) {
+                self.pending.remove(newline);
+                return Ok(());
+            }
+        }
+
         match ordering {
             Ordering::Equal => {
                 // Same same
@@ -841,8 +1213,34 @@ where
         Ok(())
     }
 
+    fn in_dedented_fstring(&self) -> bool {
+        self.fstrings.iter().any(|frame| {
+            matches!(frame, FStringFrame::Text(FStringContext { dedent: Some(_), .. }))
+        })
+    }
+
+    /// Check for a `.` followed by a name, which unlike `...` or `.5` can only be an attribute access.
+    fn is_leading_dot(&self) -> bool {
+        match (self.window[0], self.window[1]) {
+            (Some('.'), Some(c)) => self.is_identifier_start(c),
+            _ => false,
+        }
+    }
+
     /// Take a look at the next character, if any, and decide upon the next steps.
     fn consume_normal(&mut self) -> Result<(), LexicalError> {
+        match self.fstrings.last() {
+            Some(FStringFrame::Text(_) | FStringFrame::Spec) => return self.lex_fstring_middle(),
+            Some(FStringFrame::Field { nesting, .. }) if *nesting == self.nesting => {
+                if let Some(c) = self.window[0] {
+                    if self.lex_field_delimiter(c)? {
+                        return Ok(());
+                    }
+                }
+            }
+            _ => {}
+        }
+
         // Check if we have some character:
         if let Some(c) = self.window[0] {
             // First check identifier:
@@ -993,6 +1391,10 @@ where
                     }
                 }
             }
+            '%' if self.window[1] == Some('r') && self.window[2] == Some('{') => {
+                let regex = self.lex_regex()?;
+                self.emit(regex);
+            }
             '%' => {
                 let tok_start = self.get_pos();
                 self.next_char();
@@ -1012,6 +1414,10 @@ where
                     self.next_char();
                     let tok_end = self.get_pos();
                     self.emit((tok_start, Tok::VbarEqual, tok_end));
+                } else if let Some('>') = self.window[0] {
+                    self.next_char();
+                    let tok_end = self.get_pos();
+                    self.emit((tok_start, Tok::VbarGreater, tok_end));
                 } else {
                     let tok_end = self.get_pos();
                     self.emit((tok_start, Tok::Vbar, tok_end));
@@ -1090,6 +1496,34 @@ where
             '~' => {
                 self.eat_single_char(Tok::Tilde);
             }
+            '?' => {
+                let tok_start = self.get_pos();
+                self.next_char();
+                match self.window[0] {
+                    Some('.') => {
+                        self.next_char();
+                        let tok_end = self.get_pos();
+                        self.emit((tok_start, Tok::QuestionDot, tok_end));
+                    }
+                    Some('[') => {
+                        self.next_char();
+                        let tok_end = self.get_pos();
+                        self.emit((tok_start, Tok::QuestionLsqb, tok_end));
+                        self.nesting += 1;
+                    }
+                    Some('?') => {
+                        self.next_char();
+                        let tok_end = self.get_pos();
+                        self.emit((tok_start, Tok::DoubleQuestion, tok_end));
+                    }
+                    _ => {
+                        return Err(LexicalError {
+                            error: LexicalErrorType::UnrecognizedToken { tok: '?' },
+                            location: tok_start,
+                        });
+                    }
+                }
+            }
             '(' => {
                 self.eat_single_char(Tok::Lpar);
                 self.nesting += 1;
@@ -1223,6 +1657,10 @@ where
                         self.next_char();
                         let tok_end = self.get_pos();
                         self.emit((tok_start, Tok::Ellipsis, tok_end));
+                    } else if let Some('.') = self.window[0] {
+                        self.next_char();
+                        let tok_end = self.get_pos();
+                        self.emit((tok_start, Tok::DotDot, tok_end));
                     } else {
                         let tok_end = self.get_pos();
                         self.emit((tok_start, Tok::Dot, tok_end));
@@ -1289,6 +1727,13 @@ where
     fn next_char(&mut self) -> Option<char> {
         let c = self.window[0];
         self.window.slide();
+        if let Some(c) = c {
+            for frame in &mut self.fstrings {
+                if let FStringFrame::Field { text, .. } = frame {
+                    text.push(c);
+                }
+            }
+        }
         if c == Some('\n') {
             self.location.newline();
         } else {
@@ -1308,6 +1753,181 @@ where
     }
 }
 
+/// Where the lexer is inside an f-string.
+enum FStringFrame {
+    /// The literal text of an f-string.
+    Text(FStringContext),
+    /// The expression of a replacement field, whose top level is at `nesting`.
+    /// `text` collects its source for `{x=}`.
+    Field { nesting: usize, text: String },
+    /// The format spec after `:`, literal text that can hold replacement fields.
+    Spec,
+}
+
+struct FStringContext {
+    quote_char: char,
+    triple_quoted: bool,
+    is_raw: bool,
+    /// The `FStringMiddle`s of an `fd"""` string, dedented when it ends.
+    dedent: Option<Vec<DedentMiddle>>,
+    /// Whether nothing has been lexed since the opening quotes.
+    after_start: bool,
+}
+
+struct DedentMiddle {
+    /// Position of the token in `pending`.
+    index: usize,
+    text: StringText,
+    at_start: bool,
+    at_end: bool,
+}
+
+/// A source line inside a string literal, as byte offsets into the string's value.
+#[derive(Default)]
+struct SourceLine {
+    start: usize,
+    /// End of the spaces and tabs the line starts with in the source.
+    indent_end: usize,
+}
+
+impl SourceLine {
+    fn starting_at(start: usize) -> Self {
+        SourceLine {
+            start,
+            indent_end: start,
+        }
+    }
+}
+
+/// The value of a string literal being lexed, with its source lines for dedenting.
+struct StringText {
+    value: String,
+    lines: Vec<SourceLine>,
+}
+
+impl StringText {
+    fn new() -> Self {
+        StringText {
+            value: String::new(),
+            lines: vec![SourceLine::default()],
+        }
+    }
+
+    /// Push a character that comes from an escape sequence.
+    fn push(&mut self, c: char) {
+        self.value.push(c);
+    }
+
+    /// Push a character as written in the source, keeping track of lines and indentation.
+    fn push_source(&mut self, c: char) {
+        let line = self.lines.last_mut().unwrap();
+        let at_indentation = line.indent_end == self.value.len();
+        self.value.push(c);
+        if c == '\n' {
+            self.start_line();
+        } else if at_indentation && matches!(c, ' ' | '\t') {
+            line.indent_end = self.value.len();
+        }
+    }
+
+    fn start_line(&mut self) {
+        self.lines.push(SourceLine::starting_at(self.value.len()));
+    }
+}
+
+/// A run of literal text in a dedented string: the whole string, or one
+/// `FStringMiddle` of an f-string.
+struct DedentPiece<'a> {
+    text: &'a StringText,
+    /// The text starts right after the opening quotes.
+    at_start: bool,
+    /// The text ends at the closing quotes rather than at a replacement field.
+    at_end: bool,
+}
+
+/// Strip the indentation shared by the lines of a `d"""` string, like Python's
+/// `textwrap.dedent`. A blank first line is dropped and so is the whitespace in
+/// front of the closing quotes, which makes
+///
+/// ```text
+///     d"""
+///         hello
+///         """
+/// ```
+///
+/// equal to `"hello\n"`. Only indentation written in the source counts, whitespace
+/// from escapes like `\t` is kept. Returns the new value of each piece.
+fn dedent(pieces: &[DedentPiece]) -> Vec<String> {
+    struct Line<'a> {
+        content: &'a str,
+        indent: usize,
+        /// Only the first line of a piece can continue a source line.
+        starts_line: bool,
+        /// The first line, right after the opening quotes.
+        opens_string: bool,
+        blank: bool,
+    }
+
+    let pieces: Vec<Vec<Line>> = pieces
+        .iter()
+        .map(|piece| {
+            let value = &piece.text.value;
+            let lines = &piece.text.lines;
+            let ends = lines
+                .iter()
+                .skip(1)
+                .map(|line| line.start)
+                .chain(std::iter::once(value.len()));
+            lines
+                .iter()
+                .zip(ends)
+                .enumerate()
+                .map(|(i, (line, end))| {
+                    let last = i + 1 == lines.len();
+                    let rest = &value[line.indent_end..end];
+                    Line {
+                        content: &value[line.start..end],
+                        indent: line.indent_end - line.start,
+                        starts_line: i > 0,
+                        opens_string: i == 0 && piece.at_start,
+                        // A replacement field after the indentation is content.
+                        blank: rest == "\n" || (rest.is_empty() && (!last || piece.at_end)),
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    // The first line follows the opening quotes, so its indentation isn't part of the margin.
+    let mut margin: Option<&str> = None;
+    for line in pieces.iter().flatten().filter(|line| line.starts_line && !line.blank) {
+        let indent = &line.content[..line.indent];
+        margin = Some(match margin {
+            Some(margin) => {
+                let common = margin.bytes().zip(indent.bytes()).take_while(|(a, b)| a == b).count();
+                &margin[..common]
+            }
+            None => indent,
+        });
+    }
+    let margin = margin.map_or(0, str::len);
+
+    pieces
+        .iter()
+        .map(|lines| {
+            let mut value = String::new();
+            for line in lines {
+                if line.starts_line {
+                    value.push_str(&line.content[if line.blank { line.indent } else { margin }..]);
+                } else if !(line.opens_string && line.blank) {
+                    value.push_str(line.content);
+                }
+            }
+            value
+        })
+        .collect()
+}
+
 /* Implement iterator pattern for the get_tok function.
 
 Calling the next element in the iterator will yield the next lexical
@@ -1340,7 +1960,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{make_tokenizer, NewlineHandler, StringKind, Tok};
+    use super::{make_tokenizer, Location, NewlineHandler, StringKind, Tok};
     use num_bigint::BigInt;
 
     const WINDOWS_EOL: &str = "\r\n";
@@ -1649,6 +2269,91 @@ mod tests {
         test_newline_in_brackets_unix_eol: UNIX_EOL,
     }
 
+    macro_rules! test_leading_dot_continuation {
+        ($($name:ident: $eol:expr,)*) => {
+        $(
+            #[test]
+            fn $name() {
+                let source = format!("x{}  .y(){}  # comment{}  .z{}", $eol, $eol, $eol, $eol);
+                let tokens = lex_source(&source);
+                assert_eq!(
+                    tokens,
+                    vec![
+                        Tok::Name {
+                            name: String::from("x"),
+                        },
+                        Tok::Dot,
+                        Tok::Name {
+                            name: String::from("y"),
+                        },
+                        Tok::Lpar,
+                        Tok::Rpar,
+                        Tok::Comment,
+                        Tok::Dot,
+                        Tok::Name {
+                            name: String::from("z"),
+                        },
+                        Tok::Newline,
+                    ]
+                );
+            }
+        )*
+        };
+    }
+
+    test_leading_dot_continuation! {
+        test_leading_dot_continuation_windows_eol: WINDOWS_EOL,
+        test_leading_dot_continuation_mac_eol: MAC_EOL,
+        test_leading_dot_continuation_unix_eol: UNIX_EOL,
+    }
+
+    #[test]
+    fn test_leading_dot_needs_indentation() {
+        let source = "if x:\n  y\n.z";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::If,
+                Tok::Name {
+                    name: String::from("x"),
+                },
+                Tok::Colon,
+                Tok::Newline,
+                Tok::Indent,
+                Tok::Name {
+                    name: String::from("y"),
+                },
+                Tok::Newline,
+                Tok::Dedent,
+                Tok::Dot,
+                Tok::Name {
+                    name: String::from("z"),
+                },
+                Tok::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_indented_ellipsis_is_not_a_continuation() {
+        let source = "x\n  ...";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::Name {
+                    name: String::from("x"),
+                },
+                Tok::Newline,
+                Tok::Indent,
+                Tok::Ellipsis,
+                Tok::Newline,
+                Tok::Dedent,
+            ]
+        );
+    }
+
     #[test]
     fn test_operators() {
         let source = "//////=/ /";
@@ -1666,6 +2371,60 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_optional_chaining_operators() {
+        let source = "a?.b?[c] ?? d";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::Name {
+                    name: String::from("a"),
+                },
+                Tok::QuestionDot,
+                Tok::Name {
+                    name: String::from("b"),
+                },
+                Tok::QuestionLsqb,
+                Tok::Name {
+                    name: String::from("c"),
+                },
+                Tok::Rsqb,
+                Tok::DoubleQuestion,
+                Tok::Name {
+                    name: String::from("d"),
+                },
+                Tok::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ranges_and_pipeline() {
+        let source = "1..2 1...2 x |> f ...";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::Int { value: BigInt::from(1) },
+                Tok::DotDot,
+                Tok::Int { value: BigInt::from(2) },
+                Tok::Int { value: BigInt::from(1) },
+                Tok::Ellipsis,
+                Tok::Int { value: BigInt::from(2) },
+                Tok::Name {
+                    name: String::from("x"),
+                },
+                Tok::VbarGreater,
+                Tok::Name {
+                    name: String::from("f"),
+                },
+                Tok::Ellipsis,
+                Tok::Newline,
+            ]
+        );
+    }
+
     #[test]
     fn test_string() {
         let source = r#""double" 'single' 'can\'t' "\\\"" '\t\r\n' '\g' r'raw\'' '\420' '\200\0a'"#;
@@ -1712,6 +2471,148 @@ mod tests {
         test_string_continuation_unix_eol: UNIX_EOL,
     }
 
+    #[test]
+    fn test_dedented_string() {
+        let source = "x = d\"\"\"\n    first\n      nested\n\n    \\tlast\n    \"\"\"\n";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::Name {
+                    name: String::from("x")
+                },
+                Tok::Equal,
+                stok("first\n  nested\n\n\tlast\n"),
+                Tok::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedented_string_keeps_first_line() {
+        let source = "D'''  a\n    b\n      c''' rd'''\n  \\n\n  '''";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![stok("  a\nb\n  c"), stok("\\n\n"), Tok::Newline]
+        );
+    }
+
+    #[test]
+    fn test_dedented_fstring_and_bytes() {
+        let source = "fd\"\"\"\n  {x}\n  \"\"\" bd'''\n  a\n'''";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::FStringStart,
+                Tok::Lbrace,
+                Tok::Name {
+                    name: String::from("x")
+                },
+                Tok::Rbrace,
+                Tok::FStringMiddle {
+                    value: String::from("\n")
+                },
+                Tok::FStringEnd,
+                Tok::Bytes {
+                    value: b"a\n".to_vec()
+                },
+                Tok::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedented_string_locations() {
+        let source = "f(d\"\"\"\n      a\n    \"\"\", b)";
+        let spans: Vec<_> = make_tokenizer(source)
+            .map(|x| x.unwrap())
+            .map(|(start, _, end)| ((start.row(), start.column()), (end.row(), end.column())))
+            .collect();
+        assert_eq!(spans[2], ((1, 2), (3, 7)));
+        assert_eq!(spans[4], ((3, 9), (3, 10)));
+    }
+
+    #[test]
+    fn test_dedented_string_must_be_triple_quoted() {
+        let error = make_tokenizer("d'x'").find_map(Result::err).unwrap();
+        assert_eq!(
+            error.error.to_string(),
+            "dedented string literal must be triple-quoted"
+        );
+    }
+
+    #[test]
+    fn test_regex_literal() {
+        let source = r"%r{\d{2,3}[}]\}}ix % r";
+        let tokens = lex_source(source);
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::Regex {
+                    pattern: r"\d{2,3}[}]\}".to_owned(),
+                    flags: "ix".to_owned(),
+                },
+                Tok::Percent,
+                Tok::Name {
+                    name: "r".to_owned(),
+                },
+                Tok::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_regex_literal_errors() {
+        let error = make_tokenizer("%r{a+}q").find_map(Result::err).unwrap();
+        assert_eq!(error.error.to_string(), "invalid regular expression: unknown flag 'q'");
+        assert_eq!(error.location, Location::new(1, 6));
+        let error = make_tokenizer("%r{x{3,2}}").find_map(Result::err).unwrap();
+        assert_eq!(error.location, Location::new(1, 4));
+    }
+
+    #[test]
+    fn test_fstring_tokens() {
+        let source = r#"f"a{{{b!r:>{w}}}}\n{c=}{"d"}""#;
+        let tokens = lex_source(source);
+        let middle = |value: &str| Tok::FStringMiddle {
+            value: value.to_owned(),
+        };
+        let name = |name: &str| Tok::Name {
+            name: name.to_owned(),
+        };
+        assert_eq!(
+            tokens,
+            vec![
+                Tok::FStringStart,
+                middle("a{"),
+                Tok::Lbrace,
+                name("b"),
+                Tok::Exclamation,
+                name("r"),
+                Tok::Colon,
+                middle(">"),
+                Tok::Lbrace,
+                name("w"),
+                Tok::Rbrace,
+                Tok::Rbrace,
+                middle("}\n"),
+                Tok::Lbrace,
+                name("c"),
+                Tok::FStringDebug {
+                    text: String::from("c=")
+                },
+                Tok::Rbrace,
+                Tok::Lbrace,
+                stok("d"),
+                Tok::Rbrace,
+                Tok::FStringEnd,
+                Tok::Newline,
+            ]
+        );
+    }
+
     #[test]
     fn test_single_quoted_byte() {
         // single quote
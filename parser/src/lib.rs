@@ -28,9 +28,14 @@ mod fstring;
 mod function;
 pub mod lexer;
 pub mod mode;
+mod modifier;
 pub mod parser;
+mod pattern;
+mod regex;
+mod soft_keywords;
 #[rustfmt::skip]
 mod emerald;
 mod context;
 mod string;
 pub mod token;
+pub mod validate;
@@ -0,0 +1,133 @@
+//! Postfix `if`, `unless` and `while` modifiers on simple statements.
+//!
+//! With one token of lookahead `return x if ok` can't be told apart from the
+//! start of `return x if ok else y`, so the grammar parses `x if ok` as an
+//! expression. Without an `else` it gives just `x`, and keeps the condition as
+//! a `Guard` in `Guards`, out of the tree. `apply_if` takes the guard that
+//! ends a statement and turns the statement into an `If`; one that is left
+//! over was no statement modifier, and is an error.
+
+use crate::ast::{self, Location};
+use std::cell::RefCell;
+
+/// The `if test` of `body if test`, where `body` ends at `body_end` and the
+/// test at `end`.
+struct Guard {
+    body_end: Location,
+    test: ast::Expr,
+    end: Location,
+}
+
+/// The guards of a parse that no statement took yet.
+#[derive(Default)]
+pub struct Guards(RefCell<Vec<Guard>>);
+
+impl Guards {
+    pub fn push(&self, body_end: Location, test: ast::Expr, end: Location) {
+        self.0.borrow_mut().push(Guard { body_end, test, end });
+    }
+
+    /// Where the `else` of the first guard that no statement took should have
+    /// been.
+    pub fn first_missing_else(&self) -> Option<Location> {
+        self.0.borrow().first().map(|guard| guard.end)
+    }
+
+    /// Take the guard of `body if test` that ends at `end`.
+    fn take(&self, body_end: Option<Location>, end: Option<Location>) -> Option<ast::Expr> {
+        let mut guards = self.0.borrow_mut();
+        let index = guards
+            .iter()
+            .position(|guard| Some(guard.body_end) == body_end && Some(guard.end) == end)?;
+        Some(guards.remove(index).test)
+    }
+}
+
+/// Turn `statement if test` into an `If`, if the statement ends in a guarded expression.
+pub fn apply_if(guards: &Guards, mut statement: ast::Stmt) -> ast::Stmt {
+    let mut test = None;
+    let mut body_end = None;
+    let end = statement.end_location;
+    ast::modify_rightmost_expr_of_statement(&mut statement.node, |expr| {
+        test = take_condition(guards, expr, end);
+        body_end = expr.end_location;
+        test.is_some()
+    });
+    match test {
+        Some(test) => {
+            let location = statement.location;
+            let end_location = statement.end_location;
+            statement.end_location = body_end;
+            ast::Stmt {
+                custom: (),
+                location,
+                end_location,
+                node: ast::StmtKind::If { test: Box::new(test), body: vec![statement], orelse: vec![] },
+            }
+        }
+        None => statement,
+    }
+}
+
+/// `statement unless test`, which runs the statement when `test` is false.
+pub fn unless(
+    guards: &Guards,
+    location: Location,
+    end_location: Location,
+    statement: ast::Stmt,
+    test: ast::Expr,
+) -> ast::Stmt {
+    ast::Stmt::new(
+        location,
+        end_location,
+        ast::StmtKind::If { test: Box::new(negate(test)), body: vec![apply_if(guards, statement)], orelse: vec![] },
+    )
+}
+
+/// `statement while test`, which repeats the statement as long as `test` holds.
+pub fn repeat_while(
+    guards: &Guards,
+    location: Location,
+    end_location: Location,
+    statement: ast::Stmt,
+    test: ast::Expr,
+) -> ast::Stmt {
+    ast::Stmt::new(
+        location,
+        end_location,
+        ast::StmtKind::While { test: Box::new(test), body: vec![apply_if(guards, statement)], orelse: vec![] },
+    )
+}
+
+/// `not test`, spanning the same source as `test`. Used for `unless`.
+pub fn negate(test: ast::Expr) -> ast::Expr {
+    let location = test.location;
+    let end_location = test.end_location;
+    ast::Expr {
+        location,
+        end_location,
+        custom: (),
+        node: ast::ExprKind::UnaryOp { op: ast::Unaryop::Not, operand: Box::new(test) },
+    }
+}
+
+/// Take the condition of the guarded expression `expr` is. Looks through the
+/// last element of a tuple and the value of a `yield`, so that `return a, b if
+/// ok` guards the whole statement. The condition must end at `end`, the end of
+/// the statement, which rules out `x = (y if ok)`.
+fn take_condition(guards: &Guards, expr: &mut ast::Expr, end: Option<Location>) -> Option<ast::Expr> {
+    if let Some(test) = guards.take(expr.end_location, end) {
+        return Some(test);
+    }
+    let test = match &mut expr.node {
+        ast::ExprKind::Tuple { elts, .. } => elts.last_mut().and_then(|elt| take_condition(guards, elt, end)),
+        ast::ExprKind::Yield { value: Some(value) } => take_condition(guards, value, end),
+        _ => None,
+    }?;
+    expr.end_location = match &expr.node {
+        ast::ExprKind::Tuple { elts, .. } => elts.last().and_then(|elt| elt.end_location),
+        ast::ExprKind::Yield { value: Some(value) } => value.end_location,
+        _ => expr.end_location,
+    };
+    Some(test)
+}
@@ -7,7 +7,8 @@
 
 use crate::lexer::{LexResult, Tok};
 pub use crate::mode::Mode;
-use crate::{ast, error::ParseError, lexer, emerald};
+use crate::error::{ParseError, ParseErrorType, SemanticErrorType};
+use crate::{ast, lexer, emerald, modifier, soft_keywords::SoftKeywords, validate};
 use itertools::Itertools;
 use std::iter;
 
@@ -74,30 +75,58 @@ pub fn parse_expression(source: &str, path: &str) -> Result<ast::Expr, ParseErro
 // Parse a given source code
 pub fn parse(source: &str, mode: Mode, source_path: &str) -> Result<ast::Mod, ParseError> {
     let lxr = lexer::make_tokenizer(source);
-    let marker_token = (Default::default(), mode.to_marker(), Default::default());
-    let tokenizer = iter::once(Ok(marker_token))
-        .chain(lxr)
-        .filter_ok(|(_, tok, _)| !matches!(tok, Tok::Comment));
-
-    emerald::TopParser::new()
-        .parse(tokenizer)
-        .map_err(|e| crate::error::parse_error_from_lalrpop(e, source_path))
+    parse_tokens(lxr, mode, source_path)
 }
 
-// Parse a given token iterator.
+// Parse a given token iterator. Only the first validation error is returned;
+// `parse_with_errors` returns all of them.
 pub fn parse_tokens(
     lxr: impl IntoIterator<Item = LexResult>,
     mode: Mode,
     source_path: &str,
+) -> Result<ast::Mod, ParseError> {
+    let top = parse_tokens_unvalidated(lxr, mode, source_path)?;
+    match validate::validate(&top, source_path).into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(top),
+    }
+}
+
+/// Parse `source` like `parse`, but return every validation error, in source
+/// order, instead of only the first.
+pub fn parse_with_errors(source: &str, mode: Mode, source_path: &str) -> Result<ast::Mod, Vec<ParseError>> {
+    let lxr = lexer::make_tokenizer(source);
+    let top = parse_tokens_unvalidated(lxr, mode, source_path).map_err(|error| vec![error])?;
+    let errors = validate::validate(&top, source_path);
+    if errors.is_empty() {
+        Ok(top)
+    } else {
+        Err(errors)
+    }
+}
+
+fn parse_tokens_unvalidated(
+    lxr: impl IntoIterator<Item = LexResult>,
+    mode: Mode,
+    source_path: &str,
 ) -> Result<ast::Mod, ParseError> {
     let marker_token = (Default::default(), mode.to_marker(), Default::default());
     let tokenizer = iter::once(Ok(marker_token))
         .chain(lxr)
         .filter_ok(|(_, tok, _)| !matches!(tok, Tok::Comment));
 
-    emerald::TopParser::new()
-        .parse(tokenizer)
-        .map_err(|e| crate::error::parse_error_from_lalrpop(e, source_path))
+    let guards = modifier::Guards::default();
+    let top = emerald::TopParser::new()
+        .parse(&guards, SoftKeywords::new(tokenizer))
+        .map_err(|e| crate::error::parse_error_from_lalrpop(e, source_path))?;
+    match guards.first_missing_else() {
+        Some(location) => Err(ParseError {
+            error: ParseErrorType::Semantic(SemanticErrorType::ExpectedElse),
+            location,
+            source_path: source_path.to_owned(),
+        }),
+        None => Ok(top),
+    }
 }
 
 #[cfg(test)]
@@ -186,6 +215,15 @@ print('ok')";
         insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
     }
 
+    #[test]
+    fn test_async_do_blocks() {
+        let source = "\
+reply = fetch(url) async do response:
+  await response.text()
+handlers.append(async do: await tick())";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
     #[test]
     fn test_do_blocks_inside_assignment() {
         let source = "\
@@ -194,6 +232,138 @@ a = some_fun() do:
 print('ok')";
         insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
     } 
+
+    #[test]
+    fn test_do_blocks_inside_aug_assign() {
+        let source = "\
+total += items.sum() do x:
+  x.price";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_do_blocks_inside_ann_assign() {
+        let source = "\
+y: Int = f() do:
+  42";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_do_blocks_inside_raise() {
+        let source = "\
+raise Error() from cause() do:
+  'context'";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_do_blocks_inside_assert() {
+        let source = "\
+assert xs.all() do x:
+  x > 0";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_do_blocks_in_for_header() {
+        let source = "\
+for x in xs.select() do x:
+  x.is_odd()
+:
+  print(x)";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_do_blocks_in_with_header() {
+        let source = "\
+with lock.synchronize() do:
+  log('locked')
+:
+  work()";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_inline_do_block_in_expression_mode() {
+        let parse_ast = parse_expression("xs.map() do x: x * 2", "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_inline_do_block_in_argument_list() {
+        let parse_ast = parse_expression("xs.reduce(do acc, x: acc + x, 0)", "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_inline_do_block_in_parentheses() {
+        let source = "total = (do: 1)() + xs.map(f=do x: x).sum()";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_unparse_inline_do_blocks() {
+        for source in [
+            "xs.reduce(do acc, x: acc + x, 0)",
+            "(do: 1)() + xs.map(f=do x: x).sum()",
+            "xs.each(do x, sep=', ': print(x, sep))",
+            "(do *args: args)",
+        ] {
+            let expr = parse_expression(source, "<test>").unwrap();
+            assert_eq!(expr.to_string(), source);
+        }
+        // A trailing inline block is the last argument of the call.
+        let expr = parse_expression("xs.map() do x: x * 2", "<test>").unwrap();
+        assert_eq!(expr.to_string(), "xs.map(do x: x * 2)");
+    }
+
+    #[test]
+    fn test_leading_dot_method_chain() {
+        let source = "\
+names = people
+  .filter() do p:
+    p.adult
+    |.map() do p:
+      p.name
+      |.join(', ')
+        .upper()
+print(names)";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_typed_do_block() {
+        let source = "\
+vowels = chars.filter() do (c: Char, strict: Bool = True) -> Bool:
+  c in 'aeiou'";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_typed_inline_do_block() {
+        let source = "xs.map(do (x: Int) -> Int: x * 2)";
+        let parse_ast = parse_expression(source, "<test>").unwrap();
+        assert_eq!(parse_ast.to_string(), source);
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_untyped_do_block_with_default() {
+        let parse_ast = parse_expression("xs.each() do x, sep=', ': print(x, sep)", "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_do_blocks_in_with_header_after_as() {
+        let source = "\
+with open(path) as f do:
+  pass
+:
+  pass";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
  
 // TODO there are a dozen situations where the do block should be associated with an expression
 // but the last expression is part of a statement. Maybe we could keep track of the last expression
@@ -249,4 +419,216 @@ print('ok')";
         let parse_ast = parse_expression(&source, "<test>").unwrap();
         insta::assert_debug_snapshot!(parse_ast);
     }
+
+    #[test]
+    fn test_parse_optional_chaining() {
+        let source = String::from("user?.address?[0].street?.upper()");
+        let parse_ast = parse_expression(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_parse_nil_coalescing() {
+        let source = String::from("a or b ?? c ?? d if e else f");
+        let parse_ast = parse_expression(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_unparse_optional_chaining_and_coalescing() {
+        for source in [
+            "a?.b",
+            "a?.m(x)",
+            "a?[k]",
+            "a?[1:2, 3]",
+            "x ?? y ?? default",
+            "(a ?? b) or c",
+            "a or b ?? c",
+            "(x ?? y).z",
+            "f(do x: x ?? 0)",
+        ] {
+            let expr = parse_expression(source, "<test>").unwrap();
+            assert_eq!(expr.to_string(), source);
+        }
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let source = String::from("x in 1..n + 1 and y not in 0...len(xs)");
+        let parse_ast = parse_expression(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_parse_pipeline() {
+        let source = String::from("1..10 |> filter(is_even) |> sum()");
+        let parse_ast = parse_expression(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_parse_relative_import_two_levels() {
+        let source = String::from("from ..a import b");
+        let parse_ast = parse_program(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_unparse_ranges_and_pipeline() {
+        for source in [
+            "1..10",
+            "a...b + 1",
+            "(1..2).to_list()",
+            "x in 1..10",
+            "xs |> map(f) |> sum()",
+            "xs |> (f |> g)",
+            "(xs |> f).y",
+            "xs |> f ?? []",
+        ] {
+            let expr = parse_expression(source, "<test>").unwrap();
+            assert_eq!(expr.to_string(), source);
+        }
+    }
+    #[test]
+    fn test_parse_statement_modifiers() {
+        let source = String::from("\
+def f(x):
+  for y in x:
+    break if y
+  i += 1 while i < n
+  a, b = 1, 2 unless swapped
+  raise Error() unless ok
+  return x if done
+");
+        let parse_ast = parse_program(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_parse_unless_else() {
+        let source = String::from("unless x:\n  a()\nelse:\n  b()\n");
+        let parse_ast = parse_program(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_statement_modifier_is_not_a_ternary() {
+        let parse_ast = parse_program("y = a if b else c", "<test>").unwrap();
+        assert!(matches!(parse_ast[0].node, ast::StmtKind::Assign { .. }));
+        let parse_ast = parse_program("y = a if b", "<test>").unwrap();
+        assert!(matches!(parse_ast[0].node, ast::StmtKind::If { .. }));
+        let parse_ast = parse_program("f(x if c else d) if e", "<test>").unwrap();
+        match &parse_ast[0].node {
+            ast::StmtKind::If { body, .. } => match &body[0].node {
+                ast::StmtKind::Expr { value } => assert!(matches!(value.node, ast::ExprKind::Call { .. })),
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_if_expression_without_else() {
+        let source = "y = (a if b)";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_parse_regex_literal() {
+        let parse_ast = parse_expression(r"re.match(%r{^\d{2,3}[}a-z]+$}im, s)", "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
+
+    #[test]
+    fn test_unparse_regex_literal() {
+        for source in [r"%r{^\d{2,3}$}", r"%r{a\}b}ix", "x % r"] {
+            let expr = parse_expression(source, "<test>").unwrap();
+            assert_eq!(expr.to_string(), source);
+        }
+    }
+
+    #[test]
+    fn test_invalid_regex_literal() {
+        let error = parse_program("x = 1\npattern = %r{ab\n    (c}x", "<test>").unwrap_err();
+        assert_eq!(error.location, ast::Location::new(3, 4));
+        assert_eq!(error.error.to_string(), "invalid regular expression: unclosed group");
+    }
+
+    #[test]
+    fn test_parse_enum() {
+        let source = "\
+@derive(Eq)
+enum Message:
+    Ping
+    Data(bytes, int)
+    Login(user: str, password: str)
+";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_enum_variant_fields_are_all_named_or_unnamed() {
+        let error = parse_program("enum E:\n    V(int, name: str)\n", "<test>").unwrap_err();
+        assert_eq!(error.location, ast::Location::new(2, 4));
+        assert_eq!(error.error.to_string(), "enum variant fields must be either all named or all unnamed");
+    }
+
+    #[test]
+    fn test_parse_match() {
+        let source = "\
+match command.split():
+    case Message.Data(payload, 0) | Message.Ping() if ready:
+        pass
+    case Message.Login(user=name) as login:
+        pass
+    case [first, *_] | (first, 1 + 2j, -3):
+        pass
+    case {'kind': Kind.QUIT, **rest}:
+        pass
+    case None | 'a' 'b' | _:
+        pass
+";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_soft_keywords_remain_names() {
+        let source = "\
+import enum
+match = re.match(pattern, s)
+match.group(1)
+case: int = 1
+enum.auto()
+match(x)
+print(match, case)
+";
+        let parse_ast = parse_program(source, "<test>").unwrap();
+        assert_eq!(parse_ast.len(), 7);
+        assert!(parse_ast.iter().all(|stmt| !matches!(stmt.node, ast::StmtKind::Match { .. })));
+    }
+
+    #[test]
+    fn test_parse_module_and_multiple_extends() {
+        let source = "\
+module Organism:
+    energy: int
+module Predator extends Organism:
+    def eat(organism):
+        self.energy += organism.energy
+class Amoeba extends Organism, Predator:
+    pass
+module = load(name)
+";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap());
+    }
+
+    #[test]
+    fn test_parse_with_errors() {
+        let source = "break\ndef f():\n    return 1\ncontinue\n";
+        let errors = parse_with_errors(source, Mode::Module, "<test>").unwrap_err();
+        let rows: Vec<usize> = errors.iter().map(|error| error.location.row()).collect();
+        assert_eq!(rows, [1, 4]);
+        assert_eq!(parse_program(source, "<test>").unwrap_err(), errors[0]);
+        assert!(parse_with_errors("x = 1\n", Mode::Module, "<test>").is_ok());
+    }
 }
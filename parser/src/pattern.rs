@@ -0,0 +1,109 @@
+//! Helpers for the `match` statement's patterns and for `enum` declarations.
+
+use crate::ast::{self, Location};
+use crate::error::{LexicalError, LexicalErrorType};
+
+/// An argument of a class pattern: `pattern` or `name=pattern`.
+pub type ClassPatternArgument = (Option<String>, ast::Pattern);
+
+/// An entry of a mapping pattern: `key: pattern` or `**rest`.
+pub enum MappingEntry {
+    Item(ast::Expr, ast::Pattern),
+    Rest(Location, String),
+}
+
+/// A field of an enum variant: `type` or `name: type`.
+pub type VariantField = (Option<String>, ast::Expr);
+
+/// A capture pattern `name`, or the wildcard `_`.
+pub fn capture(location: Location, end_location: Location, name: String) -> ast::Pattern {
+    let name = if name == "_" { None } else { Some(name) };
+    ast::Pattern::new(location, end_location, ast::PatternKind::MatchAs { pattern: None, name })
+}
+
+pub fn class_pattern(
+    location: Location,
+    end_location: Location,
+    cls: ast::Expr,
+    arguments: Vec<ClassPatternArgument>,
+) -> Result<ast::Pattern, LexicalError> {
+    let mut patterns = vec![];
+    let mut kwd_attrs = vec![];
+    let mut kwd_patterns = vec![];
+    for (name, pattern) in arguments {
+        match name {
+            Some(name) => {
+                kwd_attrs.push(name);
+                kwd_patterns.push(pattern);
+            }
+            None if !kwd_attrs.is_empty() => {
+                return Err(LexicalError {
+                    error: LexicalErrorType::OtherError(
+                        "positional patterns follow keyword patterns".to_owned(),
+                    ),
+                    location: pattern.location,
+                });
+            }
+            None => patterns.push(pattern),
+        }
+    }
+    Ok(ast::Pattern::new(
+        location,
+        end_location,
+        ast::PatternKind::MatchClass { cls: Box::new(cls), patterns, kwd_attrs, kwd_patterns },
+    ))
+}
+
+pub fn mapping_pattern(
+    location: Location,
+    end_location: Location,
+    entries: Vec<MappingEntry>,
+) -> Result<ast::Pattern, LexicalError> {
+    let mut keys = vec![];
+    let mut patterns = vec![];
+    let mut rest = None;
+    for entry in entries {
+        if let Some((location, _)) = rest {
+            return Err(LexicalError {
+                error: LexicalErrorType::OtherError(
+                    "double star pattern must be the last in a mapping pattern".to_owned(),
+                ),
+                location,
+            });
+        }
+        match entry {
+            MappingEntry::Item(key, pattern) => {
+                keys.push(key);
+                patterns.push(pattern);
+            }
+            MappingEntry::Rest(location, name) => rest = Some((location, name)),
+        }
+    }
+    let rest = rest.map(|(_, name)| name);
+    Ok(ast::Pattern::new(location, end_location, ast::PatternKind::MatchMapping { keys, patterns, rest }))
+}
+
+/// An enum variant, which is tuple-like if none of its fields are named and
+/// record-like if all of them are.
+pub fn variant(
+    location: Location,
+    end_location: Location,
+    name: String,
+    fields: Vec<VariantField>,
+) -> Result<ast::Variant, LexicalError> {
+    let named = fields.iter().filter(|(name, _)| name.is_some()).count();
+    if named != 0 && named != fields.len() {
+        return Err(LexicalError {
+            error: LexicalErrorType::OtherError(
+                "enum variant fields must be either all named or all unnamed".to_owned(),
+            ),
+            location,
+        });
+    }
+    let (field_names, fields): (Vec<_>, Vec<_>) = fields.into_iter().unzip();
+    Ok(ast::Variant::new(
+        location,
+        end_location,
+        ast::VariantData { name, fields, field_names: field_names.into_iter().flatten().collect() },
+    ))
+}
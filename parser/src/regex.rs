@@ -0,0 +1,47 @@
+//! Validation of `%r{...}` regex literals.
+//!
+//! Patterns are parsed with `regex_syntax` while lexing, so an invalid pattern
+//! is reported as a parse error pointing into the literal instead of failing
+//! when the program runs.
+
+use crate::{
+    ast::Location,
+    error::{LexicalError, LexicalErrorType},
+};
+
+/// The flags that may follow the closing brace of a regex literal.
+pub const FLAGS: &str = "imsx";
+
+/// Check that `pattern`, which starts at `location` in the source, is a valid
+/// regular expression when compiled with `flags`.
+pub fn validate(pattern: &str, flags: &str, location: Location) -> Result<(), LexicalError> {
+    let mut parser = regex_syntax::ParserBuilder::new()
+        .case_insensitive(flags.contains('i'))
+        .multi_line(flags.contains('m'))
+        .dot_matches_new_line(flags.contains('s'))
+        .ignore_whitespace(flags.contains('x'))
+        .build();
+    let (message, offset) = match parser.parse(pattern) {
+        Ok(_) => return Ok(()),
+        Err(regex_syntax::Error::Parse(error)) => (error.kind().to_string(), error.span().start.offset),
+        Err(regex_syntax::Error::Translate(error)) => (error.kind().to_string(), error.span().start.offset),
+        Err(error) => (error.to_string(), 0),
+    };
+    Err(LexicalError {
+        error: LexicalErrorType::RegexError(message),
+        location: offset_location(pattern, offset, location),
+    })
+}
+
+/// The source location of the byte `offset` into `pattern`. The pattern is kept
+/// exactly as written, so this only has to follow its newlines.
+fn offset_location(pattern: &str, offset: usize, mut location: Location) -> Location {
+    for c in pattern[..offset].chars() {
+        if c == '\n' {
+            location.newline();
+        } else {
+            location.go_right();
+        }
+    }
+    location
+}
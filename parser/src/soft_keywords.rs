@@ -0,0 +1,112 @@
+//! `enum`, `module`, `match` and `case` are keywords only where they start a
+//! statement, so that `re.match(s)` and `import enum` keep working.
+//!
+//! The lexer produces them as names. Before the tokens reach the parser, such a
+//! name at the start of a line becomes a keyword when the rest of the line looks
+//! like the header of the statement:
+//!
+//! - `match` and `case` are followed by a `:` outside of brackets, and not
+//!   directly by `:`, `=`, `.` or `,`.
+//! - `enum` is followed by a name and a `:`.
+//! - `module` is followed by a name and a `:` or `extends`.
+
+use crate::{lexer::LexResult, token::Tok};
+use std::collections::VecDeque;
+
+pub struct SoftKeywords<I: Iterator<Item = LexResult>> {
+    underlying: I,
+    /// Tokens that were looked at but not handed out yet.
+    buffer: VecDeque<LexResult>,
+    at_line_start: bool,
+}
+
+impl<I> SoftKeywords<I>
+where
+    I: Iterator<Item = LexResult>,
+{
+    pub fn new(underlying: I) -> Self {
+        SoftKeywords {
+            underlying,
+            buffer: VecDeque::new(),
+            at_line_start: true,
+        }
+    }
+
+    /// The `n`th token after the ones handed out so far.
+    fn peek(&mut self, n: usize) -> Option<&Tok> {
+        while self.buffer.len() <= n {
+            self.buffer.push_back(self.underlying.next()?);
+        }
+        match &self.buffer[n] {
+            Ok((_, tok, _)) => Some(tok),
+            Err(_) => None,
+        }
+    }
+
+    fn starts_match_or_case(&mut self) -> bool {
+        if matches!(
+            self.peek(0),
+            None | Some(Tok::Newline | Tok::Colon | Tok::Equal | Tok::Dot | Tok::Comma)
+        ) {
+            return false;
+        }
+        let mut nesting = 0usize;
+        let mut lambdas = 0usize;
+        for i in 0.. {
+            match self.peek(i) {
+                None | Some(Tok::Newline) => return false,
+                Some(Tok::Lpar | Tok::Lsqb | Tok::QuestionLsqb | Tok::Lbrace) => nesting += 1,
+                Some(Tok::Rpar | Tok::Rsqb | Tok::Rbrace) => nesting = nesting.saturating_sub(1),
+                Some(Tok::Lambda) if nesting == 0 => lambdas += 1,
+                Some(Tok::Colon) if nesting == 0 => {
+                    // The colon of a lambda in the subject doesn't end the header.
+                    if lambdas == 0 {
+                        return true;
+                    }
+                    lambdas -= 1;
+                }
+                _ => {}
+            }
+        }
+        unreachable!()
+    }
+
+    fn starts_enum(&mut self) -> bool {
+        matches!(self.peek(0), Some(Tok::Name { .. })) && matches!(self.peek(1), Some(Tok::Colon))
+    }
+
+    fn starts_module(&mut self) -> bool {
+        matches!(self.peek(0), Some(Tok::Name { .. }))
+            && matches!(self.peek(1), Some(Tok::Colon | Tok::Extends))
+    }
+}
+
+impl<I> Iterator for SoftKeywords<I>
+where
+    I: Iterator<Item = LexResult>,
+{
+    type Item = LexResult;
+
+    fn next(&mut self) -> Option<LexResult> {
+        let mut next = self.buffer.pop_front().or_else(|| self.underlying.next());
+        if let Some(Ok((_, tok, _))) = &mut next {
+            if let (true, Tok::Name { name }) = (self.at_line_start, &*tok) {
+                let keyword = match name.as_str() {
+                    "match" if self.starts_match_or_case() => Some(Tok::Match),
+                    "case" if self.starts_match_or_case() => Some(Tok::Case),
+                    "enum" if self.starts_enum() => Some(Tok::Enum),
+                    "module" if self.starts_module() => Some(Tok::Module),
+                    _ => None,
+                };
+                if let Some(keyword) = keyword {
+                    *tok = keyword;
+                }
+            }
+            self.at_line_start = matches!(
+                tok,
+                Tok::Newline | Tok::Indent | Tok::Dedent | Tok::StartModule | Tok::StartInteractive
+            );
+        }
+        next
+    }
+}
@@ -1,98 +1,80 @@
 use crate::{
     ast::{Constant, Expr, ExprKind, Location},
-    error::{LexicalError, LexicalErrorType},
-    fstring::parse_located_fstring,
     token::StringKind,
 };
 use itertools::Itertools;
 
-pub fn parse_strings(
-    values: Vec<(Location, (String, StringKind), Location)>,
-) -> Result<Expr, LexicalError> {
+/// A string literal in a concatenation like `'a' f'{b}'`.
+pub enum StringLiteral {
+    Str(String, StringKind),
+    /// The values of an f-string's `JoinedStr`.
+    FString(Vec<Expr>),
+}
+
+pub fn parse_strings(values: Vec<(Location, StringLiteral, Location)>) -> Expr {
     // Preserve the initial location and kind.
     let initial_start = values[0].0;
-    let initial_end = values[0].2;
-    let initial_kind = (values[0].1 .1 == StringKind::U).then(|| "u".to_owned());
-
-    // Optimization: fast-track the common case of a single string.
-    if matches!(&*values, [(_, (_, StringKind::Normal | StringKind::U), _)]) {
-        let value = values.into_iter().last().unwrap().1 .0;
-        return Ok(Expr::new(
-            initial_start,
-            initial_end,
-            ExprKind::Constant {
-                value: Constant::Str(value),
-                kind: initial_kind,
-            },
-        ));
-    }
+    let final_end = values[values.len() - 1].2;
+    let initial_kind = matches!(values[0].1, StringLiteral::Str(_, StringKind::U)).then(|| "u".to_owned());
 
     // Determine whether the list of values contains any f-strings. (If not, we can return a
     // single Constant at the end, rather than a JoinedStr.)
-    let mut has_fstring = false;
+    let has_fstring = values.iter().any(|(_, value, _)| matches!(value, StringLiteral::FString(_)));
 
-    // De-duplicate adjacent constants.
+    // De-duplicate adjacent constants, which then span the source they came from.
     let mut deduped: Vec<Expr> = vec![];
-    let mut current: Vec<String> = vec![];
-
-    let take_current = |current: &mut Vec<String>| -> Expr {
-        Expr::new(
-            initial_start,
-            initial_end,
-            ExprKind::Constant {
-                value: Constant::Str(current.drain(..).join("")),
-                kind: initial_kind.clone(),
-            },
-        )
+    let mut current: Option<(String, Location, Location)> = None;
+
+    let take_current = |current: &mut Option<(String, Location, Location)>| -> Option<Expr> {
+        current.take().map(|(value, start, end)| {
+            Expr::new(
+                start,
+                end,
+                ExprKind::Constant {
+                    value: Constant::Str(value),
+                    kind: initial_kind.clone(),
+                },
+            )
+        })
+    };
+    let push_str = |current: &mut Option<(String, Location, Location)>, value: &str, start, end| match current {
+        Some((current, _, current_end)) => {
+            current.push_str(value);
+            *current_end = end;
+        }
+        None => *current = Some((value.to_owned(), start, end)),
     };
 
-    for (start, (string, string_kind), end) in values {
-        match string_kind {
-            StringKind::Normal | StringKind::U => current.push(string),
-            StringKind::F => {
-                has_fstring = true;
-                for value in
-                    parse_located_fstring(&string, start, end).map_err(|e| LexicalError {
-                        location: start,
-                        error: LexicalErrorType::FStringError(e.error),
-                    })?
-                {
+    for (start, value, end) in values {
+        match value {
+            StringLiteral::Str(value, _) => push_str(&mut current, &value, start, end),
+            StringLiteral::FString(values) => {
+                for value in values {
                     match value.node {
                         ExprKind::FormattedValue { .. } => {
-                            if !current.is_empty() {
-                                deduped.push(take_current(&mut current));
-                            }
+                            deduped.extend(take_current(&mut current));
                             deduped.push(value)
                         }
-                        ExprKind::Constant { value, .. } => {
-                            if let Constant::Str(value) = value {
-                                current.push(value);
-                            } else {
-                                unreachable!("Unexpected non-string constant.");
-                            }
-                        }
+                        ExprKind::Constant {
+                            value: Constant::Str(ref s),
+                            ..
+                        } => push_str(&mut current, s, value.location, value.end_location.unwrap()),
                         _ => unreachable!("Unexpected non-string expression."),
                     }
                 }
             }
         }
     }
-    if !current.is_empty() {
-        deduped.push(take_current(&mut current));
-    }
+    deduped.extend(take_current(&mut current));
 
-    Ok(if has_fstring {
-        Expr::new(
-            initial_start,
-            initial_end,
-            ExprKind::JoinedStr { values: deduped },
-        )
+    if has_fstring {
+        Expr::new(initial_start, final_end, ExprKind::JoinedStr { values: deduped })
     } else {
         deduped
             .into_iter()
             .exactly_one()
             .expect("String must be concatenated to a single element.")
-    })
+    }
 }
 
 #[cfg(test)]
@@ -161,4 +143,11 @@ mod tests {
         let parse_ast = parse_program(&source, "<test>").unwrap();
         insta::assert_debug_snapshot!(parse_ast);
     }
+
+    #[test]
+    fn test_parse_dedented_fstring() {
+        let source = String::from("def f():\n    return fd\"\"\"\n        name: {name}\n          {value!r}\n        \"\"\"\n");
+        let parse_ast = parse_program(&source, "<test>").unwrap();
+        insta::assert_debug_snapshot!(parse_ast);
+    }
 }
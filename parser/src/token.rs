@@ -12,6 +12,16 @@ pub enum Tok {
     Complex { real: f64, imag: f64 },
     String { value: String, kind: StringKind },
     Bytes { value: Vec<u8> },
+    /// A `%r{pattern}flags` regex literal, with the pattern as written in the source.
+    Regex { pattern: String, flags: String },
+    /// The prefix and opening quote of an f-string.
+    FStringStart,
+    /// Literal text in an f-string or its format specs, with escapes and `{{` resolved.
+    FStringMiddle { value: String },
+    FStringEnd,
+    /// The `=` of a self-documenting replacement field like `{x = }`, with the
+    /// source text of the field up to and including it.
+    FStringDebug { text: String },
     Newline,
     Indent,
     Dedent,
@@ -45,6 +55,7 @@ pub enum Tok {
     LessEqual,
     GreaterEqual,
     Tilde,
+    Exclamation, // '!', the conversion in an f-string
     CircumFlex,
     LeftShift,
     RightShift,
@@ -67,6 +78,11 @@ pub enum Tok {
     AtEqual,
     Rarrow,
     Ellipsis,
+    QuestionDot,    // '?.'
+    QuestionLsqb,   // '?['
+    DoubleQuestion, // '??'
+    DotDot,         // '..'
+    VbarGreater,    // '|>'
 
     // Keywords (alphabetically):
     False,
@@ -77,7 +93,6 @@ pub enum Tok {
     As,
     Assert,
     Async,
-    AsyncDo,
     Await,
     Break,
     Class,
@@ -87,6 +102,8 @@ pub enum Tok {
     Do,
     Elif,
     Else,
+    /// `enum`, `module`, `match` and `case` are soft keywords, see `soft_keywords`.
+    Enum,
     Except,
     Extends,
     Finally,
@@ -98,6 +115,9 @@ pub enum Tok {
     In,
     Is,
     Lambda,
+    Module,
+    Match,
+    Case,
     Nonlocal,
     Not,
     Or,
@@ -105,6 +125,7 @@ pub enum Tok {
     Raise,
     Return,
     Try,
+    Unless,
     While,
     With,
     Yield,
@@ -113,7 +134,6 @@ pub enum Tok {
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum StringKind {
     Normal,
-    F,
     U,
 }
 
@@ -127,12 +147,12 @@ impl fmt::Display for Tok {
             Complex { real, imag } => write!(f, "{}j{}", real, imag),
             String { value, kind } => {
                 match kind {
-                    StringKind::F => f.write_str("f")?,
                     StringKind::U => f.write_str("u")?,
                     StringKind::Normal => {}
                 }
                 write!(f, "{:?}", value)
             }
+            Regex { pattern, flags } => write!(f, "%r{{{}}}{}", pattern, flags),
             Bytes { value } => {
                 write!(f, "b\"")?;
                 for i in value {
@@ -146,6 +166,10 @@ impl fmt::Display for Tok {
                 }
                 f.write_str("\"")
             }
+            FStringStart => f.write_str("FStringStart"),
+            FStringMiddle { value } => write!(f, "{:?}", value),
+            FStringEnd => f.write_str("FStringEnd"),
+            FStringDebug { .. } => f.write_str("'='"),
             Newline => f.write_str("Newline"),
             Indent => f.write_str("Indent"),
             Dedent => f.write_str("Dedent"),
@@ -179,6 +203,7 @@ impl fmt::Display for Tok {
             LessEqual => f.write_str("'<='"),
             GreaterEqual => f.write_str("'>='"),
             Tilde => f.write_str("'~'"),
+            Exclamation => f.write_str("'!'"),
             CircumFlex => f.write_str("'^'"),
             LeftShift => f.write_str("'<<'"),
             RightShift => f.write_str("'>>'"),
@@ -200,6 +225,11 @@ impl fmt::Display for Tok {
             AtEqual => f.write_str("'@='"),
             Rarrow => f.write_str("'->'"),
             Ellipsis => f.write_str("'...'"),
+            QuestionDot => f.write_str("'?.'"),
+            QuestionLsqb => f.write_str("'?['"),
+            DoubleQuestion => f.write_str("'??'"),
+            DotDot => f.write_str("'..'"),
+            VbarGreater => f.write_str("'|>'"),
             False => f.write_str("'False'"),
             None => f.write_str("'None'"),
             True => f.write_str("'True'"),
@@ -207,7 +237,6 @@ impl fmt::Display for Tok {
             As => f.write_str("'as'"),
             Assert => f.write_str("'assert'"),
             Async => f.write_str("'async'"),
-            AsyncDo => f.write_str("'async do'"),
             Await => f.write_str("'await'"),
             Break => f.write_str("'break'"),
             Class => f.write_str("'class'"),
@@ -217,6 +246,7 @@ impl fmt::Display for Tok {
             Do => f.write_str("'do'"),
             Elif => f.write_str("'elif'"),
             Else => f.write_str("'else'"),
+            Enum => f.write_str("'enum'"),
             Except => f.write_str("'except'"),
             Extends=> f.write_str("'extends'"),
             Finally => f.write_str("'finally'"),
@@ -228,6 +258,9 @@ impl fmt::Display for Tok {
             In => f.write_str("'in'"),
             Is => f.write_str("'is'"),
             Lambda => f.write_str("'lambda'"),
+            Module => f.write_str("'module'"),
+            Match => f.write_str("'match'"),
+            Case => f.write_str("'case'"),
             Nonlocal => f.write_str("'nonlocal'"),
             Not => f.write_str("'not'"),
             Or => f.write_str("'or'"),
@@ -235,6 +268,7 @@ impl fmt::Display for Tok {
             Raise => f.write_str("'raise'"),
             Return => f.write_str("'return'"),
             Try => f.write_str("'try'"),
+            Unless => f.write_str("'unless'"),
             While => f.write_str("'while'"),
             With => f.write_str("'with'"),
             Yield => f.write_str("'yield'"),
@@ -0,0 +1,597 @@
+//! Checks the grammar cannot express on its own.
+//!
+//! The grammar was inherited from RustPython, which leaves these checks to the
+//! compiler. We run them right after parsing instead, so that `break` outside a
+//! loop or `f() = 1` are reported as a `ParseError` with a location.
+
+use crate::ast::{
+    self,
+    visitor::{self, Visitor},
+};
+use crate::error::{ParseError, ParseErrorType, SemanticErrorType};
+use rustc_hash::FxHashSet;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    Module,
+    Class,
+    Function { is_async: bool },
+}
+
+struct Validator<'a> {
+    source_path: &'a str,
+    scope: Scope,
+    in_loop: bool,
+    /// Where the chain of the do block being visited starts, which is the
+    /// only place a `|` marker may be.
+    chain_start: Option<ast::Location>,
+    errors: Vec<ParseError>,
+}
+
+/// Validate a parsed module, returning every error found in source order.
+pub fn validate(top: &ast::Mod, source_path: &str) -> Vec<ParseError> {
+    let mut validator = Validator {
+        source_path,
+        scope: Scope::Module,
+        in_loop: false,
+        chain_start: None,
+        errors: vec![],
+    };
+    validator.visit_mod(top);
+    validator.errors
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, error: SemanticErrorType, location: ast::Location) {
+        self.errors.push(ParseError {
+            error: ParseErrorType::Semantic(error),
+            location,
+            source_path: self.source_path.to_owned(),
+        });
+    }
+
+    fn in_scope(&mut self, scope: Scope, in_loop: bool, f: impl FnOnce(&mut Self)) {
+        let outer_scope = std::mem::replace(&mut self.scope, scope);
+        let outer_in_loop = std::mem::replace(&mut self.in_loop, in_loop);
+        f(self);
+        self.scope = outer_scope;
+        self.in_loop = outer_in_loop;
+    }
+
+    /// Report `async for` or `async with` outside of async code.
+    fn check_async(&mut self, name: &'static str, location: ast::Location) {
+        if self.scope != (Scope::Function { is_async: true }) {
+            self.error(SemanticErrorType::AsyncOutsideAsyncFunction(name), location);
+        }
+    }
+
+    fn visit_body(&mut self, body: &[ast::Stmt]) {
+        for stmt in body {
+            self.visit_stmt(stmt);
+        }
+    }
+
+    fn visit_loop(&mut self, body: &[ast::Stmt], orelse: &[ast::Stmt]) {
+        let scope = self.scope;
+        self.in_scope(scope, true, |v| v.visit_body(body));
+        self.visit_body(orelse);
+    }
+
+    fn check_parameters(&mut self, args: &ast::Arguments) {
+        let mut names = FxHashSet::default();
+        let params = args
+            .posonlyargs
+            .iter()
+            .chain(&args.args)
+            .chain(args.vararg.as_deref())
+            .chain(&args.kwonlyargs)
+            .chain(args.kwarg.as_deref());
+        for param in params {
+            if !names.insert(param.node.arg.as_str()) {
+                self.error(
+                    SemanticErrorType::DuplicateArgument(param.node.arg.clone()),
+                    param.location,
+                );
+            }
+        }
+    }
+
+    fn check_store_target(&mut self, target: &ast::Expr) {
+        match &target.node {
+            ast::ExprKind::Starred { .. } => {
+                self.error(SemanticErrorType::StarredAssignTarget, target.location)
+            }
+            _ => self.check_unpacked_store_target(target),
+        }
+    }
+
+    fn check_unpacked_store_target(&mut self, target: &ast::Expr) {
+        match &target.node {
+            ast::ExprKind::Name { .. }
+            | ast::ExprKind::Attribute { .. }
+            | ast::ExprKind::Subscript { .. } => {}
+            ast::ExprKind::Tuple { elts, .. } | ast::ExprKind::List { elts, .. } => {
+                let mut starred = elts
+                    .iter()
+                    .filter(|elt| matches!(elt.node, ast::ExprKind::Starred { .. }));
+                if let (Some(_), Some(second)) = (starred.next(), starred.next()) {
+                    self.error(SemanticErrorType::MultipleStarredTargets, second.location);
+                }
+                for elt in elts {
+                    match &elt.node {
+                        ast::ExprKind::Starred { value, .. } => {
+                            self.check_unpacked_store_target(value)
+                        }
+                        _ => self.check_unpacked_store_target(elt),
+                    }
+                }
+            }
+            node => self.error(
+                SemanticErrorType::InvalidAssignTarget(node.name()),
+                target.location,
+            ),
+        }
+    }
+
+    fn check_delete_target(&mut self, target: &ast::Expr) {
+        match &target.node {
+            ast::ExprKind::Name { .. }
+            | ast::ExprKind::Attribute { .. }
+            | ast::ExprKind::Subscript { .. } => {}
+            ast::ExprKind::Tuple { elts, .. } | ast::ExprKind::List { elts, .. } => {
+                for elt in elts {
+                    self.check_delete_target(elt);
+                }
+            }
+            node => self.error(
+                SemanticErrorType::InvalidDeleteTarget(node.name()),
+                target.location,
+            ),
+        }
+    }
+}
+
+impl Visitor for Validator<'_> {
+    fn visit_stmt(&mut self, node: &ast::Stmt) {
+        match &node.node {
+            ast::StmtKind::FunctionDef {
+                args,
+                body,
+                decorator_list,
+                returns,
+                ..
+            }
+            | ast::StmtKind::AsyncFunctionDef {
+                args,
+                body,
+                decorator_list,
+                returns,
+                ..
+            } => {
+                // Decorators, defaults and annotations are evaluated in the enclosing scope.
+                for decorator in decorator_list {
+                    self.visit_expr(decorator);
+                }
+                self.check_parameters(args);
+                self.visit_arguments(args);
+                if let Some(returns) = returns {
+                    self.visit_expr(returns);
+                }
+                let is_async = matches!(node.node, ast::StmtKind::AsyncFunctionDef { .. });
+                self.in_scope(Scope::Function { is_async }, false, |v| v.visit_body(body));
+            }
+            ast::StmtKind::ClassDef {
+                bases,
+                keywords,
+                body,
+                decorator_list,
+                ..
+            } => {
+                for expr in decorator_list.iter().chain(bases) {
+                    self.visit_expr(expr);
+                }
+                for keyword in keywords {
+                    self.visit_keyword(keyword);
+                }
+                self.in_scope(Scope::Class, false, |v| v.visit_body(body));
+            }
+            ast::StmtKind::ModuleDef {
+                bases,
+                body,
+                decorator_list,
+                ..
+            } => {
+                for expr in decorator_list.iter().chain(bases) {
+                    self.visit_expr(expr);
+                }
+                self.in_scope(Scope::Class, false, |v| v.visit_body(body));
+            }
+            ast::StmtKind::EnumDef { variants, .. } => {
+                let mut names = FxHashSet::default();
+                for variant in variants {
+                    if !names.insert(variant.node.name.as_str()) {
+                        self.error(
+                            SemanticErrorType::DuplicateVariant(variant.node.name.clone()),
+                            variant.location,
+                        );
+                    }
+                    let mut fields = FxHashSet::default();
+                    for field in &variant.node.field_names {
+                        if !fields.insert(field.as_str()) {
+                            self.error(
+                                SemanticErrorType::DuplicateVariantField(field.clone()),
+                                variant.location,
+                            );
+                        }
+                    }
+                }
+                visitor::walk_stmt(self, node);
+            }
+            ast::StmtKind::Return { .. } => {
+                if !matches!(self.scope, Scope::Function { .. }) {
+                    self.error(SemanticErrorType::ReturnOutsideFunction, node.location);
+                }
+                visitor::walk_stmt(self, node);
+            }
+            ast::StmtKind::Delete { targets } => {
+                for target in targets {
+                    self.check_delete_target(target);
+                }
+                visitor::walk_stmt(self, node);
+            }
+            ast::StmtKind::Assign { targets, .. } => {
+                for target in targets {
+                    self.check_store_target(target);
+                }
+                visitor::walk_stmt(self, node);
+            }
+            ast::StmtKind::AugAssign { target, .. } => {
+                if !matches!(
+                    target.node,
+                    ast::ExprKind::Name { .. }
+                        | ast::ExprKind::Attribute { .. }
+                        | ast::ExprKind::Subscript { .. }
+                ) {
+                    self.error(
+                        SemanticErrorType::InvalidAugAssignTarget(target.node.name()),
+                        target.location,
+                    );
+                }
+                visitor::walk_stmt(self, node);
+            }
+            ast::StmtKind::AnnAssign { target, .. } => {
+                match &target.node {
+                    ast::ExprKind::Name { .. }
+                    | ast::ExprKind::Attribute { .. }
+                    | ast::ExprKind::Subscript { .. } => {}
+                    ast::ExprKind::Tuple { .. } | ast::ExprKind::List { .. } => {
+                        self.error(SemanticErrorType::AnnotatedTupleTarget, target.location)
+                    }
+                    _ => self.error(SemanticErrorType::InvalidAnnotatedTarget, target.location),
+                }
+                visitor::walk_stmt(self, node);
+            }
+            ast::StmtKind::For {
+                target,
+                iter,
+                body,
+                orelse,
+                ..
+            }
+            | ast::StmtKind::AsyncFor {
+                target,
+                iter,
+                body,
+                orelse,
+                ..
+            } => {
+                if let ast::StmtKind::AsyncFor { .. } = node.node {
+                    self.check_async("async for", node.location);
+                }
+                self.check_store_target(target);
+                self.visit_expr(target);
+                self.visit_expr(iter);
+                self.visit_loop(body, orelse);
+            }
+            ast::StmtKind::AsyncWith { .. } => {
+                self.check_async("async with", node.location);
+                visitor::walk_stmt(self, node);
+            }
+            ast::StmtKind::While { test, body, orelse } => {
+                self.visit_expr(test);
+                self.visit_loop(body, orelse);
+            }
+            ast::StmtKind::Nonlocal { .. } => {
+                if self.scope == Scope::Module {
+                    self.error(SemanticErrorType::NonlocalAtModuleLevel, node.location);
+                }
+            }
+            ast::StmtKind::Break => {
+                if !self.in_loop {
+                    self.error(SemanticErrorType::BreakOutsideLoop, node.location);
+                }
+            }
+            ast::StmtKind::Continue => {
+                if !self.in_loop {
+                    self.error(SemanticErrorType::ContinueOutsideLoop, node.location);
+                }
+            }
+            _ => visitor::walk_stmt(self, node),
+        }
+    }
+
+    fn visit_expr(&mut self, node: &ast::Expr) {
+        match &node.node {
+            ast::ExprKind::Yield { .. } | ast::ExprKind::YieldFrom { .. } => {
+                if !matches!(self.scope, Scope::Function { .. }) {
+                    self.error(SemanticErrorType::YieldOutsideFunction, node.location);
+                }
+                visitor::walk_expr(self, node);
+            }
+            ast::ExprKind::Await { .. } => {
+                match self.scope {
+                    Scope::Function { is_async: true } => {}
+                    Scope::Function { is_async: false } => {
+                        self.error(SemanticErrorType::AwaitOutsideAsyncFunction, node.location)
+                    }
+                    Scope::Module | Scope::Class => {
+                        self.error(SemanticErrorType::AwaitOutsideFunction, node.location)
+                    }
+                }
+                visitor::walk_expr(self, node);
+            }
+            ast::ExprKind::Lambda { args, body } => {
+                self.check_parameters(args);
+                self.visit_arguments(args);
+                self.in_scope(Scope::Function { is_async: false }, false, |v| {
+                    v.visit_expr(body)
+                });
+            }
+            ast::ExprKind::DoBlock {
+                args,
+                body,
+                returns,
+                chain,
+                is_async,
+            } => {
+                // A do block is a closure: `return` and `yield` apply to the block itself,
+                // and loops around it can't be broken out of from inside. Only an `async do`
+                // block can `await`, as it runs apart from the function it is written in.
+                self.check_parameters(args);
+                self.visit_arguments(args);
+                if let Some(returns) = returns {
+                    self.visit_expr(returns);
+                }
+                let is_async = *is_async > 0;
+                self.in_scope(Scope::Function { is_async }, false, |v| v.visit_body(body));
+                if let Some(chain) = chain {
+                    let outer = self.chain_start.replace(chain.location);
+                    self.visit_expr(chain);
+                    self.chain_start = outer;
+                }
+            }
+            ast::ExprKind::EndOfBlockMarker => {
+                if self.chain_start != Some(node.location) {
+                    self.error(SemanticErrorType::MisplacedBlockMarker, node.location);
+                }
+            }
+            _ => visitor::walk_expr(self, node),
+        }
+    }
+
+    fn visit_comprehension(&mut self, node: &ast::Comprehension) {
+        self.check_store_target(&node.target);
+        visitor::walk_comprehension(self, node);
+    }
+
+    fn visit_withitem(&mut self, node: &ast::Withitem) {
+        if let Some(optional_vars) = &node.optional_vars {
+            self.check_store_target(optional_vars);
+        }
+        visitor::walk_withitem(self, node);
+    }
+
+    fn visit_pattern(&mut self, node: &ast::Pattern) {
+        if let ast::PatternKind::MatchSequence { patterns } = &node.node {
+            let mut starred = patterns
+                .iter()
+                .filter(|pattern| matches!(pattern.node, ast::PatternKind::MatchStar { .. }));
+            if let (Some(_), Some(second)) = (starred.next(), starred.next()) {
+                self.error(SemanticErrorType::MultipleStarredPatterns, second.location);
+            }
+        }
+        visitor::walk_pattern(self, node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_program;
+
+    #[test]
+    fn test_break_outside_loop() {
+        let source = "if x:\n  break";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_continue_in_function_outside_loop() {
+        let source = "for x in y:\n  def f():\n    continue";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_break_in_loop_else() {
+        let source = "while x:\n  pass\nelse:\n  break";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_return_outside_function() {
+        let source = "class A:\n  return 1";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_yield_outside_function() {
+        let source = "x = yield 1";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_await_outside_function() {
+        let source = "await x";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_await_outside_async_function() {
+        let source = "def f():\n  await x";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_async_for_outside_async_function() {
+        let source = "def f(xs):\n  async for x in xs:\n    pass";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_await_in_do_block() {
+        let source = "async def f():\n  xs.each() do x:\n    await x";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_duplicate_argument() {
+        let source = "def f(a, b, *, a=1):\n  pass";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_duplicate_do_block_argument() {
+        let source = "xs.each() do x, x:\n  pass";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_misplaced_block_marker() {
+        let source = "xs.each() do x:\n  print(x)\n  |.then(|)";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_nonlocal_at_module_level() {
+        let source = "nonlocal x";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_assign_to_call() {
+        let source = "f() = 1";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_assign_to_literal_in_tuple() {
+        let source = "a, 1 = x";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_assign_to_optional_chain() {
+        let source = "a?.b = 1";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_assign_starred_alone() {
+        let source = "*a = x";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_assign_multiple_starred() {
+        let source = "*a, *b = x";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_aug_assign_to_tuple() {
+        let source = "a, b += 1";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_ann_assign_to_tuple() {
+        let source = "(a, b): int = 1";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_delete_call() {
+        let source = "del f()";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_for_target_call() {
+        let source = "for f() in x:\n  pass";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_with_target_literal() {
+        let source = "with x as 1:\n  pass";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_comprehension_target_call() {
+        let source = "[x for f() in y]";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_error_message() {
+        let error = parse_program("f() = 1", "<test>").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot assign to function call at line 1 column 0"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_enum_variant() {
+        let source = "enum E:\n  A\n  B(x: int, y: int)\n  A(int)";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_multiple_starred_patterns() {
+        let source = "match xs:\n  case [*a, b, *c]:\n    pass";
+        insta::assert_debug_snapshot!(parse_program(source, "<test>").unwrap_err());
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let source = "\
+async def f(a, b=1, *args, c, **kwargs):
+  while a:
+    for x in args:
+      if x:
+        continue
+      break
+    a, *rest = await g()
+    del a, rest[0]
+  nonlocal_ok = [y for y in args]
+  return (yield a)
+xs.map() do x:
+  return x * 2
+ys = xs.map() async do x:
+  return await x
+zs = xs.map(async do x: await x)
+";
+        assert!(parse_program(source, "<test>").is_ok());
+    }
+}
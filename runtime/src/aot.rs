@@ -0,0 +1,299 @@
+//! Ahead-of-time compilation of programs to native executables.
+//!
+//! `build` lowers every code object of a program and of the modules it
+//! imports, the way the JIT lowers a specialisation but without inlining
+//! anything, into an object file. Next to the native code, the object has
+//! the image of the program, a table of the native code of its code objects
+//! in the order `program_code_objects` lists them, and a `main` that hands
+//! those to `emerald_aot_main`. The system linker links it against the
+//! runtime library, the static library of this crate, into an executable.
+//!
+//! The executable decodes its code objects from the image when it starts,
+//! without the source of the program, and runs their native code instead of
+//! interpreting them. Its hot functions are still specialised and compiled
+//! by the JIT.
+
+use crate::bytecode::{CodeObject, Constant};
+use crate::error::{BuildError, JitError, RuntimeError};
+use crate::image;
+use crate::jit::{self, Compiled, CompiledCode, Lowering};
+use crate::program::Program;
+use crate::value::Value;
+use crate::vm::Vm;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, UserFuncName};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{default_libcall_names, DataDescription, DataId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+
+/// The libraries the runtime library needs, besides the C library.
+const SYSTEM_LIBRARIES: [&str; 3] = ["-lpthread", "-ldl", "-lm"];
+
+/// The code objects of a program: its module, and the code objects in the
+/// constants of each code object after it, depth first.
+pub fn code_objects(module: &Rc<CodeObject>) -> Vec<Rc<CodeObject>> {
+    let mut objects = vec![];
+    let mut pending = vec![Rc::clone(module)];
+    while let Some(code) = pending.pop() {
+        for constant in code.constants.iter().rev() {
+            if let Constant::Code(inner) = constant {
+                pending.push(Rc::clone(inner));
+            }
+        }
+        objects.push(code);
+    }
+    objects
+}
+
+/// The code objects of `program`: those of its main module, then those of
+/// the modules it imports, in its order.
+pub fn program_code_objects(program: &Program) -> Vec<Rc<CodeObject>> {
+    let mut objects = code_objects(&program.main);
+    for (_, code) in &program.modules {
+        objects.extend(code_objects(code));
+    }
+    objects
+}
+
+/// Compile `program` to an object file.
+pub fn emit_object(program: &Program) -> Result<Vec<u8>, BuildError> {
+    let builder = ObjectBuilder::new(jit::native_isa(true)?, "emerald", default_libcall_names())?;
+    let mut object = ObjectModule::new(builder);
+    let mut lowering = Lowering::new(&mut object)?;
+    let signature = Lowering::signature(&object);
+
+    let mut table = DataDescription::new();
+    let pointer_bytes = object.target_config().pointer_bytes() as u32;
+    let codes = program_code_objects(program);
+    // Not `define_zeroinit`, which would put it in `.bss`, without the
+    // relocations that fill it in.
+    table.define(vec![0; codes.len() * pointer_bytes as usize].into());
+    table.set_align(pointer_bytes as u64);
+    for (i, code) in codes.iter().enumerate() {
+        let id = object.declare_function(&format!("emerald_code_{}", i), Linkage::Local, &signature)?;
+        lowering.define_generic(&mut object, id, code)?;
+        let function = object.declare_func_in_data(id, &mut table);
+        table.write_function_addr(i as u32 * pointer_bytes, function);
+    }
+    let table = define_data(&mut object, "emerald_code", &table)?;
+    let image = define_bytes(&mut object, "emerald_image", &image::encode(program))?;
+    define_main(&mut object, image, (table, codes.len()))?;
+
+    object
+        .finish()
+        .emit()
+        .map_err(|error| BuildError::Codegen(JitError(error.to_string())))
+}
+
+/// A read-only data object with `bytes`, and how long it is.
+fn define_bytes(object: &mut ObjectModule, name: &str, bytes: &[u8]) -> Result<(DataId, usize), BuildError> {
+    let mut data = DataDescription::new();
+    data.define(bytes.into());
+    Ok((define_data(object, name, &data)?, bytes.len()))
+}
+
+fn define_data(object: &mut ObjectModule, name: &str, data: &DataDescription) -> Result<DataId, BuildError> {
+    let id = object.declare_data(name, Linkage::Local, false, false)?;
+    object.define_data(id, data)?;
+    Ok(id)
+}
+
+/// Define the `main` of the executable, which calls `emerald_aot_main` with
+/// the image and the table of native code, and their lengths.
+fn define_main(object: &mut ObjectModule, image: (DataId, usize), table: (DataId, usize)) -> Result<(), BuildError> {
+    let pointer = object.target_config().pointer_type();
+    let mut signature = object.make_signature();
+    signature.returns.push(AbiParam::new(types::I32));
+    let main = object.declare_function("main", Linkage::Export, &signature)?;
+
+    let mut runtime_signature = object.make_signature();
+    runtime_signature.params = vec![AbiParam::new(pointer); 4];
+    runtime_signature.returns.push(AbiParam::new(types::I32));
+    let runtime_main = object.declare_function("emerald_aot_main", Linkage::Import, &runtime_signature)?;
+
+    let mut context = object.make_context();
+    context.func.signature = signature;
+    context.func.name = UserFuncName::user(0, main.as_u32());
+    let runtime_main = object.declare_func_in_func(runtime_main, &mut context.func);
+    let mut args = vec![];
+    for (data, len) in [image, table] {
+        args.push((object.declare_data_in_func(data, &mut context.func), len));
+    }
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+    let entry = builder.create_block();
+    builder.switch_to_block(entry);
+    let mut values = vec![];
+    for (data, len) in args {
+        values.push(builder.ins().symbol_value(pointer, data));
+        values.push(builder.ins().iconst(pointer, len as i64));
+    }
+    let call = builder.ins().call(runtime_main, &values);
+    let status = builder.inst_results(call)[0];
+    builder.ins().return_(&[status]);
+    builder.seal_all_blocks();
+    builder.finalize();
+    object.define_function(main, &mut context)?;
+    Ok(())
+}
+
+/// Where the runtime library is: in `EMERALD_RUNTIME` if it is set, and
+/// next to the running compiler otherwise.
+fn runtime_library() -> Result<PathBuf, BuildError> {
+    let path = match std::env::var_os("EMERALD_RUNTIME") {
+        Some(path) => PathBuf::from(path),
+        None => std::env::current_exe()?.with_file_name("libemerald_lang_runtime.a"),
+    };
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(BuildError::MissingRuntime(path))
+    }
+}
+
+/// Build `program` into the executable `output`. The linker is the one in
+/// `CC`, or `cc`.
+pub fn build(program: &Program, output: &Path) -> Result<(), BuildError> {
+    let object = emit_object(program)?;
+    let runtime = runtime_library()?;
+    let object_path = output.with_extension("o");
+    std::fs::write(&object_path, object)?;
+    let linker = std::env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    let linked = Command::new(linker)
+        .arg(&object_path)
+        .arg(&runtime)
+        .args(SYSTEM_LIBRARIES)
+        .arg("-o")
+        .arg(output)
+        .output();
+    std::fs::remove_file(&object_path)?;
+    let linked = linked?;
+    if !linked.status.success() {
+        return Err(BuildError::Link(String::from_utf8_lossy(&linked.stderr).into_owned()));
+    }
+    Ok(())
+}
+
+/// Run `program` on `vm`, with `compiled`, the native code of its code
+/// objects in the order `program_code_objects` lists them.
+fn run_precompiled(vm: &mut Vm, program: Program, compiled: Vec<Compiled>) -> Result<Value, RuntimeError> {
+    let codes = program_code_objects(&program);
+    assert_eq!(codes.len(), compiled.len(), "the program doesn't match its native code");
+    for (code, compiled) in codes.iter().zip(compiled) {
+        vm.add_precompiled(code, compiled);
+    }
+    vm.run_program(program)
+}
+
+/// The entry point of executables: `main` calls it with the image of the
+/// program and the native code of its code objects.
+///
+/// # Safety
+///
+/// The pointers point to as many bytes, or native code functions, as their
+/// lengths say, and the functions are those `emit_object` lowered from the
+/// code objects of the image.
+#[no_mangle]
+pub unsafe extern "C" fn emerald_aot_main(
+    image: *const u8,
+    image_len: usize,
+    functions: *const CompiledCode,
+    count: usize,
+) -> i32 {
+    let functions = std::slice::from_raw_parts(functions, count);
+    let program = match image::decode(std::slice::from_raw_parts(image, image_len)) {
+        Some(program) => program,
+        None => {
+            eprintln!("the image of the program is corrupt, or from another version of the runtime");
+            return 1;
+        }
+    };
+    let compiled = program_code_objects(&program)
+        .iter()
+        .zip(functions)
+        .map(|(code, entry)| Compiled::generic(*entry, code))
+        .collect();
+    let result = run_precompiled(&mut Vm::new(), program, compiled);
+    // Nothing else flushes it, without a Rust `main`.
+    let _ = std::io::stdout().flush();
+    match result {
+        Ok(_) => 0,
+        Err(error) => {
+            eprintln!("{}", error);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_source;
+    use crate::jit::{Jit, JitMode};
+    use crate::vm::tests::SharedBuffer;
+
+    #[test]
+    fn test_code_objects() {
+        let source = "\
+class Counter:
+    def initialize(self):
+        self.count = 0
+    def step(self, by):
+        self.count = self.count + by
+
+counter = Counter()
+[1, 2].map() do x:
+    counter.step(x)
+print(counter.count)
+";
+        let module = compile_source(source, "<test>").unwrap();
+        let names: Vec<String> = code_objects(&module).iter().map(|code| code.name.clone()).collect();
+        insta::assert_snapshot!(names.join("\n"));
+        let program = Program { main: module, modules: vec![] };
+        let object = emit_object(&program).unwrap();
+        assert!(object.starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn test_run_precompiled() {
+        let source = "\
+from numbers import twice
+
+def fib(n):
+    if n < 2:
+        return n
+    return fib(n - 1) + fib(n - 2)
+
+result = None
+try:
+    fib(None)
+except TypeError:
+    result = [1, 2, 3].map() do x:
+        twice(fib(x + 10))
+print(result)
+";
+        let program = Program {
+            main: compile_source(source, "<test>").unwrap(),
+            modules: vec![(
+                "numbers".to_owned(),
+                compile_source("def twice(x):\n    return x * 2\n", "numbers.em").unwrap(),
+            )],
+        };
+        // The JIT lowers code the same way, so its generic code stands in for
+        // the code in an executable.
+        let mut jit = Jit::new().unwrap();
+        let compiled = program_code_objects(&program)
+            .iter()
+            .map(|code| jit.compile_generic(code).unwrap())
+            .collect();
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_jit_mode(JitMode::Interpreter);
+        run_precompiled(&mut vm, program, compiled).unwrap();
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "[178, 288, 466]\n");
+        assert!(vm.jit_stats().entries > 0);
+    }
+}
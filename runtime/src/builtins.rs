@@ -0,0 +1,688 @@
+//! The builtin functions, exception classes and methods of the builtin types.
+//!
+//! Methods of builtin types are called with the receiver as their first
+//! argument. The methods that take a block, like `map`, take it as their last
+//! argument, which is where a `do` block goes.
+
+use crate::bytecode::{BinaryOperator, ComparisonOperator};
+use crate::event_loop;
+use emerald_lang_analysis::mro;
+use crate::heap::{Builtin, BuiltinFunction, Class, ObjRef, Object};
+use crate::value::{Unboxed, Value};
+use crate::vm::{ErrorKind, Vm, VmResult};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, Signed};
+use rustc_hash::FxHashMap;
+
+const FUNCTIONS: &[(&str, BuiltinFunction)] = &[
+    ("abs", abs),
+    ("bool", bool),
+    ("collect_garbage", collect_garbage),
+    ("connect", event_loop::connect),
+    ("finalize", finalize),
+    ("float", float),
+    ("int", int),
+    ("isinstance", isinstance),
+    ("len", len),
+    ("list", list),
+    ("listen", event_loop::listen),
+    ("max", max),
+    ("min", min),
+    ("print", print),
+    ("range", range),
+    ("read_file", event_loop::read_file),
+    ("repr", repr),
+    ("set", set),
+    ("set_timeout", event_loop::set_timeout),
+    ("sleep", event_loop::sleep),
+    ("str", str),
+    ("weakref", weakref),
+    ("write_file", event_loop::write_file),
+];
+
+const STR_METHODS: &[(&str, BuiltinFunction)] = &[
+    ("characters", str_characters),
+    ("endswith", str_endswith),
+    ("join", str_join),
+    ("length", length),
+    ("lower", str_lower),
+    ("replace", str_replace),
+    ("split", str_split),
+    ("startswith", str_startswith),
+    ("strip", str_strip),
+    ("upper", str_upper),
+];
+
+const LIST_METHODS: &[(&str, BuiltinFunction)] = &[("append", list_append), ("length", length), ("pop", list_pop)];
+
+const SET_METHODS: &[(&str, BuiltinFunction)] = &[("add", set_add), ("length", length)];
+
+const DICT_METHODS: &[(&str, BuiltinFunction)] = &[
+    ("get", dict_get),
+    ("items", dict_items),
+    ("keys", dict_keys),
+    ("length", length),
+    ("values", dict_values),
+];
+
+/// The methods of everything that can be iterated over.
+const ITERABLE_METHODS: &[(&str, BuiltinFunction)] = &[
+    ("each", each),
+    ("filter", select),
+    ("join", join),
+    ("map", map),
+    ("reduce", reduce),
+    ("select", select),
+    ("sum", sum),
+    ("to_list", to_list),
+];
+
+const INT_METHODS: &[(&str, BuiltinFunction)] = &[("times", times)];
+
+const WEAKREF_METHODS: &[(&str, BuiltinFunction)] = &[("get", weakref_get)];
+
+const PROMISE_METHODS: &[(&str, BuiltinFunction)] = &[("then", event_loop::promise_then)];
+
+const SERVER_METHODS: &[(&str, BuiltinFunction)] = &[
+    ("accept", event_loop::server_accept),
+    ("close", event_loop::socket_close),
+    ("port", event_loop::server_port),
+];
+
+const CONNECTION_METHODS: &[(&str, BuiltinFunction)] = &[
+    ("close", event_loop::socket_close),
+    ("read", event_loop::connection_read),
+    ("write", event_loop::connection_write),
+];
+
+/// Define the builtin functions and exception classes of `vm`.
+pub(crate) fn install(vm: &mut Vm) {
+    for &(name, function) in FUNCTIONS {
+        let builtin = vm.heap.alloc(Object::Builtin(Builtin { name, function }));
+        vm.define_builtin(name, Value::from(builtin));
+    }
+    let initialize = vm.heap.alloc(Object::Builtin(Builtin {
+        name: "initialize",
+        function: exception_initialize,
+    }));
+    let mut base_mro = vec![];
+    for kind in ErrorKind::ALL {
+        let mut namespace = FxHashMap::default();
+        if kind == ErrorKind::Exception {
+            namespace.insert("initialize".to_owned(), Value::from(initialize));
+        }
+        let class = vm.heap.alloc(Object::Class(Class {
+            name: kind.name().to_owned(),
+            is_module: false,
+            bases: base_mro.first().copied().into_iter().collect(),
+            mro: vec![],
+            namespace,
+        }));
+        let mro = mro::resolution_order(class, [base_mro.clone()]);
+        if let Object::Class(class) = vm.heap.get_mut(class) {
+            class.mro = mro;
+        }
+        if kind == ErrorKind::Exception {
+            base_mro.push(class);
+        }
+        vm.register_error(class);
+        vm.define_builtin(kind.name(), Value::from(class));
+    }
+}
+
+/// The builtin method `name` of `receiver`, if it has one.
+pub(crate) fn method(vm: &Vm, receiver: &Value, name: &str) -> Option<Builtin> {
+    let tables: &[&[(&str, BuiltinFunction)]] = match receiver.unbox() {
+        Unboxed::Int(_) => &[INT_METHODS],
+        Unboxed::Object(object) => match vm.heap.get(object) {
+            Object::BigInt(_) => &[INT_METHODS],
+            Object::Str(_) => &[STR_METHODS, ITERABLE_METHODS],
+            Object::List(_) => &[LIST_METHODS, ITERABLE_METHODS],
+            Object::Dict(_) => &[DICT_METHODS, ITERABLE_METHODS],
+            Object::Set(_) => &[SET_METHODS, ITERABLE_METHODS],
+            Object::Tuple(_) | Object::Range { .. } | Object::Iterator(_) => &[ITERABLE_METHODS],
+            Object::WeakRef(_) => &[WEAKREF_METHODS],
+            Object::Promise(_) => &[PROMISE_METHODS],
+            Object::Server(_) => &[SERVER_METHODS],
+            Object::Connection(_) => &[CONNECTION_METHODS],
+            _ => &[],
+        },
+        _ => &[],
+    };
+    tables
+        .iter()
+        .flat_map(|table| table.iter())
+        .find(|(method, _)| *method == name)
+        .map(|&(name, function)| Builtin { name, function })
+}
+
+/// Check that a builtin got from `min` to `max` arguments.
+pub(crate) fn arity(vm: &mut Vm, name: &str, args: &[Value], min: usize, max: usize) -> VmResult<()> {
+    if (min..=max).contains(&args.len()) {
+        return Ok(());
+    }
+    let expected = if min == max {
+        min.to_string()
+    } else {
+        format!("from {} to {}", min, max)
+    };
+    let message = format!("{}() takes {} arguments but {} were given", name, expected, args.len());
+    Err(vm.error(ErrorKind::TypeError, message))
+}
+
+/// Check the arguments of a method, which don't count the receiver.
+pub(crate) fn method_arity(vm: &mut Vm, name: &str, args: &[Value], min: usize, max: usize) -> VmResult<()> {
+    arity(vm, name, &args[1..], min, max)
+}
+
+pub(crate) fn string(vm: &mut Vm, value: &Value) -> VmResult<String> {
+    match value.as_object().and_then(|object| vm.heap.str(object)) {
+        Some(s) => Ok(s.to_owned()),
+        None => {
+            let message = format!("expected a str, not '{}'", vm.type_name(value));
+            Err(vm.error(ErrorKind::TypeError, message))
+        }
+    }
+}
+
+/// The `initialize` of the variants of enums with fields, which takes the
+/// fields in the order of the `match_args` of the variant.
+pub(crate) const VARIANT_INITIALIZE: Builtin = Builtin {
+    name: "initialize",
+    function: variant_initialize,
+};
+
+fn variant_initialize(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let class = match vm.heap.get(args[0].as_object().unwrap()) {
+        Object::Instance(instance) => instance.class,
+        _ => unreachable!(),
+    };
+    let fields = match vm.lookup_in_class(class, "match_args").and_then(|fields| fields.as_object()) {
+        Some(fields) => match vm.heap.get(fields) {
+            Object::Tuple(fields) => fields.clone(),
+            _ => vec![],
+        },
+        None => vec![],
+    };
+    let name = vm.heap.class(class).unwrap().name.clone();
+    method_arity(vm, &name, &args, fields.len(), fields.len())?;
+    for (field, value) in fields.iter().zip(&args[1..]) {
+        let field = vm.to_str(field);
+        vm.set_attr(&args[0], &field, *value)?;
+    }
+    Ok(Value::NONE)
+}
+
+fn exception_initialize(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "initialize", &args, 0, 1)?;
+    let message = match args.get(1) {
+        Some(message) => *message,
+        None => vm.alloc_str(""),
+    };
+    vm.set_attr(&args[0], "message", message)?;
+    Ok(Value::NONE)
+}
+
+fn print(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let mut line: String = args.iter().map(|arg| vm.to_str(arg)).collect::<Vec<_>>().join(" ");
+    line.push('\n');
+    vm.write_output(&line)?;
+    Ok(Value::NONE)
+}
+
+fn len(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "len", &args, 1, 1)?;
+    let len = match args[0].as_object().map(|object| vm.heap.get(object)) {
+        Some(Object::Str(s)) => s.chars().count(),
+        Some(Object::List(items) | Object::Tuple(items) | Object::Set(items)) => items.len(),
+        Some(Object::Dict(entries)) => entries.len(),
+        Some(Object::Range { start, stop }) => (stop - start).max(0) as usize,
+        _ => {
+            let message = format!("object of type '{}' has no len()", vm.type_name(&args[0]));
+            return Err(vm.error(ErrorKind::TypeError, message));
+        }
+    };
+    Ok(vm.int(len as i64))
+}
+
+fn length(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "length", &args, 0, 0)?;
+    len(vm, args)
+}
+
+fn str(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "str", &args, 0, 1)?;
+    let s = args.first().map_or_else(String::new, |arg| vm.to_str(arg));
+    Ok(vm.alloc_str(s))
+}
+
+fn repr(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "repr", &args, 1, 1)?;
+    let s = vm.repr(&args[0]);
+    Ok(vm.alloc_str(s))
+}
+
+fn int(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "int", &args, 0, 1)?;
+    let value = match args.first() {
+        None => return Ok(vm.int(0)),
+        Some(value) => value,
+    };
+    match value.unbox() {
+        Unboxed::Bool(b) => Ok(vm.int(b as i64)),
+        Unboxed::Float(x) => match BigInt::from_f64(x.trunc()) {
+            Some(i) => Ok(vm.alloc_int(i)),
+            None if x.is_nan() => Err(vm.error(ErrorKind::ValueError, "cannot convert float NaN to integer")),
+            None => Err(vm.error(ErrorKind::OverflowError, "cannot convert float infinity to integer")),
+        },
+        _ if vm.integer(value).is_some() => Ok(*value),
+        _ => {
+            let s = string(vm, value)?;
+            match s.trim().replace('_', "").parse::<BigInt>() {
+                Ok(i) => Ok(vm.alloc_int(i)),
+                Err(_) => {
+                    let message = format!("invalid literal for int(): {}", vm.repr(value));
+                    Err(vm.error(ErrorKind::ValueError, message))
+                }
+            }
+        }
+    }
+}
+
+fn float(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "float", &args, 0, 1)?;
+    let value = match args.first() {
+        None => return Ok(Value::from(0.0)),
+        Some(value) => value,
+    };
+    match value.unbox() {
+        Unboxed::Float(_) => Ok(*value),
+        Unboxed::Bool(_) => vm.binary_op(BinaryOperator::Add, value, &Value::from(0.0)),
+        _ if vm.integer(value).is_some() => vm.binary_op(BinaryOperator::Add, value, &Value::from(0.0)),
+        _ => {
+            let s = string(vm, value)?;
+            match s.trim().parse::<f64>() {
+                Ok(x) => Ok(Value::from(x)),
+                Err(_) => {
+                    let message = format!("could not convert string to float: {}", vm.repr(value));
+                    Err(vm.error(ErrorKind::ValueError, message))
+                }
+            }
+        }
+    }
+}
+
+fn bool(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "bool", &args, 0, 1)?;
+    Ok(Value::from(args.first().is_some_and(|arg| vm.truthy(arg))))
+}
+
+fn list(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "list", &args, 0, 1)?;
+    let items = match args.first() {
+        Some(iterable) => vm.items(iterable)?,
+        None => vec![],
+    };
+    Ok(vm.alloc_list(items))
+}
+
+fn set(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "set", &args, 0, 1)?;
+    let items = match args.first() {
+        Some(iterable) => vm.items(iterable)?,
+        None => vec![],
+    };
+    let set = vm.heap.alloc(Object::Set(vec![]));
+    for item in items {
+        vm.add_to_set(set, item);
+    }
+    Ok(Value::from(set))
+}
+
+fn range(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "range", &args, 1, 2)?;
+    let (start, stop) = match args.as_slice() {
+        [stop] => (0, vm.to_i64(stop)?),
+        [start, stop] => (vm.to_i64(start)?, vm.to_i64(stop)?),
+        _ => unreachable!(),
+    };
+    Ok(Value::from(vm.heap.alloc(Object::Range { start, stop })))
+}
+
+fn abs(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "abs", &args, 1, 1)?;
+    match args[0].unbox() {
+        Unboxed::Bool(b) => Ok(vm.int(b as i64)),
+        Unboxed::Float(x) => Ok(Value::from(x.abs())),
+        _ => match vm.integer(&args[0]) {
+            Some(i) => Ok(vm.alloc_int(i.abs())),
+            None => {
+                let message = format!("bad operand type for abs(): '{}'", vm.type_name(&args[0]));
+                Err(vm.error(ErrorKind::TypeError, message))
+            }
+        },
+    }
+}
+
+/// The least of `args`, or of the items of its only argument, by `op`.
+fn extreme(vm: &mut Vm, name: &str, args: Vec<Value>, op: ComparisonOperator) -> VmResult<Value> {
+    let items = match args.len() {
+        0 => return arity(vm, name, &args, 1, usize::MAX).map(|_| Value::NONE),
+        1 => vm.items(&args[0])?,
+        _ => args,
+    };
+    let mut items = items.into_iter();
+    let mut best = match items.next() {
+        Some(first) => first,
+        None => return Err(vm.error(ErrorKind::ValueError, format!("{}() arg is an empty sequence", name))),
+    };
+    for item in items {
+        if vm.compare(op, &item, &best)? {
+            best = item;
+        }
+    }
+    Ok(best)
+}
+
+fn min(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    extreme(vm, "min", args, ComparisonOperator::Lt)
+}
+
+fn max(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    extreme(vm, "max", args, ComparisonOperator::Gt)
+}
+
+fn collect_garbage(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "collect_garbage", &args, 0, 0)?;
+    let freed = vm.collect_garbage();
+    Ok(vm.int(freed as i64))
+}
+
+/// The object `args[0]`, for the builtins that only take objects.
+fn object_arg(vm: &mut Vm, name: &str, args: &[Value]) -> VmResult<ObjRef> {
+    match args[0].as_object() {
+        Some(object) => Ok(object),
+        None => {
+            let message = format!("{}() takes an object, not '{}'", name, vm.type_name(&args[0]));
+            Err(vm.error(ErrorKind::TypeError, message))
+        }
+    }
+}
+
+fn finalize(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "finalize", &args, 2, 2)?;
+    let object = object_arg(vm, "finalize", &args)?;
+    vm.heap.add_finaliser(object, args[1]);
+    Ok(Value::NONE)
+}
+
+fn weakref(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "weakref", &args, 1, 1)?;
+    let object = object_arg(vm, "weakref", &args)?;
+    Ok(Value::from(vm.heap.alloc(Object::WeakRef(Some(object)))))
+}
+
+fn weakref_get(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "get", &args, 0, 0)?;
+    match args[0].as_object().map(|object| vm.heap.get(object)) {
+        Some(Object::WeakRef(Some(target))) => Ok(Value::from(*target)),
+        _ => Ok(Value::NONE),
+    }
+}
+
+fn isinstance(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "isinstance", &args, 2, 2)?;
+    let class = match args[0].as_object().map(|object| vm.heap.get(object)) {
+        Some(Object::Instance(instance)) => instance.class,
+        _ => return Ok(Value::from(false)),
+    };
+    let mro = &vm.heap.class(class).unwrap().mro;
+    let classes = match args[1].as_object().map(|object| vm.heap.get(object)) {
+        Some(Object::Tuple(classes)) => classes.clone(),
+        _ => vec![args[1]],
+    };
+    Ok(Value::from(
+        classes
+            .iter()
+            .any(|class| class.as_object().is_some_and(|class| mro.contains(&class))),
+    ))
+}
+
+fn str_method(vm: &mut Vm, args: &[Value], name: &str, min: usize, max: usize) -> VmResult<String> {
+    method_arity(vm, name, args, min, max)?;
+    string(vm, &args[0])
+}
+
+fn str_upper(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "upper", 0, 0)?;
+    Ok(vm.alloc_str(s.to_uppercase()))
+}
+
+fn str_lower(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "lower", 0, 0)?;
+    Ok(vm.alloc_str(s.to_lowercase()))
+}
+
+fn str_strip(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "strip", 0, 0)?;
+    Ok(vm.alloc_str(s.trim()))
+}
+
+fn str_split(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "split", 0, 1)?;
+    let parts: Vec<String> = match args.get(1) {
+        Some(separator) => {
+            let separator = string(vm, separator)?;
+            if separator.is_empty() {
+                return Err(vm.error(ErrorKind::ValueError, "empty separator"));
+            }
+            s.split(separator.as_str()).map(str::to_owned).collect()
+        }
+        None => s.split_whitespace().map(str::to_owned).collect(),
+    };
+    let parts = parts.into_iter().map(|part| vm.alloc_str(part)).collect();
+    Ok(vm.alloc_list(parts))
+}
+
+fn str_join(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let separator = str_method(vm, &args, "join", 1, 1)?;
+    let mut parts = vec![];
+    for item in vm.items(&args[1])? {
+        parts.push(string(vm, &item)?);
+    }
+    Ok(vm.alloc_str(parts.join(&separator)))
+}
+
+fn str_startswith(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "startswith", 1, 1)?;
+    let prefix = string(vm, &args[1])?;
+    Ok(Value::from(s.starts_with(&prefix)))
+}
+
+fn str_endswith(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "endswith", 1, 1)?;
+    let suffix = string(vm, &args[1])?;
+    Ok(Value::from(s.ends_with(&suffix)))
+}
+
+fn str_replace(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "replace", 2, 2)?;
+    let old = string(vm, &args[1])?;
+    let new = string(vm, &args[2])?;
+    Ok(vm.alloc_str(s.replace(&old, &new)))
+}
+
+fn str_characters(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    let s = str_method(vm, &args, "characters", 0, 0)?;
+    let characters = s.chars().map(|c| vm.alloc_str(c.to_string())).collect();
+    Ok(vm.alloc_list(characters))
+}
+
+fn list_append(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "append", &args, 1, 1)?;
+    if let Object::List(items) = vm.heap.get_mut(args[0].as_object().unwrap()) {
+        items.push(args[1]);
+    }
+    Ok(Value::NONE)
+}
+
+fn set_add(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "add", &args, 1, 1)?;
+    vm.add_to_set(args[0].as_object().unwrap(), args[1]);
+    Ok(Value::NONE)
+}
+
+fn list_pop(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "pop", &args, 0, 0)?;
+    let popped = match vm.heap.get_mut(args[0].as_object().unwrap()) {
+        Object::List(items) => items.pop(),
+        _ => unreachable!(),
+    };
+    match popped {
+        Some(item) => Ok(item),
+        None => Err(vm.error(ErrorKind::IndexError, "pop from empty list")),
+    }
+}
+
+fn dict_entries(vm: &Vm, dict: &Value) -> Vec<(Value, Value)> {
+    match vm.heap.get(dict.as_object().unwrap()) {
+        Object::Dict(entries) => entries.clone(),
+        _ => unreachable!(),
+    }
+}
+
+fn dict_keys(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "keys", &args, 0, 0)?;
+    let keys = dict_entries(vm, &args[0]).into_iter().map(|(key, _)| key).collect();
+    Ok(vm.alloc_list(keys))
+}
+
+fn dict_values(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "values", &args, 0, 0)?;
+    let values = dict_entries(vm, &args[0]).into_iter().map(|(_, value)| value).collect();
+    Ok(vm.alloc_list(values))
+}
+
+fn dict_items(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "items", &args, 0, 0)?;
+    let items = dict_entries(vm, &args[0])
+        .into_iter()
+        .map(|(key, value)| Value::from(vm.heap.alloc(Object::Tuple(vec![key, value]))))
+        .collect();
+    Ok(vm.alloc_list(items))
+}
+
+fn dict_get(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "get", &args, 1, 2)?;
+    let found = dict_entries(vm, &args[0])
+        .into_iter()
+        .find(|(key, _)| vm.equals(key, &args[1]))
+        .map(|(_, value)| value);
+    Ok(found.or_else(|| args.get(2).cloned()).unwrap_or(Value::NONE))
+}
+
+/// The arguments of a method that calls its block for each item, and the
+/// items, which stay pinned while the block runs: the block may drop the
+/// last other reference to them.
+fn block_roots(vm: &mut Vm, args: &[Value]) -> VmResult<(Vec<Value>, Vec<Value>)> {
+    let items = vm.items(&args[0])?;
+    Ok(([args, &items].concat(), items))
+}
+
+fn each(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "each", &args, 1, 1)?;
+    let (roots, items) = block_roots(vm, &args)?;
+    vm.with_pinned(&roots, |vm| {
+        for item in items {
+            vm.call(args[1], vec![item])?;
+        }
+        Ok(args[0])
+    })
+}
+
+fn map(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "map", &args, 1, 1)?;
+    let (roots, items) = block_roots(vm, &args)?;
+    vm.with_pinned(&roots, |vm| {
+        let mut mapped = vec![];
+        for item in items {
+            let value = vm.call(args[1], vec![item])?;
+            vm.pin(value);
+            mapped.push(value);
+        }
+        Ok(vm.alloc_list(mapped))
+    })
+}
+
+fn select(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "select", &args, 1, 1)?;
+    let (roots, items) = block_roots(vm, &args)?;
+    vm.with_pinned(&roots, |vm| {
+        let mut selected = vec![];
+        for item in items {
+            let keep = vm.call(args[1], vec![item])?;
+            if vm.truthy(&keep) {
+                selected.push(item);
+            }
+        }
+        Ok(vm.alloc_list(selected))
+    })
+}
+
+/// `reduce(initial) do total, item: ...`, starting from the first item when
+/// there is no initial value.
+fn reduce(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "reduce", &args, 1, 2)?;
+    let block = *args.last().unwrap();
+    let (roots, items) = block_roots(vm, &args)?;
+    let mut items = items.into_iter();
+    let initial = if args.len() == 3 { Some(args[1]) } else { items.next() };
+    let mut total = match initial {
+        Some(initial) => initial,
+        None => return Err(vm.error(ErrorKind::TypeError, "reduce() of empty sequence with no initial value")),
+    };
+    vm.with_pinned(&roots, |vm| {
+        for item in items {
+            total = vm.call(block, vec![total, item])?;
+            vm.pin(total);
+        }
+        Ok(total)
+    })
+}
+
+fn sum(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "sum", &args, 0, 0)?;
+    let mut total = vm.int(0);
+    for item in vm.items(&args[0])? {
+        total = vm.binary_op(BinaryOperator::Add, &total, &item)?;
+    }
+    Ok(total)
+}
+
+fn join(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "join", &args, 0, 1)?;
+    let separator = match args.get(1) {
+        Some(separator) => string(vm, separator)?,
+        None => String::new(),
+    };
+    let parts: Vec<String> = vm.items(&args[0])?.iter().map(|item| vm.to_str(item)).collect();
+    Ok(vm.alloc_str(parts.join(&separator)))
+}
+
+fn to_list(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "to_list", &args, 0, 0)?;
+    let items = vm.items(&args[0])?;
+    Ok(vm.alloc_list(items))
+}
+
+fn times(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "times", &args, 1, 1)?;
+    let n = vm.to_i64(&args[0])?;
+    vm.with_pinned(&args, |vm| {
+        for i in 0..n {
+            let i = vm.int(i);
+            vm.call(args[1], vec![i])?;
+        }
+        Ok(args[0])
+    })
+}
@@ -0,0 +1,639 @@
+//! The instructions the virtual machine executes, and the code objects that
+//! hold them together with their constants and the names they refer to.
+//!
+//! The machine is stack based: instructions pop their operands off the value
+//! stack of the running frame and push their result. Operands that are known
+//! at compile time are indices into the tables of the code object: `constants`
+//! for literals and nested code, `names` for globals and attributes, and the
+//! local, cell and free variable lists for variables.
+
+use crate::ast::{self, ConversionFlag, Location};
+use num_bigint::BigInt;
+use crate::cache::CacheSite;
+use std::{cell::RefCell, fmt, rc::Rc};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mult,
+    MatMult,
+    Div,
+    Mod,
+    Pow,
+    LShift,
+    RShift,
+    BitOr,
+    BitXor,
+    BitAnd,
+    FloorDiv,
+}
+
+impl From<&ast::Operator> for BinaryOperator {
+    fn from(op: &ast::Operator) -> Self {
+        match op {
+            ast::Operator::Add => BinaryOperator::Add,
+            ast::Operator::Sub => BinaryOperator::Sub,
+            ast::Operator::Mult => BinaryOperator::Mult,
+            ast::Operator::MatMult => BinaryOperator::MatMult,
+            ast::Operator::Div => BinaryOperator::Div,
+            ast::Operator::Mod => BinaryOperator::Mod,
+            ast::Operator::Pow => BinaryOperator::Pow,
+            ast::Operator::LShift => BinaryOperator::LShift,
+            ast::Operator::RShift => BinaryOperator::RShift,
+            ast::Operator::BitOr => BinaryOperator::BitOr,
+            ast::Operator::BitXor => BinaryOperator::BitXor,
+            ast::Operator::BitAnd => BinaryOperator::BitAnd,
+            ast::Operator::FloorDiv => BinaryOperator::FloorDiv,
+        }
+    }
+}
+
+impl BinaryOperator {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mult => "*",
+            BinaryOperator::MatMult => "@",
+            BinaryOperator::Div => "/",
+            BinaryOperator::Mod => "%",
+            BinaryOperator::Pow => "**",
+            BinaryOperator::LShift => "<<",
+            BinaryOperator::RShift => ">>",
+            BinaryOperator::BitOr => "|",
+            BinaryOperator::BitXor => "^",
+            BinaryOperator::BitAnd => "&",
+            BinaryOperator::FloorDiv => "//",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Invert,
+    Not,
+    Plus,
+    Minus,
+}
+
+impl From<&ast::Unaryop> for UnaryOperator {
+    fn from(op: &ast::Unaryop) -> Self {
+        match op {
+            ast::Unaryop::Invert => UnaryOperator::Invert,
+            ast::Unaryop::Not => UnaryOperator::Not,
+            ast::Unaryop::UAdd => UnaryOperator::Plus,
+            ast::Unaryop::USub => UnaryOperator::Minus,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComparisonOperator {
+    Eq,
+    NotEq,
+    Lt,
+    LtE,
+    Gt,
+    GtE,
+    Is,
+    IsNot,
+    In,
+    NotIn,
+}
+
+impl From<&ast::Cmpop> for ComparisonOperator {
+    fn from(op: &ast::Cmpop) -> Self {
+        match op {
+            ast::Cmpop::Eq => ComparisonOperator::Eq,
+            ast::Cmpop::NotEq => ComparisonOperator::NotEq,
+            ast::Cmpop::Lt => ComparisonOperator::Lt,
+            ast::Cmpop::LtE => ComparisonOperator::LtE,
+            ast::Cmpop::Gt => ComparisonOperator::Gt,
+            ast::Cmpop::GtE => ComparisonOperator::GtE,
+            ast::Cmpop::Is => ComparisonOperator::Is,
+            ast::Cmpop::IsNot => ComparisonOperator::IsNot,
+            ast::Cmpop::In => ComparisonOperator::In,
+            ast::Cmpop::NotIn => ComparisonOperator::NotIn,
+        }
+    }
+}
+
+impl ComparisonOperator {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            ComparisonOperator::Eq => "==",
+            ComparisonOperator::NotEq => "!=",
+            ComparisonOperator::Lt => "<",
+            ComparisonOperator::LtE => "<=",
+            ComparisonOperator::Gt => ">",
+            ComparisonOperator::GtE => ">=",
+            ComparisonOperator::Is => "is",
+            ComparisonOperator::IsNot => "is not",
+            ComparisonOperator::In => "in",
+            ComparisonOperator::NotIn => "not in",
+        }
+    }
+}
+
+/// A single instruction. Jump targets are instruction indices in the same
+/// code object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// Push `constants[i]`.
+    LoadConst(u32),
+    Pop,
+    /// Push the top of the stack again.
+    Dup,
+    /// Push the top two entries again, in the same order.
+    DupTwo,
+    /// Swap the top two entries.
+    RotTwo,
+    /// Move the top entry below the two entries under it.
+    RotThree,
+
+    LoadLocal(u32),
+    StoreLocal(u32),
+    /// Load the content of cell `i`: the cells of the frame, followed by the
+    /// ones its function closes over.
+    LoadCell(u32),
+    StoreCell(u32),
+    /// Push cell `i` itself, for `MakeFunction` to close over.
+    LoadClosure(u32),
+    /// Load `names[i]` from the globals, or else from the builtins.
+    LoadGlobal(u32),
+    StoreGlobal(u32),
+    DeleteGlobal(u32),
+    /// Load `names[i]` from the namespace of a class body, or else as a global.
+    LoadName(u32),
+    StoreName(u32),
+    DeleteName(u32),
+
+    /// Replace an object with its attribute `names[name]`, looked up through
+    /// the inline cache `caches[cache]`.
+    LoadAttr { name: u32, cache: u32 },
+    /// Pop an object and the value below it, and set the attribute
+    /// `names[name]` of the object to the value.
+    StoreAttr { name: u32, cache: u32 },
+    /// Pop an object and delete its attribute `names[i]`.
+    DeleteAttr(u32),
+    /// Pop an index and the object below it, and push the item.
+    LoadSubscript,
+    /// Pop an index, the object below it and the value below that, and set the
+    /// item of the object to the value.
+    StoreSubscript,
+    /// Pop an index and the object below it, and delete the item.
+    DeleteSubscript,
+
+    BinaryOp(BinaryOperator),
+    UnaryOp(UnaryOperator),
+    Compare(ComparisonOperator),
+
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+    /// Jump if the top of the stack is falsy, keeping it; pop it otherwise.
+    JumpIfFalseOrPop(u32),
+    /// Jump if the top of the stack is truthy, keeping it; pop it otherwise.
+    JumpIfTrueOrPop(u32),
+    /// Jump if the top of the stack isn't None, keeping it; pop it otherwise.
+    JumpIfNotNoneOrPop(u32),
+    /// Jump if the top of the stack is None, keeping it.
+    JumpIfNone(u32),
+
+    /// Replace the top of the stack with an iterator over it.
+    GetIter,
+    /// Push the next item of the iterator on top of the stack, or pop the
+    /// iterator and jump when it is exhausted.
+    ForIter(u32),
+
+    BuildList(u32),
+    BuildTuple(u32),
+    /// Build a dict from `n` keys and values, pushed alternately.
+    BuildDict(u32),
+    BuildSet(u32),
+    /// Concatenate `n` strings.
+    BuildString(u32),
+    /// Convert the top of the stack to a string, for an f-string.
+    FormatValue(ConversionFlag),
+    /// Pop a format specification, and convert the value below it to a string
+    /// by it, for an f-string.
+    FormatWithSpec(ConversionFlag),
+    /// Build a range from a lower and an upper bound.
+    BuildRange { inclusive: bool },
+    /// Build a slice from a lower bound, an upper bound and a step.
+    BuildSlice,
+    /// Replace a sequence with its `n` items, the first one on top.
+    UnpackSequence(u32),
+    /// Replace a sequence with its first `before` items, a list of the items
+    /// after them but for the last `after` ones, and those, the first on top.
+    UnpackStarred { before: u32, after: u32 },
+    /// Pop an item and append it to the list `n` entries down the stack, for
+    /// a comprehension that is building it.
+    ListAppend(u32),
+    /// Pop an item and add it to the set `n` entries down the stack.
+    SetAdd(u32),
+    /// Pop a value and the key below it, and set the item of the dict `n`
+    /// entries down the stack.
+    MapAdd(u32),
+
+    /// Make a function of the code object `constants[code]`. Below the cells
+    /// it closes over are its `defaults` default values.
+    MakeFunction { code: u32, defaults: u32 },
+    /// Call the callable below `argc` arguments.
+    Call(u32),
+    /// Pop a tuple of the names of keyword arguments, and call the callable
+    /// below `argc` arguments, the last of which are the keyword arguments.
+    CallKw(u32),
+    /// Call the method `names[name]` of the object below `argc` arguments.
+    CallMethod { name: u32, argc: u32, cache: u32 },
+    /// Call the definition of the method `names[name]` that follows the one
+    /// running in the method resolution order of `self`, which is below `argc`
+    /// arguments.
+    CallSuper { name: u32, argc: u32 },
+    /// Replace `self` with the method `CallSuper` would call, bound to it.
+    LoadSuper(u32),
+    Return,
+    /// Suspend the coroutine until the value on top of the stack settles, if
+    /// it is a promise, and replace it with what it settled to.
+    Await,
+    /// Suspend the generator, which gives the value on top of the stack as
+    /// its next item, and replace the value with None when it goes on.
+    Yield,
+
+    /// Run the body function below `bases` classes or modules, and push the
+    /// class or module built from the namespace it fills.
+    BuildClass { name: u32, bases: u32, module: bool },
+    /// Build the enum `names[name]` from `variants` names of variants, each
+    /// pushed before the tuple of the names of its fields.
+    BuildEnum { name: u32, variants: u32 },
+
+    /// Push whether the value on top of the stack is a list or tuple of `len`
+    /// items, or of at least `len` if the pattern has a `star` too.
+    MatchSequence { len: u32, star: bool },
+    /// Pop a tuple of keys, and push the tuple of the values the dict below
+    /// has for them, or None if it isn't a dict or doesn't have them all.
+    MatchKeys,
+    /// Pop a tuple of attribute names and a class below it, and push the tuple
+    /// of the first `npos` attributes of its `match_args` and of the named
+    /// ones, of the value below if it is an instance of the class, or else
+    /// None. A value that isn't a class, like a variant of an enum without
+    /// fields, matches only itself.
+    MatchClass(u32),
+    /// Push the module `names[i]`, relative to the module of the running code
+    /// if it starts with dots, running its code the first time it is imported.
+    ImportModule(u32),
+    /// Push the attribute `names[i]` of the module on top of the stack.
+    ImportName(u32),
+
+    /// Handle the exceptions raised until the matching `PopBlock` at `target`,
+    /// with the exception on top of the stack.
+    SetupExcept(u32),
+    PopBlock,
+    /// Pop a class or a tuple of classes, and push whether the exception below
+    /// it is an instance of one.
+    ExceptionMatch,
+    /// Finish handling the current exception.
+    PopExcept,
+    /// Pop an exception, or a class to instantiate, and raise it.
+    Raise,
+    /// Raise the exception being handled again.
+    Reraise,
+}
+
+impl Instruction {
+    pub fn jump_target(&self) -> Option<u32> {
+        match *self {
+            Instruction::Jump(target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::JumpIfTrue(target)
+            | Instruction::JumpIfFalseOrPop(target)
+            | Instruction::JumpIfTrueOrPop(target)
+            | Instruction::JumpIfNotNoneOrPop(target)
+            | Instruction::JumpIfNone(target)
+            | Instruction::ForIter(target)
+            | Instruction::SetupExcept(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set_jump_target(&mut self, new: u32) {
+        match self {
+            Instruction::Jump(target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::JumpIfTrue(target)
+            | Instruction::JumpIfFalseOrPop(target)
+            | Instruction::JumpIfTrueOrPop(target)
+            | Instruction::JumpIfNotNoneOrPop(target)
+            | Instruction::JumpIfNone(target)
+            | Instruction::ForIter(target)
+            | Instruction::SetupExcept(target) => *target = new,
+            _ => unreachable!("{:?} doesn't jump", self),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Constant {
+    None,
+    Bool(bool),
+    Int(BigInt),
+    Float(f64),
+    Str(String),
+    Code(Rc<CodeObject>),
+}
+
+impl PartialEq for Constant {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Constant::None, Constant::None) => true,
+            (Constant::Bool(a), Constant::Bool(b)) => a == b,
+            (Constant::Int(a), Constant::Int(b)) => a == b,
+            // Keep 0.0 and -0.0 apart.
+            (Constant::Float(a), Constant::Float(b)) => a.to_bits() == b.to_bits(),
+            (Constant::Str(a), Constant::Str(b)) => a == b,
+            (Constant::Code(a), Constant::Code(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constant::None => write!(f, "None"),
+            Constant::Bool(b) => write!(f, "{}", if *b { "True" } else { "False" }),
+            Constant::Int(i) => write!(f, "{}", i),
+            Constant::Float(x) => write!(f, "{:?}", x),
+            Constant::Str(s) => write!(f, "{:?}", s),
+            Constant::Code(code) => write!(f, "<code {}>", code.name),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodeKind {
+    /// The top level of a program.
+    Module,
+    /// The body of a `class` or `module` statement.
+    Class,
+    Function,
+    /// A function defined directly in a class or module body, which takes
+    /// `self` as its first local whether or not it is spelled out.
+    Method,
+    /// A `do` block or a lambda.
+    Block,
+    /// A comprehension or generator expression, whose variables are its own.
+    /// It takes an iterator over the iterable of its first `for` as its only
+    /// argument.
+    Comprehension,
+}
+
+#[derive(Clone, Debug)]
+pub struct CodeObject {
+    pub name: String,
+    pub kind: CodeKind,
+    /// Whether the code is an `async def` or `async do`, whose calls run as
+    /// coroutines and give a promise of what they return.
+    pub is_async: bool,
+    /// Whether the code yields, so that its calls give a generator, which
+    /// runs it up to the next `yield` whenever it is asked for an item.
+    pub is_generator: bool,
+    pub source_path: String,
+    pub location: Location,
+    /// The number of positional parameters, including `self` for methods.
+    pub arg_count: u32,
+    /// Whether the positional arguments beyond `arg_count` are collected in a
+    /// tuple, in the local that follows the parameters.
+    pub varargs: bool,
+    /// The names of the local variables, parameters first.
+    pub locals: Vec<String>,
+    /// The variables of this code that nested code closes over.
+    pub cells: Vec<String>,
+    /// For each cell, the local of the parameter it starts out with.
+    pub cell_args: Vec<Option<u32>>,
+    /// The variables of enclosing code that this code closes over.
+    pub frees: Vec<String>,
+    pub constants: Vec<Constant>,
+    pub names: Vec<String>,
+    pub instructions: Vec<Instruction>,
+    /// The location in the source of each instruction.
+    pub locations: Vec<Location>,
+    /// The fields the body of a class or module declares, like `energy: int`,
+    /// which its instances start out with slots for.
+    pub fields: Vec<String>,
+    /// The inline caches of the attribute and method call instructions.
+    pub caches: RefCell<Vec<CacheSite>>,
+}
+
+impl CodeObject {
+    /// The name of cell `i`, as used by `LoadCell`, `StoreCell` and `LoadClosure`.
+    pub fn cell_name(&self, i: u32) -> &str {
+        let i = i as usize;
+        match self.cells.get(i) {
+            Some(name) => name,
+            None => &self.frees[i - self.cells.len()],
+        }
+    }
+
+    /// The depth of the stack of a frame running this code before each
+    /// instruction, relative to where the stack of the frame starts, or None
+    /// for instructions that can't be reached. Every way to reach an
+    /// instruction reaches it with the same depth.
+    pub fn stack_depths(&self) -> Vec<Option<u32>> {
+        let mut depths = vec![None; self.instructions.len()];
+        let mut pending = vec![(0, 0)];
+        while let Some((pc, depth)) = pending.pop() {
+            match depths[pc] {
+                Some(known) => {
+                    debug_assert_eq!(known, depth, "inconsistent stack depth at {} of {}", pc, self.name);
+                    continue;
+                }
+                None => depths[pc] = Some(depth),
+            }
+            let instruction = &self.instructions[pc];
+            let after = |effect: i32| (depth as i32 + effect) as u32;
+            if let Some(effect) = self.stack_effect(instruction, false) {
+                pending.push((pc + 1, after(effect)));
+            }
+            if let Some(target) = instruction.jump_target() {
+                let effect = self.stack_effect(instruction, true).unwrap();
+                pending.push((target as usize, after(effect)));
+            }
+        }
+        depths
+    }
+
+    /// How `instruction` changes the depth of the stack when it jumps, or
+    /// when it goes on with the next instruction. None if it never does.
+    fn stack_effect(&self, instruction: &Instruction, jumped: bool) -> Option<i32> {
+        let effect = match *instruction {
+            Instruction::LoadConst(_)
+            | Instruction::Dup
+            | Instruction::LoadLocal(_)
+            | Instruction::LoadCell(_)
+            | Instruction::LoadClosure(_)
+            | Instruction::LoadGlobal(_)
+            | Instruction::LoadName(_)
+            | Instruction::ImportModule(_)
+            | Instruction::ImportName(_)
+            | Instruction::MatchSequence { .. } => 1,
+            Instruction::DupTwo => 2,
+            Instruction::Pop
+            | Instruction::StoreLocal(_)
+            | Instruction::StoreCell(_)
+            | Instruction::StoreGlobal(_)
+            | Instruction::StoreName(_)
+            | Instruction::DeleteAttr(_)
+            | Instruction::LoadSubscript
+            | Instruction::FormatWithSpec(_)
+            | Instruction::BinaryOp(_)
+            | Instruction::Compare(_)
+            | Instruction::JumpIfFalse(_)
+            | Instruction::JumpIfTrue(_)
+            | Instruction::BuildRange { .. }
+            | Instruction::ListAppend(_)
+            | Instruction::SetAdd(_)
+            | Instruction::MatchClass(_) => -1,
+            Instruction::RotTwo
+            | Instruction::RotThree
+            | Instruction::LoadAttr { .. }
+            | Instruction::UnaryOp(_)
+            | Instruction::JumpIfNone(_)
+            | Instruction::GetIter
+            | Instruction::FormatValue(_)
+            | Instruction::PopBlock
+            | Instruction::ExceptionMatch
+            | Instruction::PopExcept
+            | Instruction::LoadSuper(_)
+            | Instruction::Await
+            | Instruction::Yield
+            | Instruction::MatchKeys
+            | Instruction::DeleteGlobal(_)
+            | Instruction::DeleteName(_) => 0,
+            Instruction::StoreAttr { .. }
+            | Instruction::DeleteSubscript
+            | Instruction::BuildSlice
+            | Instruction::MapAdd(_) => -2,
+            Instruction::StoreSubscript => -3,
+            Instruction::Jump(_) => match jumped {
+                true => 0,
+                false => return None,
+            },
+            Instruction::JumpIfFalseOrPop(_)
+            | Instruction::JumpIfTrueOrPop(_)
+            | Instruction::JumpIfNotNoneOrPop(_) => match jumped {
+                true => 0,
+                false => -1,
+            },
+            Instruction::ForIter(_) => match jumped {
+                true => -1,
+                false => 1,
+            },
+            // The handler starts with the exception pushed.
+            Instruction::SetupExcept(_) => jumped as i32,
+            Instruction::BuildList(n)
+            | Instruction::BuildTuple(n)
+            | Instruction::BuildSet(n)
+            | Instruction::BuildString(n) => 1 - n as i32,
+            Instruction::BuildDict(n) => 1 - 2 * n as i32,
+            Instruction::UnpackSequence(n) => n as i32 - 1,
+            Instruction::UnpackStarred { before, after } => (before + after) as i32,
+            Instruction::MakeFunction { code, defaults } => {
+                let frees = match &self.constants[code as usize] {
+                    Constant::Code(code) => code.frees.len(),
+                    _ => 0,
+                };
+                1 - (frees as i32 + defaults as i32)
+            }
+            Instruction::Call(argc) | Instruction::CallMethod { argc, .. } | Instruction::CallSuper { argc, .. } => {
+                -(argc as i32)
+            }
+            Instruction::CallKw(argc) => -(argc as i32) - 1,
+            Instruction::BuildClass { bases, .. } => -(bases as i32),
+            Instruction::BuildEnum { variants, .. } => 1 - 2 * variants as i32,
+            Instruction::Return | Instruction::Raise | Instruction::Reraise => return None,
+        };
+        Some(effect)
+    }
+
+    fn describe_operand(&self, instruction: &Instruction) -> Option<String> {
+        let description = match *instruction {
+            Instruction::LoadConst(i) => self.constants[i as usize].to_string(),
+            Instruction::LoadLocal(i) | Instruction::StoreLocal(i) => self.locals[i as usize].clone(),
+            Instruction::LoadCell(i) | Instruction::StoreCell(i) | Instruction::LoadClosure(i) => {
+                self.cell_name(i).to_owned()
+            }
+            Instruction::LoadGlobal(i)
+            | Instruction::StoreGlobal(i)
+            | Instruction::DeleteGlobal(i)
+            | Instruction::LoadName(i)
+            | Instruction::StoreName(i)
+            | Instruction::DeleteName(i)
+            | Instruction::LoadAttr { name: i, .. }
+            | Instruction::StoreAttr { name: i, .. }
+            | Instruction::DeleteAttr(i)
+            | Instruction::CallMethod { name: i, .. }
+            | Instruction::CallSuper { name: i, .. }
+            | Instruction::LoadSuper(i)
+            | Instruction::BuildClass { name: i, .. }
+            | Instruction::BuildEnum { name: i, .. }
+            | Instruction::ImportModule(i)
+            | Instruction::ImportName(i) => self.names[i as usize].clone(),
+            Instruction::MakeFunction { code, .. } => self.constants[code as usize].to_string(),
+            Instruction::BinaryOp(op) => op.symbol().to_owned(),
+            Instruction::Compare(op) => op.symbol().to_owned(),
+            _ => return None,
+        };
+        Some(description)
+    }
+}
+
+/// The disassembly of the code object and all code nested in it.
+impl fmt::Display for CodeObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_async {
+            write!(f, "async ")?;
+        }
+        if self.is_generator {
+            write!(f, "generator ")?;
+        }
+        write!(f, "{:?} {} ({}:{})", self.kind, self.name, self.source_path, self.location.row())?;
+        let tables = [
+            ("locals", &self.locals),
+            ("cells", &self.cells),
+            ("frees", &self.frees),
+            ("fields", &self.fields),
+        ];
+        for (label, names) in tables {
+            if !names.is_empty() {
+                write!(f, "\n  {}: {}", label, names.join(", "))?;
+            }
+        }
+        let mut previous = None;
+        for (pc, (instruction, location)) in self.instructions.iter().zip(&self.locations).enumerate() {
+            let position = if previous == Some(location) {
+                String::new()
+            } else {
+                format!("{}:{}", location.row(), location.column())
+            };
+            previous = Some(location);
+            let target = if self.instructions.iter().any(|other| other.jump_target() == Some(pc as u32)) {
+                ">>"
+            } else {
+                ""
+            };
+            let text = format!("{:?}", instruction);
+            write!(f, "\n  {:>7} {:>2} {:>4} {}", position, target, pc, text)?;
+            if let Some(operand) = self.describe_operand(instruction) {
+                write!(f, " ({})", operand)?;
+            }
+        }
+        for constant in &self.constants {
+            if let Constant::Code(code) = constant {
+                write!(f, "\n\n{}", code)?;
+            }
+        }
+        Ok(())
+    }
+}
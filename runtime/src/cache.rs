@@ -0,0 +1,255 @@
+//! Inline caches for attribute access and method calls.
+//!
+//! Every `LoadAttr`, `StoreAttr` and `CallMethod` instruction has a cache site
+//! in its code object, which remembers what the attribute resolved to for the
+//! receivers it has seen. Receivers are told apart by their shape, so an
+//! instance attribute is found by its slot and a method without walking the
+//! method resolution order. Stores remember the slot, or the shape the
+//! instance grows into when the attribute is new. Classes and modules are
+//! told apart by identity, and values of builtin types by their type.
+//!
+//! A site starts out empty, becomes monomorphic with the first receiver and
+//! polymorphic with the next ones, up to `POLYMORPHIC_LIMIT`. After that it is
+//! megamorphic, and looks attributes up in one table shared by all sites.
+//!
+//! What a name resolves to on a class only changes when a class or module is
+//! reopened, or an attribute of one is set or deleted. Each of those starts a
+//! new epoch, which empties the megamorphic table, and every site the next
+//! time it is used.
+//!
+//! Entries don't keep the classes of their receivers alive: the collector
+//! drops the entries of classes it collected.
+
+use crate::gc::Marker;
+use crate::heap::ObjRef;
+use crate::shape::ShapeId;
+use crate::value::Value;
+use rustc_hash::FxHashMap;
+use std::fmt;
+
+/// The most receivers a polymorphic site tells apart.
+pub const POLYMORPHIC_LIMIT: usize = 4;
+
+/// What a cache site tells receivers apart by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Receiver {
+    Shape(ShapeId),
+    /// A class or module.
+    Class(ObjRef),
+    /// A value of a builtin type, by the name of the type.
+    Type(&'static str),
+}
+
+/// What an attribute resolved to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lookup {
+    /// An attribute of the instance, in this slot.
+    Slot(usize),
+    /// A function of the class, or a method of a builtin type, that takes the
+    /// receiver as its first argument.
+    Method(Value),
+    /// Any other value of the class.
+    Value(Value),
+    /// For a store, an attribute the instance doesn't have yet, which moves
+    /// it to this shape that grew from its own.
+    Grow(ShapeId),
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum InlineCache {
+    #[default]
+    Empty,
+    Monomorphic(Receiver, Lookup),
+    Polymorphic(Vec<(Receiver, Lookup)>),
+    Megamorphic,
+}
+
+/// The cache of one instruction.
+#[derive(Clone, Debug, Default)]
+pub struct CacheSite {
+    pub cache: InlineCache,
+    /// The epoch the cache was filled in.
+    epoch: u64,
+}
+
+/// What looking a receiver up in a site found.
+pub enum Probe<'a> {
+    Hit(&'a Lookup),
+    Miss,
+    /// The site doesn't cache anymore.
+    Megamorphic,
+}
+
+impl CacheSite {
+    /// Empty the cache if it was filled in an earlier epoch. Returns whether
+    /// there was anything to empty.
+    pub fn revalidate(&mut self, epoch: u64) -> bool {
+        if self.epoch == epoch {
+            return false;
+        }
+        self.epoch = epoch;
+        !matches!(std::mem::take(&mut self.cache), InlineCache::Empty)
+    }
+
+    /// Whether the cache was filled in `epoch`, so what it says still holds.
+    pub fn is_current(&self, epoch: u64) -> bool {
+        self.epoch == epoch
+    }
+
+    pub fn probe(&self, receiver: Receiver) -> Probe<'_> {
+        let found = match &self.cache {
+            InlineCache::Empty => None,
+            InlineCache::Monomorphic(cached, lookup) => (*cached == receiver).then_some(lookup),
+            InlineCache::Polymorphic(entries) => entries
+                .iter()
+                .find_map(|(cached, lookup)| (*cached == receiver).then_some(lookup)),
+            InlineCache::Megamorphic => return Probe::Megamorphic,
+        };
+        match found {
+            Some(lookup) => Probe::Hit(lookup),
+            None => Probe::Miss,
+        }
+    }
+
+    /// Drop the entries for receivers that aren't `alive` anymore.
+    pub fn prune(&mut self, alive: impl Fn(Receiver) -> bool) {
+        self.cache = match std::mem::take(&mut self.cache) {
+            InlineCache::Monomorphic(receiver, _) if !alive(receiver) => InlineCache::Empty,
+            InlineCache::Polymorphic(mut entries) => {
+                entries.retain(|(receiver, _)| alive(*receiver));
+                match entries.len() {
+                    0 => InlineCache::Empty,
+                    1 => {
+                        let (receiver, lookup) = entries.pop().unwrap();
+                        InlineCache::Monomorphic(receiver, lookup)
+                    }
+                    _ => InlineCache::Polymorphic(entries),
+                }
+            }
+            cache => cache,
+        };
+    }
+
+    /// Remember what `receiver` resolved to.
+    pub fn insert(&mut self, receiver: Receiver, lookup: Lookup) {
+        self.cache = match std::mem::take(&mut self.cache) {
+            InlineCache::Empty => InlineCache::Monomorphic(receiver, lookup),
+            InlineCache::Monomorphic(cached, cached_lookup) => {
+                InlineCache::Polymorphic(vec![(cached, cached_lookup), (receiver, lookup)])
+            }
+            InlineCache::Polymorphic(entries) if entries.len() >= POLYMORPHIC_LIMIT => InlineCache::Megamorphic,
+            InlineCache::Polymorphic(mut entries) => {
+                entries.push((receiver, lookup));
+                InlineCache::Polymorphic(entries)
+            }
+            InlineCache::Megamorphic => InlineCache::Megamorphic,
+        };
+    }
+}
+
+/// How often the cache sites found what they looked for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub monomorphic_hits: u64,
+    pub polymorphic_hits: u64,
+    pub megamorphic_hits: u64,
+    /// Lookups that had to walk the method resolution order.
+    pub misses: u64,
+    /// Sites emptied because a class changed.
+    pub invalidations: u64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.monomorphic_hits + self.polymorphic_hits + self.megamorphic_hits
+    }
+
+    /// The fraction of lookups that were hits, or 0 without lookups.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits() + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits() as f64 / lookups as f64
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "monomorphic hits: {}", self.monomorphic_hits)?;
+        writeln!(f, "polymorphic hits: {}", self.polymorphic_hits)?;
+        writeln!(f, "megamorphic hits: {}", self.megamorphic_hits)?;
+        writeln!(f, "misses: {}", self.misses)?;
+        writeln!(f, "invalidations: {}", self.invalidations)?;
+        write!(f, "hit rate: {:.1}%", self.hit_rate() * 100.0)
+    }
+}
+
+/// The state the cache sites of all code objects share.
+#[derive(Debug, Default)]
+pub struct Caches {
+    epoch: u64,
+    /// What names resolved to on receivers that megamorphic sites saw.
+    megamorphic: FxHashMap<Receiver, FxHashMap<String, Lookup>>,
+    pub stats: CacheStats,
+}
+
+impl Caches {
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Forget everything cached, because a class or module changed.
+    pub fn invalidate(&mut self) {
+        self.epoch += 1;
+        self.megamorphic.clear();
+    }
+
+    pub fn megamorphic(&self, receiver: Receiver, name: &str) -> Option<&Lookup> {
+        self.megamorphic.get(&receiver)?.get(name)
+    }
+
+    pub fn trace(&self, marker: &mut Marker) {
+        for (receiver, lookups) in &self.megamorphic {
+            for lookup in lookups.values() {
+                marker.cached(*receiver, lookup);
+            }
+        }
+    }
+
+    /// Drop what was cached for receivers that aren't `alive` anymore.
+    pub fn prune(&mut self, alive: impl Fn(Receiver) -> bool) {
+        self.megamorphic.retain(|receiver, _| alive(*receiver));
+    }
+
+    pub fn insert_megamorphic(&mut self, receiver: Receiver, name: &str, lookup: Lookup) {
+        self.megamorphic
+            .entry(receiver)
+            .or_default()
+            .insert(name.to_owned(), lookup);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_site_states() {
+        let mut site = CacheSite::default();
+        site.insert(Receiver::Type("str"), Lookup::Slot(0));
+        assert!(matches!(site.cache, InlineCache::Monomorphic(..)));
+        for i in 1..POLYMORPHIC_LIMIT {
+            site.insert(Receiver::Type(["list", "dict", "tuple"][i - 1]), Lookup::Slot(i));
+        }
+        assert!(matches!(&site.cache, InlineCache::Polymorphic(entries) if entries.len() == POLYMORPHIC_LIMIT));
+        assert!(matches!(site.probe(Receiver::Type("dict")), Probe::Hit(Lookup::Slot(2))));
+        assert!(matches!(site.probe(Receiver::Type("int")), Probe::Miss));
+        site.insert(Receiver::Type("int"), Lookup::Slot(4));
+        assert!(matches!(site.probe(Receiver::Type("str")), Probe::Megamorphic));
+        assert!(!site.revalidate(0));
+        assert!(site.revalidate(1));
+        assert!(matches!(site.probe(Receiver::Type("str")), Probe::Miss));
+    }
+}
@@ -0,0 +1,1753 @@
+//! Compilation of a parsed program to bytecode.
+//!
+//! Every module, function, class body, lambda, `do` block and comprehension
+//! becomes a code object. Nested code objects are constants of the code they are defined in,
+//! and turned into functions at run time by `MakeFunction`, which also hands
+//! them the cells they close over.
+//!
+//! A `do` block evaluates to its last expression statement, like a Ruby block.
+//! The chain that follows a block, as in
+//!
+//! ```text
+//! xs.map() do x:
+//!     x * 2
+//!     |.filter() do x:
+//!         x > 2
+//! ```
+//!
+//! is compiled right after the call the block is passed to, with the result of
+//! that call standing in for the end of block marker it starts with.
+
+use crate::ast::{self, ConversionFlag, Location};
+use crate::bytecode::{CodeKind, CodeObject, ComparisonOperator, Constant, Instruction};
+use crate::cache::CacheSite;
+use crate::error::{CompileError, CompileErrorType};
+use crate::symbols::{Scope, Symbol, SymbolTable};
+use std::{cell::RefCell, rc::Rc};
+
+type CompileResult<T = ()> = Result<T, CompileError>;
+
+/// Compile the statements of a program to the code object of its top level.
+pub fn compile_program(body: &[ast::Stmt], source_path: &str) -> CompileResult<Rc<CodeObject>> {
+    let table = SymbolTable::build(body)?;
+    let mut compiler = Compiler {
+        table: &table,
+        source_path,
+        units: vec![],
+        chains: 0,
+    };
+    compiler.push_unit("<module>", table.module(), Location::new(1, 0), false);
+    for stmt in body {
+        compiler.compile_stmt(stmt)?;
+    }
+    compiler.emit_return_none();
+    Ok(Rc::new(compiler.pop_unit()))
+}
+
+/// A construct that code leaving it early, through `break`, `continue` or
+/// `return`, has to clean up after.
+enum Fblock<'a> {
+    Loop {
+        start: usize,
+        breaks: Vec<usize>,
+        /// A `for` loop keeps its iterator on the stack.
+        is_for: bool,
+    },
+    /// The body of a `try` with `except` clauses.
+    Try,
+    /// The body of a `try` with a `finally` clause.
+    Finally(&'a [ast::Stmt]),
+    /// An `except` clause, or the `finally` clause run for an exception.
+    Handler,
+    /// The body of a `with` statement, whose context manager is on the stack.
+    With { is_async: bool },
+}
+
+/// The code object being compiled for one scope.
+struct Unit<'a> {
+    scope: &'a Scope,
+    code: CodeObject,
+    /// The location given to emitted instructions.
+    location: Location,
+    fblocks: Vec<Fblock<'a>>,
+}
+
+struct Compiler<'a> {
+    table: &'a SymbolTable,
+    source_path: &'a str,
+    units: Vec<Unit<'a>>,
+    /// How many block chains are being compiled, whose start is on the stack.
+    chains: usize,
+}
+
+/// Syntax the compiler doesn't handle yet. The README lists it all as still
+/// to be done.
+fn unsupported(name: &'static str, location: Location) -> CompileError {
+    CompileError {
+        error: CompileErrorType::Unsupported(name),
+        location,
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn unit(&self) -> &Unit<'a> {
+        self.units.last().unwrap()
+    }
+
+    fn unit_mut(&mut self) -> &mut Unit<'a> {
+        self.units.last_mut().unwrap()
+    }
+
+    fn push_unit(&mut self, name: &str, scope: &'a Scope, location: Location, varargs: bool) {
+        let locals = scope.locals();
+        let cells = scope.cells();
+        let cell_args = cells
+            .iter()
+            .map(|cell| scope.params.iter().position(|param| param == cell).map(|i| i as u32))
+            .collect();
+        let arg_count = (scope.params.len() - varargs as usize) as u32;
+        self.units.push(Unit {
+            scope,
+            code: CodeObject {
+                name: name.to_owned(),
+                kind: scope.kind,
+                is_async: false,
+                is_generator: false,
+                source_path: self.source_path.to_owned(),
+                location,
+                arg_count,
+                varargs,
+                locals,
+                cells,
+                cell_args,
+                frees: scope.frees(),
+                constants: vec![],
+                names: vec![],
+                instructions: vec![],
+                locations: vec![],
+                fields: vec![],
+                caches: RefCell::default(),
+            },
+            location,
+            fblocks: vec![],
+        });
+    }
+
+    fn pop_unit(&mut self) -> CodeObject {
+        self.units.pop().unwrap().code
+    }
+
+    fn set_location(&mut self, location: Location) -> Location {
+        std::mem::replace(&mut self.unit_mut().location, location)
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let unit = self.unit_mut();
+        unit.code.instructions.push(instruction);
+        unit.code.locations.push(unit.location);
+        unit.code.instructions.len() - 1
+    }
+
+    fn here(&self) -> usize {
+        self.unit().code.instructions.len()
+    }
+
+    /// Make the jump at `at` go to the next instruction emitted.
+    fn patch(&mut self, at: usize) {
+        let target = self.here() as u32;
+        self.unit_mut().code.instructions[at].set_jump_target(target);
+    }
+
+    fn constant(&mut self, constant: Constant) -> u32 {
+        let constants = &mut self.unit_mut().code.constants;
+        let index = match constants.iter().position(|known| *known == constant) {
+            Some(index) => index,
+            None => {
+                constants.push(constant);
+                constants.len() - 1
+            }
+        };
+        index as u32
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let names = &mut self.unit_mut().code.names;
+        let index = match names.iter().position(|known| known == name) {
+            Some(index) => index,
+            None => {
+                names.push(name.to_owned());
+                names.len() - 1
+            }
+        };
+        index as u32
+    }
+
+    /// A new inline cache for an instruction of the current code.
+    fn cache_site(&mut self) -> u32 {
+        let caches = self.unit_mut().code.caches.get_mut();
+        caches.push(CacheSite::default());
+        (caches.len() - 1) as u32
+    }
+
+    fn emit_constant(&mut self, constant: Constant) {
+        let index = self.constant(constant);
+        self.emit(Instruction::LoadConst(index));
+    }
+
+    fn emit_return_none(&mut self) {
+        self.emit_constant(Constant::None);
+        self.emit(Instruction::Return);
+    }
+
+    fn local(&self, name: &str) -> u32 {
+        self.unit().code.locals.iter().position(|local| local == name).unwrap() as u32
+    }
+
+    fn cell(&self, name: &str) -> u32 {
+        let code = &self.unit().code;
+        let index = match code.cells.iter().position(|cell| cell == name) {
+            Some(index) => index,
+            None => code.cells.len() + code.frees.iter().position(|free| free == name).unwrap(),
+        };
+        index as u32
+    }
+
+    fn load_name(&mut self, name: &str) {
+        let instruction = match self.unit().scope.symbol(name) {
+            Symbol::Local => Instruction::LoadLocal(self.local(name)),
+            Symbol::Cell | Symbol::Free => Instruction::LoadCell(self.cell(name)),
+            Symbol::Global => Instruction::LoadGlobal(self.name(name)),
+            Symbol::Name => Instruction::LoadName(self.name(name)),
+        };
+        self.emit(instruction);
+    }
+
+    fn store_name(&mut self, name: &str) {
+        let instruction = match self.unit().scope.symbol(name) {
+            Symbol::Local => Instruction::StoreLocal(self.local(name)),
+            Symbol::Cell | Symbol::Free => Instruction::StoreCell(self.cell(name)),
+            Symbol::Global => Instruction::StoreGlobal(self.name(name)),
+            Symbol::Name => Instruction::StoreName(self.name(name)),
+        };
+        self.emit(instruction);
+    }
+
+    /// Delete a variable. Locals and cells have no unbound state, so deleting
+    /// one sets it to None.
+    fn delete_name(&mut self, name: &str) {
+        let instruction = match self.unit().scope.symbol(name) {
+            Symbol::Local | Symbol::Cell | Symbol::Free => {
+                self.emit_constant(Constant::None);
+                self.store_name(name);
+                return;
+            }
+            Symbol::Global => Instruction::DeleteGlobal(self.name(name)),
+            Symbol::Name => Instruction::DeleteName(self.name(name)),
+        };
+        self.emit(instruction);
+    }
+
+    fn compile_body(&mut self, body: &'a [ast::Stmt]) -> CompileResult {
+        for stmt in body {
+            self.compile_stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn compile_stmt(&mut self, stmt: &'a ast::Stmt) -> CompileResult {
+        let outer = self.set_location(stmt.location);
+        match &stmt.node {
+            ast::StmtKind::Expr { value } => {
+                self.compile_expr(value)?;
+                self.emit(Instruction::Pop);
+            }
+            ast::StmtKind::Assign { targets, value, .. } => {
+                self.compile_expr(value)?;
+                for (i, target) in targets.iter().enumerate() {
+                    if i + 1 < targets.len() {
+                        self.emit(Instruction::Dup);
+                    }
+                    self.compile_store(target)?;
+                }
+            }
+            ast::StmtKind::AugAssign { target, op, value } => self.compile_aug_assign(target, op, value)?,
+            ast::StmtKind::AnnAssign { target, value, .. } => match value {
+                Some(value) => {
+                    self.compile_expr(value)?;
+                    self.compile_store(target)?;
+                }
+                // Without a value, the statement only declares a field.
+                None => {
+                    let code = &mut self.unit_mut().code;
+                    if let (CodeKind::Class, ast::ExprKind::Name { id, .. }) = (code.kind, &target.node) {
+                        if !code.fields.contains(id) {
+                            code.fields.push(id.clone());
+                        }
+                    }
+                }
+            },
+            ast::StmtKind::If { test, body, orelse } => {
+                self.compile_expr(test)?;
+                let to_else = self.emit(Instruction::JumpIfFalse(0));
+                self.compile_body(body)?;
+                if orelse.is_empty() {
+                    self.patch(to_else);
+                } else {
+                    let to_end = self.emit(Instruction::Jump(0));
+                    self.patch(to_else);
+                    self.compile_body(orelse)?;
+                    self.patch(to_end);
+                }
+            }
+            ast::StmtKind::While { test, body, orelse } => {
+                let start = self.here();
+                self.compile_expr(test)?;
+                let to_else = self.emit(Instruction::JumpIfFalse(0));
+                self.compile_loop_body(start, false, body, |compiler| {
+                    compiler.emit(Instruction::Jump(start as u32));
+                    compiler.patch(to_else);
+                    compiler.compile_body(orelse)
+                })?;
+            }
+            ast::StmtKind::For {
+                target,
+                iter,
+                body,
+                orelse,
+                ..
+            } => {
+                self.compile_expr(iter)?;
+                self.emit(Instruction::GetIter);
+                let start = self.here();
+                let to_else = self.emit(Instruction::ForIter(0));
+                self.compile_store(target)?;
+                self.compile_loop_body(start, true, body, |compiler| {
+                    compiler.emit(Instruction::Jump(start as u32));
+                    compiler.patch(to_else);
+                    compiler.compile_body(orelse)
+                })?;
+            }
+            ast::StmtKind::Break => self.compile_break(false),
+            ast::StmtKind::Continue => self.compile_break(true),
+            ast::StmtKind::Return { value } => {
+                match value {
+                    Some(value) => self.compile_expr(value)?,
+                    None => self.emit_constant(Constant::None),
+                }
+                self.unwind_fblocks(0, true)?;
+                self.emit(Instruction::Return);
+            }
+            ast::StmtKind::Pass | ast::StmtKind::Global { .. } | ast::StmtKind::Nonlocal { .. } => {}
+            ast::StmtKind::FunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                ..
+            }
+            | ast::StmtKind::AsyncFunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                ..
+            } => {
+                for decorator in decorator_list {
+                    self.compile_expr(decorator)?;
+                }
+                self.compile_defaults(args, stmt.location)?;
+                let scope = self.table.stmt_scope(stmt);
+                self.push_unit(name, scope, stmt.location, args.vararg.is_some());
+                self.unit_mut().code.is_async = matches!(stmt.node, ast::StmtKind::AsyncFunctionDef { .. });
+                self.compile_body(body)?;
+                self.emit_return_none();
+                let code = self.pop_unit();
+                self.make_function(code, args.defaults.len());
+                for _ in decorator_list {
+                    self.emit(Instruction::Call(1));
+                }
+                self.store_name(name);
+            }
+            ast::StmtKind::ClassDef {
+                name,
+                bases,
+                keywords,
+                body,
+                decorator_list,
+            } => {
+                if let Some(keyword) = keywords.first() {
+                    return Err(unsupported("class keyword", keyword.location));
+                }
+                self.compile_class(stmt, name, bases, body, decorator_list, false)?;
+            }
+            ast::StmtKind::ModuleDef {
+                name,
+                bases,
+                body,
+                decorator_list,
+            } => self.compile_class(stmt, name, bases, body, decorator_list, true)?,
+            ast::StmtKind::Raise { exc, cause } => {
+                if cause.is_some() {
+                    return Err(unsupported("'raise from'", stmt.location));
+                }
+                match exc {
+                    Some(exc) => {
+                        self.compile_expr(exc)?;
+                        self.emit(Instruction::Raise);
+                    }
+                    None => {
+                        self.emit(Instruction::Reraise);
+                    }
+                }
+            }
+            ast::StmtKind::Try {
+                body,
+                handlers,
+                orelse,
+                finalbody,
+            } => {
+                if finalbody.is_empty() {
+                    self.compile_try_except(body, handlers, orelse)?;
+                } else {
+                    self.compile_try_finally(body, handlers, orelse, finalbody)?;
+                }
+            }
+            ast::StmtKind::Assert { test, msg } => {
+                self.compile_expr(test)?;
+                let to_end = self.emit(Instruction::JumpIfTrue(0));
+                let error = self.name("AssertionError");
+                self.emit(Instruction::LoadGlobal(error));
+                match msg {
+                    Some(msg) => {
+                        self.compile_expr(msg)?;
+                        self.emit(Instruction::Call(1));
+                    }
+                    None => {
+                        self.emit(Instruction::Call(0));
+                    }
+                }
+                self.emit(Instruction::Raise);
+                self.patch(to_end);
+            }
+            ast::StmtKind::EnumDef {
+                name,
+                variants,
+                decorator_list,
+            } => {
+                for decorator in decorator_list {
+                    self.compile_expr(decorator)?;
+                }
+                for variant in variants {
+                    let variant = &variant.node;
+                    self.emit_constant(Constant::Str(variant.name.clone()));
+                    // The fields of a tuple-like variant are `_0`, `_1` and so on.
+                    let fields: Vec<String> = match variant.field_names.is_empty() {
+                        true => (0..variant.fields.len()).map(|i| format!("_{}", i)).collect(),
+                        false => variant.field_names.clone(),
+                    };
+                    for field in &fields {
+                        self.emit_constant(Constant::Str(field.clone()));
+                    }
+                    self.emit(Instruction::BuildTuple(fields.len() as u32));
+                }
+                let name_index = self.name(name);
+                self.emit(Instruction::BuildEnum {
+                    name: name_index,
+                    variants: variants.len() as u32,
+                });
+                for _ in decorator_list {
+                    self.emit(Instruction::Call(1));
+                }
+                self.store_name(name);
+            }
+            ast::StmtKind::Delete { targets } => {
+                for target in targets {
+                    self.compile_delete(target)?;
+                }
+            }
+            ast::StmtKind::AsyncFor {
+                target,
+                iter,
+                body,
+                orelse,
+                ..
+            } => {
+                // The loop awaits `next()` of the iterator until it gives None.
+                self.compile_expr(iter)?;
+                let start = self.here();
+                self.emit(Instruction::Dup);
+                let next = self.name("next");
+                let cache = self.cache_site();
+                self.emit(Instruction::CallMethod {
+                    name: next,
+                    argc: 0,
+                    cache,
+                });
+                self.emit(Instruction::Await);
+                let to_else = self.emit(Instruction::JumpIfNone(0));
+                self.compile_store(target)?;
+                self.compile_loop_body(start, true, body, |compiler| {
+                    compiler.emit(Instruction::Jump(start as u32));
+                    compiler.patch(to_else);
+                    compiler.emit(Instruction::Pop);
+                    compiler.emit(Instruction::Pop);
+                    compiler.compile_body(orelse)
+                })?;
+            }
+            ast::StmtKind::With { items, body, .. } => self.compile_with(items, body, false)?,
+            ast::StmtKind::AsyncWith { items, body, .. } => self.compile_with(items, body, true)?,
+            ast::StmtKind::Match { subject, cases } => self.compile_match(subject, cases)?,
+            ast::StmtKind::Import { names } => {
+                for alias in names {
+                    let alias = &alias.node;
+                    // Nothing would be bound to the outer modules.
+                    if alias.name.contains('.') && alias.asname.is_none() {
+                        return Err(unsupported("'import' of a dotted name without 'as'", stmt.location));
+                    }
+                    let module = self.name(&alias.name);
+                    self.emit(Instruction::ImportModule(module));
+                    self.store_name(alias.asname.as_ref().unwrap_or(&alias.name));
+                }
+            }
+            ast::StmtKind::ImportFrom { module, names, level } => {
+                let module = match module {
+                    Some(module) => format!("{}{}", ".".repeat(level.unwrap_or(0)), module),
+                    None => return Err(unsupported("'from . import'", stmt.location)),
+                };
+                let module = self.name(&module);
+                self.emit(Instruction::ImportModule(module));
+                for alias in names {
+                    let alias = &alias.node;
+                    if alias.name == "*" {
+                        return Err(unsupported("'import *'", stmt.location));
+                    }
+                    let name = self.name(&alias.name);
+                    self.emit(Instruction::ImportName(name));
+                    self.store_name(alias.asname.as_ref().unwrap_or(&alias.name));
+                }
+                self.emit(Instruction::Pop);
+            }
+        }
+        self.set_location(outer);
+        Ok(())
+    }
+
+    /// Compile the body of a loop starting at `start`, then `end`, which
+    /// closes the loop. Breaks jump past `end`.
+    fn compile_loop_body(
+        &mut self,
+        start: usize,
+        is_for: bool,
+        body: &'a [ast::Stmt],
+        end: impl FnOnce(&mut Self) -> CompileResult,
+    ) -> CompileResult {
+        self.unit_mut().fblocks.push(Fblock::Loop {
+            start,
+            breaks: vec![],
+            is_for,
+        });
+        self.compile_body(body)?;
+        let breaks = match self.unit_mut().fblocks.pop() {
+            Some(Fblock::Loop { breaks, .. }) => breaks,
+            _ => unreachable!(),
+        };
+        end(self)?;
+        for at in breaks {
+            self.patch(at);
+        }
+        Ok(())
+    }
+
+    fn compile_break(&mut self, is_continue: bool) {
+        // The validator only lets `break` and `continue` appear in loops.
+        let index = self
+            .unit()
+            .fblocks
+            .iter()
+            .rposition(|fblock| matches!(fblock, Fblock::Loop { .. }))
+            .unwrap();
+        // Finally clauses in a loop can't fail to compile where the loop body didn't.
+        self.unwind_fblocks(index + 1, false).unwrap();
+        let (start, is_for) = match &self.unit().fblocks[index] {
+            Fblock::Loop { start, is_for, .. } => (*start, *is_for),
+            _ => unreachable!(),
+        };
+        if is_continue {
+            self.emit(Instruction::Jump(start as u32));
+            return;
+        }
+        if is_for {
+            self.emit(Instruction::Pop);
+        }
+        let at = self.emit(Instruction::Jump(0));
+        if let Fblock::Loop { breaks, .. } = &mut self.unit_mut().fblocks[index] {
+            breaks.push(at);
+        }
+    }
+
+    /// Leave every construct above the first `depth` ones, innermost first,
+    /// keeping the value on top of the stack if `returning` one.
+    fn unwind_fblocks(&mut self, depth: usize, returning: bool) -> CompileResult {
+        let mut index = self.unit().fblocks.len();
+        while index > depth {
+            index -= 1;
+            match &self.unit().fblocks[index] {
+                // The iterator of a `for` loop is left to `Return`, which
+                // clears the stack, unless a context manager is below it.
+                Fblock::Loop { is_for, .. } => {
+                    let fblocks = &self.unit().fblocks[depth..index];
+                    if *is_for && fblocks.iter().any(|fblock| matches!(fblock, Fblock::With { .. })) {
+                        self.emit(Instruction::RotTwo);
+                        self.emit(Instruction::Pop);
+                    }
+                }
+                &Fblock::With { is_async } => {
+                    if returning {
+                        self.emit(Instruction::RotTwo);
+                    }
+                    self.emit(Instruction::PopBlock);
+                    self.emit_constant(Constant::None);
+                    self.emit_exit(is_async);
+                    self.emit(Instruction::Pop);
+                }
+                Fblock::Try => {
+                    self.emit(Instruction::PopBlock);
+                }
+                Fblock::Handler => {
+                    self.emit(Instruction::PopExcept);
+                }
+                Fblock::Finally(body) => {
+                    let body = *body;
+                    // The clause runs outside of the constructs it protects.
+                    let inner = self.unit_mut().fblocks.split_off(index);
+                    self.emit(Instruction::PopBlock);
+                    self.compile_body(body)?;
+                    self.unit_mut().fblocks.extend(inner);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_try_except(
+        &mut self,
+        body: &'a [ast::Stmt],
+        handlers: &'a [ast::Excepthandler],
+        orelse: &'a [ast::Stmt],
+    ) -> CompileResult {
+        if handlers.is_empty() {
+            self.compile_body(body)?;
+            return self.compile_body(orelse);
+        }
+        let setup = self.emit(Instruction::SetupExcept(0));
+        self.unit_mut().fblocks.push(Fblock::Try);
+        self.compile_body(body)?;
+        self.unit_mut().fblocks.pop();
+        self.emit(Instruction::PopBlock);
+        self.compile_body(orelse)?;
+        let mut to_end = vec![self.emit(Instruction::Jump(0))];
+
+        // The exception is on top of the stack.
+        self.patch(setup);
+        self.unit_mut().fblocks.push(Fblock::Handler);
+        for handler in handlers {
+            let ast::ExcepthandlerKind::ExceptHandler { type_, name, body } = &handler.node;
+            let outer = self.set_location(handler.location);
+            let to_next = match type_ {
+                Some(type_) => {
+                    self.compile_expr(type_)?;
+                    self.emit(Instruction::ExceptionMatch);
+                    Some(self.emit(Instruction::JumpIfFalse(0)))
+                }
+                None => None,
+            };
+            match name {
+                Some(name) => self.store_name(name),
+                None => {
+                    self.emit(Instruction::Pop);
+                }
+            }
+            self.compile_body(body)?;
+            self.emit(Instruction::PopExcept);
+            to_end.push(self.emit(Instruction::Jump(0)));
+            if let Some(to_next) = to_next {
+                self.patch(to_next);
+            }
+            self.set_location(outer);
+        }
+        self.unit_mut().fblocks.pop();
+        self.emit(Instruction::Reraise);
+        for at in to_end {
+            self.patch(at);
+        }
+        Ok(())
+    }
+
+    fn compile_try_finally(
+        &mut self,
+        body: &'a [ast::Stmt],
+        handlers: &'a [ast::Excepthandler],
+        orelse: &'a [ast::Stmt],
+        finalbody: &'a [ast::Stmt],
+    ) -> CompileResult {
+        let setup = self.emit(Instruction::SetupExcept(0));
+        self.unit_mut().fblocks.push(Fblock::Finally(finalbody));
+        self.compile_try_except(body, handlers, orelse)?;
+        self.unit_mut().fblocks.pop();
+        self.emit(Instruction::PopBlock);
+        self.compile_body(finalbody)?;
+        let to_end = self.emit(Instruction::Jump(0));
+
+        // Run the clause for an exception too, and raise it again afterwards.
+        self.patch(setup);
+        self.emit(Instruction::Pop);
+        self.unit_mut().fblocks.push(Fblock::Handler);
+        self.compile_body(finalbody)?;
+        self.unit_mut().fblocks.pop();
+        self.emit(Instruction::Reraise);
+        self.patch(to_end);
+        Ok(())
+    }
+
+    /// Compile a `match` statement. The subject stays on the stack while the
+    /// pattern of each case is tried on a copy of it, and is popped before the
+    /// body of the case that matches.
+    fn compile_match(&mut self, subject: &'a ast::Expr, cases: &'a [ast::MatchCase]) -> CompileResult {
+        self.compile_expr(subject)?;
+        let mut to_end = vec![];
+        for case in cases {
+            let mut fails = vec![];
+            self.emit(Instruction::Dup);
+            self.compile_pattern(&case.pattern, 0, &mut fails)?;
+            if let Some(guard) = &case.guard {
+                self.compile_expr(guard)?;
+                fails.push((self.emit(Instruction::JumpIfFalse(0)), 0));
+            }
+            self.emit(Instruction::Pop);
+            self.compile_body(&case.body)?;
+            to_end.push(self.emit(Instruction::Jump(0)));
+            self.patch_fails(fails, 0);
+        }
+        self.emit(Instruction::Pop);
+        for at in to_end {
+            self.patch(at);
+        }
+        Ok(())
+    }
+
+    /// Compile `pattern`, which matches the value on top of the stack, above
+    /// `depth` other values, and pops it. Where it fails to match, it jumps
+    /// with the number of values it leaves above those it started with added
+    /// to `fails`, next to the jump.
+    fn compile_pattern(
+        &mut self,
+        pattern: &'a ast::Pattern,
+        depth: u32,
+        fails: &mut Vec<(usize, u32)>,
+    ) -> CompileResult {
+        let outer = self.set_location(pattern.location);
+        match &pattern.node {
+            ast::PatternKind::MatchValue { value } => {
+                self.compile_expr(value)?;
+                self.emit(Instruction::Compare(ComparisonOperator::Eq));
+                fails.push((self.emit(Instruction::JumpIfFalse(0)), depth));
+            }
+            ast::PatternKind::MatchSingleton { value } => {
+                self.compile_constant(value, pattern.location)?;
+                self.emit(Instruction::Compare(ComparisonOperator::Is));
+                fails.push((self.emit(Instruction::JumpIfFalse(0)), depth));
+            }
+            ast::PatternKind::MatchAs { pattern: None, name } | ast::PatternKind::MatchStar { name } => match name {
+                Some(name) => self.store_name(name),
+                None => {
+                    self.emit(Instruction::Pop);
+                }
+            },
+            ast::PatternKind::MatchAs {
+                pattern: Some(inner),
+                name,
+            } => match name {
+                Some(name) => {
+                    self.emit(Instruction::Dup);
+                    self.compile_pattern(inner, depth + 1, fails)?;
+                    self.store_name(name);
+                }
+                None => self.compile_pattern(inner, depth, fails)?,
+            },
+            ast::PatternKind::MatchOr { patterns } => {
+                let mut to_end = vec![];
+                let (last, alternatives) = patterns.split_last().unwrap();
+                // Every alternative but the last one tries a copy of the value.
+                for alternative in alternatives {
+                    let mut alternative_fails = vec![];
+                    self.emit(Instruction::Dup);
+                    self.compile_pattern(alternative, depth + 1, &mut alternative_fails)?;
+                    self.emit(Instruction::Pop);
+                    to_end.push(self.emit(Instruction::Jump(0)));
+                    self.patch_fails(alternative_fails, depth + 1);
+                }
+                self.compile_pattern(last, depth, fails)?;
+                for at in to_end {
+                    self.patch(at);
+                }
+            }
+            ast::PatternKind::MatchSequence { patterns } => {
+                let star = patterns
+                    .iter()
+                    .position(|pattern| matches!(pattern.node, ast::PatternKind::MatchStar { .. }));
+                let len = (patterns.len() - star.is_some() as usize) as u32;
+                self.emit(Instruction::MatchSequence {
+                    len,
+                    star: star.is_some(),
+                });
+                fails.push((self.emit(Instruction::JumpIfFalse(0)), depth + 1));
+                match star {
+                    Some(star) => self.emit(Instruction::UnpackStarred {
+                        before: star as u32,
+                        after: len - star as u32,
+                    }),
+                    None => self.emit(Instruction::UnpackSequence(len)),
+                };
+                self.compile_subpatterns(patterns, depth, fails)?;
+            }
+            ast::PatternKind::MatchMapping { keys, patterns, rest } => {
+                if rest.is_some() {
+                    return Err(unsupported("'**' in a mapping pattern", pattern.location));
+                }
+                for key in keys {
+                    self.compile_expr(key)?;
+                }
+                self.emit(Instruction::BuildTuple(keys.len() as u32));
+                self.emit(Instruction::MatchKeys);
+                self.unpack_match(patterns.len() as u32, depth, fails);
+                self.compile_subpatterns(patterns, depth, fails)?;
+            }
+            ast::PatternKind::MatchClass {
+                cls,
+                patterns,
+                kwd_attrs,
+                kwd_patterns,
+            } => {
+                self.compile_expr(cls)?;
+                for attr in kwd_attrs {
+                    self.emit_constant(Constant::Str(attr.clone()));
+                }
+                self.emit(Instruction::BuildTuple(kwd_attrs.len() as u32));
+                self.emit(Instruction::MatchClass(patterns.len() as u32));
+                self.unpack_match(patterns.len() as u32 + kwd_patterns.len() as u32, depth, fails);
+                self.compile_subpatterns(patterns.iter().chain(kwd_patterns), depth, fails)?;
+            }
+        }
+        self.set_location(outer);
+        Ok(())
+    }
+
+    /// Fail if the value on top of the stack, which `MatchKeys` or
+    /// `MatchClass` pushed above the value matched, is None, and replace both
+    /// with the `n` values in it otherwise.
+    fn unpack_match(&mut self, n: u32, depth: u32, fails: &mut Vec<(usize, u32)>) {
+        fails.push((self.emit(Instruction::JumpIfNone(0)), depth + 2));
+        self.emit(Instruction::RotTwo);
+        self.emit(Instruction::Pop);
+        self.emit(Instruction::UnpackSequence(n));
+    }
+
+    /// Compile the patterns that match the values unpacked on the stack, the
+    /// first one on top.
+    fn compile_subpatterns(
+        &mut self,
+        patterns: impl IntoIterator<Item = &'a ast::Pattern>,
+        depth: u32,
+        fails: &mut Vec<(usize, u32)>,
+    ) -> CompileResult {
+        let patterns: Vec<_> = patterns.into_iter().collect();
+        for (i, pattern) in patterns.iter().enumerate() {
+            let below = (patterns.len() - 1 - i) as u32;
+            self.compile_pattern(pattern, depth + below, fails)?;
+        }
+        Ok(())
+    }
+
+    /// Make the jumps of patterns that failed land here, and pop what each of
+    /// them left on the stack above `depth` values.
+    fn patch_fails(&mut self, fails: Vec<(usize, u32)>, depth: u32) {
+        let highest = fails.iter().map(|&(_, left)| left).max().unwrap_or(depth);
+        for left in (depth..=highest).rev() {
+            for &(at, _) in fails.iter().filter(|&&(_, jumped_with)| jumped_with == left) {
+                self.patch(at);
+            }
+            if left > depth {
+                self.emit(Instruction::Pop);
+            }
+        }
+    }
+
+    /// Compile a `with` statement, which calls `enter()` on each context
+    /// manager before the body, and `exit(exception)` after it, with None
+    /// unless the body raised. An exception is swallowed if `exit` returns
+    /// something truthy.
+    /// Compile a `with` statement, or an `async with` one, which awaits what
+    /// `enter` and `exit` return.
+    fn compile_with(&mut self, items: &'a [ast::Withitem], body: &'a [ast::Stmt], is_async: bool) -> CompileResult {
+        let (item, rest) = match items.split_first() {
+            Some(split) => split,
+            None => return self.compile_body(body),
+        };
+        self.compile_expr(&item.context_expr)?;
+        self.emit(Instruction::Dup);
+        let enter = self.name("enter");
+        let cache = self.cache_site();
+        self.emit(Instruction::CallMethod {
+            name: enter,
+            argc: 0,
+            cache,
+        });
+        if is_async {
+            self.emit(Instruction::Await);
+        }
+        match &item.optional_vars {
+            Some(target) => self.compile_store(target)?,
+            None => {
+                self.emit(Instruction::Pop);
+            }
+        }
+        let setup = self.emit(Instruction::SetupExcept(0));
+        self.unit_mut().fblocks.push(Fblock::With { is_async });
+        self.compile_with(rest, body, is_async)?;
+        self.unit_mut().fblocks.pop();
+        self.emit(Instruction::PopBlock);
+        self.emit_constant(Constant::None);
+        self.emit_exit(is_async);
+        self.emit(Instruction::Pop);
+        let to_end = self.emit(Instruction::Jump(0));
+
+        // The exception is on top of the stack, above the context manager.
+        self.patch(setup);
+        self.emit(Instruction::DupTwo);
+        self.emit_exit(is_async);
+        let to_swallow = self.emit(Instruction::JumpIfTrue(0));
+        self.emit(Instruction::Reraise);
+        self.patch(to_swallow);
+        self.emit(Instruction::Pop);
+        self.emit(Instruction::Pop);
+        self.emit(Instruction::PopExcept);
+        self.patch(to_end);
+        Ok(())
+    }
+
+    /// Call `exit` on the context manager below its argument, and await what
+    /// it returns if `is_async`.
+    fn emit_exit(&mut self, is_async: bool) {
+        let exit = self.name("exit");
+        let cache = self.cache_site();
+        self.emit(Instruction::CallMethod {
+            name: exit,
+            argc: 1,
+            cache,
+        });
+        if is_async {
+            self.emit(Instruction::Await);
+        }
+    }
+
+    fn compile_class(
+        &mut self,
+        stmt: &'a ast::Stmt,
+        name: &str,
+        bases: &'a [ast::Expr],
+        body: &'a [ast::Stmt],
+        decorator_list: &'a [ast::Expr],
+        module: bool,
+    ) -> CompileResult {
+        for decorator in decorator_list {
+            self.compile_expr(decorator)?;
+        }
+        self.push_unit(name, self.table.stmt_scope(stmt), stmt.location, false);
+        self.compile_body(body)?;
+        self.emit_return_none();
+        let code = self.pop_unit();
+        self.make_function(code, 0);
+        for base in bases {
+            self.compile_expr(base)?;
+        }
+        let name_index = self.name(name);
+        self.emit(Instruction::BuildClass {
+            name: name_index,
+            bases: bases.len() as u32,
+            module,
+        });
+        for _ in decorator_list {
+            self.emit(Instruction::Call(1));
+        }
+        self.store_name(name);
+        Ok(())
+    }
+
+    fn compile_defaults(&mut self, args: &'a ast::Arguments, location: Location) -> CompileResult {
+        if let Some(arg) = args.kwonlyargs.first() {
+            return Err(unsupported("keyword-only parameter", arg.location));
+        }
+        if args.kwarg.is_some() {
+            return Err(unsupported("'**' parameter", location));
+        }
+        for default in &args.defaults {
+            self.compile_expr(default)?;
+        }
+        Ok(())
+    }
+
+    /// Push a function of `code`, closing over the cells it needs and taking
+    /// the `defaults` values on the stack.
+    fn make_function(&mut self, code: CodeObject, defaults: usize) {
+        for free in &code.frees {
+            let cell = self.cell(free);
+            self.emit(Instruction::LoadClosure(cell));
+        }
+        let code = self.constant(Constant::Code(Rc::new(code)));
+        self.emit(Instruction::MakeFunction {
+            code,
+            defaults: defaults as u32,
+        });
+    }
+
+    /// Compile a lambda or `do` block, which evaluates to its last expression.
+    fn compile_block(
+        &mut self,
+        expr: &'a ast::Expr,
+        args: &'a ast::Arguments,
+        body: BlockBody<'a>,
+        is_async: bool,
+    ) -> CompileResult {
+        self.compile_defaults(args, expr.location)?;
+        let name = match body {
+            BlockBody::Expr(_) => "<lambda>",
+            BlockBody::Stmts(_) => "<do>",
+        };
+        self.push_unit(name, self.table.expr_scope(expr), expr.location, args.vararg.is_some());
+        self.unit_mut().code.is_async = is_async;
+        // The block doesn't continue a chain of the code around it.
+        let chains = std::mem::take(&mut self.chains);
+        match body {
+            BlockBody::Expr(body) => {
+                self.compile_expr(body)?;
+                self.emit(Instruction::Return);
+            }
+            BlockBody::Stmts(body) => match body.split_last() {
+                Some((
+                    ast::Stmt {
+                        node: ast::StmtKind::Expr { value },
+                        location,
+                        ..
+                    },
+                    init,
+                )) => {
+                    self.compile_body(init)?;
+                    let outer = self.set_location(*location);
+                    self.compile_expr(value)?;
+                    self.emit(Instruction::Return);
+                    self.set_location(outer);
+                }
+                _ => {
+                    self.compile_body(body)?;
+                    self.emit_return_none();
+                }
+            },
+        }
+        self.chains = chains;
+        let code = self.pop_unit();
+        self.make_function(code, args.defaults.len());
+        Ok(())
+    }
+
+    fn compile_aug_assign(&mut self, target: &'a ast::Expr, op: &ast::Operator, value: &'a ast::Expr) -> CompileResult {
+        let op = Instruction::BinaryOp(op.into());
+        match &target.node {
+            ast::ExprKind::Name { id, .. } => {
+                self.load_name(id);
+                self.compile_expr(value)?;
+                self.emit(op);
+                self.store_name(id);
+            }
+            ast::ExprKind::Attribute { value: object, attr, .. } => {
+                let attr = self.name(attr);
+                self.compile_expr(object)?;
+                self.emit(Instruction::Dup);
+                let cache = self.cache_site();
+                self.emit(Instruction::LoadAttr { name: attr, cache });
+                self.compile_expr(value)?;
+                self.emit(op);
+                self.emit(Instruction::RotTwo);
+                let cache = self.cache_site();
+                self.emit(Instruction::StoreAttr { name: attr, cache });
+            }
+            ast::ExprKind::Subscript { value: object, slice, .. } => {
+                self.compile_expr(object)?;
+                self.compile_expr(slice)?;
+                self.emit(Instruction::DupTwo);
+                self.emit(Instruction::LoadSubscript);
+                self.compile_expr(value)?;
+                self.emit(op);
+                self.emit(Instruction::RotThree);
+                self.emit(Instruction::StoreSubscript);
+            }
+            // The validator rejects other targets.
+            _ => return Err(unsupported(target.node.name(), target.location)),
+        }
+        Ok(())
+    }
+
+    /// Delete `target`, the way `del` does.
+    fn compile_delete(&mut self, target: &'a ast::Expr) -> CompileResult {
+        match &target.node {
+            ast::ExprKind::Name { id, .. } => self.delete_name(id),
+            ast::ExprKind::Attribute { value, attr, .. } => {
+                self.compile_expr(value)?;
+                let name = self.name(attr);
+                self.emit(Instruction::DeleteAttr(name));
+            }
+            ast::ExprKind::Subscript { value, slice, .. } => {
+                self.compile_expr(value)?;
+                self.compile_expr(slice)?;
+                self.emit(Instruction::DeleteSubscript);
+            }
+            ast::ExprKind::Tuple { elts, .. } | ast::ExprKind::List { elts, .. } => {
+                for elt in elts {
+                    self.compile_delete(elt)?;
+                }
+            }
+            _ => return Err(unsupported(target.node.name(), target.location)),
+        }
+        Ok(())
+    }
+
+    /// Store the value on top of the stack in `target`.
+    fn compile_store(&mut self, target: &'a ast::Expr) -> CompileResult {
+        let outer = self.set_location(target.location);
+        match &target.node {
+            ast::ExprKind::Name { id, .. } => self.store_name(id),
+            ast::ExprKind::Attribute { value, attr, .. } => {
+                self.compile_expr(value)?;
+                let name = self.name(attr);
+                let cache = self.cache_site();
+                self.emit(Instruction::StoreAttr { name, cache });
+            }
+            ast::ExprKind::Subscript { value, slice, .. } => {
+                self.compile_expr(value)?;
+                self.compile_expr(slice)?;
+                self.emit(Instruction::StoreSubscript);
+            }
+            ast::ExprKind::Tuple { elts, .. } | ast::ExprKind::List { elts, .. } => {
+                let len = elts.len() as u32;
+                match elts.iter().position(|elt| matches!(elt.node, ast::ExprKind::Starred { .. })) {
+                    Some(star) => self.emit(Instruction::UnpackStarred {
+                        before: star as u32,
+                        after: len - star as u32 - 1,
+                    }),
+                    None => self.emit(Instruction::UnpackSequence(len)),
+                };
+                for elt in elts {
+                    match &elt.node {
+                        ast::ExprKind::Starred { value, .. } => self.compile_store(value)?,
+                        _ => self.compile_store(elt)?,
+                    }
+                }
+            }
+            _ => return Err(unsupported(target.node.name(), target.location)),
+        }
+        self.set_location(outer);
+        Ok(())
+    }
+
+    fn compile_expr(&mut self, expr: &'a ast::Expr) -> CompileResult {
+        let outer = self.set_location(expr.location);
+        match &expr.node {
+            ast::ExprKind::BoolOp { op, values } => {
+                let jump = match op {
+                    ast::Boolop::And => Instruction::JumpIfFalseOrPop(0),
+                    ast::Boolop::Or => Instruction::JumpIfTrueOrPop(0),
+                    ast::Boolop::Coalesce => Instruction::JumpIfNotNoneOrPop(0),
+                };
+                let mut to_end = vec![];
+                for (i, value) in values.iter().enumerate() {
+                    self.compile_expr(value)?;
+                    if i + 1 < values.len() {
+                        to_end.push(self.emit(jump));
+                    }
+                }
+                for at in to_end {
+                    self.patch(at);
+                }
+            }
+            ast::ExprKind::NamedExpr { target, value } => {
+                self.compile_expr(value)?;
+                self.emit(Instruction::Dup);
+                self.compile_store(target)?;
+            }
+            ast::ExprKind::BinOp { left, op, right } => {
+                self.compile_expr(left)?;
+                self.compile_expr(right)?;
+                self.emit(Instruction::BinaryOp(op.into()));
+            }
+            ast::ExprKind::UnaryOp { op, operand } => {
+                self.compile_expr(operand)?;
+                self.emit(Instruction::UnaryOp(op.into()));
+            }
+            ast::ExprKind::Lambda { args, body } => self.compile_block(expr, args, BlockBody::Expr(body), false)?,
+            ast::ExprKind::DoBlock {
+                args, body, is_async, ..
+            } => self.compile_block(expr, args, BlockBody::Stmts(body), *is_async > 0)?,
+            ast::ExprKind::Await { value } => {
+                if self.unit().code.kind == CodeKind::Comprehension {
+                    return Err(unsupported("'await' in a comprehension", expr.location));
+                }
+                self.compile_expr(value)?;
+                self.emit(Instruction::Await);
+            }
+            ast::ExprKind::Yield { value } => {
+                if self.unit().code.is_async {
+                    return Err(unsupported("'yield' in async code", expr.location));
+                }
+                if self.unit().code.kind == CodeKind::Comprehension {
+                    return Err(CompileError {
+                        error: CompileErrorType::YieldInComprehension,
+                        location: expr.location,
+                    });
+                }
+                match value {
+                    Some(value) => self.compile_expr(value)?,
+                    None => self.emit_constant(Constant::None),
+                }
+                self.unit_mut().code.is_generator = true;
+                self.emit(Instruction::Yield);
+            }
+            ast::ExprKind::EndOfBlockMarker => {
+                // The start of a chain is the result of the call before it,
+                // which is already on the stack. Anywhere else the validator
+                // reports the marker.
+                if self.chains == 0 {
+                    return Err(unsupported(expr.node.name(), expr.location));
+                }
+            }
+            ast::ExprKind::IfExp { test, body, orelse } => {
+                self.compile_expr(test)?;
+                let to_else = self.emit(Instruction::JumpIfFalse(0));
+                self.compile_expr(body)?;
+                let to_end = self.emit(Instruction::Jump(0));
+                self.patch(to_else);
+                self.compile_expr(orelse)?;
+                self.patch(to_end);
+            }
+            ast::ExprKind::Dict { keys, values } => {
+                for (key, value) in keys.iter().zip(values) {
+                    self.compile_expr(key)?;
+                    self.compile_expr(value)?;
+                }
+                self.emit(Instruction::BuildDict(values.len() as u32));
+            }
+            ast::ExprKind::Compare {
+                left,
+                ops,
+                comparators,
+            } => {
+                self.compile_expr(left)?;
+                let mut to_cleanup = vec![];
+                for (i, (op, comparator)) in ops.iter().zip(comparators).enumerate() {
+                    self.compile_expr(comparator)?;
+                    if i + 1 < ops.len() {
+                        // Keep the comparator for the next comparison.
+                        self.emit(Instruction::Dup);
+                        self.emit(Instruction::RotThree);
+                        self.emit(Instruction::Compare(op.into()));
+                        to_cleanup.push(self.emit(Instruction::JumpIfFalseOrPop(0)));
+                    } else {
+                        self.emit(Instruction::Compare(op.into()));
+                    }
+                }
+                if !to_cleanup.is_empty() {
+                    let to_end = self.emit(Instruction::Jump(0));
+                    for at in to_cleanup {
+                        self.patch(at);
+                    }
+                    self.emit(Instruction::RotTwo);
+                    self.emit(Instruction::Pop);
+                    self.patch(to_end);
+                }
+            }
+            ast::ExprKind::Call { .. }
+            | ast::ExprKind::Attribute { .. }
+            | ast::ExprKind::Subscript { .. }
+            | ast::ExprKind::OptionalAttribute { .. }
+            | ast::ExprKind::OptionalSubscript { .. } => {
+                let mut exits = vec![];
+                self.compile_link(expr, &mut exits)?;
+                for exit in exits {
+                    self.patch(exit);
+                }
+            }
+            ast::ExprKind::Pipe { value, func } => match &func.node {
+                ast::ExprKind::Call { func, args, keywords } => {
+                    let mut exits = vec![];
+                    self.compile_call(func, Some(value), args, keywords, &mut exits)?;
+                    for exit in exits {
+                        self.patch(exit);
+                    }
+                }
+                _ => {
+                    self.compile_expr(func)?;
+                    self.compile_expr(value)?;
+                    self.emit(Instruction::Call(1));
+                }
+            },
+            ast::ExprKind::FormattedValue {
+                value,
+                conversion,
+                format_spec,
+            } => {
+                self.compile_expr(value)?;
+                let conversion = ConversionFlag::try_from(*conversion).unwrap_or(ConversionFlag::None);
+                match format_spec {
+                    Some(format_spec) => {
+                        self.compile_expr(format_spec)?;
+                        self.emit(Instruction::FormatWithSpec(conversion));
+                    }
+                    None => {
+                        self.emit(Instruction::FormatValue(conversion));
+                    }
+                }
+            }
+            ast::ExprKind::JoinedStr { values } => {
+                for value in values {
+                    self.compile_expr(value)?;
+                }
+                self.emit(Instruction::BuildString(values.len() as u32));
+            }
+            ast::ExprKind::Constant { value, .. } => self.compile_constant(value, expr.location)?,
+            ast::ExprKind::Range { lower, upper, inclusive } => {
+                self.compile_expr(lower)?;
+                self.compile_expr(upper)?;
+                self.emit(Instruction::BuildRange {
+                    inclusive: *inclusive != 0,
+                });
+            }
+            ast::ExprKind::Name { id, .. } => self.load_name(id),
+            ast::ExprKind::List { elts, .. } => {
+                self.compile_elements(elts)?;
+                self.emit(Instruction::BuildList(elts.len() as u32));
+            }
+            ast::ExprKind::Tuple { elts, .. } => {
+                self.compile_elements(elts)?;
+                self.emit(Instruction::BuildTuple(elts.len() as u32));
+            }
+            ast::ExprKind::Slice { lower, upper, step } => {
+                for part in [lower, upper, step] {
+                    match part {
+                        Some(part) => self.compile_expr(part)?,
+                        None => self.emit_constant(Constant::None),
+                    }
+                }
+                self.emit(Instruction::BuildSlice);
+            }
+            ast::ExprKind::Set { elts } => {
+                self.compile_elements(elts)?;
+                self.emit(Instruction::BuildSet(elts.len() as u32));
+            }
+            ast::ExprKind::ListComp { elt, generators } => {
+                self.compile_comprehension(expr, generators, Element::List(elt))?
+            }
+            ast::ExprKind::SetComp { elt, generators } => {
+                self.compile_comprehension(expr, generators, Element::Set(elt))?
+            }
+            ast::ExprKind::DictComp { key, value, generators } => {
+                self.compile_comprehension(expr, generators, Element::Dict(key, value))?
+            }
+            ast::ExprKind::GeneratorExp { elt, generators } => {
+                self.compile_comprehension(expr, generators, Element::Generator(elt))?
+            }
+            ast::ExprKind::YieldFrom { .. } => return Err(unsupported("'yield from'", expr.location)),
+            ast::ExprKind::Starred { .. } => return Err(unsupported(expr.node.name(), expr.location)),
+        }
+        self.set_location(outer);
+        Ok(())
+    }
+
+    /// Compile a comprehension or generator expression to a function, which
+    /// is called right away with an iterator over its first iterable.
+    fn compile_comprehension(
+        &mut self,
+        expr: &'a ast::Expr,
+        generators: &'a [ast::Comprehension],
+        element: Element<'a>,
+    ) -> CompileResult {
+        if generators.iter().any(|generator| generator.is_async > 0) {
+            return Err(unsupported("asynchronous comprehension", expr.location));
+        }
+        let name = match element {
+            Element::List(_) => "<listcomp>",
+            Element::Set(_) => "<setcomp>",
+            Element::Dict(..) => "<dictcomp>",
+            Element::Generator(_) => "<genexpr>",
+        };
+        self.push_unit(name, self.table.expr_scope(expr), expr.location, false);
+        let chains = std::mem::take(&mut self.chains);
+        match element {
+            Element::List(_) => {
+                self.emit(Instruction::BuildList(0));
+            }
+            Element::Set(_) => {
+                self.emit(Instruction::BuildSet(0));
+            }
+            Element::Dict(..) => {
+                self.emit(Instruction::BuildDict(0));
+            }
+            Element::Generator(_) => self.unit_mut().code.is_generator = true,
+        }
+        // Every loop keeps its iterator on the stack, above what is built.
+        let mut loops = vec![];
+        for (i, generator) in generators.iter().enumerate() {
+            if i == 0 {
+                self.load_name(".0");
+            } else {
+                self.compile_expr(&generator.iter)?;
+                self.emit(Instruction::GetIter);
+            }
+            let start = self.here();
+            loops.push((start, self.emit(Instruction::ForIter(0))));
+            self.compile_store(&generator.target)?;
+            for condition in &generator.ifs {
+                self.compile_expr(condition)?;
+                self.emit(Instruction::JumpIfFalse(start as u32));
+            }
+        }
+        let depth = generators.len() as u32 + 1;
+        match element {
+            Element::List(elt) => {
+                self.compile_expr(elt)?;
+                self.emit(Instruction::ListAppend(depth));
+            }
+            Element::Set(elt) => {
+                self.compile_expr(elt)?;
+                self.emit(Instruction::SetAdd(depth));
+            }
+            Element::Dict(key, value) => {
+                self.compile_expr(key)?;
+                self.compile_expr(value)?;
+                self.emit(Instruction::MapAdd(depth));
+            }
+            Element::Generator(elt) => {
+                self.compile_expr(elt)?;
+                self.emit(Instruction::Yield);
+                self.emit(Instruction::Pop);
+            }
+        }
+        for (start, exit) in loops.into_iter().rev() {
+            self.emit(Instruction::Jump(start as u32));
+            self.patch(exit);
+        }
+        if self.unit().code.is_generator {
+            self.emit_return_none();
+        } else {
+            self.emit(Instruction::Return);
+        }
+        self.chains = chains;
+        let code = self.pop_unit();
+        self.make_function(code, 0);
+        self.compile_expr(&generators[0].iter)?;
+        self.emit(Instruction::GetIter);
+        self.emit(Instruction::Call(1));
+        Ok(())
+    }
+
+    fn compile_elements(&mut self, elts: &'a [ast::Expr]) -> CompileResult {
+        for elt in elts {
+            if let ast::ExprKind::Starred { .. } = elt.node {
+                return Err(unsupported(elt.node.name(), elt.location));
+            }
+            self.compile_expr(elt)?;
+        }
+        Ok(())
+    }
+
+    fn compile_constant(&mut self, value: &ast::Constant, location: Location) -> CompileResult {
+        let constant = match value {
+            ast::Constant::None => Constant::None,
+            ast::Constant::Bool(b) => Constant::Bool(*b),
+            ast::Constant::Str(s) => Constant::Str(s.clone()),
+            ast::Constant::Int(i) => Constant::Int(i.clone()),
+            ast::Constant::Float(x) => Constant::Float(*x),
+            ast::Constant::Tuple(elts) => {
+                for elt in elts {
+                    self.compile_constant(elt, location)?;
+                }
+                self.emit(Instruction::BuildTuple(elts.len() as u32));
+                return Ok(());
+            }
+            ast::Constant::Bytes(_) => return Err(unsupported("bytes literal", location)),
+            ast::Constant::Complex { .. } => return Err(unsupported("complex literal", location)),
+            ast::Constant::Ellipsis => return Err(unsupported("ellipsis", location)),
+            ast::Constant::Regex { .. } => return Err(unsupported("regular expression literal", location)),
+        };
+        self.emit_constant(constant);
+        Ok(())
+    }
+
+    /// Compile `expr` as a link of a chain of attributes, subscripts and calls.
+    /// Where the object of a `?.` or `?[` is None, the rest of the chain is
+    /// skipped by a jump that is added to `exits`, and the chain evaluates to
+    /// that None.
+    fn compile_link(&mut self, expr: &'a ast::Expr, exits: &mut Vec<usize>) -> CompileResult {
+        let outer = self.set_location(expr.location);
+        match &expr.node {
+            ast::ExprKind::Call { func, args, keywords } => self.compile_call(func, None, args, keywords, exits)?,
+            ast::ExprKind::Attribute { value, attr, .. } | ast::ExprKind::OptionalAttribute { value, attr } => {
+                self.compile_link(value, exits)?;
+                if let ast::ExprKind::OptionalAttribute { .. } = expr.node {
+                    exits.push(self.emit(Instruction::JumpIfNone(0)));
+                }
+                let name = self.name(attr);
+                let cache = self.cache_site();
+                self.emit(Instruction::LoadAttr { name, cache });
+            }
+            ast::ExprKind::Subscript { value, slice, .. } | ast::ExprKind::OptionalSubscript { value, slice } => {
+                self.compile_link(value, exits)?;
+                if let ast::ExprKind::OptionalSubscript { .. } = expr.node {
+                    exits.push(self.emit(Instruction::JumpIfNone(0)));
+                }
+                self.compile_expr(slice)?;
+                self.emit(Instruction::LoadSubscript);
+            }
+            _ => self.compile_expr(expr)?,
+        }
+        self.set_location(outer);
+        Ok(())
+    }
+
+    /// Compile a call of `func` with `first` and `args` as its arguments,
+    /// then `keywords`, and the chain of a `do` block passed last. `exits`
+    /// are the jumps out of the chain the call is a link of, see
+    /// `compile_link`.
+    fn compile_call(
+        &mut self,
+        func: &'a ast::Expr,
+        first: Option<&'a ast::Expr>,
+        args: &'a [ast::Expr],
+        keywords: &'a [ast::Keyword],
+        exits: &mut Vec<usize>,
+    ) -> CompileResult {
+        if let Some(keyword) = keywords.iter().find(|keyword| keyword.node.arg.is_none()) {
+            return Err(unsupported("'**' argument", keyword.location));
+        }
+        let argc = (args.len() + first.is_some() as usize) as u32;
+        let compile_args = |compiler: &mut Self| -> CompileResult {
+            if let Some(first) = first {
+                compiler.compile_expr(first)?;
+            }
+            compiler.compile_elements(args)?;
+            for keyword in keywords {
+                compiler.compile_expr(&keyword.node.value)?;
+            }
+            Ok(())
+        };
+        match &func.node {
+            ast::ExprKind::Attribute { value, attr, .. } if is_super_call(value) => {
+                if !matches!(self.unit().scope.symbol("self"), Symbol::Local | Symbol::Cell | Symbol::Free) {
+                    return Err(CompileError {
+                        error: CompileErrorType::InvalidSuper,
+                        location: value.location,
+                    });
+                }
+                self.load_name("self");
+                if keywords.is_empty() {
+                    compile_args(self)?;
+                    let name = self.name(attr);
+                    self.emit(Instruction::CallSuper { name, argc });
+                } else {
+                    let name = self.name(attr);
+                    self.emit(Instruction::LoadSuper(name));
+                    compile_args(self)?;
+                    self.emit_call(argc, keywords);
+                }
+            }
+            ast::ExprKind::Attribute { value, attr, .. } | ast::ExprKind::OptionalAttribute { value, attr } => {
+                self.compile_link(value, exits)?;
+                if let ast::ExprKind::OptionalAttribute { .. } = func.node {
+                    exits.push(self.emit(Instruction::JumpIfNone(0)));
+                }
+                if keywords.is_empty() {
+                    compile_args(self)?;
+                    let name = self.name(attr);
+                    let cache = self.cache_site();
+                    self.emit(Instruction::CallMethod { name, argc, cache });
+                } else {
+                    // Keyword arguments are bound to the parameters of the
+                    // method itself, so it is looked up first.
+                    let name = self.name(attr);
+                    let cache = self.cache_site();
+                    self.emit(Instruction::LoadAttr { name, cache });
+                    compile_args(self)?;
+                    self.emit_call(argc, keywords);
+                }
+            }
+            _ if is_super_call(func) => {
+                return Err(CompileError {
+                    error: CompileErrorType::InvalidSuper,
+                    location: func.location,
+                })
+            }
+            _ => {
+                self.compile_link(func, exits)?;
+                compile_args(self)?;
+                self.emit_call(argc, keywords);
+            }
+        }
+        if let Some(ast::ExprKind::DoBlock { chain: Some(chain), .. }) = args.last().map(|arg| &arg.node) {
+            self.chains += 1;
+            self.compile_expr(chain)?;
+            self.chains -= 1;
+        }
+        Ok(())
+    }
+
+    /// Call the callable below `argc` positional arguments and the values
+    /// of `keywords`.
+    fn emit_call(&mut self, argc: u32, keywords: &[ast::Keyword]) {
+        if keywords.is_empty() {
+            self.emit(Instruction::Call(argc));
+            return;
+        }
+        for keyword in keywords {
+            // `**` arguments are rejected before their values are compiled.
+            self.emit_constant(Constant::Str(keyword.node.arg.clone().unwrap()));
+        }
+        self.emit(Instruction::BuildTuple(keywords.len() as u32));
+        self.emit(Instruction::CallKw(argc + keywords.len() as u32));
+    }
+}
+
+/// What a comprehension makes of each item it goes through.
+#[derive(Clone, Copy)]
+enum Element<'a> {
+    List(&'a ast::Expr),
+    Set(&'a ast::Expr),
+    Dict(&'a ast::Expr, &'a ast::Expr),
+    /// The item a generator expression yields.
+    Generator(&'a ast::Expr),
+}
+
+enum BlockBody<'a> {
+    Expr(&'a ast::Expr),
+    Stmts(&'a [ast::Stmt]),
+}
+
+/// Whether `expr` is `super()`.
+fn is_super_call(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ast::ExprKind::Call { func, args, keywords } => {
+            args.is_empty()
+                && keywords.is_empty()
+                && matches!(&func.node, ast::ExprKind::Name { id, .. } if id == "super")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emerald_lang_parser::parser::parse_program;
+
+    fn compile(source: &str) -> CompileResult<Rc<CodeObject>> {
+        let body = parse_program(source, "<test>").unwrap();
+        compile_program(&body, "<test>")
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let source = "\
+def scale(xs, factor=2):
+    total = 0
+    doubled = xs.map() do x:
+        total += x
+        x * factor
+        |.select() do x:
+            x > 2
+    for x in doubled:
+        if x == 4:
+            continue
+        total += x
+    return total
+";
+        insta::assert_snapshot!(compile(source).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_disassemble_class() {
+        let source = "\
+class Greeter extends Base:
+    def greet(self, name):
+        try:
+            return super().greet(name)
+        except AttributeError as e:
+            return f'hello {name!r}'
+";
+        insta::assert_snapshot!(compile(source).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_disassemble_imports() {
+        let source = "\
+import geometry
+import shapes.circle as circle
+from ..shapes import square as sq, triangle
+";
+        insta::assert_snapshot!(compile(source).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_disassemble_async() {
+        let source = "\
+async def fetch(urls):
+    pages = urls.map() async do url:
+        await get(url)
+    return await pages[0]
+";
+        insta::assert_snapshot!(compile(source).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_disassemble_comprehensions() {
+        let source = "\
+def table(rows):
+    return {row: [cell for cell in row if cell] for row in rows}
+squares = (n * n for n in range(10))
+";
+        insta::assert_snapshot!(compile(source).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_disassemble_match() {
+        let source = "\
+enum Shape:
+    Circle(float)
+    Empty
+match shape:
+    case Shape.Circle(radius) if radius > 0:
+        pass
+    case [first, *rest] | {'first': first}:
+        pass
+";
+        insta::assert_snapshot!(compile(source).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let error = compile("def f():\n    yield from g()\n").unwrap_err();
+        assert_eq!(error.to_string(), "'yield from' is not supported yet at line 2 column 4");
+        let error = compile("def f(z):\n    return [(yield y) for y in z]\n").unwrap_err();
+        assert_eq!(error.to_string(), "'yield' inside a comprehension at line 2 column 13");
+        let error = compile("import shapes.circle\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "'import' of a dotted name without 'as' is not supported yet at line 1 column 0"
+        );
+    }
+}
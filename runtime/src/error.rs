@@ -0,0 +1,204 @@
+//! Errors from compiling and from running a program.
+
+use crate::ast::Location;
+use emerald_lang_parser::error::ParseError;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, PartialEq)]
+pub struct CompileError {
+    pub error: CompileErrorType,
+    pub location: Location,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.location.fmt_with(f, &self.error)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Debug, PartialEq)]
+pub enum CompileErrorType {
+    /// Syntax the compiler doesn't handle yet, named like `ExprKind::name`.
+    Unsupported(&'static str),
+    /// A `nonlocal` declaration of a name no enclosing function binds.
+    UnboundNonlocal(String),
+    /// `super()` outside of a method, or used other than to call a method.
+    InvalidSuper,
+    /// `yield` in a comprehension, which would make it a generator.
+    YieldInComprehension,
+}
+
+impl fmt::Display for CompileErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileErrorType::Unsupported(name) => write!(f, "{} is not supported yet", name),
+            CompileErrorType::UnboundNonlocal(name) => write!(f, "no binding for nonlocal '{}' found", name),
+            CompileErrorType::InvalidSuper => write!(f, "super() can only be used to call a method, in a method"),
+            CompileErrorType::YieldInComprehension => write!(f, "'yield' inside a comprehension"),
+        }
+    }
+}
+
+/// A frame that an exception passed through.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// The name of the code object that was running.
+    pub function: String,
+    pub source_path: String,
+    pub location: Location,
+}
+
+/// An exception that no handler caught.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    /// The name of the class of the exception.
+    pub class: String,
+    pub message: String,
+    /// The frames the exception passed through, innermost first.
+    pub traceback: Vec<TraceEntry>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Traceback (most recent call last):")?;
+        for entry in self.traceback.iter().rev() {
+            writeln!(
+                f,
+                "  File \"{}\", line {} column {}, in {}",
+                entry.source_path,
+                entry.location.row(),
+                entry.location.column(),
+                entry.function
+            )?;
+        }
+        if self.message.is_empty() {
+            write!(f, "{}", self.class)
+        } else {
+            write!(f, "{}: {}", self.class, self.message)
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Why a specialisation couldn't be compiled to native code. It stays
+/// interpreted.
+#[derive(Debug, PartialEq)]
+pub struct JitError(pub String);
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can't compile to native code: {}", self.0)
+    }
+}
+
+impl std::error::Error for JitError {}
+
+impl From<cranelift_codegen::settings::SetError> for JitError {
+    fn from(error: cranelift_codegen::settings::SetError) -> Self {
+        JitError(error.to_string())
+    }
+}
+
+impl From<cranelift_codegen::CodegenError> for JitError {
+    fn from(error: cranelift_codegen::CodegenError) -> Self {
+        JitError(error.to_string())
+    }
+}
+
+impl From<cranelift_module::ModuleError> for JitError {
+    fn from(error: cranelift_module::ModuleError) -> Self {
+        JitError(error.to_string())
+    }
+}
+
+/// Why a program couldn't be built into an executable.
+#[derive(Debug)]
+pub enum BuildError {
+    Codegen(JitError),
+    /// The runtime library to link against isn't at this path.
+    MissingRuntime(PathBuf),
+    Io(std::io::Error),
+    /// The linker failed, and said this.
+    Link(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Codegen(error) => write!(f, "{}", error),
+            BuildError::MissingRuntime(path) => write!(f, "can't find the runtime library at {}", path.display()),
+            BuildError::Io(error) => write!(f, "can't build: {}", error),
+            BuildError::Link(output) => write!(f, "linking failed:\n{}", output),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<JitError> for BuildError {
+    fn from(error: JitError) -> Self {
+        BuildError::Codegen(error)
+    }
+}
+
+impl From<cranelift_module::ModuleError> for BuildError {
+    fn from(error: cranelift_module::ModuleError) -> Self {
+        BuildError::Codegen(error.into())
+    }
+}
+
+impl From<std::io::Error> for BuildError {
+    fn from(error: std::io::Error) -> Self {
+        BuildError::Io(error)
+    }
+}
+
+/// Anything that can go wrong running a program from its source.
+#[derive(Debug)]
+pub enum Error {
+    Parse(ParseError),
+    Compile(CompileError),
+    Runtime(RuntimeError),
+    Build(BuildError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(error) => write!(f, "{}", error),
+            Error::Compile(error) => write!(f, "{}", error),
+            Error::Runtime(error) => write!(f, "{}", error),
+            Error::Build(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Error::Parse(error)
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(error: CompileError) -> Self {
+        Error::Compile(error)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(error: RuntimeError) -> Self {
+        Error::Runtime(error)
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(error: BuildError) -> Self {
+        Error::Build(error)
+    }
+}
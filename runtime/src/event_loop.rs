@@ -0,0 +1,1112 @@
+//! The event loop, which runs coroutines and does I/O like JavaScript.
+//!
+//! Everything runs on one thread. Calling an `async def` or `async do` gives
+//! a promise of its result, and `await` suspends the coroutine it is in
+//! until the promise it is given settles. What reacts to a promise settling,
+//! like resuming a coroutine that awaits it, is queued as a task, and the
+//! tasks run in order before anything else happens: before timers fire,
+//! files are read or written, or the loop waits for sockets.
+//!
+//! Sockets are non-blocking and wait in epoll until they are ready for what
+//! they wait to do. Regular files are always ready as far as epoll is
+//! concerned, and looking up a host name has no descriptor to wait for at
+//! all, so those run on a pool of worker threads instead. A worker that is
+//! done signals an eventfd that is registered with epoll too, and the loop
+//! settles the promise of what it did on its own thread.
+//!
+//! The event loop of a program runs once its module code has finished,
+//! until no tasks, timers or I/O are left. A promise that is rejected with
+//! nothing reacting to it by the time the tasks have run stops the loop with
+//! its exception.
+
+use crate::builtins::{arity, method_arity, string};
+use crate::gc::Marker;
+use crate::heap::{ObjRef, Object};
+use crate::value::{Unboxed, Value};
+use crate::vm::{Coroutine, ErrorKind, Raised, Vm, VmResult};
+use rustc_hash::FxHashMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The most a `read` of a connection reads at once.
+const READ_SIZE: usize = 64 * 1024;
+/// The most sockets one wait reports.
+const MAX_EVENTS: usize = 64;
+/// The worker threads that do blocking work for the loop.
+const WORKERS: usize = 4;
+
+pub type CoroutineId = u64;
+
+/// What a promise settled to: a value, or an exception.
+pub type Outcome = Result<Value, Raised>;
+
+#[derive(Debug)]
+pub enum Promise {
+    /// Not settled yet, with what to do once it is.
+    Pending(Vec<Reaction>),
+    Fulfilled(Value),
+    Rejected(Raised),
+}
+
+/// What to do once a promise settles.
+#[derive(Clone, Copy, Debug)]
+pub enum Reaction {
+    /// Resume the coroutine that awaits it.
+    Resume(CoroutineId),
+    /// Settle `promise` the same way, which was resolved with it.
+    Adopt(ObjRef),
+    /// Call `callback` with the value, and resolve `promise` with what it
+    /// returns. A rejection rejects `promise` without calling it.
+    Then { callback: Value, promise: ObjRef },
+}
+
+impl Reaction {
+    pub(crate) fn trace(&self, marker: &mut Marker) {
+        match self {
+            Reaction::Resume(_) => {}
+            Reaction::Adopt(promise) => marker.object(*promise),
+            Reaction::Then { callback, promise } => {
+                marker.value(callback);
+                marker.object(*promise);
+            }
+        }
+    }
+}
+
+/// A reaction to run, with the outcome it reacts to.
+struct Task {
+    reaction: Reaction,
+    outcome: Outcome,
+}
+
+/// Work that blocks, which a worker thread does.
+enum Job {
+    Read { path: String },
+    Write { path: String, text: String },
+    /// Look up the address of a host name.
+    Resolve { host: String, port: u16 },
+}
+
+/// What a job did.
+enum Done {
+    Text(String),
+    Written,
+    Address(SocketAddr),
+}
+
+impl Job {
+    fn run(self) -> io::Result<Done> {
+        let (done, path, verb) = match self {
+            Job::Read { path } => (std::fs::read_to_string(&path).map(Done::Text), path, "read"),
+            Job::Write { path, text } => (std::fs::write(&path, text).map(|()| Done::Written), path, "write"),
+            Job::Resolve { host, port } => {
+                return (host.as_str(), port)
+                    .to_socket_addrs()?
+                    .next()
+                    .map(Done::Address)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", host)));
+            }
+        };
+        done.map_err(|error| io::Error::new(error.kind(), format!("can't {} {}: {}", verb, path, error)))
+    }
+}
+
+/// The worker threads, with the promises of the jobs they haven't finished.
+/// They stop once the loop is dropped.
+struct Workers {
+    jobs: mpsc::Sender<(u64, Job)>,
+    done: mpsc::Receiver<(u64, io::Result<Done>)>,
+    /// An eventfd that a worker adds to when it finished a job.
+    signal: Arc<OwnedFd>,
+    pending: FxHashMap<u64, ObjRef>,
+    next: u64,
+}
+
+impl Workers {
+    fn new() -> io::Result<Workers> {
+        let signal = check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        let signal = Arc::new(unsafe { OwnedFd::from_raw_fd(signal) });
+        let (jobs, queue) = mpsc::channel::<(u64, Job)>();
+        let queue = Arc::new(Mutex::new(queue));
+        let (finished, done) = mpsc::channel();
+        for _ in 0..WORKERS {
+            let queue = Arc::clone(&queue);
+            let finished = finished.clone();
+            let signal = Arc::clone(&signal);
+            std::thread::Builder::new()
+                .name("emerald-worker".to_owned())
+                .spawn(move || loop {
+                    // The lock is let go of before the job runs.
+                    let next = queue.lock().unwrap().recv();
+                    let (id, job) = match next {
+                        Ok(next) => next,
+                        Err(_) => return,
+                    };
+                    if finished.send((id, job.run())).is_err() {
+                        return;
+                    }
+                    let one: u64 = 1;
+                    unsafe { libc::write(signal.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+                })?;
+        }
+        Ok(Workers {
+            jobs,
+            done,
+            signal,
+            pending: FxHashMap::default(),
+            next: 0,
+        })
+    }
+
+    /// The jobs that were finished since the last time, after resetting the
+    /// eventfd.
+    fn finished(&mut self) -> Vec<(ObjRef, io::Result<Done>)> {
+        let mut count: u64 = 0;
+        // It fails when nothing was signalled, which is fine.
+        unsafe { libc::read(self.signal.as_raw_fd(), &mut count as *mut u64 as *mut libc::c_void, 8) };
+        let mut finished = vec![];
+        while let Ok((id, done)) = self.done.try_recv() {
+            finished.push((self.pending.remove(&id).unwrap(), done));
+        }
+        finished
+    }
+}
+
+/// A TCP connection, with the start of a character that the last read
+/// split, which the next one finishes.
+#[derive(Debug)]
+pub struct Connection {
+    pub stream: TcpStream,
+    partial: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Connection {
+        Connection { stream, partial: vec![] }
+    }
+}
+
+/// The text of `read` after the bytes `partial` holds, leaving the start of
+/// a character `read` ends with in `partial` for the next read. Bytes that
+/// can't be part of a character are decoded as U+FFFD.
+fn decode(partial: &mut Vec<u8>, read: &[u8]) -> String {
+    partial.extend_from_slice(read);
+    let mut text = String::new();
+    let mut rest = &partial[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                rest = &[];
+                break;
+            }
+            Err(error) => {
+                let (valid, after) = rest.split_at(error.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap());
+                match error.error_len() {
+                    Some(invalid) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[invalid..];
+                    }
+                    // The start of a character that the next read finishes.
+                    None => {
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    let tail = partial.len() - rest.len();
+    partial.drain(..tail);
+    text
+}
+
+/// An operation on a socket, which waits until the socket is ready for it.
+enum IoOp {
+    Accept(ObjRef),
+    Connect(ObjRef),
+    Read(ObjRef),
+    /// With the bytes left to write.
+    Write(ObjRef, Vec<u8>),
+}
+
+impl IoOp {
+    fn socket(&self) -> ObjRef {
+        match self {
+            IoOp::Accept(socket) | IoOp::Connect(socket) | IoOp::Read(socket) | IoOp::Write(socket, _) => *socket,
+        }
+    }
+
+    /// The epoll events the socket has to be ready for.
+    fn interest(&self) -> u32 {
+        match self {
+            IoOp::Accept(_) | IoOp::Read(_) => libc::EPOLLIN as u32,
+            IoOp::Connect(_) | IoOp::Write(..) => libc::EPOLLOUT as u32,
+        }
+    }
+}
+
+struct IoWait {
+    op: IoOp,
+    promise: ObjRef,
+}
+
+#[derive(Default)]
+pub struct EventLoop {
+    tasks: VecDeque<Task>,
+    coroutines: FxHashMap<CoroutineId, Coroutine>,
+    next_coroutine: CoroutineId,
+    /// The promises to fulfil at a time, in the order they were set for the
+    /// same time.
+    timers: BTreeMap<(Instant, u64), ObjRef>,
+    next_timer: u64,
+    /// Made when the first job is given to a worker.
+    workers: Option<Workers>,
+    waits: Vec<IoWait>,
+    /// Made when the first socket has to wait.
+    poller: Option<Poller>,
+    /// Rejected promises that nothing reacted to yet.
+    unhandled: Vec<ObjRef>,
+}
+
+impl EventLoop {
+    /// Keep a suspended coroutine until what it awaits settles.
+    pub(crate) fn suspend(&mut self, coroutine: Coroutine) -> CoroutineId {
+        let id = self.next_coroutine;
+        self.next_coroutine += 1;
+        self.coroutines.insert(id, coroutine);
+        id
+    }
+
+    /// The suspended coroutines, which are roots.
+    pub(crate) fn coroutines(&self) -> impl Iterator<Item = &Coroutine> {
+        self.coroutines.values()
+    }
+
+    /// Mark everything the loop holds on to but the coroutines.
+    pub(crate) fn trace(&self, marker: &mut Marker) {
+        for task in &self.tasks {
+            task.reaction.trace(marker);
+            match &task.outcome {
+                Ok(value) => marker.value(value),
+                Err(raised) => marker.object(raised.exception),
+            }
+        }
+        let promises = self
+            .timers
+            .values()
+            .chain(self.workers.iter().flat_map(|workers| workers.pending.values()))
+            .chain(self.waits.iter().map(|wait| &wait.promise))
+            .chain(&self.unhandled);
+        for promise in promises {
+            marker.object(*promise);
+        }
+        for wait in &self.waits {
+            marker.object(wait.op.socket());
+        }
+    }
+}
+
+/// The result of a system call, or the error it set.
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// An epoll instance, and the sockets registered with it with what for.
+struct Poller {
+    epoll: OwnedFd,
+    registered: FxHashMap<RawFd, u32>,
+}
+
+impl Poller {
+    fn new() -> io::Result<Poller> {
+        let epoll = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Poller {
+            epoll: unsafe { OwnedFd::from_raw_fd(epoll) },
+            registered: FxHashMap::default(),
+        })
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, events: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: fd as u64 };
+        check(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut event) }).map(drop)
+    }
+
+    /// Register exactly the sockets of `interests`, for the events it gives.
+    fn register(&mut self, interests: FxHashMap<RawFd, u32>) -> io::Result<()> {
+        for (&fd, &events) in &interests {
+            match self.registered.get(&fd) {
+                Some(&registered) if registered == events => {}
+                // A socket closed since took itself out, and its descriptor
+                // may belong to a new one.
+                Some(_) => match self.control(libc::EPOLL_CTL_MOD, fd, events) {
+                    Err(error) if error.raw_os_error() == Some(libc::ENOENT) => {
+                        self.control(libc::EPOLL_CTL_ADD, fd, events)?
+                    }
+                    result => result?,
+                },
+                None => self.control(libc::EPOLL_CTL_ADD, fd, events)?,
+            }
+        }
+        for fd in self.registered.keys() {
+            if !interests.contains_key(fd) {
+                // It fails for a socket that was closed, which is gone already.
+                let _ = self.control(libc::EPOLL_CTL_DEL, *fd, 0);
+            }
+        }
+        self.registered = interests;
+        Ok(())
+    }
+
+    /// Take a socket out before it is closed.
+    fn forget(&mut self, fd: RawFd) {
+        if self.registered.remove(&fd).is_some() {
+            let _ = self.control(libc::EPOLL_CTL_DEL, fd, 0);
+        }
+    }
+
+    /// Wait until registered descriptors are ready, or `timeout` passed. Returns
+    /// the events of the sockets that are ready.
+    fn wait(&mut self, timeout: Option<Duration>) -> io::Result<FxHashMap<RawFd, u32>> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        // Rounded up, so a timer is due once the wait is over.
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int
+        });
+        let waited = unsafe {
+            libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), MAX_EVENTS as libc::c_int, timeout)
+        };
+        let ready = match check(waited) {
+            Ok(ready) => ready as usize,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => 0,
+            Err(error) => return Err(error),
+        };
+        Ok(events[..ready].iter().map(|event| (event.u64 as RawFd, event.events)).collect())
+    }
+}
+
+/// Start connecting to `address` without waiting for the connection.
+fn connect_nonblocking(address: SocketAddr) -> io::Result<TcpStream> {
+    let family = match address {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = check(unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) })?;
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let length = match address {
+        SocketAddr::V4(address) => {
+            let mut sockaddr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = address.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from_ne_bytes(address.ip().octets());
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sockaddr) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let mut sockaddr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = address.port().to_be();
+            sockaddr.sin6_flowinfo = address.flowinfo();
+            sockaddr.sin6_addr.s6_addr = address.ip().octets();
+            sockaddr.sin6_scope_id = address.scope_id();
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sockaddr) };
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    let connected = unsafe {
+        libc::connect(
+            fd,
+            &storage as *const _ as *const libc::sockaddr,
+            length as libc::socklen_t,
+        )
+    };
+    match check(connected) {
+        Err(error) if error.raw_os_error() != Some(libc::EINPROGRESS) => Err(error),
+        _ => Ok(stream),
+    }
+}
+
+/// Whether the connection of `stream` was made, or why not.
+fn connected(stream: &TcpStream) -> io::Result<()> {
+    if let Some(error) = stream.take_error()? {
+        return Err(error);
+    }
+    match stream.peer_addr() {
+        Ok(_) => Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::NotConnected => Err(io::ErrorKind::WouldBlock.into()),
+        Err(error) => Err(error),
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the socket is closed")
+}
+
+impl Vm {
+    fn new_promise(&mut self) -> ObjRef {
+        self.heap.alloc(Object::Promise(Promise::Pending(vec![])))
+    }
+
+    /// Fulfil `promise` with `value`, or settle it the way `value` settles
+    /// if that is a promise too.
+    pub(crate) fn resolve(&mut self, promise: ObjRef, value: Value) {
+        match value.as_object() {
+            Some(other) if other != promise && matches!(self.heap.get(other), Object::Promise(_)) => {
+                self.react(other, Reaction::Adopt(promise))
+            }
+            _ => self.settle(promise, Ok(value)),
+        }
+    }
+
+    pub(crate) fn reject(&mut self, promise: ObjRef, raised: Raised) {
+        self.settle(promise, Err(raised));
+    }
+
+    /// Settle `promise`, unless it was settled already, and queue what
+    /// reacts to it.
+    fn settle(&mut self, promise: ObjRef, outcome: Outcome) {
+        let settled = match &outcome {
+            Ok(value) => Promise::Fulfilled(*value),
+            Err(raised) => Promise::Rejected(raised.clone()),
+        };
+        let reactions = match self.heap.get_mut(promise) {
+            Object::Promise(state @ Promise::Pending(_)) => match std::mem::replace(state, settled) {
+                Promise::Pending(reactions) => reactions,
+                _ => unreachable!(),
+            },
+            _ => return,
+        };
+        if reactions.is_empty() && outcome.is_err() {
+            self.event_loop.unhandled.push(promise);
+        }
+        for reaction in reactions {
+            let outcome = outcome.clone();
+            self.event_loop.tasks.push_back(Task { reaction, outcome });
+        }
+    }
+
+    /// Run `reaction` once `promise` settles, or soon if it has.
+    fn react(&mut self, promise: ObjRef, reaction: Reaction) {
+        let outcome = match self.heap.get_mut(promise) {
+            Object::Promise(Promise::Pending(reactions)) => {
+                reactions.push(reaction);
+                return;
+            }
+            Object::Promise(Promise::Fulfilled(value)) => Ok(*value),
+            Object::Promise(Promise::Rejected(raised)) => Err(raised.clone()),
+            _ => unreachable!("reacted to something that isn't a promise"),
+        };
+        self.event_loop.unhandled.retain(|&unhandled| unhandled != promise);
+        self.event_loop.tasks.push_back(Task { reaction, outcome });
+    }
+
+    /// Run `reaction` once `awaited` settles if it is a promise, or soon with
+    /// `awaited` itself if it isn't.
+    pub(crate) fn await_value(&mut self, awaited: Value, reaction: Reaction) {
+        match awaited.as_object() {
+            Some(promise) if matches!(self.heap.get(promise), Object::Promise(_)) => self.react(promise, reaction),
+            _ => self.event_loop.tasks.push_back(Task {
+                reaction,
+                outcome: Ok(awaited),
+            }),
+        }
+    }
+
+    fn run_task(&mut self, task: Task) {
+        match task.reaction {
+            Reaction::Resume(id) => {
+                let coroutine = self.event_loop.coroutines.remove(&id).expect("resumed a coroutine twice");
+                self.resume(coroutine, task.outcome);
+            }
+            Reaction::Adopt(promise) => self.settle(promise, task.outcome),
+            Reaction::Then { callback, promise } => match task.outcome {
+                Ok(value) => {
+                    let roots = [callback, value, Value::from(promise)];
+                    match self.with_pinned(&roots, |vm| vm.call(callback, vec![value])) {
+                        Ok(result) => self.resolve(promise, result),
+                        Err(raised) => self.reject(promise, raised),
+                    }
+                }
+                Err(raised) => self.reject(promise, raised),
+            },
+        }
+    }
+
+    /// Run the event loop until nothing is left for it to do, or a promise
+    /// is rejected without anything reacting to it.
+    pub(crate) fn run_event_loop(&mut self) -> VmResult<()> {
+        loop {
+            while let Some(task) = self.event_loop.tasks.pop_front() {
+                self.run_task(task);
+            }
+            if let Some(&promise) = self.event_loop.unhandled.first() {
+                self.event_loop.unhandled.clear();
+                return match self.heap.get(promise) {
+                    Object::Promise(Promise::Rejected(raised)) => Err(raised.clone()),
+                    _ => unreachable!("unhandled promise that wasn't rejected"),
+                };
+            }
+            let now = Instant::now();
+            if self.fire_timers(now) {
+                continue;
+            }
+            let timeout = self
+                .event_loop
+                .timers
+                .keys()
+                .next()
+                .map(|&(deadline, _)| deadline.saturating_duration_since(now));
+            let working = self.event_loop.workers.as_ref().is_some_and(|workers| !workers.pending.is_empty());
+            match timeout {
+                _ if working || !self.event_loop.waits.is_empty() => self.poll(timeout)?,
+                Some(timeout) => std::thread::sleep(timeout),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Fulfil the promises of the timers due at `now`. Returns whether any were.
+    fn fire_timers(&mut self, now: Instant) -> bool {
+        let mut fired = false;
+        while let Some(timer) = self.event_loop.timers.first_entry() {
+            if timer.key().0 > now {
+                break;
+            }
+            let promise = timer.remove();
+            self.settle(promise, Ok(Value::NONE));
+            fired = true;
+        }
+        fired
+    }
+
+    /// A promise fulfilled with `Value::NONE` after `duration`.
+    fn timer(&mut self, duration: Duration) -> ObjRef {
+        let promise = self.new_promise();
+        let id = self.event_loop.next_timer;
+        self.event_loop.next_timer += 1;
+        self.event_loop.timers.insert((Instant::now() + duration, id), promise);
+        promise
+    }
+
+    /// A promise of what `job` does, which a worker thread does.
+    fn submit(&mut self, job: Job) -> Value {
+        if self.event_loop.workers.is_none() {
+            match Workers::new() {
+                Ok(workers) => self.event_loop.workers = Some(workers),
+                Err(error) => return self.rejected(error),
+            }
+        }
+        let promise = self.new_promise();
+        let workers = self.event_loop.workers.as_mut().unwrap();
+        let id = workers.next;
+        workers.next += 1;
+        workers.pending.insert(id, promise);
+        // The workers only stop once the loop is gone.
+        workers.jobs.send((id, job)).unwrap();
+        Value::from(promise)
+    }
+
+    /// Settle the promises of the jobs the workers finished.
+    fn finish_jobs(&mut self) {
+        let finished = match &mut self.event_loop.workers {
+            Some(workers) => workers.finished(),
+            None => return,
+        };
+        for (promise, done) in finished {
+            match done {
+                Ok(Done::Text(text)) => {
+                    let text = self.alloc_str(text);
+                    self.settle(promise, Ok(text));
+                }
+                Ok(Done::Written) => self.settle(promise, Ok(Value::NONE)),
+                Ok(Done::Address(address)) => {
+                    let connected = self.start_connect(address);
+                    self.resolve(promise, connected);
+                }
+                Err(error) => {
+                    let raised = self.os_error(error);
+                    self.reject(promise, raised);
+                }
+            }
+        }
+    }
+
+    /// A promise of a connection to `address`.
+    fn start_connect(&mut self, address: SocketAddr) -> Value {
+        match connect_nonblocking(address) {
+            Ok(stream) => {
+                let connection = self.heap.alloc(Object::Connection(Some(Connection::new(stream))));
+                self.start_io(IoOp::Connect(connection))
+            }
+            Err(error) => self.rejected(error),
+        }
+    }
+
+    fn os_error(&mut self, error: io::Error) -> Raised {
+        self.error(ErrorKind::OSError, error.to_string())
+    }
+
+    /// A promise rejected with `error`.
+    fn rejected(&mut self, error: io::Error) -> Value {
+        let promise = self.new_promise();
+        let raised = self.os_error(error);
+        self.reject(promise, raised);
+        Value::from(promise)
+    }
+
+    /// The descriptor of a socket, unless it is closed.
+    fn socket_fd(&self, socket: ObjRef) -> Option<RawFd> {
+        match self.heap.get(socket) {
+            Object::Server(Some(listener)) => Some(listener.as_raw_fd()),
+            Object::Connection(Some(connection)) => Some(connection.stream.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    /// Do as much of `op` as its socket is ready for. Returns how it ended,
+    /// or None if it has to wait.
+    fn attempt(&mut self, op: &mut IoOp) -> Option<Outcome> {
+        let done = match op {
+            IoOp::Accept(server) => {
+                let accepted = match self.heap.get(*server) {
+                    Object::Server(Some(listener)) => listener.accept(),
+                    _ => Err(closed()),
+                };
+                accepted.and_then(|(stream, _)| {
+                    stream.set_nonblocking(true)?;
+                    Ok(Value::from(self.heap.alloc(Object::Connection(Some(Connection::new(stream))))))
+                })
+            }
+            IoOp::Connect(connection) => match self.heap.get(*connection) {
+                Object::Connection(Some(open)) => connected(&open.stream).map(|()| Value::from(*connection)),
+                _ => Err(closed()),
+            },
+            IoOp::Read(connection) => {
+                let mut buffer = vec![0; READ_SIZE];
+                let read = match self.heap.get_mut(*connection) {
+                    Object::Connection(Some(open)) => match open.stream.read(&mut buffer) {
+                        // What is left of a split character once the other
+                        // end closed the connection is no character.
+                        Ok(0) if !open.partial.is_empty() => {
+                            Ok(String::from_utf8_lossy(&std::mem::take(&mut open.partial)).into_owned())
+                        }
+                        Ok(0) => Ok(String::new()),
+                        // Only the start of a character is no text yet, and
+                        // empty text means the connection was closed.
+                        Ok(n) => match decode(&mut open.partial, &buffer[..n]) {
+                            text if text.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+                            text => Ok(text),
+                        },
+                        Err(error) => Err(error),
+                    },
+                    _ => Err(closed()),
+                };
+                read.map(|text| self.alloc_str(text))
+            }
+            IoOp::Write(connection, bytes) => {
+                let written = match self.heap.get(*connection) {
+                    Object::Connection(Some(open)) => {
+                        let mut stream: &TcpStream = &open.stream;
+                        stream.write(bytes)
+                    }
+                    _ => Err(closed()),
+                };
+                written.and_then(|n| {
+                    bytes.drain(..n);
+                    match bytes.is_empty() {
+                        true => Ok(Value::NONE),
+                        false => Err(io::ErrorKind::WouldBlock.into()),
+                    }
+                })
+            }
+        };
+        match done {
+            Ok(value) => Some(Ok(value)),
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => None,
+            Err(error) => Some(Err(self.os_error(error))),
+        }
+    }
+
+    /// A promise of how `op` ends, which waits for its socket if it has to.
+    fn start_io(&mut self, mut op: IoOp) -> Value {
+        let promise = self.new_promise();
+        match self.attempt(&mut op) {
+            Some(outcome) => self.settle(promise, outcome),
+            None => self.event_loop.waits.push(IoWait { op, promise }),
+        }
+        Value::from(promise)
+    }
+
+    /// Wait for the sockets that operations wait for and for the workers, at
+    /// most `timeout`, and go on with the operations whose sockets are ready
+    /// and the jobs that are done.
+    fn poll(&mut self, timeout: Option<Duration>) -> VmResult<()> {
+        if self.event_loop.poller.is_none() {
+            match Poller::new() {
+                Ok(poller) => self.event_loop.poller = Some(poller),
+                Err(error) => return Err(self.os_error(error)),
+            }
+        }
+        let ready = self.register_waits().and_then(|()| self.event_loop.poller.as_mut().unwrap().wait(timeout));
+        let ready = match ready {
+            Ok(ready) => ready,
+            Err(error) => return Err(self.os_error(error)),
+        };
+        // Errors and hang-ups are for the operation to find out about.
+        let always = (libc::EPOLLERR | libc::EPOLLHUP) as u32;
+        for mut wait in std::mem::take(&mut self.event_loop.waits) {
+            let is_ready = match self.socket_fd(wait.op.socket()) {
+                Some(fd) => ready.get(&fd).is_some_and(|events| events & (wait.op.interest() | always) != 0),
+                None => true,
+            };
+            if is_ready {
+                if let Some(outcome) = self.attempt(&mut wait.op) {
+                    self.settle(wait.promise, outcome);
+                    continue;
+                }
+            }
+            self.event_loop.waits.push(wait);
+        }
+        self.finish_jobs();
+        // Sockets that are done waiting may be collected, and their
+        // descriptors used again, before the next poll.
+        self.register_waits().map_err(|error| self.os_error(error))
+    }
+
+    /// Register the sockets that operations wait for with the poller.
+    fn register_waits(&mut self) -> io::Result<()> {
+        let mut interests = FxHashMap::default();
+        for wait in &self.event_loop.waits {
+            if let Some(fd) = self.socket_fd(wait.op.socket()) {
+                *interests.entry(fd).or_insert(0) |= wait.op.interest();
+            }
+        }
+        if let Some(workers) = &self.event_loop.workers {
+            interests.insert(workers.signal.as_raw_fd(), libc::EPOLLIN as u32);
+        }
+        match &mut self.event_loop.poller {
+            Some(poller) => poller.register(interests),
+            None => Ok(()),
+        }
+    }
+
+    /// Close a socket, rejecting what waits for it.
+    fn close_socket(&mut self, socket: ObjRef) {
+        if let (Some(fd), Some(poller)) = (self.socket_fd(socket), &mut self.event_loop.poller) {
+            poller.forget(fd);
+        }
+        let (closing, waiting) = std::mem::take(&mut self.event_loop.waits)
+            .into_iter()
+            .partition(|wait: &IoWait| wait.op.socket() == socket);
+        self.event_loop.waits = waiting;
+        match self.heap.get_mut(socket) {
+            Object::Server(listener) => *listener = None,
+            Object::Connection(stream) => *stream = None,
+            _ => {}
+        }
+        for wait in closing {
+            let raised = self.os_error(closed());
+            self.reject(wait.promise, raised);
+        }
+    }
+}
+
+/// `value` as a number of seconds, for a builtin that waits.
+fn duration(vm: &mut Vm, name: &str, value: &Value) -> VmResult<Duration> {
+    let seconds = match value.unbox() {
+        Unboxed::Int(i) => i as f64,
+        Unboxed::Float(x) => x,
+        _ => {
+            let message = format!("{}() takes a number of seconds, not '{}'", name, vm.type_name(value));
+            return Err(vm.error(ErrorKind::TypeError, message));
+        }
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        let message = format!("{}() takes a non-negative number of seconds", name);
+        vm.error(ErrorKind::ValueError, message)
+    })
+}
+
+fn port(vm: &mut Vm, value: &Value) -> VmResult<u16> {
+    match value.as_int().and_then(|port| u16::try_from(port).ok()) {
+        Some(port) => Ok(port),
+        None => Err(vm.error(ErrorKind::ValueError, "a port is an int from 0 to 65535")),
+    }
+}
+
+/// The receiver of a method of promises or sockets.
+fn receiver(args: &[Value]) -> ObjRef {
+    args[0].as_object().unwrap()
+}
+
+pub(crate) fn sleep(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "sleep", &args, 1, 1)?;
+    let duration = duration(vm, "sleep", &args[0])?;
+    Ok(Value::from(vm.timer(duration)))
+}
+
+pub(crate) fn set_timeout(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "set_timeout", &args, 2, 2)?;
+    let duration = duration(vm, "set_timeout", &args[0])?;
+    let timer = vm.timer(duration);
+    let promise = vm.new_promise();
+    let callback = args[1];
+    vm.react(timer, Reaction::Then { callback, promise });
+    Ok(Value::from(promise))
+}
+
+pub(crate) fn read_file(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "read_file", &args, 1, 1)?;
+    let path = string(vm, &args[0])?;
+    Ok(vm.submit(Job::Read { path }))
+}
+
+pub(crate) fn write_file(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "write_file", &args, 2, 2)?;
+    let path = string(vm, &args[0])?;
+    let text = string(vm, &args[1])?;
+    Ok(vm.submit(Job::Write { path, text }))
+}
+
+/// Listen for TCP connections on a host and port, port 0 being any free one.
+pub(crate) fn listen(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "listen", &args, 2, 2)?;
+    let host = string(vm, &args[0])?;
+    let port = port(vm, &args[1])?;
+    let listener = TcpListener::bind((host.as_str(), port)).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    });
+    match listener {
+        Ok(listener) => Ok(Value::from(vm.heap.alloc(Object::Server(Some(listener))))),
+        Err(error) => Err(vm.os_error(error)),
+    }
+}
+
+/// A promise of a TCP connection to a host and port. A host name is looked
+/// up by a worker, an IP address is used as it is.
+pub(crate) fn connect(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    arity(vm, "connect", &args, 2, 2)?;
+    let host = string(vm, &args[0])?;
+    let port = port(vm, &args[1])?;
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(vm.start_connect(SocketAddr::new(ip, port))),
+        Err(_) => Ok(vm.submit(Job::Resolve { host, port })),
+    }
+}
+
+/// A promise of what a callback returns for the value of the promise.
+pub(crate) fn promise_then(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "then", &args, 1, 1)?;
+    let promise = vm.new_promise();
+    let callback = args[1];
+    vm.react(receiver(&args), Reaction::Then { callback, promise });
+    Ok(Value::from(promise))
+}
+
+/// A promise of the next connection to the server.
+pub(crate) fn server_accept(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "accept", &args, 0, 0)?;
+    Ok(vm.start_io(IoOp::Accept(receiver(&args))))
+}
+
+pub(crate) fn server_port(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "port", &args, 0, 0)?;
+    let address = match vm.heap.get(receiver(&args)) {
+        Object::Server(Some(listener)) => listener.local_addr(),
+        _ => Err(closed()),
+    };
+    match address {
+        Ok(address) => Ok(vm.int(address.port() as i64)),
+        Err(error) => Err(vm.os_error(error)),
+    }
+}
+
+pub(crate) fn socket_close(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "close", &args, 0, 0)?;
+    vm.close_socket(receiver(&args));
+    Ok(Value::NONE)
+}
+
+/// A promise of what arrives next on the connection, as text, which is
+/// empty once the other end closed it.
+pub(crate) fn connection_read(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "read", &args, 0, 0)?;
+    Ok(vm.start_io(IoOp::Read(receiver(&args))))
+}
+
+/// A promise that is fulfilled once all of the text is written.
+pub(crate) fn connection_write(vm: &mut Vm, args: Vec<Value>) -> VmResult<Value> {
+    method_arity(vm, "write", &args, 1, 1)?;
+    let text = string(vm, &args[1])?;
+    Ok(vm.start_io(IoOp::Write(receiver(&args), text.into_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::{output, run};
+
+    #[test]
+    fn test_decode() {
+        let mut partial = vec![];
+        assert_eq!(decode(&mut partial, b"caf\xC3"), "caf");
+        assert_eq!(partial, b"\xC3");
+        assert_eq!(decode(&mut partial, b"\xA9 \xF0\x9F"), "\u{e9} ");
+        // Only more of the same character is no text yet.
+        assert_eq!(decode(&mut partial, b"\x98"), "");
+        assert_eq!(decode(&mut partial, b"\x80!"), "\u{1F600}!");
+        assert!(partial.is_empty());
+        // Bytes that no character starts or continues with don't wait.
+        assert_eq!(decode(&mut partial, b"a\xFFb\xC3("), "a\u{FFFD}b\u{FFFD}(");
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn test_character_split_across_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let writer = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for chunk in [&b"h\xC3"[..], b"\xA9llo \xF0\x9F", b"\x98", b"\x80\xE2"] {
+                stream.write_all(chunk).unwrap();
+                stream.flush().unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let source = format!(
+            "\
+async def main():
+    connection = await connect('127.0.0.1', {})
+    text = ''
+    reads = 0
+    while True:
+        chunk = await connection.read()
+        if chunk == '':
+            break
+        text = text + chunk
+        reads = reads + 1
+    print(text, reads > 1)
+main()
+",
+            port
+        );
+        // A character the other end never finished is undecodable.
+        assert_eq!(output(&source), "h\u{e9}llo \u{1F600}\u{FFFD} True\n");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_host_names_are_looked_up_off_the_loop() {
+        let source = "\
+server = listen('localhost', 0)
+
+async def serve():
+    connection = await server.accept()
+    await connection.write('hello')
+    connection.close()
+    server.close()
+
+async def client():
+    connection = connect('localhost', server.port())
+    print(connection)
+    print(await (await connection).read())
+
+serve()
+client()
+";
+        assert_eq!(output(source), "<promise pending>\nhello\n");
+    }
+
+    #[test]
+    fn test_timers() {
+        let source = "\
+log = []
+set_timeout(0.02) do:
+    log.append('late')
+set_timeout(0.01) do:
+    log.append('early')
+set_timeout(0.01) do:
+    log.append('early, second')
+set_timeout(0) do:
+    log.append('now')
+sleep(0.03).then() do value:
+    print(log, value)
+";
+        assert_eq!(output(source), "['now', 'early', 'early, second', 'late'] None\n");
+    }
+
+    #[test]
+    fn test_resolution_order() {
+        // Coroutines run until they first await, and what reacts to settled
+        // promises runs in the order it was queued, before any timer fires.
+        let source = "\
+log = []
+async def task(name):
+    log.append(name + ' start')
+    await None
+    log.append(name + ' end')
+    return name
+
+sleep(0).then() do value:
+    log.append('timer')
+first = task('a')
+first.then() do value:
+    log.append('then ' + value)
+task('b')
+first.then() do value:
+    log.append('then again ' + value)
+log.append('module')
+sleep(0.01).then() do value:
+    print(log)
+";
+        assert_eq!(
+            output(source),
+            "['a start', 'b start', 'module', 'a end', 'b end', 'then a', 'then again a', 'timer']\n"
+        );
+    }
+
+    #[test]
+    fn test_error_propagation() {
+        let source = "\
+async def fail(message):
+    await sleep(0)
+    raise ValueError(message)
+
+async def main():
+    chained = fail('in chain').then() do value:
+        print('never called')
+    try:
+        await chained
+    except ValueError as e:
+        print('caught', e.message)
+    broken = sleep(0).then() do value:
+        raise KeyError('in callback')
+    try:
+        await broken
+    except KeyError as e:
+        print('caught', e.message)
+    fail('unawaited')
+    await sleep(0.01)
+    print('never printed')
+
+main()
+set_timeout(0.05) do:
+    print('never fires')
+";
+        let (printed, result) = run(source);
+        assert_eq!(printed, "caught in chain\ncaught in callback\n");
+        let error = result.unwrap_err().to_string();
+        assert!(error.ends_with("in fail\nValueError: unawaited"), "{}", error);
+    }
+}
@@ -0,0 +1,140 @@
+//! A growable array that native code can read and write in place.
+//!
+//! `Vec` doesn't promise where its pointer and length are. A `FlatVec` does:
+//! it is `#[repr(C)]`, with the pointer to its elements first and its length
+//! after it, so the code the JIT compiles finds them at fixed offsets, and
+//! can pop off the value stack by writing the length. Native code only does
+//! that for elements that own nothing, or that it moved out first.
+
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
+use std::ops::{Deref, DerefMut, RangeBounds};
+
+#[repr(C)]
+pub struct FlatVec<T> {
+    ptr: *mut T,
+    len: usize,
+    capacity: usize,
+}
+
+impl<T> FlatVec<T> {
+    /// Where the pointer to the elements is.
+    pub const PTR_OFFSET: i32 = mem::offset_of!(FlatVec<T>, ptr) as i32;
+    /// Where the number of elements is.
+    pub const LEN_OFFSET: i32 = mem::offset_of!(FlatVec<T>, len) as i32;
+
+    pub fn new() -> Self {
+        Vec::new().into()
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        let this = ManuallyDrop::new(self);
+        unsafe { Vec::from_raw_parts(this.ptr, this.len, this.capacity) }
+    }
+
+    /// Run `f` on the elements as a `Vec`, for what can reallocate or drop
+    /// them.
+    fn with_vec<R>(&mut self, f: impl FnOnce(&mut Vec<T>) -> R) -> R {
+        // If `f` panics, `self` is left empty rather than dangling.
+        let mut vec = mem::take(self).into_vec();
+        let result = f(&mut vec);
+        *self = vec.into();
+        result
+    }
+
+    pub fn push(&mut self, value: T) {
+        if self.len == self.capacity {
+            self.with_vec(|vec| vec.reserve(1));
+        }
+        unsafe { self.ptr.add(self.len).write(value) };
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { self.ptr.add(self.len).read() })
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.with_vec(|vec| vec.truncate(len));
+    }
+
+    pub fn insert(&mut self, index: usize, value: T) {
+        self.with_vec(|vec| vec.insert(index, value));
+    }
+
+    /// Take the elements from `at` on.
+    pub fn split_off(&mut self, at: usize) -> Vec<T> {
+        self.with_vec(|vec| vec.split_off(at))
+    }
+}
+
+impl<T: Clone> FlatVec<T> {
+    pub fn extend_from_within(&mut self, range: impl RangeBounds<usize>) {
+        self.with_vec(|vec| vec.extend_from_within(range));
+    }
+}
+
+impl<T> Default for FlatVec<T> {
+    fn default() -> Self {
+        FlatVec::new()
+    }
+}
+
+impl<T> From<Vec<T>> for FlatVec<T> {
+    fn from(vec: Vec<T>) -> Self {
+        let mut vec = ManuallyDrop::new(vec);
+        FlatVec {
+            ptr: vec.as_mut_ptr(),
+            len: vec.len(),
+            capacity: vec.capacity(),
+        }
+    }
+}
+
+impl<T> Drop for FlatVec<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Vec::from_raw_parts(self.ptr, self.len, self.capacity) });
+    }
+}
+
+impl<T: Clone> Clone for FlatVec<T> {
+    fn clone(&self) -> Self {
+        self.to_vec().into()
+    }
+}
+
+impl<T> Deref for FlatVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T> DerefMut for FlatVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T> FromIterator<T> for FlatVec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<T>>().into()
+    }
+}
+
+impl<T> Extend<T> for FlatVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.with_vec(|vec| vec.extend(iter));
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for FlatVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,452 @@
+//! A precise, tracing garbage collector for the heap.
+//!
+//! Collection is mark-sweep. The machine collects at safe points, between
+//! instructions, when enough objects were allocated since the last
+//! collection, or after every allocation in stress mode. At a safe point
+//! every value the program can still reach is in a root: the value stack,
+//! the frames, the globals and builtins, the values builtins that call back
+//! into Emerald pinned, the suspended coroutines and what the event loop
+//! waits for, and the tables of the runtime. Native code keeps no
+//! values of its own: every value of a native frame is in the frame or on
+//! the stack, at the depth its frame map says, so native frames are scanned
+//! like interpreted ones. What native code does keep is the functions and
+//! shapes it inlined, which its `Compiled` lists.
+//!
+//! Code objects aren't on the heap, but their inline caches and string
+//! constants refer to objects. The caches hold on to their receivers weakly:
+//! what an entry cached is kept alive while the class of its receiver is,
+//! and entries for collected classes are dropped. Shapes are freed with
+//! their class, and their ids are never used again, so a stale id can't
+//! match a live instance.
+//!
+//! Weak references are cleared, and the finalisers of their objects run,
+//! after the objects are collected.
+//!
+//! Objects don't move, so `ObjRef`s stay valid for as long as their object
+//! lives. Marks are kept apart from the objects, which leaves room for
+//! generations later.
+
+use crate::bytecode::{CodeObject, Constant};
+use crate::cache::{CacheSite, InlineCache, Lookup, Receiver};
+use crate::event_loop::Promise;
+use crate::heap::{Heap, Iter, ObjRef, Object};
+use crate::jit::Compiled;
+use crate::shape::Shapes;
+use crate::value::Value;
+use rustc_hash::FxHashSet;
+use std::fmt;
+use std::rc::Rc;
+
+/// How many objects are allocated before the first collection.
+pub const INITIAL_THRESHOLD: usize = 10_000;
+/// After a collection, the next one is due once the heap grew by this many
+/// times the objects that survived.
+pub const GROWTH_FACTOR: usize = 2;
+
+/// How the collector did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: u64,
+    /// Objects freed over all collections.
+    pub freed: u64,
+    /// Objects alive after the last collection.
+    pub live: u64,
+    pub finalisers_run: u64,
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "collections: {}", self.collections)?;
+        writeln!(f, "objects freed: {}", self.freed)?;
+        writeln!(f, "objects live: {}", self.live)?;
+        write!(f, "finalisers run: {}", self.finalisers_run)
+    }
+}
+
+/// The class whose lifetime decides whether a cache entry for `receiver`
+/// stays, if there is one.
+fn receiver_class(shapes: &Shapes, receiver: Receiver) -> Option<ObjRef> {
+    match receiver {
+        Receiver::Shape(shape) => Some(shapes.get(shape).class),
+        Receiver::Class(class) => Some(class),
+        Receiver::Type(_) => None,
+    }
+}
+
+/// The mark phase: everything reachable from the roots it is given.
+pub struct Marker<'a> {
+    heap: &'a Heap,
+    shapes: &'a Shapes,
+    marks: Vec<bool>,
+    /// Objects marked but not traced yet.
+    pending: Vec<ObjRef>,
+    /// The code objects traced, and their addresses.
+    codes: Vec<Rc<CodeObject>>,
+    code_addresses: FxHashSet<usize>,
+    /// Cached values, which are alive if the class in front of them is.
+    ephemerons: Vec<(ObjRef, Value)>,
+}
+
+impl<'a> Marker<'a> {
+    pub fn new(heap: &'a Heap, shapes: &'a Shapes) -> Self {
+        Marker {
+            heap,
+            shapes,
+            marks: vec![false; heap.capacity()],
+            pending: vec![],
+            codes: vec![],
+            code_addresses: FxHashSet::default(),
+            ephemerons: vec![],
+        }
+    }
+
+    pub fn object(&mut self, object: ObjRef) {
+        if !self.marks[object.index()] {
+            self.marks[object.index()] = true;
+            self.pending.push(object);
+        }
+    }
+
+    pub fn value(&mut self, value: &Value) {
+        if let Some(object) = value.as_object() {
+            self.object(object);
+        }
+    }
+
+    /// Keep what `receiver` is told apart by alive.
+    pub fn receiver(&mut self, receiver: Receiver) {
+        if let Some(class) = receiver_class(self.shapes, receiver) {
+            self.object(class);
+        }
+    }
+
+    /// Trace `code`, its caches and the code objects in its constants.
+    pub fn code(&mut self, code: &Rc<CodeObject>) {
+        if !self.code_addresses.insert(Rc::as_ptr(code) as usize) {
+            return;
+        }
+        self.codes.push(Rc::clone(code));
+        for site in code.caches.borrow().iter() {
+            self.site(site);
+        }
+        for constant in &code.constants {
+            if let Constant::Code(inner) = constant {
+                self.code(inner);
+            }
+        }
+    }
+
+    fn site(&mut self, site: &CacheSite) {
+        match &site.cache {
+            InlineCache::Empty | InlineCache::Megamorphic => {}
+            InlineCache::Monomorphic(receiver, lookup) => self.cached(*receiver, lookup),
+            InlineCache::Polymorphic(entries) => {
+                for (receiver, lookup) in entries {
+                    self.cached(*receiver, lookup);
+                }
+            }
+        }
+    }
+
+    /// Keep what was cached for `receiver` alive while its class is, or for
+    /// good if it has none.
+    pub fn cached(&mut self, receiver: Receiver, lookup: &Lookup) {
+        let value = match lookup {
+            Lookup::Method(value) | Lookup::Value(value) => value,
+            Lookup::Slot(_) | Lookup::Grow(_) => return,
+        };
+        match receiver_class(self.shapes, receiver) {
+            Some(class) => self.ephemerons.push((class, *value)),
+            None => self.value(value),
+        }
+    }
+
+    pub fn compiled(&mut self, compiled: &Compiled) {
+        for function in &compiled.functions {
+            self.object(*function);
+        }
+        for shape in &compiled.method_shapes {
+            self.object(self.shapes.get(*shape).class);
+        }
+    }
+
+    /// Trace everything reachable from what was marked so far.
+    pub fn finish(mut self) -> Marks {
+        loop {
+            while let Some(object) = self.pending.pop() {
+                self.trace(object);
+            }
+            let marks = &self.marks;
+            let (alive, waiting) = std::mem::take(&mut self.ephemerons)
+                .into_iter()
+                .partition(|(class, _)| marks[class.index()]);
+            self.ephemerons = waiting;
+            let alive: Vec<(ObjRef, Value)> = alive;
+            if alive.is_empty() {
+                break;
+            }
+            for (_, value) in alive {
+                self.value(&value);
+            }
+        }
+        Marks {
+            marks: self.marks,
+            codes: self.codes,
+            code_addresses: self.code_addresses,
+        }
+    }
+
+    /// Mark what `object` refers to.
+    fn trace(&mut self, object: ObjRef) {
+        let heap = self.heap;
+        match heap.get(object) {
+            Object::BigInt(_)
+            | Object::Str(_)
+            | Object::Range { .. }
+            | Object::Builtin(_)
+            | Object::WeakRef(_)
+            | Object::Server(_)
+            | Object::Connection(_) => {}
+            Object::List(items) | Object::Tuple(items) | Object::Set(items) => {
+                for item in items {
+                    self.value(item);
+                }
+            }
+            Object::Dict(entries) => {
+                for (key, value) in entries {
+                    self.value(key);
+                    self.value(value);
+                }
+            }
+            Object::Slice { lower, upper, step } => {
+                self.value(lower);
+                self.value(upper);
+                self.value(step);
+            }
+            Object::Iterator(iter) => match iter {
+                Iter::Sequence { sequence: object, .. } | Iter::Chars { string: object, .. } => self.object(*object),
+                Iter::Range { .. } => {}
+                Iter::Generator(generator) => generator.trace(self),
+            },
+            Object::Function(function) => {
+                self.code(&function.code);
+                for default in &function.defaults {
+                    self.value(default);
+                }
+                for cell in &function.closure {
+                    self.object(*cell);
+                }
+                for object in function.owner.iter().chain(&function.module) {
+                    self.object(*object);
+                }
+            }
+            Object::BoundMethod { receiver, method } => {
+                self.value(receiver);
+                self.value(method);
+            }
+            Object::Class(class) => {
+                for base in class.bases.iter().chain(&class.mro) {
+                    self.object(*base);
+                }
+                for value in class.namespace.values() {
+                    self.value(value);
+                }
+            }
+            Object::Instance(instance) => {
+                self.object(instance.class);
+                for value in instance.slots.iter().filter(|value| !value.is_absent()) {
+                    self.value(value);
+                }
+            }
+            Object::Cell(value) => self.value(value),
+            Object::Promise(promise) => match promise {
+                Promise::Pending(reactions) => {
+                    for reaction in reactions {
+                        reaction.trace(self);
+                    }
+                }
+                Promise::Fulfilled(value) => self.value(value),
+                Promise::Rejected(raised) => self.object(raised.exception),
+            },
+        }
+    }
+}
+
+/// What the mark phase found alive.
+pub struct Marks {
+    marks: Vec<bool>,
+    /// The code objects that are alive.
+    pub codes: Vec<Rc<CodeObject>>,
+    code_addresses: FxHashSet<usize>,
+}
+
+impl Marks {
+    pub fn is_marked(&self, object: ObjRef) -> bool {
+        self.marks[object.index()]
+    }
+
+    /// Whether the code object at `address` is alive.
+    pub fn is_code_alive(&self, address: usize) -> bool {
+        self.code_addresses.contains(&address)
+    }
+
+    /// Whether cache entries for `receiver` stay.
+    pub fn is_receiver_alive(&self, shapes: &Shapes, receiver: Receiver) -> bool {
+        receiver_class(shapes, receiver).is_none_or(|class| self.is_marked(class))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheSite;
+    use crate::event_loop::Reaction;
+    use crate::heap::{Class, Function};
+    use crate::vm::Vm;
+    use rustc_hash::FxHashMap;
+
+    fn list(heap: &mut Heap, items: Vec<Value>) -> ObjRef {
+        heap.alloc(Object::List(items))
+    }
+
+    fn class(heap: &mut Heap, name: &str) -> ObjRef {
+        heap.alloc(Object::Class(Class {
+            name: name.to_owned(),
+            is_module: false,
+            bases: vec![],
+            mro: vec![],
+            namespace: FxHashMap::default(),
+        }))
+    }
+
+    fn function(heap: &mut Heap, closure: Vec<ObjRef>) -> ObjRef {
+        let code = crate::compile_source("pass\n", "<test>").unwrap();
+        heap.alloc(Object::Function(Function {
+            code,
+            defaults: vec![],
+            closure,
+            owner: None,
+            module: None,
+        }))
+    }
+
+    /// Mark from `roots`, and sweep what wasn't marked.
+    fn collect(heap: &mut Heap, shapes: &Shapes, roots: &[ObjRef]) -> Marks {
+        let marks = {
+            let mut marker = Marker::new(heap, shapes);
+            for &root in roots {
+                marker.object(root);
+            }
+            marker.finish()
+        };
+        heap.sweep(&marks);
+        marks
+    }
+
+    #[test]
+    fn test_cycles() {
+        let mut heap = Heap::default();
+        let shapes = Shapes::default();
+        let reachable = list(&mut heap, vec![]);
+        let other = list(&mut heap, vec![Value::from(reachable)]);
+        let unreachable = list(&mut heap, vec![]);
+        let garbage = list(&mut heap, vec![Value::from(unreachable)]);
+        for (head, tail) in [(reachable, other), (unreachable, garbage)] {
+            if let Object::List(items) = heap.get_mut(head) {
+                items.push(Value::from(tail));
+            }
+        }
+        let marks = collect(&mut heap, &shapes, &[reachable]);
+        assert!(marks.is_marked(reachable) && marks.is_marked(other));
+        assert!(!marks.is_marked(unreachable) && !marks.is_marked(garbage));
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.stats.freed, 2);
+    }
+
+    #[test]
+    fn test_promises_and_closures() {
+        let mut heap = Heap::default();
+        let shapes = Shapes::default();
+        let captured = list(&mut heap, vec![]);
+        let cell = heap.alloc(Object::Cell(Value::from(captured)));
+        let callback = function(&mut heap, vec![cell]);
+        let chained = heap.alloc(Object::Promise(Promise::Pending(vec![])));
+        let reaction = Reaction::Then {
+            callback: Value::from(callback),
+            promise: chained,
+        };
+        let pending = heap.alloc(Object::Promise(Promise::Pending(vec![reaction])));
+        let result = list(&mut heap, vec![]);
+        let fulfilled = heap.alloc(Object::Promise(Promise::Fulfilled(Value::from(result))));
+        let dropped = heap.alloc(Object::Promise(Promise::Fulfilled(Value::from(captured))));
+
+        let marks = collect(&mut heap, &shapes, &[pending, fulfilled]);
+        for object in [callback, cell, captured, chained, result] {
+            assert!(marks.is_marked(object), "{:?}", heap.get(object));
+        }
+        assert!(!marks.is_marked(dropped));
+        assert_eq!(heap.len(), 7);
+    }
+
+    #[test]
+    fn test_cached_values_live_as_long_as_their_class() {
+        let mut heap = Heap::default();
+        let mut shapes = Shapes::default();
+        let point = class(&mut heap, "Point");
+        let line = class(&mut heap, "Line");
+        let point_method = function(&mut heap, vec![]);
+        let line_method = function(&mut heap, vec![]);
+        let str_method = function(&mut heap, vec![]);
+        let code = crate::compile_source("x.length()\n", "<test>").unwrap();
+        let mut site = CacheSite::default();
+        let point_shape = shapes.root(point);
+        site.insert(Receiver::Shape(point_shape), Lookup::Method(Value::from(point_method)));
+        site.insert(Receiver::Shape(shapes.root(line)), Lookup::Method(Value::from(line_method)));
+        *code.caches.borrow_mut() = vec![site];
+        let mut caches = crate::cache::Caches::default();
+        caches.insert_megamorphic(Receiver::Type("str"), "length", Lookup::Method(Value::from(str_method)));
+
+        // Only the instance of `Point` is alive: its class keeps the method
+        // cached for its shape alive, and builtin types live for good.
+        let instance = heap.alloc(Object::Instance(Box::new(crate::heap::Instance {
+            class: point,
+            shape: point_shape,
+            slots: crate::flat_vec::FlatVec::new(),
+        })));
+        let marks = {
+            let mut marker = Marker::new(&heap, &shapes);
+            marker.object(instance);
+            marker.code(&code);
+            caches.trace(&mut marker);
+            marker.finish()
+        };
+        heap.sweep(&marks);
+        for object in [instance, point, point_method, str_method] {
+            assert!(marks.is_marked(object), "{:?}", heap.get(object));
+        }
+        assert!(!marks.is_marked(line) && !marks.is_marked(line_method));
+        assert!(marks.is_code_alive(Rc::as_ptr(&code) as usize));
+        assert!(marks.is_receiver_alive(&shapes, Receiver::Shape(point_shape)));
+        assert!(!marks.is_receiver_alive(&shapes, Receiver::Class(line)));
+        assert_eq!(shapes.collect(|class| marks.is_marked(class)), 1);
+    }
+
+    #[test]
+    fn test_objects_rooted_only_from_frames() {
+        let source = "\
+def make():
+    kept = [[1, 2], 'kept']
+    ref = weakref(kept)
+    collect_garbage()
+    assert ref.get() is kept
+    return ref
+
+ref = make()
+collect_garbage()
+assert ref.get() is None
+";
+        let mut vm = Vm::new();
+        crate::run_source(&mut vm, source, "<test>").unwrap();
+        assert!(vm.gc_stats().freed > 0);
+    }
+}
@@ -0,0 +1,336 @@
+//! The objects a program allocates, and the heap that owns them.
+//!
+//! Objects refer to each other through `ObjRef` handles rather than Rust
+//! references, so reference cycles, like a closure stored in a variable it
+//! closes over, need no special care. The collector in `gc` frees the objects
+//! the program can't reach anymore, and their slots are used again.
+
+use crate::bytecode::CodeObject;
+use crate::event_loop::{Connection, Promise};
+use crate::flat_vec::FlatVec;
+use crate::gc::{self, GcStats, Marks};
+use crate::shape::ShapeId;
+use crate::value::Value;
+use crate::vm::{Generator, Vm, VmResult};
+use num_bigint::BigInt;
+use rustc_hash::FxHashMap;
+use std::net::TcpListener;
+use std::rc::Rc;
+
+/// A handle to an object on the heap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef(u32);
+
+impl ObjRef {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// The handle with `index`, which native code keeps as a number.
+    pub(crate) fn from_index(index: u32) -> ObjRef {
+        ObjRef(index)
+    }
+}
+
+pub type BuiltinFunction = fn(&mut Vm, Vec<Value>) -> VmResult<Value>;
+
+#[derive(Clone)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub function: BuiltinFunction,
+}
+
+impl std::fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<builtin {}>", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub code: Rc<CodeObject>,
+    pub defaults: Vec<Value>,
+    /// The cells of the enclosing frames that the code closes over.
+    pub closure: Vec<ObjRef>,
+    /// The class or module whose method this is, or that the method a block
+    /// was made in belongs to. `super()` calls continue after it.
+    pub owner: Option<ObjRef>,
+    /// The imported module whose attributes are the globals of the code, or
+    /// None for code of the program itself.
+    pub module: Option<ObjRef>,
+}
+
+/// A class or a module. Both have a method resolution order; only classes
+/// can be instantiated.
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub is_module: bool,
+    /// The classes and modules of the `extends` clause.
+    pub bases: Vec<ObjRef>,
+    /// The class itself and everything it includes, with the module included
+    /// last before the ones included earlier, as in `emerald_lang_analysis::mro`.
+    pub mro: Vec<ObjRef>,
+    pub namespace: FxHashMap<String, Value>,
+}
+
+/// An instance of a class. Native code reads its shape and slots in place.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Instance {
+    pub shape: ShapeId,
+    /// The class of the instance, which is also the class of its shape.
+    pub class: ObjRef,
+    /// The values of the attributes of the shape, by slot. A deleted attribute
+    /// leaves `Value::ABSENT` in its slot.
+    pub slots: FlatVec<Value>,
+}
+
+impl Instance {
+    /// Where native code finds the shape.
+    pub(crate) const SHAPE_OFFSET: i32 = std::mem::offset_of!(Instance, shape) as i32;
+    /// Where native code finds the pointer to the slots.
+    pub(crate) const SLOTS_OFFSET: i32 = std::mem::offset_of!(Instance, slots) as i32 + FlatVec::<Value>::PTR_OFFSET;
+
+    /// The value in `slot`, unless its attribute was deleted.
+    pub fn slot(&self, slot: usize) -> Option<&Value> {
+        Some(&self.slots[slot]).filter(|value| !value.is_absent())
+    }
+
+    /// Empty `slot`, returning what was in it.
+    pub fn take_slot(&mut self, slot: usize) -> Option<Value> {
+        let value = std::mem::replace(&mut self.slots[slot], Value::ABSENT);
+        (!value.is_absent()).then_some(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum Iter {
+    /// The items of a list or tuple, or the keys of a dict.
+    Sequence { sequence: ObjRef, index: usize },
+    Range { next: i64, stop: i64 },
+    /// The characters of a string, from a byte offset.
+    Chars { string: ObjRef, offset: usize },
+    /// The items a generator yields.
+    Generator(Box<Generator>),
+}
+
+#[derive(Debug)]
+pub enum Object {
+    /// An integer too big for a value. Never one that fits in one.
+    BigInt(BigInt),
+    Str(String),
+    List(Vec<Value>),
+    Tuple(Vec<Value>),
+    /// Keys and values in insertion order. Keys are compared by equality.
+    Dict(Vec<(Value, Value)>),
+    /// Items in insertion order, each once. Items are compared by equality.
+    Set(Vec<Value>),
+    /// The integers from `start` up to, but not including, `stop`.
+    Range { start: i64, stop: i64 },
+    Slice { lower: Value, upper: Value, step: Value },
+    Iterator(Iter),
+    Function(Function),
+    Builtin(Builtin),
+    /// A function or builtin bound to the object it is called on.
+    BoundMethod { receiver: Value, method: Value },
+    Class(Class),
+    /// Boxed, so it stays where native code finds it.
+    Instance(Box<Instance>),
+    /// A variable shared between a frame and the closures made in it.
+    Cell(Value),
+    /// A reference that doesn't keep its object alive, and is cleared when
+    /// the object is collected.
+    WeakRef(Option<ObjRef>),
+    /// A value that the event loop provides later, or an exception.
+    Promise(Promise),
+    /// A socket listening for TCP connections, until it is closed.
+    Server(Option<TcpListener>),
+    /// A TCP connection, until it is closed.
+    Connection(Option<Connection>),
+}
+
+impl Object {
+    /// The name of the type of the object, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::BigInt(_) => "int",
+            Object::Str(_) => "str",
+            Object::List(_) => "list",
+            Object::Tuple(_) => "tuple",
+            Object::Dict(_) => "dict",
+            Object::Set(_) => "set",
+            Object::Range { .. } => "range",
+            Object::Slice { .. } => "slice",
+            Object::Iterator(_) => "iterator",
+            Object::Function(_) => "function",
+            Object::Builtin(_) => "builtin",
+            Object::BoundMethod { .. } => "method",
+            Object::Class(class) if class.is_module => "module",
+            Object::Class(_) => "class",
+            Object::Instance(_) => "object",
+            Object::Cell(_) => "cell",
+            Object::WeakRef(_) => "weakref",
+            Object::Promise(_) => "promise",
+            Object::Server(_) => "server",
+            Object::Connection(_) => "connection",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Heap {
+    /// The objects by index, with `None` for free slots.
+    objects: Vec<Option<Object>>,
+    /// The instances among the objects, by the same index, and null for
+    /// everything else, for native code to find instances without asking.
+    /// An object that is an instance stays one until it is freed.
+    instances: FlatVec<*const Instance>,
+    /// The free slots, which are used before the heap grows.
+    free: Vec<u32>,
+    /// Objects allocated since the last collection.
+    allocated: usize,
+    /// How many allocations make the next collection due.
+    threshold: usize,
+    /// Whether every allocation makes a collection due.
+    stress: bool,
+    /// The objects with finalisers, and the callables to call once they are
+    /// collected.
+    finalisers: Vec<(ObjRef, Value)>,
+    pub stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: vec![],
+            instances: FlatVec::new(),
+            free: vec![],
+            allocated: 0,
+            threshold: gc::INITIAL_THRESHOLD,
+            stress: false,
+            finalisers: vec![],
+            stats: GcStats::default(),
+        }
+    }
+}
+
+impl Heap {
+    /// Where native code finds the pointer to the instance table.
+    pub(crate) const INSTANCES_OFFSET: i32 =
+        std::mem::offset_of!(Heap, instances) as i32 + FlatVec::<*const Instance>::PTR_OFFSET;
+
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        self.allocated += 1;
+        let instance = match &object {
+            Object::Instance(instance) => &**instance as *const Instance,
+            _ => std::ptr::null(),
+        };
+        if let Some(index) = self.free.pop() {
+            self.objects[index as usize] = Some(object);
+            self.instances[index as usize] = instance;
+            return ObjRef(index);
+        }
+        let index = self.objects.len().try_into().expect("heap over u32::MAX objects");
+        self.objects.push(Some(object));
+        self.instances.push(instance);
+        ObjRef(index)
+    }
+
+    pub fn get(&self, object: ObjRef) -> &Object {
+        self.objects[object.index()].as_ref().expect("use of a collected object")
+    }
+
+    pub fn get_mut(&mut self, object: ObjRef) -> &mut Object {
+        self.objects[object.index()].as_mut().expect("use of a collected object")
+    }
+
+    /// The number of live objects.
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of slots, live or free.
+    pub fn capacity(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn str(&self, object: ObjRef) -> Option<&str> {
+        match self.get(object) {
+            Object::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn class(&self, object: ObjRef) -> Option<&Class> {
+        match self.get(object) {
+            Object::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    /// Collect at every safe point after an allocation, to shake out objects
+    /// the roots miss.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    /// Whether enough was allocated for the machine to collect at its next
+    /// safe point.
+    pub fn collection_due(&self) -> bool {
+        self.allocated >= self.threshold || (self.stress && self.allocated > 0)
+    }
+
+    /// Call `finaliser` once `object` is collected.
+    pub fn add_finaliser(&mut self, object: ObjRef, finaliser: Value) {
+        self.finalisers.push((object, finaliser));
+    }
+
+    /// The finalisers, which are roots.
+    pub fn finalisers(&self) -> impl Iterator<Item = &Value> {
+        self.finalisers.iter().map(|(_, finaliser)| finaliser)
+    }
+
+    /// Free the objects that weren't marked, after clearing the weak
+    /// references to them. Returns the finalisers of the freed objects, for
+    /// the machine to call.
+    pub fn sweep(&mut self, marks: &Marks) -> Vec<Value> {
+        let mut due = vec![];
+        self.finalisers.retain(|(object, finaliser)| {
+            let alive = marks.is_marked(*object);
+            if !alive {
+                due.push(*finaliser);
+            }
+            alive
+        });
+        let mut freed = 0;
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            let object = match slot {
+                Some(object) => object,
+                None => continue,
+            };
+            if marks.is_marked(ObjRef(index as u32)) {
+                if let Object::WeakRef(target) = object {
+                    if target.is_some_and(|target| !marks.is_marked(target)) {
+                        *target = None;
+                    }
+                }
+            } else {
+                *slot = None;
+                self.instances[index] = std::ptr::null();
+                self.free.push(index as u32);
+                freed += 1;
+            }
+        }
+        self.allocated = 0;
+        self.threshold = gc::INITIAL_THRESHOLD.max(self.len() * gc::GROWTH_FACTOR);
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+        self.stats.live = self.len() as u64;
+        due
+    }
+}
@@ -0,0 +1,552 @@
+//! Images of compiled programs, which executables are built with.
+//!
+//! An image holds the code objects of a program and of the modules it
+//! imports, with their constants, names, source locations and the fields
+//! their classes declare, which are the shapes their instances start out
+//! with. An executable decodes its image instead of parsing and compiling
+//! the source again, and runs the code objects with the native code that was
+//! compiled for them next to it.
+//!
+//! Integers are little endian, strings and lists are prefixed with their
+//! length, and every code object is followed by the ones in its constants.
+//! An image only decodes with the version of the runtime that encoded it.
+
+use crate::ast::{ConversionFlag, Location};
+use crate::bytecode::{BinaryOperator, CodeKind, CodeObject, ComparisonOperator, Constant, Instruction, UnaryOperator};
+use crate::cache::CacheSite;
+use crate::program::Program;
+use num_bigint::BigInt;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// What an image starts with: the magic and the version of the runtime.
+const HEADER: &[u8] = concat!("emerald image ", env!("CARGO_PKG_VERSION"), "\n").as_bytes();
+
+const BINARY_OPERATORS: [BinaryOperator; 13] = [
+    BinaryOperator::Add,
+    BinaryOperator::Sub,
+    BinaryOperator::Mult,
+    BinaryOperator::MatMult,
+    BinaryOperator::Div,
+    BinaryOperator::Mod,
+    BinaryOperator::Pow,
+    BinaryOperator::LShift,
+    BinaryOperator::RShift,
+    BinaryOperator::BitOr,
+    BinaryOperator::BitXor,
+    BinaryOperator::BitAnd,
+    BinaryOperator::FloorDiv,
+];
+const UNARY_OPERATORS: [UnaryOperator; 4] = [
+    UnaryOperator::Invert,
+    UnaryOperator::Not,
+    UnaryOperator::Plus,
+    UnaryOperator::Minus,
+];
+const COMPARISON_OPERATORS: [ComparisonOperator; 10] = [
+    ComparisonOperator::Eq,
+    ComparisonOperator::NotEq,
+    ComparisonOperator::Lt,
+    ComparisonOperator::LtE,
+    ComparisonOperator::Gt,
+    ComparisonOperator::GtE,
+    ComparisonOperator::Is,
+    ComparisonOperator::IsNot,
+    ComparisonOperator::In,
+    ComparisonOperator::NotIn,
+];
+const CODE_KINDS: [CodeKind; 6] = [
+    CodeKind::Module,
+    CodeKind::Class,
+    CodeKind::Function,
+    CodeKind::Method,
+    CodeKind::Block,
+    CodeKind::Comprehension,
+];
+
+/// Encode `program`.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut encoder = Encoder(HEADER.to_vec());
+    encoder.code(&program.main);
+    encoder.u32(program.modules.len() as u32);
+    for (name, code) in &program.modules {
+        encoder.str(name);
+        encoder.code(code);
+    }
+    encoder.0
+}
+
+/// Decode the program `encode` encoded in `bytes`, or None if they aren't an
+/// image of this version of the runtime.
+pub fn decode(bytes: &[u8]) -> Option<Program> {
+    let mut decoder = Decoder {
+        bytes: bytes.strip_prefix(HEADER)?,
+    };
+    let main = decoder.code()?;
+    let modules = decoder.list(|decoder| Some((decoder.string()?, decoder.code()?)))?;
+    decoder.bytes.is_empty().then_some(Program { main, modules })
+}
+
+/// The index of `item` in `all`.
+fn index_of<T: PartialEq>(all: &[T], item: &T) -> u8 {
+    all.iter().position(|known| known == item).unwrap() as u8
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, string: &str) {
+        self.bytes(string.as_bytes());
+    }
+
+    fn strs(&mut self, strings: &[String]) {
+        self.u32(strings.len() as u32);
+        for string in strings {
+            self.str(string);
+        }
+    }
+
+    fn location(&mut self, location: Location) {
+        self.u32(location.row() as u32);
+        self.u32(location.column() as u32);
+    }
+
+    fn code(&mut self, code: &CodeObject) {
+        self.str(&code.name);
+        self.u8(index_of(&CODE_KINDS, &code.kind));
+        self.u8(code.is_async as u8);
+        self.u8(code.is_generator as u8);
+        self.str(&code.source_path);
+        self.location(code.location);
+        self.u32(code.arg_count);
+        self.u8(code.varargs as u8);
+        self.strs(&code.locals);
+        self.strs(&code.cells);
+        self.u32(code.cell_args.len() as u32);
+        for arg in &code.cell_args {
+            // No local has the largest index.
+            self.u32(arg.unwrap_or(u32::MAX));
+        }
+        self.strs(&code.frees);
+        self.strs(&code.names);
+        self.strs(&code.fields);
+        self.u32(code.instructions.len() as u32);
+        for (instruction, location) in code.instructions.iter().zip(&code.locations) {
+            self.instruction(instruction);
+            self.location(*location);
+        }
+        self.u32(code.caches.borrow().len() as u32);
+        self.u32(code.constants.len() as u32);
+        for constant in &code.constants {
+            self.constant(constant);
+        }
+    }
+
+    fn constant(&mut self, constant: &Constant) {
+        match constant {
+            Constant::None => self.u8(0),
+            Constant::Bool(value) => {
+                self.u8(1);
+                self.u8(*value as u8);
+            }
+            Constant::Int(value) => {
+                self.u8(2);
+                self.bytes(&value.to_signed_bytes_le());
+            }
+            Constant::Float(value) => {
+                self.u8(3);
+                self.0.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+            Constant::Str(value) => {
+                self.u8(4);
+                self.str(value);
+            }
+            Constant::Code(code) => {
+                self.u8(5);
+                self.code(code);
+            }
+        }
+    }
+
+    /// An opcode, followed by the operands.
+    fn instruction(&mut self, instruction: &Instruction) {
+        let (opcode, operands): (u8, &[u32]) = match *instruction {
+            Instruction::LoadConst(i) => (0, &[i]),
+            Instruction::Pop => (1, &[]),
+            Instruction::Dup => (2, &[]),
+            Instruction::DupTwo => (3, &[]),
+            Instruction::RotTwo => (4, &[]),
+            Instruction::RotThree => (5, &[]),
+            Instruction::LoadLocal(i) => (6, &[i]),
+            Instruction::StoreLocal(i) => (7, &[i]),
+            Instruction::LoadCell(i) => (8, &[i]),
+            Instruction::StoreCell(i) => (9, &[i]),
+            Instruction::LoadClosure(i) => (10, &[i]),
+            Instruction::LoadGlobal(i) => (11, &[i]),
+            Instruction::StoreGlobal(i) => (12, &[i]),
+            Instruction::LoadName(i) => (13, &[i]),
+            Instruction::StoreName(i) => (14, &[i]),
+            Instruction::LoadAttr { name, cache } => (15, &[name, cache]),
+            Instruction::StoreAttr { name, cache } => (16, &[name, cache]),
+            Instruction::DeleteAttr(i) => (17, &[i]),
+            Instruction::LoadSubscript => (18, &[]),
+            Instruction::StoreSubscript => (19, &[]),
+            Instruction::BinaryOp(op) => (20, &[index_of(&BINARY_OPERATORS, &op) as u32]),
+            Instruction::UnaryOp(op) => (21, &[index_of(&UNARY_OPERATORS, &op) as u32]),
+            Instruction::Compare(op) => (22, &[index_of(&COMPARISON_OPERATORS, &op) as u32]),
+            Instruction::Jump(target) => (23, &[target]),
+            Instruction::JumpIfFalse(target) => (24, &[target]),
+            Instruction::JumpIfTrue(target) => (25, &[target]),
+            Instruction::JumpIfFalseOrPop(target) => (26, &[target]),
+            Instruction::JumpIfTrueOrPop(target) => (27, &[target]),
+            Instruction::JumpIfNotNoneOrPop(target) => (28, &[target]),
+            Instruction::JumpIfNone(target) => (29, &[target]),
+            Instruction::GetIter => (30, &[]),
+            Instruction::ForIter(target) => (31, &[target]),
+            Instruction::BuildList(n) => (32, &[n]),
+            Instruction::BuildTuple(n) => (33, &[n]),
+            Instruction::BuildDict(n) => (34, &[n]),
+            Instruction::BuildString(n) => (35, &[n]),
+            Instruction::FormatValue(flag) => (36, &[flag as u32]),
+            Instruction::BuildRange { inclusive } => (37, &[inclusive as u32]),
+            Instruction::BuildSlice => (38, &[]),
+            Instruction::UnpackSequence(n) => (39, &[n]),
+            Instruction::MakeFunction { code, defaults } => (40, &[code, defaults]),
+            Instruction::Call(argc) => (41, &[argc]),
+            Instruction::CallMethod { name, argc, cache } => (42, &[name, argc, cache]),
+            Instruction::CallSuper { name, argc } => (43, &[name, argc]),
+            Instruction::Return => (44, &[]),
+            Instruction::BuildClass { name, bases, module } => (45, &[name, bases, module as u32]),
+            Instruction::ImportModule(i) => (46, &[i]),
+            Instruction::ImportName(i) => (47, &[i]),
+            Instruction::SetupExcept(target) => (48, &[target]),
+            Instruction::PopBlock => (49, &[]),
+            Instruction::ExceptionMatch => (50, &[]),
+            Instruction::PopExcept => (51, &[]),
+            Instruction::Raise => (52, &[]),
+            Instruction::Reraise => (53, &[]),
+            Instruction::Await => (54, &[]),
+            Instruction::CallKw(argc) => (55, &[argc]),
+            Instruction::LoadSuper(i) => (56, &[i]),
+            Instruction::Yield => (57, &[]),
+            Instruction::BuildSet(n) => (58, &[n]),
+            Instruction::ListAppend(n) => (59, &[n]),
+            Instruction::SetAdd(n) => (60, &[n]),
+            Instruction::MapAdd(n) => (61, &[n]),
+            Instruction::UnpackStarred { before, after } => (62, &[before, after]),
+            Instruction::BuildEnum { name, variants } => (63, &[name, variants]),
+            Instruction::MatchSequence { len, star } => (64, &[len, star as u32]),
+            Instruction::MatchKeys => (65, &[]),
+            Instruction::MatchClass(npos) => (66, &[npos]),
+            Instruction::DeleteGlobal(i) => (67, &[i]),
+            Instruction::DeleteName(i) => (68, &[i]),
+            Instruction::DeleteSubscript => (69, &[]),
+            Instruction::FormatWithSpec(flag) => (70, &[flag as u32]),
+        };
+        self.u8(opcode);
+        for &operand in operands {
+            self.u32(operand);
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Option<T>) -> Option<Vec<T>> {
+        let len = self.u32()?;
+        (0..len).map(|_| item(self)).collect()
+    }
+
+    fn strings(&mut self) -> Option<Vec<String>> {
+        self.list(Decoder::string)
+    }
+
+    fn location(&mut self) -> Option<Location> {
+        Some(Location::new(self.u32()? as usize, self.u32()? as usize))
+    }
+
+    fn code(&mut self) -> Option<Rc<CodeObject>> {
+        let name = self.string()?;
+        let kind = *CODE_KINDS.get(self.u8()? as usize)?;
+        let is_async = self.bool()?;
+        let is_generator = self.bool()?;
+        let source_path = self.string()?;
+        let location = self.location()?;
+        let arg_count = self.u32()?;
+        let varargs = self.bool()?;
+        let locals = self.strings()?;
+        let cells = self.strings()?;
+        let cell_args = self.list(|decoder| decoder.u32().map(|arg| (arg != u32::MAX).then_some(arg)))?;
+        let frees = self.strings()?;
+        let names = self.strings()?;
+        let fields = self.strings()?;
+        let (instructions, locations) = self
+            .list(|decoder| Some((decoder.instruction()?, decoder.location()?)))?
+            .into_iter()
+            .unzip();
+        let caches = vec![CacheSite::default(); self.u32()? as usize];
+        let constants = self.list(Decoder::constant)?;
+        Some(Rc::new(CodeObject {
+            name,
+            kind,
+            is_async,
+            is_generator,
+            source_path,
+            location,
+            arg_count,
+            varargs,
+            locals,
+            cells,
+            cell_args,
+            frees,
+            constants,
+            names,
+            instructions,
+            locations,
+            fields,
+            caches: RefCell::new(caches),
+        }))
+    }
+
+    fn constant(&mut self) -> Option<Constant> {
+        let constant = match self.u8()? {
+            0 => Constant::None,
+            1 => Constant::Bool(self.bool()?),
+            2 => Constant::Int(BigInt::from_signed_bytes_le(self.bytes()?)),
+            3 => Constant::Float(f64::from_bits(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))),
+            4 => Constant::Str(self.string()?),
+            5 => Constant::Code(self.code()?),
+            _ => return None,
+        };
+        Some(constant)
+    }
+
+    fn instruction(&mut self) -> Option<Instruction> {
+        let instruction = match self.u8()? {
+            0 => Instruction::LoadConst(self.u32()?),
+            1 => Instruction::Pop,
+            2 => Instruction::Dup,
+            3 => Instruction::DupTwo,
+            4 => Instruction::RotTwo,
+            5 => Instruction::RotThree,
+            6 => Instruction::LoadLocal(self.u32()?),
+            7 => Instruction::StoreLocal(self.u32()?),
+            8 => Instruction::LoadCell(self.u32()?),
+            9 => Instruction::StoreCell(self.u32()?),
+            10 => Instruction::LoadClosure(self.u32()?),
+            11 => Instruction::LoadGlobal(self.u32()?),
+            12 => Instruction::StoreGlobal(self.u32()?),
+            13 => Instruction::LoadName(self.u32()?),
+            14 => Instruction::StoreName(self.u32()?),
+            15 => Instruction::LoadAttr {
+                name: self.u32()?,
+                cache: self.u32()?,
+            },
+            16 => Instruction::StoreAttr {
+                name: self.u32()?,
+                cache: self.u32()?,
+            },
+            17 => Instruction::DeleteAttr(self.u32()?),
+            18 => Instruction::LoadSubscript,
+            19 => Instruction::StoreSubscript,
+            20 => Instruction::BinaryOp(*BINARY_OPERATORS.get(self.u32()? as usize)?),
+            21 => Instruction::UnaryOp(*UNARY_OPERATORS.get(self.u32()? as usize)?),
+            22 => Instruction::Compare(*COMPARISON_OPERATORS.get(self.u32()? as usize)?),
+            23 => Instruction::Jump(self.u32()?),
+            24 => Instruction::JumpIfFalse(self.u32()?),
+            25 => Instruction::JumpIfTrue(self.u32()?),
+            26 => Instruction::JumpIfFalseOrPop(self.u32()?),
+            27 => Instruction::JumpIfTrueOrPop(self.u32()?),
+            28 => Instruction::JumpIfNotNoneOrPop(self.u32()?),
+            29 => Instruction::JumpIfNone(self.u32()?),
+            30 => Instruction::GetIter,
+            31 => Instruction::ForIter(self.u32()?),
+            32 => Instruction::BuildList(self.u32()?),
+            33 => Instruction::BuildTuple(self.u32()?),
+            34 => Instruction::BuildDict(self.u32()?),
+            35 => Instruction::BuildString(self.u32()?),
+            36 => Instruction::FormatValue(ConversionFlag::try_from(self.u32()? as usize).ok()?),
+            37 => Instruction::BuildRange {
+                inclusive: self.u32()? != 0,
+            },
+            38 => Instruction::BuildSlice,
+            39 => Instruction::UnpackSequence(self.u32()?),
+            40 => Instruction::MakeFunction {
+                code: self.u32()?,
+                defaults: self.u32()?,
+            },
+            41 => Instruction::Call(self.u32()?),
+            42 => Instruction::CallMethod {
+                name: self.u32()?,
+                argc: self.u32()?,
+                cache: self.u32()?,
+            },
+            43 => Instruction::CallSuper {
+                name: self.u32()?,
+                argc: self.u32()?,
+            },
+            44 => Instruction::Return,
+            45 => Instruction::BuildClass {
+                name: self.u32()?,
+                bases: self.u32()?,
+                module: self.u32()? != 0,
+            },
+            46 => Instruction::ImportModule(self.u32()?),
+            47 => Instruction::ImportName(self.u32()?),
+            48 => Instruction::SetupExcept(self.u32()?),
+            49 => Instruction::PopBlock,
+            50 => Instruction::ExceptionMatch,
+            51 => Instruction::PopExcept,
+            52 => Instruction::Raise,
+            53 => Instruction::Reraise,
+            54 => Instruction::Await,
+            55 => Instruction::CallKw(self.u32()?),
+            56 => Instruction::LoadSuper(self.u32()?),
+            57 => Instruction::Yield,
+            58 => Instruction::BuildSet(self.u32()?),
+            59 => Instruction::ListAppend(self.u32()?),
+            60 => Instruction::SetAdd(self.u32()?),
+            61 => Instruction::MapAdd(self.u32()?),
+            62 => Instruction::UnpackStarred {
+                before: self.u32()?,
+                after: self.u32()?,
+            },
+            63 => Instruction::BuildEnum {
+                name: self.u32()?,
+                variants: self.u32()?,
+            },
+            64 => Instruction::MatchSequence {
+                len: self.u32()?,
+                star: self.u32()? != 0,
+            },
+            65 => Instruction::MatchKeys,
+            66 => Instruction::MatchClass(self.u32()?),
+            67 => Instruction::DeleteGlobal(self.u32()?),
+            68 => Instruction::DeleteName(self.u32()?),
+            69 => Instruction::DeleteSubscript,
+            70 => Instruction::FormatWithSpec(ConversionFlag::try_from(self.u32()? as usize).ok()?),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_source;
+
+    #[test]
+    fn test_round_trip() {
+        let source = "\
+module Organism:
+    energy: int
+
+class Amoeba extends Organism:
+    size: int
+    def initialize(self, size, *rest):
+        self.size = size
+        self.energy = -2 ** 70 + 0.5 // 1
+    def eat(self, other):
+        try:
+            other.energy += 1
+        except AttributeError as e:
+            raise
+        return super().eat(other, amount=1)
+
+async def main(xs, scale=2):
+    total = 0
+    seen = {k: [v for v in k] for k in (x for x in {y for y in xs})}
+    doubled = xs.map() do x:
+        total += x * scale
+        f'{x!r} {x}'
+    for x in doubled[1:2]:
+        if x is not None and not x in [1, (2, 3)] or {'a': 1...3}:
+            continue
+    del total.x
+    a, b = await main(xs)
+    return [~a, +b, a < b]
+
+def spawn(n):
+    yield n
+
+enum Token:
+    Word(text: str)
+    End
+
+def lex(token):
+    match token:
+        case Token.Word(text) | [text, *_] | {'text': text}:
+            return text
+
+import geometry
+from ..shapes import circle as c
+";
+        let main = compile_source(source, "main.em").unwrap();
+        let module = compile_source("x = 1.5\n", "geometry.em").unwrap();
+        let program = Program {
+            main,
+            modules: vec![("geometry".to_owned(), module)],
+        };
+        let image = encode(&program);
+        let decoded = decode(&image).unwrap();
+        assert_eq!(decoded.main.to_string(), program.main.to_string());
+        assert_eq!(decoded.modules[0].0, "geometry");
+        assert_eq!(decoded.modules[0].1.to_string(), program.modules[0].1.to_string());
+        assert_eq!(decoded.main.caches.borrow().len(), program.main.caches.borrow().len());
+        assert_eq!(encode(&decoded), image);
+
+        assert!(decode(&image[..image.len() - 1]).is_none());
+        assert!(decode(&image[1..]).is_none());
+    }
+}
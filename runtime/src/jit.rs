@@ -0,0 +1,737 @@
+//! A JIT compiler for specialisations, on Cranelift.
+//!
+//! A specialisation that is called often enough, or loops often enough, is
+//! compiled to native code. The native code keeps the state of its frame
+//! where the interpreter keeps it, in the frame and on the value stack, so it
+//! can be entered at any instruction: at the start of a call, at the loop
+//! header where the loop counter tripped, or at an exception handler after
+//! the machine unwound to it.
+//!
+//! Control flow is native: every instruction is a block, jumps are branches,
+//! and the instructions that may jump branch on the next instruction the
+//! runtime reports. Attribute loads and stores and method calls whose inline
+//! cache in the specialisation saw a single instance shape find the receiver
+//! on the value stack and its shape in the instance table of the heap, and
+//! compare the shape inline. Slot loads and stores then read and write the
+//! slots of the instance in place, and method calls call the method directly.
+//! Everything else calls back into the machine to run the instruction as the
+//! interpreter does. Code entered at the start of a call first checks that
+//! its arguments have the shapes of its specialisation.
+//!
+//! Native code deoptimises, handing its frame back to the interpreter in the
+//! middle of a call, when a guard fails, when a slot it loads was deleted,
+//! and when the code is invalidated
+//! because a class or module whose methods it calls directly changed. It
+//! checks whether it was invalidated after everything that may have run
+//! Emerald code. The frame map of the code says how deep the stack of the
+//! frame is at each instruction, which is where the interpreter resumes; the
+//! instruction the frame is at only lives in native code until then.
+
+use crate::bytecode::{CodeObject, Instruction};
+use crate::cache::{CacheSite, InlineCache, Lookup, Receiver};
+use crate::error::JitError;
+use crate::heap::{Instance, ObjRef};
+use crate::shape::ShapeId;
+use crate::value::Value;
+use crate::vm::Vm;
+use cranelift_codegen::ir::{
+    self, condcodes::IntCC, types, AbiParam, Block, BlockCall, FuncRef, InstBuilder, JumpTableData, MemFlags, Signature,
+    TrapCode,
+};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+/// How many calls of a specialisation make it compiled.
+pub const CALL_THRESHOLD: u64 = 100;
+/// How many backward jumps in calls of a specialisation make it compiled.
+pub const LOOP_THRESHOLD: u64 = 1000;
+
+/// What native code returns when the frame returned, with the value in
+/// `Vm::compiled_result`.
+pub(crate) const RETURNED: i32 = -2;
+/// What native code returns when an exception was raised, with the
+/// exception in `Vm::compiled_result`.
+pub(crate) const RAISED: i32 = -1;
+/// What native code returns when it deoptimised, and the interpreter runs
+/// the rest of the frame.
+pub(crate) const EXITED: i32 = -3;
+
+/// Native code that fails this many guards is thrown away, so it can be
+/// compiled again with what the inline caches saw since.
+pub const GUARD_FAILURE_LIMIT: u32 = 16;
+/// How often a specialisation is compiled again before it stays interpreted.
+pub const RECOMPILATION_LIMIT: u32 = 3;
+
+/// Native code of a specialisation. It runs the frame on top of the machine
+/// from the instruction it is given, and returns `RETURNED`, `RAISED` or
+/// `EXITED`.
+pub type CompiledCode = unsafe extern "C" fn(vm: *mut Vm, pc: u32) -> i32;
+
+/// Why native code handed its frame back to the interpreter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DeoptReason {
+    /// A receiver didn't have the shape an inline cache saw.
+    GuardFailed = 0,
+    /// A class or module the code depends on changed.
+    Invalidated = 1,
+}
+
+/// The native code of a specialisation, and what it assumes.
+pub struct Compiled {
+    pub entry: CompiledCode,
+    /// The depth of the stack of the frame before each instruction, where
+    /// the interpreter can take over.
+    pub frame_map: Vec<Option<u32>>,
+    /// The shapes of the receivers whose methods the code calls directly. It
+    /// depends on their classes, and everything those include.
+    pub method_shapes: Vec<ShapeId>,
+    /// The functions the code calls directly, which it keeps alive.
+    pub functions: Vec<ObjRef>,
+    /// Native code reads this in place, so it is boxed.
+    invalidated: Box<Cell<bool>>,
+    guard_failures: Cell<u32>,
+}
+
+impl Compiled {
+    /// Native code of `code` at `entry` that inlines nothing, and so is never
+    /// invalidated.
+    pub(crate) fn generic(entry: CompiledCode, code: &CodeObject) -> Self {
+        Compiled {
+            entry,
+            frame_map: code.stack_depths(),
+            method_shapes: vec![],
+            functions: vec![],
+            invalidated: Box::new(Cell::new(false)),
+            guard_failures: Cell::new(0),
+        }
+    }
+
+    /// Make the code deoptimise the next time it checks, and never run again.
+    pub fn invalidate(&self) {
+        self.invalidated.set(true);
+    }
+
+    pub fn is_invalidated(&self) -> bool {
+        self.invalidated.get()
+    }
+
+    /// Count a failed guard. Returns whether the code failed too many.
+    pub fn guard_failed(&self) -> bool {
+        self.guard_failures.set(self.guard_failures.get() + 1);
+        self.guard_failures.get() >= GUARD_FAILURE_LIMIT
+    }
+}
+
+impl fmt::Debug for Compiled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Compiled")
+            .field("method_shapes", &self.method_shapes)
+            .field("invalidated", &self.is_invalidated())
+            .finish()
+    }
+}
+
+/// When specialisations are compiled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JitMode {
+    /// Never; everything is interpreted.
+    Interpreter,
+    /// Once they are called or loop often enough.
+    #[default]
+    Tiered,
+    /// Before they first run.
+    Force,
+}
+
+impl FromStr for JitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(JitMode::Interpreter),
+            "tiered" => Ok(JitMode::Tiered),
+            "force" => Ok(JitMode::Force),
+            _ => Err(format!("unknown JIT mode '{}', expected off, tiered or force", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JitStats {
+    /// Specialisations compiled.
+    pub compiled: u64,
+    /// Specialisations that failed to compile, and stay interpreted.
+    pub failed: u64,
+    /// Times native code was entered.
+    pub entries: u64,
+    /// Times native code was entered at a loop header, from a frame the
+    /// interpreter was running.
+    pub loop_entries: u64,
+    /// Inline guards that failed, and deoptimised.
+    pub guard_failures: u64,
+    /// Frames native code handed back to the interpreter, because a guard
+    /// failed or because the code was invalidated.
+    pub deopts: u64,
+    /// Native code thrown away after failing too many guards.
+    pub discarded: u64,
+    /// Attribute loads and stores native code did in place.
+    pub inlined: u64,
+}
+
+impl fmt::Display for JitStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "compiled: {}", self.compiled)?;
+        writeln!(f, "failed to compile: {}", self.failed)?;
+        writeln!(f, "native entries: {}", self.entries)?;
+        writeln!(f, "loop entries: {}", self.loop_entries)?;
+        writeln!(f, "guard failures: {}", self.guard_failures)?;
+        writeln!(f, "deoptimisations: {}", self.deopts)?;
+        writeln!(f, "discarded: {}", self.discarded)?;
+        write!(f, "inlined attribute accesses: {}", self.inlined)
+    }
+}
+
+// The entry points native code calls back into. They return the next
+// instruction to run, or `RETURNED` or `RAISED`. They keep their names in
+// the runtime library, for executables built ahead of time to link against.
+
+#[no_mangle]
+extern "C" fn emerald_jit_step(vm: *mut Vm, pc: u32) -> i32 {
+    unsafe { &mut *vm }.jit_step(pc as usize)
+}
+
+/// Hand the frame to the interpreter at `pc`.
+#[no_mangle]
+extern "C" fn emerald_jit_deopt(vm: *mut Vm, pc: u32, reason: u32) -> i32 {
+    let reason = match reason {
+        0 => DeoptReason::GuardFailed,
+        _ => DeoptReason::Invalidated,
+    };
+    unsafe { &mut *vm }.jit_deopt(pc as usize, reason)
+}
+
+#[no_mangle]
+extern "C" fn emerald_jit_call_method(vm: *mut Vm, pc: u32, function: u32, argc: u32) -> i32 {
+    unsafe { &mut *vm }.jit_call_method(pc as usize, ObjRef::from_index(function), argc as usize)
+}
+
+/// The entry points, as declared in the module.
+struct Helpers {
+    step: FuncId,
+    deopt: FuncId,
+    call_method: FuncId,
+}
+
+/// The entry points, as imported in the function being compiled.
+struct HelperRefs {
+    step: FuncRef,
+    deopt: FuncRef,
+    call_method: FuncRef,
+}
+
+/// What an instruction can do without calling back into the machine, going
+/// by the inline cache of the specialisation.
+#[derive(Clone, Copy)]
+enum Inline {
+    LoadSlot { shape: ShapeId, slot: u32 },
+    StoreSlot { shape: ShapeId, slot: u32 },
+    CallMethod { shape: ShapeId, function: ObjRef, argc: u32 },
+}
+
+/// The target native code is compiled for: the machine the compiler runs on.
+/// Code for executables has to be position independent.
+pub(crate) fn native_isa(is_pic: bool) -> Result<OwnedTargetIsa, JitError> {
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false")?;
+    flags.set("is_pic", if is_pic { "true" } else { "false" })?;
+    flags.set("opt_level", "speed")?;
+    let isa = cranelift_native::builder()
+        .map_err(|message| JitError(message.to_owned()))?
+        .finish(settings::Flags::new(flags))?;
+    Ok(isa)
+}
+
+/// Lowers code objects to Cranelift functions, in the JIT or in an object
+/// file.
+pub(crate) struct Lowering {
+    builder_context: FunctionBuilderContext,
+    helpers: Helpers,
+}
+
+impl Lowering {
+    /// Declare the entry points in `module`, for the code lowered into it.
+    pub(crate) fn new(module: &mut impl Module) -> Result<Self, JitError> {
+        let pointer = module.target_config().pointer_type();
+        let mut declare = |name: &str, params: &[types::Type], returns: types::Type| {
+            let mut signature = module.make_signature();
+            signature.params.push(AbiParam::new(pointer));
+            signature.params.extend(params.iter().map(|&param| AbiParam::new(param)));
+            signature.returns.push(AbiParam::new(returns));
+            module.declare_function(name, Linkage::Import, &signature).map_err(JitError::from)
+        };
+        let i32 = types::I32;
+        let helpers = Helpers {
+            step: declare("emerald_jit_step", &[i32], i32)?,
+            deopt: declare("emerald_jit_deopt", &[i32, i32], i32)?,
+            call_method: declare("emerald_jit_call_method", &[i32, i32, i32], i32)?,
+        };
+        Ok(Lowering {
+            builder_context: FunctionBuilderContext::new(),
+            helpers,
+        })
+    }
+
+    /// The signature of native code, `CompiledCode`.
+    pub(crate) fn signature(module: &impl Module) -> Signature {
+        let pointer = module.target_config().pointer_type();
+        Signature {
+            params: vec![AbiParam::new(pointer), AbiParam::new(types::I32)],
+            returns: vec![AbiParam::new(types::I32)],
+            call_conv: module.target_config().default_call_conv,
+        }
+    }
+
+    /// Lower `code` into the function of `context`, doing inline what
+    /// `inlined` says for each instruction, for calls with `arguments`. Code
+    /// that can be invalidated checks the flag at the address `flag`.
+    fn lower(
+        &mut self,
+        module: &mut impl Module,
+        context: &mut Context,
+        code: &CodeObject,
+        arguments: &[Receiver],
+        inlined: Vec<Option<Inline>>,
+        flag: Option<i64>,
+    ) {
+        let pointer = module.target_config().pointer_type();
+        context.func.signature = Lowering::signature(module);
+        let mut import = |id| module.declare_func_in_func(id, &mut context.func);
+        let helpers = HelperRefs {
+            step: import(self.helpers.step),
+            deopt: import(self.helpers.deopt),
+            call_method: import(self.helpers.call_method),
+        };
+
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        let start = builder.create_block();
+        builder.append_block_param(start, types::I32);
+        let blocks: Vec<Block> = code.instructions.iter().map(|_| builder.create_block()).collect();
+        let dispatch = builder.create_block();
+        builder.append_block_param(dispatch, types::I32);
+        let done = builder.create_block();
+        builder.append_block_param(done, types::I32);
+        let trap = builder.create_block();
+        let invalidate = builder.create_block();
+        builder.append_block_param(invalidate, types::I32);
+
+        builder.switch_to_block(entry);
+        let vm = builder.block_params(entry)[0];
+        let pc = builder.block_params(entry)[1];
+        builder.ins().jump(start, &[pc]);
+
+        // Enter at any instruction, or continue after one that jumped.
+        builder.switch_to_block(dispatch);
+        let target = builder.block_params(dispatch)[0];
+        let default = builder.func.dfg.block_call(trap, &[]);
+        let table: Vec<BlockCall> = blocks
+            .iter()
+            .map(|&block| builder.func.dfg.block_call(block, &[]))
+            .collect();
+        let table = builder.create_jump_table(JumpTableData::new(default, &table));
+        builder.ins().br_table(target, table);
+
+        builder.switch_to_block(done);
+        let status = builder.block_params(done)[0];
+        builder.ins().return_(&[status]);
+
+        builder.switch_to_block(trap);
+        builder.ins().trap(TrapCode::unwrap_user(1));
+
+        // Hand the frame to the interpreter at the instruction in the
+        // parameter, which is where it would have gone on.
+        builder.switch_to_block(invalidate);
+        let pc = builder.block_params(invalidate)[0];
+        let reason = builder.ins().iconst(types::I32, DeoptReason::Invalidated as i64);
+        let call = builder.ins().call(helpers.deopt, &[vm, pc, reason]);
+        let status = builder.inst_results(call)[0];
+        builder.ins().return_(&[status]);
+
+        let mut compiler = FunctionCompiler {
+            builder,
+            helpers,
+            vm,
+            blocks,
+            dispatch,
+            done,
+            trap,
+            invalidate,
+            flag,
+            pointer,
+        };
+        // The arguments past those the code takes are in a tuple.
+        let arguments = &arguments[..arguments.len().min(code.arg_count as usize)];
+        compiler.start(start, arguments);
+        for (pc, (instruction, inlined)) in code.instructions.iter().zip(inlined).enumerate() {
+            compiler.instruction(pc, instruction, inlined);
+        }
+        compiler.builder.seal_all_blocks();
+        compiler.builder.finalize();
+    }
+
+    /// Lower `code` into a function of its own in `module`, without inlining
+    /// anything, so it depends on nothing and is never invalidated.
+    pub(crate) fn define_generic(
+        &mut self,
+        module: &mut impl Module,
+        id: FuncId,
+        code: &CodeObject,
+    ) -> Result<(), JitError> {
+        let mut context = module.make_context();
+        let inlined = code.instructions.iter().map(|_| None).collect();
+        self.lower(module, &mut context, code, &[], inlined, None);
+        module.define_function(id, &mut context)?;
+        module.clear_context(&mut context);
+        Ok(())
+    }
+}
+
+pub struct Jit {
+    module: JITModule,
+    lowering: Lowering,
+}
+
+impl Jit {
+    pub fn new() -> Result<Self, JitError> {
+        let mut builder = JITBuilder::with_isa(native_isa(false)?, default_libcall_names());
+        builder.symbol("emerald_jit_step", emerald_jit_step as *const u8);
+        builder.symbol("emerald_jit_deopt", emerald_jit_deopt as *const u8);
+        builder.symbol("emerald_jit_call_method", emerald_jit_call_method as *const u8);
+        let mut module = JITModule::new(builder);
+        let lowering = Lowering::new(&mut module)?;
+        Ok(Jit { module, lowering })
+    }
+
+    /// Compile `code` without inlining anything, the way it is compiled
+    /// ahead of time.
+    #[cfg(test)]
+    pub(crate) fn compile_generic(&mut self, code: &CodeObject) -> Result<Compiled, JitError> {
+        let id = self.module.declare_anonymous_function(&Lowering::signature(&self.module))?;
+        self.lowering.define_generic(&mut self.module, id, code)?;
+        self.module.finalize_definitions()?;
+        let function = self.module.get_finalized_function(id);
+        let entry = unsafe { std::mem::transmute::<*const u8, CompiledCode>(function) };
+        Ok(Compiled::generic(entry, code))
+    }
+
+    /// Compile the code of the specialisation for `arguments`, inlining what
+    /// its inline caches saw in `epoch`.
+    pub fn compile(&mut self, code: &CodeObject, arguments: &[Receiver], epoch: u64) -> Result<Compiled, JitError> {
+        let inlined: Vec<Option<Inline>> = {
+            let sites = code.caches.borrow();
+            code.instructions
+                .iter()
+                .map(|instruction| inline(instruction, &sites, epoch))
+                .collect()
+        };
+        let (method_shapes, functions) = inlined
+            .iter()
+            .filter_map(|inlined| match inlined {
+                Some(Inline::CallMethod { shape, function, .. }) => Some((*shape, *function)),
+                _ => None,
+            })
+            .unzip();
+        let invalidated = Box::new(Cell::new(false));
+
+        let mut context = self.module.make_context();
+        let flag = invalidated.as_ptr() as i64;
+        self.lowering
+            .lower(&mut self.module, &mut context, code, arguments, inlined, Some(flag));
+        let id = self.module.declare_anonymous_function(&context.func.signature)?;
+        self.module.define_function(id, &mut context)?;
+        self.module.clear_context(&mut context);
+        self.module.finalize_definitions()?;
+        let function = self.module.get_finalized_function(id);
+        Ok(Compiled {
+            entry: unsafe { std::mem::transmute::<*const u8, CompiledCode>(function) },
+            frame_map: code.stack_depths(),
+            method_shapes,
+            functions,
+            invalidated,
+            guard_failures: Cell::new(0),
+        })
+    }
+}
+
+/// What `instruction` can do inline, if its site only saw one shape.
+fn inline(instruction: &Instruction, sites: &[CacheSite], epoch: u64) -> Option<Inline> {
+    let (cache, argc) = match *instruction {
+        Instruction::LoadAttr { cache, .. } | Instruction::StoreAttr { cache, .. } => (cache, 0),
+        Instruction::CallMethod { cache, argc, .. } => (cache, argc),
+        _ => return None,
+    };
+    let site = &sites[cache as usize];
+    if !site.is_current(epoch) {
+        return None;
+    }
+    let (shape, lookup) = match &site.cache {
+        InlineCache::Monomorphic(Receiver::Shape(shape), lookup) => (*shape, lookup),
+        _ => return None,
+    };
+    match (instruction, lookup) {
+        (Instruction::LoadAttr { .. }, Lookup::Slot(slot)) => Some(Inline::LoadSlot {
+            shape,
+            slot: *slot as u32,
+        }),
+        (Instruction::StoreAttr { .. }, Lookup::Slot(slot)) => Some(Inline::StoreSlot {
+            shape,
+            slot: *slot as u32,
+        }),
+        (Instruction::CallMethod { .. }, Lookup::Method(method)) => method.as_object().map(|function| Inline::CallMethod {
+            shape,
+            function,
+            argc,
+        }),
+        _ => None,
+    }
+}
+
+struct FunctionCompiler<'a> {
+    builder: FunctionBuilder<'a>,
+    helpers: HelperRefs,
+    /// The machine, the first parameter of the function.
+    vm: ir::Value,
+    /// The block of each instruction.
+    blocks: Vec<Block>,
+    /// Jumps to the instruction in its parameter.
+    dispatch: Block,
+    /// Returns its parameter.
+    done: Block,
+    /// For instructions past the end, which the compiler never jumps to.
+    trap: Block,
+    /// Deoptimises because the code was invalidated, going on with the
+    /// instruction in its parameter.
+    invalidate: Block,
+    /// The address of the flag that says whether the code was invalidated,
+    /// for code that can be.
+    flag: Option<i64>,
+    pointer: types::Type,
+}
+
+impl FunctionCompiler<'_> {
+    fn block(&self, pc: usize) -> Block {
+        self.blocks.get(pc).copied().unwrap_or(self.trap)
+    }
+
+    fn call(&mut self, helper: FuncRef, args: &[i64]) -> ir::Value {
+        let mut values = vec![self.vm];
+        values.extend(args.iter().map(|&arg| self.builder.ins().iconst(types::I32, arg)));
+        let call = self.builder.ins().call(helper, &values);
+        self.builder.inst_results(call)[0]
+    }
+
+    fn load(&mut self, ty: types::Type, address: ir::Value, offset: i32) -> ir::Value {
+        self.builder.ins().load(ty, MemFlags::trusted(), address, offset)
+    }
+
+    fn store(&mut self, value: ir::Value, address: ir::Value, offset: i32) {
+        self.builder.ins().store(MemFlags::trusted(), value, address, offset);
+    }
+
+    /// Deoptimise at `pc` because a guard failed, in a block of its own.
+    fn guard_failure(&mut self, pc: usize) -> Block {
+        let current = self.builder.current_block().unwrap();
+        let slow = self.builder.create_block();
+        self.builder.switch_to_block(slow);
+        let status = self.call(self.helpers.deopt, &[pc as i64, DeoptReason::GuardFailed as i64]);
+        self.builder.ins().jump(self.done, &[status]);
+        self.builder.switch_to_block(current);
+        slow
+    }
+
+    /// Check the arguments of a call against `arguments`, before entering at
+    /// the instruction in the parameter of `start`, which is only a call when
+    /// it is the first. An instance has to have the shape it had, and a class
+    /// or module has to be the same one. Any other value only has to not be
+    /// an instance, which is all the code assumes of it.
+    fn start(&mut self, start: Block, arguments: &[Receiver]) {
+        self.builder.switch_to_block(start);
+        let pc = self.builder.block_params(start)[0];
+        if arguments.is_empty() {
+            self.builder.ins().jump(self.dispatch, &[pc]);
+            return;
+        }
+        let guards = self.builder.create_block();
+        let called = self.builder.ins().icmp_imm(IntCC::Equal, pc, 0);
+        self.builder.ins().brif(called, guards, &[], self.dispatch, &[pc]);
+
+        self.builder.switch_to_block(guards);
+        let slow = self.guard_failure(0);
+        let locals = self.load(self.pointer, self.vm, Vm::LOCALS_OFFSET);
+        for (i, argument) in arguments.iter().enumerate() {
+            let value = self.load(types::I64, locals, i as i32 * 8);
+            let checked = self.builder.create_block();
+            match *argument {
+                Receiver::Shape(shape) => {
+                    let instance = self.instance(value, slow);
+                    self.check_shape(instance, shape, checked, slow);
+                }
+                Receiver::Class(class) => {
+                    let bits = Value::from(class).to_bits() as i64;
+                    let same = self.builder.ins().icmp_imm(IntCC::Equal, value, bits);
+                    self.builder.ins().brif(same, checked, &[], slow, &[]);
+                }
+                Receiver::Type(_) => {
+                    let instance = self.instance(value, checked);
+                    self.builder.ins().brif(instance, slow, &[], checked, &[]);
+                }
+            }
+            self.builder.switch_to_block(checked);
+        }
+        self.builder.ins().jump(self.dispatch, &[pc]);
+    }
+
+    /// The address of the entry `depth` entries below the top of the stack.
+    fn stack_entry(&mut self, depth: u32) -> ir::Value {
+        let stack = self.load(self.pointer, self.vm, Vm::STACK_OFFSET);
+        let len = self.load(self.pointer, self.vm, Vm::STACK_LEN_OFFSET);
+        let index = self.builder.ins().iadd_imm(len, -(depth as i64 + 1));
+        let offset = self.builder.ins().ishl_imm(index, 3);
+        self.builder.ins().iadd(stack, offset)
+    }
+
+    /// The instance `value` is, going on in the block that is current after,
+    /// or null in `otherwise`, where it goes if `value` isn't an object.
+    fn instance(&mut self, value: ir::Value, otherwise: Block) -> ir::Value {
+        let object = self.builder.create_block();
+        let tag = self.builder.ins().ushr_imm(value, Value::TAG_SHIFT as i64);
+        let is_object = self.builder.ins().icmp_imm(IntCC::Equal, tag, Value::OBJECT_TAG as i64);
+        self.builder.ins().brif(is_object, object, &[], otherwise, &[]);
+
+        self.builder.switch_to_block(object);
+        let index = self.builder.ins().band_imm(value, 0xFFFF_FFFF);
+        let offset = self.builder.ins().imul_imm(index, self.pointer.bytes() as i64);
+        let table = self.load(self.pointer, self.vm, Vm::INSTANCES_OFFSET);
+        let entry = self.builder.ins().iadd(table, offset);
+        self.load(self.pointer, entry, 0)
+    }
+
+    /// Go to `matched` if `instance` isn't null and has `shape`, and to
+    /// `slow` if it hasn't.
+    fn check_shape(&mut self, instance: ir::Value, shape: ShapeId, matched: Block, slow: Block) {
+        let check = self.builder.create_block();
+        self.builder.ins().brif(instance, check, &[], slow, &[]);
+        self.builder.switch_to_block(check);
+        let actual = self.load(types::I32, instance, Instance::SHAPE_OFFSET);
+        let matches = self.builder.ins().icmp_imm(IntCC::Equal, actual, shape.index() as i64);
+        self.builder.ins().brif(matches, matched, &[], slow, &[]);
+    }
+
+    /// Count an attribute access done in place.
+    fn count_inlined(&mut self) {
+        let count = self.load(types::I64, self.vm, Vm::INLINED_OFFSET);
+        let count = self.builder.ins().iadd_imm(count, 1);
+        self.store(count, self.vm, Vm::INLINED_OFFSET);
+    }
+
+    fn instruction(&mut self, pc: usize, instruction: &Instruction, inlined: Option<Inline>) {
+        self.builder.switch_to_block(self.blocks[pc]);
+        if let Instruction::Jump(target) = *instruction {
+            let target = self.block(target as usize);
+            self.builder.ins().jump(target, &[]);
+            return;
+        }
+        let next = match inlined {
+            Some(Inline::LoadSlot { shape, slot }) => self.guarded(pc, 0, shape, |compiler, receiver, slots, slow| {
+                // A deleted attribute is looked up in the class, which the
+                // interpreter does.
+                let value = compiler.load(types::I64, slots, slot as i32 * 8);
+                let present = compiler.builder.create_block();
+                let absent = compiler.builder.ins().icmp_imm(IntCC::Equal, value, Value::ABSENT.to_bits() as i64);
+                compiler.builder.ins().brif(absent, slow, &[], present, &[]);
+                compiler.builder.switch_to_block(present);
+                compiler.store(value, receiver, 0);
+                compiler.count_inlined();
+                compiler.builder.ins().iconst(types::I32, pc as i64 + 1)
+            }),
+            Some(Inline::StoreSlot { shape, slot }) => self.guarded(pc, 0, shape, |compiler, receiver, slots, _| {
+                let value = compiler.load(types::I64, receiver, -8);
+                compiler.store(value, slots, slot as i32 * 8);
+                let len = compiler.load(compiler.pointer, compiler.vm, Vm::STACK_LEN_OFFSET);
+                let len = compiler.builder.ins().iadd_imm(len, -2);
+                compiler.store(len, compiler.vm, Vm::STACK_LEN_OFFSET);
+                compiler.count_inlined();
+                compiler.builder.ins().iconst(types::I32, pc as i64 + 1)
+            }),
+            // The call itself sets up a frame, which only the machine does.
+            Some(Inline::CallMethod { shape, function, argc }) => self.guarded(pc, argc, shape, |compiler, _, _, _| {
+                let args = [pc as i64, function.index() as i64, argc as i64];
+                compiler.call(compiler.helpers.call_method, &args)
+            }),
+            None => self.call(self.helpers.step, &[pc as i64]),
+        };
+        self.successor(pc, instruction.jump_target(), next);
+    }
+
+    /// Check that the receiver `depth` entries below the top of the stack is
+    /// an instance of `shape`, and run `fast` if it is, or deoptimise at `pc`
+    /// if it isn't. `fast` gets the address of the receiver on the stack,
+    /// the slots of the instance, and the block that deoptimises, and
+    /// returns the next instruction.
+    fn guarded(
+        &mut self,
+        pc: usize,
+        depth: u32,
+        shape: ShapeId,
+        fast: impl FnOnce(&mut Self, ir::Value, ir::Value, Block) -> ir::Value,
+    ) -> ir::Value {
+        let slow = self.guard_failure(pc);
+        let fast_block = self.builder.create_block();
+        let receiver = self.stack_entry(depth);
+        let value = self.load(types::I64, receiver, 0);
+        let instance = self.instance(value, slow);
+        self.check_shape(instance, shape, fast_block, slow);
+
+        self.builder.switch_to_block(fast_block);
+        let slots = self.load(self.pointer, instance, Instance::SLOTS_OFFSET);
+        fast(self, receiver, slots, slow)
+    }
+
+    /// Continue with the instruction `next` says: the one after `pc`, the
+    /// jump target of the instruction at `pc`, or any other through the
+    /// dispatch block. A negative `next` is returned, and if the code was
+    /// invalidated it deoptimises instead.
+    fn successor(&mut self, pc: usize, target: Option<u32>, next: ir::Value) {
+        let running = self.builder.create_block();
+        let stopped = self.builder.ins().icmp_imm(IntCC::SignedLessThan, next, 0);
+        self.builder.ins().brif(stopped, self.done, &[next], running, &[]);
+        self.builder.switch_to_block(running);
+        if let Some(flag) = self.flag {
+            let valid = self.builder.create_block();
+            let flag = self.builder.ins().iconst(self.pointer, flag);
+            let invalidated = self.builder.ins().load(types::I8, MemFlags::trusted(), flag, 0);
+            self.builder.ins().brif(invalidated, self.invalidate, &[next], valid, &[]);
+            self.builder.switch_to_block(valid);
+        }
+
+        if let Some(target) = target {
+            let other = self.builder.create_block();
+            let target_block = self.block(target as usize);
+            let jumped = self.builder.ins().icmp_imm(IntCC::Equal, next, target as i64);
+            self.builder.ins().brif(jumped, target_block, &[], other, &[]);
+            self.builder.switch_to_block(other);
+        }
+        let next_block = self.block(pc + 1);
+        let fell_through = self.builder.ins().icmp_imm(IntCC::Equal, next, pc as i64 + 1);
+        self.builder.ins().brif(fell_through, next_block, &[], self.dispatch, &[next]);
+    }
+}
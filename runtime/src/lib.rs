@@ -0,0 +1,49 @@
+//! A bytecode compiler and virtual machine for Emerald.
+//!
+//! A parsed program is compiled to code objects by `compile`, which the
+//! stack based machine in `vm` runs. Code objects print as their
+//! disassembly, with the source location of every instruction. Hot code is
+//! compiled to native code by `jit`, and whole programs ahead of time by
+//! `aot`. Coroutines, promises and I/O run on the single-threaded event
+//! loop of `event_loop`. `program` finds and compiles the modules a program
+//! imports.
+
+pub use emerald_lang_ast as ast;
+
+pub mod aot;
+pub mod builtins;
+pub mod bytecode;
+pub mod cache;
+pub mod compile;
+pub mod error;
+pub mod event_loop;
+pub mod flat_vec;
+pub mod gc;
+pub mod heap;
+pub mod image;
+pub mod jit;
+mod ops;
+pub mod program;
+pub mod shape;
+pub mod specialise;
+pub mod symbols;
+pub mod value;
+pub mod vm;
+
+use bytecode::CodeObject;
+use error::Error;
+use std::rc::Rc;
+use value::Value;
+use vm::Vm;
+
+/// Parse and compile a program.
+pub fn compile_source(source: &str, source_path: &str) -> Result<Rc<CodeObject>, Error> {
+    let body = emerald_lang_parser::parser::parse_program(source, source_path)?;
+    Ok(compile::compile_program(&body, source_path)?)
+}
+
+/// Parse, compile and run a program on `vm`.
+pub fn run_source(vm: &mut Vm, source: &str, source_path: &str) -> Result<Value, Error> {
+    let code = compile_source(source, source_path)?;
+    Ok(vm.run(code)?)
+}
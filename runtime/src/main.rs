@@ -0,0 +1,101 @@
+use emerald_lang_runtime::{aot, error::Error, jit::JitMode, program, vm::Vm};
+use std::path::Path;
+use std::process::exit;
+
+const USAGE: &str = "usage: emerald [--jit off | tiered | force] [--gc-stress] \
+(run | build | dis | shapes | specialisations | stats) <file>";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut mode = JitMode::default();
+    let mut gc_stress = false;
+    let mut rest = args.as_slice();
+    loop {
+        match rest {
+            [flag, value, tail @ ..] if flag == "--jit" => {
+                mode = match value.parse() {
+                    Ok(mode) => mode,
+                    Err(error) => {
+                        eprintln!("{}", error);
+                        exit(2);
+                    }
+                };
+                rest = tail;
+            }
+            [flag, tail @ ..] if flag == "--gc-stress" => {
+                gc_stress = true;
+                rest = tail;
+            }
+            _ => break,
+        }
+    }
+    let (command, path) = match rest {
+        [command, path] => (command.as_str(), path.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    let new_vm = || {
+        let mut vm = Vm::new();
+        vm.set_jit_mode(mode);
+        vm.set_gc_stress(gc_stress);
+        vm
+    };
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("can't read {}: {}", path, error);
+            exit(1);
+        }
+    };
+    let result = program::load(&source, path).and_then(|program| match command {
+        "run" => new_vm().run_program(program).map(|_| ()).map_err(Error::from),
+        // Build an executable named after the file, in the working directory.
+        "build" => {
+            let output = Path::new(path).with_extension("");
+            let output = Path::new(output.file_name().unwrap_or(output.as_os_str()));
+            aot::build(&program, output).map_err(Error::from)
+        }
+        // Run the program, then show the shapes its objects got.
+        "shapes" => {
+            let mut vm = new_vm();
+            vm.run_program(program)?;
+            print!("{}", vm.dump_shapes());
+            Ok(())
+        }
+        // Run the program, then show the specialisations its functions got.
+        "specialisations" => {
+            let mut vm = new_vm();
+            vm.run_program(program)?;
+            print!("{}", vm.dump_specialisations());
+            Ok(())
+        }
+        // Run the program, then show how the inline caches, the
+        // specialisations, the JIT and the collector did.
+        "stats" => {
+            let mut vm = new_vm();
+            vm.run_program(program)?;
+            println!("{}", vm.cache_stats());
+            println!("{}", vm.specialisation_stats());
+            println!("{}", vm.jit_stats());
+            println!("{}", vm.gc_stats());
+            Ok(())
+        }
+        "dis" => {
+            print!("{}", program.main);
+            for (_, code) in &program.modules {
+                print!("\n\n{}", code);
+            }
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    });
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        exit(1);
+    }
+}
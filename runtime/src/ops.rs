@@ -0,0 +1,1158 @@
+//! The operators, conversions and iteration of the builtin types.
+//!
+//! Numbers behave like Python's: integers don't overflow, `/` always divides
+//! to a float, and `//` and `%` round towards negative infinity. Booleans are
+//! the integers 0 and 1 in arithmetic. Integers that fit in a value take a
+//! fast path, and results that don't fit anymore go on the heap.
+//!
+//! The format specifications of f-strings, like the `>8.2f` of
+//! `f'{price:>8.2f}'`, follow Python's mini-language too, for strings and
+//! numbers. Anything else formats as its string.
+
+use crate::bytecode::{BinaryOperator, ComparisonOperator, UnaryOperator};
+use crate::event_loop::Promise;
+use crate::heap::{Iter, ObjRef, Object};
+use crate::value::{Unboxed, Value};
+use crate::vm::{ErrorKind, Raised, Vm, VmResult};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{Signed, ToPrimitive, Zero};
+use std::cmp::Ordering;
+
+enum Number {
+    Int(BigInt),
+    Float(f64),
+}
+
+/// `op` on two integers that fit in values, if the result does too and `op`
+/// can't fail. Anything else takes the way through `BigInt`.
+fn small_int_op(op: BinaryOperator, a: i64, b: i64) -> Option<Value> {
+    let result = match op {
+        BinaryOperator::Add => a + b,
+        BinaryOperator::Sub => a - b,
+        BinaryOperator::Mult => a.checked_mul(b)?,
+        BinaryOperator::Div if b != 0 => return Some(Value::from(a as f64 / b as f64)),
+        BinaryOperator::FloorDiv if b != 0 => Integer::div_floor(&a, &b),
+        BinaryOperator::Mod if b != 0 => Integer::mod_floor(&a, &b),
+        BinaryOperator::BitOr => a | b,
+        BinaryOperator::BitXor => a ^ b,
+        BinaryOperator::BitAnd => a & b,
+        _ => return None,
+    };
+    Value::int(result)
+}
+
+fn int_to_f64(i: &BigInt) -> f64 {
+    i.to_f64().unwrap_or(f64::INFINITY)
+}
+
+impl Number {
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(i) => int_to_f64(i),
+            Number::Float(x) => *x,
+        }
+    }
+}
+
+fn format_float(x: f64) -> String {
+    if x.is_nan() {
+        "nan".to_owned()
+    } else {
+        format!("{:?}", x)
+    }
+}
+
+/// `x` in scientific notation with `precision` digits after the point, and
+/// at least two digits of exponent.
+fn scientific(x: f64, precision: usize, upper: bool) -> String {
+    let formatted = format!("{:.*e}", precision, x);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let e = if upper { 'E' } else { 'e' };
+    format!("{}{}{}{:02}", mantissa, e, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+/// `x` with `precision` significant digits, in scientific notation if its
+/// exponent is below -4 or not below the precision, without trailing zeros
+/// unless `alternate`.
+fn general(x: f64, precision: usize, alternate: bool, upper: bool) -> String {
+    let precision = precision.max(1);
+    let exponent: i32 = match x {
+        0.0 => 0,
+        _ => format!("{:.*e}", precision - 1, x).split_once('e').unwrap().1.parse().unwrap(),
+    };
+    let (mut mantissa, exponent) = if -4 <= exponent && exponent < precision as i32 {
+        (format!("{:.*}", (precision as i32 - 1 - exponent) as usize, x), String::new())
+    } else {
+        let formatted = scientific(x, precision - 1, upper);
+        let at = formatted.find(['e', 'E']).unwrap();
+        (formatted[..at].to_owned(), formatted[at..].to_owned())
+    };
+    if !alternate && mantissa.contains('.') {
+        mantissa.truncate(mantissa.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    mantissa + &exponent
+}
+
+/// `digits` with `separator` between every group of `every` of them, from
+/// the right.
+fn group_digits(digits: &str, separator: char, every: usize) -> String {
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(every) {
+            grouped.push(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// A parsed format specification.
+struct FormatSpec {
+    fill: char,
+    align: Option<char>,
+    sign: char,
+    alternate: bool,
+    width: usize,
+    grouping: Option<char>,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+impl FormatSpec {
+    /// Parse `[[fill]align][sign][#][0][width][,|_][.precision][type]`.
+    fn parse(spec: &str) -> Option<FormatSpec> {
+        let chars: Vec<char> = spec.chars().collect();
+        let mut format = FormatSpec {
+            fill: ' ',
+            align: None,
+            sign: '-',
+            alternate: false,
+            width: 0,
+            grouping: None,
+            precision: None,
+            kind: None,
+        };
+        let is_align = |c: &char| "<>^=".contains(*c);
+        let mut i = 0;
+        if chars.get(1).is_some_and(is_align) {
+            format.fill = chars[0];
+            format.align = Some(chars[1]);
+            i = 2;
+        } else if chars.first().is_some_and(is_align) {
+            format.align = Some(chars[0]);
+            i = 1;
+        }
+        if let Some(&sign) = chars.get(i).filter(|c| "+- ".contains(**c)) {
+            format.sign = sign;
+            i += 1;
+        }
+        if chars.get(i) == Some(&'#') {
+            format.alternate = true;
+            i += 1;
+        }
+        if chars.get(i) == Some(&'0') {
+            if format.align.is_none() {
+                format.fill = '0';
+                format.align = Some('=');
+            }
+            i += 1;
+        }
+        let digits = |i: &mut usize| {
+            let start = *i;
+            while chars.get(*i).is_some_and(char::is_ascii_digit) {
+                *i += 1;
+            }
+            chars[start..*i].iter().collect::<String>().parse::<usize>().ok()
+        };
+        format.width = digits(&mut i).unwrap_or(0);
+        if let Some(&grouping) = chars.get(i).filter(|c| **c == ',' || **c == '_') {
+            format.grouping = Some(grouping);
+            i += 1;
+        }
+        if chars.get(i) == Some(&'.') {
+            i += 1;
+            format.precision = Some(digits(&mut i)?);
+        }
+        format.kind = chars.get(i).copied();
+        i += format.kind.is_some() as usize;
+        (i == chars.len()).then_some(format)
+    }
+
+    /// `sign` and `body` padded with the fill to the width, aligned as the
+    /// specification says or else to `default`. `=` pads between them.
+    fn pad(&self, sign: &str, body: &str, default: char) -> String {
+        let padding = self.width.saturating_sub(sign.chars().count() + body.chars().count());
+        let fill = |n: usize| self.fill.to_string().repeat(n);
+        match self.align.unwrap_or(default) {
+            '<' => format!("{}{}{}", sign, body, fill(padding)),
+            '^' => format!("{}{}{}{}", fill(padding / 2), sign, body, fill(padding - padding / 2)),
+            '=' => format!("{}{}{}", sign, fill(padding), body),
+            _ => format!("{}{}{}", fill(padding), sign, body),
+        }
+    }
+}
+
+fn repr_str(s: &str) -> String {
+    let mut repr = String::with_capacity(s.len() + 2);
+    repr.push('\'');
+    for c in s.chars() {
+        match c {
+            '\'' => repr.push_str("\\'"),
+            '\\' => repr.push_str("\\\\"),
+            '\n' => repr.push_str("\\n"),
+            '\r' => repr.push_str("\\r"),
+            '\t' => repr.push_str("\\t"),
+            c => repr.push(c),
+        }
+    }
+    repr.push('\'');
+    repr
+}
+
+/// The positions a slice from `lower` to `upper` by `step` selects in a
+/// sequence of `len` items, like Python's `slice.indices`.
+fn slice_positions(lower: Option<i64>, upper: Option<i64>, step: i64, len: usize) -> Vec<usize> {
+    let len = len as i64;
+    let clamp = |i: i64| {
+        if i < 0 {
+            (i + len).max(if step > 0 { 0 } else { -1 })
+        } else {
+            i.min(if step > 0 { len } else { len - 1 })
+        }
+    };
+    let (default_lower, default_upper) = if step > 0 { (0, len) } else { (len - 1, -1) };
+    let mut i = lower.map_or(default_lower, clamp);
+    let upper = upper.map_or(default_upper, clamp);
+    let mut positions = vec![];
+    while (step > 0 && i < upper) || (step < 0 && i > upper) {
+        positions.push(i as usize);
+        i += step;
+    }
+    positions
+}
+
+impl Vm {
+    /// The integer `i`, on the heap if it doesn't fit in a value.
+    pub fn int(&mut self, i: i64) -> Value {
+        Value::int(i).unwrap_or_else(|| Value::from(self.heap.alloc(Object::BigInt(i.into()))))
+    }
+
+    /// The integer `i`, in a value if it fits.
+    pub fn alloc_int(&mut self, i: BigInt) -> Value {
+        match i.to_i64().and_then(Value::int) {
+            Some(value) => value,
+            None => Value::from(self.heap.alloc(Object::BigInt(i))),
+        }
+    }
+
+    /// The integer in `value`, wherever it is. Booleans aren't integers here.
+    pub fn integer(&self, value: &Value) -> Option<BigInt> {
+        match value.unbox() {
+            Unboxed::Int(i) => Some(i.into()),
+            Unboxed::Object(object) => match self.heap.get(object) {
+                Object::BigInt(i) => Some(i.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn number(&self, value: &Value) -> Option<Number> {
+        match value.unbox() {
+            Unboxed::Bool(b) => Some(Number::Int((b as u8).into())),
+            Unboxed::Float(x) => Some(Number::Float(x)),
+            _ => self.integer(value).map(Number::Int),
+        }
+    }
+
+    /// The name of the type of `value`, or of its class, for error messages.
+    pub fn type_name(&self, value: &Value) -> String {
+        match value.unbox() {
+            Unboxed::None => "NoneType".to_owned(),
+            Unboxed::Bool(_) => "bool".to_owned(),
+            Unboxed::Int(_) => "int".to_owned(),
+            Unboxed::Float(_) => "float".to_owned(),
+            Unboxed::Object(object) => match self.heap.get(object) {
+                Object::Instance(instance) => self.heap.class(instance.class).unwrap().name.clone(),
+                object => object.type_name().to_owned(),
+            },
+        }
+    }
+
+    pub fn is_exception(&self, object: ObjRef) -> bool {
+        match self.heap.get(object) {
+            Object::Instance(instance) => {
+                let exception = self.error_class(ErrorKind::Exception);
+                self.heap.class(instance.class).unwrap().mro.contains(&exception)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn truthy(&self, value: &Value) -> bool {
+        match value.unbox() {
+            Unboxed::None => false,
+            Unboxed::Bool(b) => b,
+            Unboxed::Int(i) => i != 0,
+            Unboxed::Float(x) => x != 0.0,
+            Unboxed::Object(object) => match self.heap.get(object) {
+                Object::Str(s) => !s.is_empty(),
+                Object::List(items) | Object::Tuple(items) | Object::Set(items) => !items.is_empty(),
+                Object::Dict(entries) => !entries.is_empty(),
+                Object::Range { start, stop } => start < stop,
+                _ => true,
+            },
+        }
+    }
+
+    /// `value` converted to a string, as by `str()`.
+    pub fn to_str(&self, value: &Value) -> String {
+        if let Some(object) = value.as_object() {
+            match self.heap.get(object) {
+                Object::Str(s) => return s.clone(),
+                Object::Instance(instance) if self.is_exception(object) => {
+                    return self
+                        .attribute(instance, "message")
+                        .map_or_else(String::new, |message| self.to_str(message));
+                }
+                _ => {}
+            }
+        }
+        self.repr(value)
+    }
+
+    /// `value` formatted by the format specification `spec` of an f-string.
+    pub fn format(&mut self, value: &Value, spec: &str) -> VmResult<String> {
+        let format = match FormatSpec::parse(spec) {
+            Some(format) => format,
+            None => return Err(self.error(ErrorKind::ValueError, format!("Invalid format specifier '{}'", spec))),
+        };
+        let number = match value.unbox() {
+            Unboxed::Bool(_) if format.kind.is_none() => None,
+            _ => self.number(value),
+        };
+        let unknown = |vm: &mut Vm, kind: char| {
+            let message = format!("Unknown format code '{}' for object of type '{}'", kind, vm.type_name(value));
+            Err(vm.error(ErrorKind::ValueError, message))
+        };
+        let (negative, prefix, body) = match (number, format.kind) {
+            (None, None | Some('s')) => {
+                if format.sign != '-' || format.alternate || format.grouping.is_some() || format.align == Some('=') {
+                    let message = "Sign, alternate form, grouping and '=' alignment aren't allowed for strings";
+                    return Err(self.error(ErrorKind::ValueError, message));
+                }
+                let string = self.to_str(value);
+                let string = match format.precision {
+                    Some(precision) => string.chars().take(precision).collect(),
+                    None => string,
+                };
+                return Ok(format.pad("", &string, '<'));
+            }
+            (None, Some(kind)) => return unknown(self, kind),
+            (Some(Number::Int(i)), None | Some('d' | 'n' | 'b' | 'o' | 'x' | 'X' | 'c')) => {
+                if format.precision.is_some() {
+                    return Err(self.error(ErrorKind::ValueError, "Precision not allowed in integer format specifier"));
+                }
+                let (radix, prefix) = match format.kind {
+                    Some('b') => (2, "0b"),
+                    Some('o') => (8, "0o"),
+                    Some('x') => (16, "0x"),
+                    Some('X') => (16, "0X"),
+                    _ => (10, ""),
+                };
+                let magnitude = i.magnitude().to_str_radix(radix);
+                let body = match (format.kind, format.grouping) {
+                    (Some('c'), _) => match i.to_u32().and_then(char::from_u32) {
+                        Some(c) => c.to_string(),
+                        None => return Err(self.error(ErrorKind::OverflowError, "%c arg not in range(0x110000)")),
+                    },
+                    (Some('X'), _) => magnitude.to_uppercase(),
+                    (_, Some(separator)) => group_digits(&magnitude, separator, if radix == 10 { 3 } else { 4 }),
+                    _ => magnitude,
+                };
+                (i.is_negative(), if format.alternate { prefix } else { "" }, body)
+            }
+            (Some(number), None | Some('e' | 'E' | 'f' | 'F' | 'g' | 'G' | 'n' | '%')) => {
+                let x = number.to_f64();
+                let upper = matches!(format.kind, Some('E' | 'F' | 'G'));
+                let body = if !x.is_finite() {
+                    let body = if x.is_nan() { "nan" } else { "inf" };
+                    if upper { body.to_uppercase() } else { body.to_owned() }
+                } else {
+                    let precision = format.precision.unwrap_or(6);
+                    match format.kind {
+                        Some('e' | 'E') => scientific(x.abs(), precision, upper),
+                        Some('f' | 'F') => format!("{:.*}", precision, x.abs()),
+                        Some('%') => format!("{:.*}", precision, x.abs() * 100.0),
+                        Some(_) => general(x.abs(), precision, format.alternate, upper),
+                        // Like `str()` without a precision, and like `g`
+                        // with at least one digit after the point with one.
+                        None => match format.precision {
+                            Some(precision) => {
+                                let body = general(x.abs(), precision, format.alternate, false);
+                                match body.contains(['.', 'e']) {
+                                    true => body,
+                                    false => body + ".0",
+                                }
+                            }
+                            None => format_float(x.abs()),
+                        },
+                    }
+                };
+                let body = match format.grouping {
+                    Some(separator) => {
+                        let end = body.find(|c: char| !c.is_ascii_digit()).unwrap_or(body.len());
+                        group_digits(&body[..end], separator, 3) + &body[end..]
+                    }
+                    None => body,
+                };
+                let body = if format.kind == Some('%') { body + "%" } else { body };
+                (x.is_sign_negative() && !x.is_nan(), "", body)
+            }
+            (Some(_), Some(kind)) => return unknown(self, kind),
+        };
+        let sign = match (negative, format.sign) {
+            (true, _) => "-",
+            (false, '+') => "+",
+            (false, ' ') => " ",
+            _ => "",
+        };
+        Ok(format.pad(&format!("{}{}", sign, prefix), &body, '>'))
+    }
+
+    /// `value` converted to a string the way it would be written in a program,
+    /// as by `repr()`.
+    pub fn repr(&self, value: &Value) -> String {
+        let object = match value.unbox() {
+            Unboxed::None => return "None".to_owned(),
+            Unboxed::Bool(true) => return "True".to_owned(),
+            Unboxed::Bool(false) => return "False".to_owned(),
+            Unboxed::Int(i) => return i.to_string(),
+            Unboxed::Float(x) => return format_float(x),
+            Unboxed::Object(object) => object,
+        };
+        let join = |items: &[Value]| items.iter().map(|item| self.repr(item)).collect::<Vec<_>>().join(", ");
+        match self.heap.get(object) {
+            Object::BigInt(i) => i.to_string(),
+            Object::Str(s) => repr_str(s),
+            Object::List(items) => format!("[{}]", join(items)),
+            Object::Tuple(items) if items.len() == 1 => format!("({},)", join(items)),
+            Object::Tuple(items) => format!("({})", join(items)),
+            Object::Dict(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", self.repr(key), self.repr(value)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Object::Set(items) if items.is_empty() => "set()".to_owned(),
+            Object::Set(items) => format!("{{{}}}", join(items)),
+            Object::Range { start, stop } => format!("range({}, {})", start, stop),
+            Object::Slice { lower, upper, step } => {
+                format!("slice({}, {}, {})", self.repr(lower), self.repr(upper), self.repr(step))
+            }
+            Object::Iterator(_) => "<iterator>".to_owned(),
+            Object::Function(function) => format!("<function {}>", function.code.name),
+            Object::Builtin(builtin) => format!("<builtin {}>", builtin.name),
+            Object::BoundMethod { receiver, method } => {
+                let name = match method.as_object().map(|method| self.heap.get(method)) {
+                    Some(Object::Function(function)) => function.code.name.clone(),
+                    Some(Object::Builtin(builtin)) => builtin.name.to_owned(),
+                    _ => "?".to_owned(),
+                };
+                format!("<bound method {} of {}>", name, self.repr(receiver))
+            }
+            Object::Class(class) if class.is_module => format!("<module {}>", class.name),
+            Object::Class(class) => format!("<class {}>", class.name),
+            Object::Instance(instance) => {
+                let name = &self.heap.class(instance.class).unwrap().name;
+                if self.is_exception(object) {
+                    let message = self.attribute(instance, "message").cloned().unwrap_or(Value::NONE);
+                    format!("{}({})", name, self.repr(&message))
+                } else {
+                    format!("<{} object>", name)
+                }
+            }
+            Object::Cell(_) => "<cell>".to_owned(),
+            Object::WeakRef(Some(target)) => format!("<weakref to {}>", self.repr(&Value::from(*target))),
+            Object::WeakRef(None) => "<weakref dead>".to_owned(),
+            Object::Promise(Promise::Pending(_)) => "<promise pending>".to_owned(),
+            Object::Promise(Promise::Fulfilled(value)) => format!("<promise fulfilled {}>", self.repr(value)),
+            Object::Promise(Promise::Rejected(raised)) => {
+                format!("<promise rejected {}>", self.repr(&Value::from(raised.exception)))
+            }
+            Object::Server(Some(listener)) => match listener.local_addr() {
+                Ok(address) => format!("<server {}>", address),
+                Err(_) => "<server>".to_owned(),
+            },
+            Object::Server(None) => "<server closed>".to_owned(),
+            Object::Connection(Some(connection)) => match connection.stream.peer_addr() {
+                Ok(address) => format!("<connection to {}>", address),
+                Err(_) => "<connection>".to_owned(),
+            },
+            Object::Connection(None) => "<connection closed>".to_owned(),
+        }
+    }
+
+    fn unsupported_operands(&mut self, op: BinaryOperator, left: &Value, right: &Value) -> Raised {
+        let message = format!(
+            "unsupported operand type(s) for {}: '{}' and '{}'",
+            op.symbol(),
+            self.type_name(left),
+            self.type_name(right)
+        );
+        self.error(ErrorKind::TypeError, message)
+    }
+
+    pub fn binary_op(&mut self, op: BinaryOperator, left: &Value, right: &Value) -> VmResult<Value> {
+        if let (Some(a), Some(b)) = (left.as_int(), right.as_int()) {
+            if let Some(result) = small_int_op(op, a, b) {
+                return Ok(result);
+            }
+        }
+        if let (Some(a), Some(b)) = (self.number(left), self.number(right)) {
+            return match (a, b) {
+                (Number::Int(a), Number::Int(b)) => self.int_op(op, a, b, left, right),
+                (a, b) => self.float_op(op, a.to_f64(), b.to_f64(), left, right),
+            };
+        }
+        let objects = (
+            left.as_object().map(|object| self.heap.get(object)),
+            right.as_object().map(|object| self.heap.get(object)),
+        );
+        let result = match (op, objects) {
+            (BinaryOperator::Add, (Some(Object::Str(a)), Some(Object::Str(b)))) => Object::Str(format!("{}{}", a, b)),
+            (BinaryOperator::Add, (Some(Object::List(a)), Some(Object::List(b)))) => {
+                Object::List(a.iter().chain(b).cloned().collect())
+            }
+            (BinaryOperator::Add, (Some(Object::Tuple(a)), Some(Object::Tuple(b)))) => {
+                Object::Tuple(a.iter().chain(b).cloned().collect())
+            }
+            (BinaryOperator::Mult, (Some(sequence), None)) | (BinaryOperator::Mult, (None, Some(sequence))) => {
+                let count = match (left.as_int(), right.as_int()) {
+                    (Some(count), _) | (_, Some(count)) => count.try_into().unwrap_or(0),
+                    _ => return Err(self.unsupported_operands(op, left, right)),
+                };
+                match sequence {
+                    Object::Str(s) => Object::Str(s.repeat(count)),
+                    Object::List(items) => Object::List(items.iter().cycle().take(items.len() * count).cloned().collect()),
+                    Object::Tuple(items) => Object::Tuple(items.iter().cycle().take(items.len() * count).cloned().collect()),
+                    _ => return Err(self.unsupported_operands(op, left, right)),
+                }
+            }
+            _ => return Err(self.unsupported_operands(op, left, right)),
+        };
+        Ok(Value::from(self.heap.alloc(result)))
+    }
+
+    fn int_op(&mut self, op: BinaryOperator, a: BigInt, b: BigInt, left: &Value, right: &Value) -> VmResult<Value> {
+        let result = match op {
+            BinaryOperator::Add => a + b,
+            BinaryOperator::Sub => a - b,
+            BinaryOperator::Mult => a * b,
+            BinaryOperator::Div => {
+                if b.is_zero() {
+                    return Err(self.error(ErrorKind::ZeroDivisionError, "division by zero"));
+                }
+                return Ok(Value::from(int_to_f64(&a) / int_to_f64(&b)));
+            }
+            BinaryOperator::FloorDiv | BinaryOperator::Mod if b.is_zero() => {
+                return Err(self.error(ErrorKind::ZeroDivisionError, "integer division or modulo by zero"));
+            }
+            BinaryOperator::FloorDiv => a.div_floor(&b),
+            BinaryOperator::Mod => a.mod_floor(&b),
+            BinaryOperator::Pow => {
+                if b.is_negative() {
+                    return self.float_op(op, int_to_f64(&a), int_to_f64(&b), left, right);
+                }
+                match b.to_u32() {
+                    Some(exponent) => a.pow(exponent),
+                    None => return Err(self.error(ErrorKind::OverflowError, "exponent too large")),
+                }
+            }
+            BinaryOperator::LShift | BinaryOperator::RShift => {
+                if b.is_negative() {
+                    return Err(self.error(ErrorKind::ValueError, "negative shift count"));
+                }
+                match (op, b.to_usize()) {
+                    (BinaryOperator::LShift, Some(shift)) => a << shift,
+                    (_, Some(shift)) => a >> shift,
+                    (BinaryOperator::LShift, None) => {
+                        return Err(self.error(ErrorKind::OverflowError, "shift count too large"))
+                    }
+                    (_, None) if a.is_negative() => BigInt::from(-1),
+                    (_, None) => BigInt::zero(),
+                }
+            }
+            BinaryOperator::BitOr => a | b,
+            BinaryOperator::BitXor => a ^ b,
+            BinaryOperator::BitAnd => a & b,
+            BinaryOperator::MatMult => return Err(self.unsupported_operands(op, left, right)),
+        };
+        Ok(self.alloc_int(result))
+    }
+
+    fn float_op(&mut self, op: BinaryOperator, a: f64, b: f64, left: &Value, right: &Value) -> VmResult<Value> {
+        let result = match op {
+            BinaryOperator::Add => a + b,
+            BinaryOperator::Sub => a - b,
+            BinaryOperator::Mult => a * b,
+            BinaryOperator::Div | BinaryOperator::FloorDiv | BinaryOperator::Mod if b == 0.0 => {
+                return Err(self.error(ErrorKind::ZeroDivisionError, "float division by zero"));
+            }
+            BinaryOperator::Div => a / b,
+            BinaryOperator::FloorDiv => (a / b).floor(),
+            BinaryOperator::Mod => {
+                let remainder = a % b;
+                if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+                    remainder + b
+                } else {
+                    remainder
+                }
+            }
+            BinaryOperator::Pow => {
+                if a == 0.0 && b < 0.0 {
+                    return Err(self.error(
+                        ErrorKind::ZeroDivisionError,
+                        "0.0 cannot be raised to a negative power",
+                    ));
+                }
+                a.powf(b)
+            }
+            _ => return Err(self.unsupported_operands(op, left, right)),
+        };
+        Ok(Value::from(result))
+    }
+
+    pub fn unary_op(&mut self, op: UnaryOperator, operand: &Value) -> VmResult<Value> {
+        if let (UnaryOperator::Minus, Some(i)) = (op, operand.as_int()) {
+            if let Some(result) = Value::int(-i) {
+                return Ok(result);
+            }
+        }
+        let result = match (op, self.number(operand)) {
+            (UnaryOperator::Not, _) => Value::from(!self.truthy(operand)),
+            (UnaryOperator::Plus, Some(Number::Int(i))) => self.alloc_int(i),
+            (UnaryOperator::Plus, Some(Number::Float(x))) => Value::from(x),
+            (UnaryOperator::Minus, Some(Number::Int(i))) => self.alloc_int(-i),
+            (UnaryOperator::Minus, Some(Number::Float(x))) => Value::from(-x),
+            (UnaryOperator::Invert, Some(Number::Int(i))) => self.alloc_int(!i),
+            _ => {
+                let symbol = match op {
+                    UnaryOperator::Invert => "~",
+                    UnaryOperator::Plus => "+",
+                    _ => "-",
+                };
+                let message = format!("bad operand type for unary {}: '{}'", symbol, self.type_name(operand));
+                return Err(self.error(ErrorKind::TypeError, message));
+            }
+        };
+        Ok(result)
+    }
+
+    pub fn compare(&mut self, op: ComparisonOperator, left: &Value, right: &Value) -> VmResult<bool> {
+        let ordering = match op {
+            ComparisonOperator::Eq => return Ok(self.equals(left, right)),
+            ComparisonOperator::NotEq => return Ok(!self.equals(left, right)),
+            ComparisonOperator::Is => return Ok(left == right),
+            ComparisonOperator::IsNot => return Ok(left != right),
+            ComparisonOperator::In => return self.contains(right, left),
+            ComparisonOperator::NotIn => return self.contains(right, left).map(|contains| !contains),
+            _ => self.ordering(op, left, right)?,
+        };
+        Ok(match ordering {
+            None => false,
+            Some(ordering) => match op {
+                ComparisonOperator::Lt => ordering == Ordering::Less,
+                ComparisonOperator::LtE => ordering != Ordering::Greater,
+                ComparisonOperator::Gt => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            },
+        })
+    }
+
+    /// How `left` orders against `right`, or `None` if either is NaN.
+    fn ordering(&mut self, op: ComparisonOperator, left: &Value, right: &Value) -> VmResult<Option<Ordering>> {
+        if let (Some(a), Some(b)) = (left.as_int(), right.as_int()) {
+            return Ok(Some(a.cmp(&b)));
+        }
+        if let (Some(a), Some(b)) = (self.number(left), self.number(right)) {
+            return Ok(match (a, b) {
+                (Number::Int(a), Number::Int(b)) => Some(a.cmp(&b)),
+                (a, b) => a.to_f64().partial_cmp(&b.to_f64()),
+            });
+        }
+        let objects = (
+            left.as_object().map(|object| self.heap.get(object)),
+            right.as_object().map(|object| self.heap.get(object)),
+        );
+        let (a, b) = match objects {
+            (Some(Object::Str(a)), Some(Object::Str(b))) => return Ok(Some(a.cmp(b))),
+            (Some(Object::List(a)), Some(Object::List(b))) | (Some(Object::Tuple(a)), Some(Object::Tuple(b))) => {
+                (a.clone(), b.clone())
+            }
+            _ => {
+                let message = format!(
+                    "'{}' not supported between instances of '{}' and '{}'",
+                    op.symbol(),
+                    self.type_name(left),
+                    self.type_name(right)
+                );
+                return Err(self.error(ErrorKind::TypeError, message));
+            }
+        };
+        for (a, b) in a.iter().zip(&b) {
+            if !self.equals(a, b) {
+                return self.ordering(op, a, b);
+            }
+        }
+        Ok(Some(a.len().cmp(&b.len())))
+    }
+
+    /// Whether `left == right`: numbers are equal by value, and strings and
+    /// containers by their contents.
+    pub fn equals(&self, left: &Value, right: &Value) -> bool {
+        if let (Some(a), Some(b)) = (left.as_int(), right.as_int()) {
+            return a == b;
+        }
+        if let (Some(a), Some(b)) = (self.number(left), self.number(right)) {
+            return match (a, b) {
+                (Number::Int(a), Number::Int(b)) => a == b,
+                (a, b) => a.to_f64() == b.to_f64(),
+            };
+        }
+        let (a, b) = match (left.as_object(), right.as_object()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return left == right,
+        };
+        if a == b {
+            return true;
+        }
+        match (self.heap.get(a), self.heap.get(b)) {
+            (Object::Str(a), Object::Str(b)) => a == b,
+            (Object::List(a), Object::List(b)) | (Object::Tuple(a), Object::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| self.equals(a, b))
+            }
+            (Object::Dict(a), Object::Dict(b)) => {
+                a.len() == b.len()
+                    && a.iter().all(|(key, value)| {
+                        b.iter()
+                            .any(|(other_key, other_value)| self.equals(key, other_key) && self.equals(value, other_value))
+                    })
+            }
+            (Object::Set(a), Object::Set(b)) => {
+                a.len() == b.len() && a.iter().all(|item| b.iter().any(|other| self.equals(item, other)))
+            }
+            (Object::Range { start, stop }, Object::Range { start: a, stop: b }) => start == a && stop == b,
+            _ => false,
+        }
+    }
+
+    /// Whether `item in container`.
+    pub fn contains(&mut self, container: &Value, item: &Value) -> VmResult<bool> {
+        if let Some(object) = container.as_object() {
+            match self.heap.get(object) {
+                Object::Str(s) => {
+                    return match item.as_object().and_then(|item| self.heap.str(item)) {
+                        Some(part) => Ok(s.contains(part)),
+                        None => {
+                            let message =
+                                format!("'in <string>' requires string as left operand, not {}", self.type_name(item));
+                            Err(self.error(ErrorKind::TypeError, message))
+                        }
+                    };
+                }
+                Object::List(items) | Object::Tuple(items) | Object::Set(items) => {
+                    return Ok(items.iter().any(|x| self.equals(x, item)))
+                }
+                Object::Dict(entries) => return Ok(entries.iter().any(|(key, _)| self.equals(key, item))),
+                Object::Range { start, stop } => {
+                    let item = self.integer(item).and_then(|i| i.to_i64());
+                    return Ok(item.is_some_and(|i| *start <= i && i < *stop));
+                }
+                _ => {}
+            }
+        }
+        let message = format!("argument of type '{}' is not iterable", self.type_name(container));
+        Err(self.error(ErrorKind::TypeError, message))
+    }
+
+    pub fn to_i64(&mut self, value: &Value) -> VmResult<i64> {
+        if let Some(i) = value.as_int() {
+            return Ok(i);
+        }
+        match self.number(value) {
+            Some(Number::Int(i)) => match i.to_i64() {
+                Some(i) => Ok(i),
+                None => Err(self.error(ErrorKind::OverflowError, "int too large to convert")),
+            },
+            _ => {
+                let message = format!("'{}' object cannot be interpreted as an integer", self.type_name(value));
+                Err(self.error(ErrorKind::TypeError, message))
+            }
+        }
+    }
+
+    /// The position `index` refers to in a sequence of `len` items, counting
+    /// from the end if it is negative.
+    fn position(&mut self, index: &Value, len: usize, what: &str) -> VmResult<Option<usize>> {
+        let index = match self.number(index) {
+            Some(Number::Int(_)) => self.to_i64(index).unwrap_or(i64::MAX),
+            _ => {
+                let message = format!("{} indices must be integers or slices, not {}", what, self.type_name(index));
+                return Err(self.error(ErrorKind::TypeError, message));
+            }
+        };
+        let position = if index < 0 { index + len as i64 } else { index };
+        Ok((0..len as i64).contains(&position).then_some(position as usize))
+    }
+
+    fn slice_positions(&mut self, slice: ObjRef, len: usize) -> VmResult<Vec<usize>> {
+        let (lower, upper, step) = match self.heap.get(slice) {
+            Object::Slice { lower, upper, step } => (*lower, *upper, *step),
+            _ => unreachable!(),
+        };
+        let bound = |vm: &mut Self, value: &Value| match value.unbox() {
+            Unboxed::None => Ok(None),
+            _ => vm.to_i64(value).map(Some),
+        };
+        let lower = bound(self, &lower)?;
+        let upper = bound(self, &upper)?;
+        let step = bound(self, &step)?.unwrap_or(1);
+        if step == 0 {
+            return Err(self.error(ErrorKind::ValueError, "slice step cannot be zero"));
+        }
+        Ok(slice_positions(lower, upper, step, len))
+    }
+
+    fn is_slice(&self, value: &Value) -> Option<ObjRef> {
+        value
+            .as_object()
+            .filter(|&object| matches!(self.heap.get(object), Object::Slice { .. }))
+    }
+
+    /// `object[index]`.
+    pub fn get_item(&mut self, object: &Value, index: &Value) -> VmResult<Value> {
+        let reference = match object.as_object() {
+            Some(reference) => reference,
+            None => return Err(self.not_subscriptable(object)),
+        };
+        match self.heap.get(reference) {
+            Object::List(items) | Object::Tuple(items) => {
+                let is_list = matches!(self.heap.get(reference), Object::List(_));
+                let len = items.len();
+                if let Some(slice) = self.is_slice(index) {
+                    let positions = self.slice_positions(slice, len)?;
+                    let items = match self.heap.get(reference) {
+                        Object::List(items) | Object::Tuple(items) => {
+                            positions.into_iter().map(|i| items[i]).collect()
+                        }
+                        _ => unreachable!(),
+                    };
+                    let sliced = if is_list { Object::List(items) } else { Object::Tuple(items) };
+                    return Ok(Value::from(self.heap.alloc(sliced)));
+                }
+                let what = if is_list { "list" } else { "tuple" };
+                match self.position(index, len, what)? {
+                    Some(i) => match self.heap.get(reference) {
+                        Object::List(items) | Object::Tuple(items) => Ok(items[i]),
+                        _ => unreachable!(),
+                    },
+                    None => Err(self.error(ErrorKind::IndexError, format!("{} index out of range", what))),
+                }
+            }
+            Object::Str(s) => {
+                let chars: Vec<char> = s.chars().collect();
+                if let Some(slice) = self.is_slice(index) {
+                    let positions = self.slice_positions(slice, chars.len())?;
+                    return Ok(self.alloc_str(positions.into_iter().map(|i| chars[i]).collect::<String>()));
+                }
+                match self.position(index, chars.len(), "string")? {
+                    Some(i) => Ok(self.alloc_str(chars[i].to_string())),
+                    None => Err(self.error(ErrorKind::IndexError, "string index out of range")),
+                }
+            }
+            Object::Dict(entries) => match entries.iter().find(|(key, _)| self.equals(key, index)) {
+                Some((_, value)) => Ok(*value),
+                None => {
+                    let key = self.repr(index);
+                    Err(self.error(ErrorKind::KeyError, key))
+                }
+            },
+            Object::Range { start, stop } => {
+                let (start, len) = (*start, (*stop - *start).max(0) as usize);
+                match self.position(index, len, "range")? {
+                    Some(i) => Ok(self.int(start + i as i64)),
+                    None => Err(self.error(ErrorKind::IndexError, "range object index out of range")),
+                }
+            }
+            _ => Err(self.not_subscriptable(object)),
+        }
+    }
+
+    fn not_subscriptable(&mut self, object: &Value) -> Raised {
+        let message = format!("'{}' object is not subscriptable", self.type_name(object));
+        self.error(ErrorKind::TypeError, message)
+    }
+
+    /// `object[index] = value`.
+    pub fn set_item(&mut self, object: &Value, index: Value, value: Value) -> VmResult<()> {
+        if let Some(reference) = object.as_object() {
+            match self.heap.get(reference) {
+                Object::List(items) => {
+                    let len = items.len();
+                    return match self.position(&index, len, "list")? {
+                        Some(i) => {
+                            if let Object::List(items) = self.heap.get_mut(reference) {
+                                items[i] = value;
+                            }
+                            Ok(())
+                        }
+                        None => Err(self.error(ErrorKind::IndexError, "list assignment index out of range")),
+                    };
+                }
+                Object::Dict(entries) => {
+                    let existing = entries.iter().position(|(key, _)| self.equals(key, &index));
+                    if let Object::Dict(entries) = self.heap.get_mut(reference) {
+                        match existing {
+                            Some(i) => entries[i].1 = value,
+                            None => entries.push((index, value)),
+                        }
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        let message = format!("'{}' object does not support item assignment", self.type_name(object));
+        Err(self.error(ErrorKind::TypeError, message))
+    }
+
+    /// `del object[index]`.
+    pub fn delete_item(&mut self, object: &Value, index: &Value) -> VmResult<()> {
+        if let Some(reference) = object.as_object() {
+            match self.heap.get(reference) {
+                Object::List(items) => {
+                    let len = items.len();
+                    return match self.position(index, len, "list")? {
+                        Some(i) => {
+                            if let Object::List(items) = self.heap.get_mut(reference) {
+                                items.remove(i);
+                            }
+                            Ok(())
+                        }
+                        None => Err(self.error(ErrorKind::IndexError, "list assignment index out of range")),
+                    };
+                }
+                Object::Dict(entries) => {
+                    return match entries.iter().position(|(key, _)| self.equals(key, index)) {
+                        Some(i) => {
+                            if let Object::Dict(entries) = self.heap.get_mut(reference) {
+                                entries.remove(i);
+                            }
+                            Ok(())
+                        }
+                        None => {
+                            let key = self.repr(index);
+                            Err(self.error(ErrorKind::KeyError, key))
+                        }
+                    };
+                }
+                _ => {}
+            }
+        }
+        let message = format!("'{}' object does not support item deletion", self.type_name(object));
+        Err(self.error(ErrorKind::TypeError, message))
+    }
+
+    /// Add `item` to a set, unless an equal item is in it already.
+    pub fn add_to_set(&mut self, set: ObjRef, item: Value) {
+        let present = match self.heap.get(set) {
+            Object::Set(items) => items.iter().any(|known| self.equals(known, &item)),
+            _ => unreachable!(),
+        };
+        if let (false, Object::Set(items)) = (present, self.heap.get_mut(set)) {
+            items.push(item);
+        }
+    }
+
+    /// An iterator over `value`.
+    pub fn iter(&mut self, value: &Value) -> VmResult<Value> {
+        if let Some(object) = value.as_object() {
+            let iter = match self.heap.get(object) {
+                Object::List(_) | Object::Tuple(_) | Object::Dict(_) | Object::Set(_) => Iter::Sequence {
+                    sequence: object,
+                    index: 0,
+                },
+                Object::Range { start, stop } => Iter::Range {
+                    next: *start,
+                    stop: *stop,
+                },
+                Object::Str(_) => Iter::Chars {
+                    string: object,
+                    offset: 0,
+                },
+                Object::Iterator(_) => return Ok(*value),
+                _ => return Err(self.not_iterable(value)),
+            };
+            return Ok(Value::from(self.heap.alloc(Object::Iterator(iter))));
+        }
+        Err(self.not_iterable(value))
+    }
+
+    fn not_iterable(&mut self, value: &Value) -> Raised {
+        let message = format!("'{}' object is not iterable", self.type_name(value));
+        self.error(ErrorKind::TypeError, message)
+    }
+
+    /// The next item of an iterator made by `iter`, or `None` once it is done.
+    pub fn next(&mut self, iterator: &Value) -> VmResult<Option<Value>> {
+        let reference = iterator.as_object().unwrap();
+        let item = match self.heap.get(reference) {
+            Object::Iterator(Iter::Generator(_)) => return self.resume_generator(reference),
+            Object::Iterator(Iter::Sequence { sequence, index }) => match self.heap.get(*sequence) {
+                Object::List(items) | Object::Tuple(items) | Object::Set(items) => items.get(*index).cloned(),
+                Object::Dict(entries) => entries.get(*index).map(|(key, _)| *key),
+                _ => unreachable!(),
+            },
+            Object::Iterator(Iter::Range { next, stop }) => {
+                let (next, stop) = (*next, *stop);
+                (next < stop).then(|| self.int(next))
+            }
+            Object::Iterator(Iter::Chars { string, offset }) => {
+                let c = self.heap.str(*string).unwrap()[*offset..].chars().next();
+                c.map(|c| self.alloc_str(c.to_string()))
+            }
+            _ => return Err(self.not_iterable(iterator)),
+        };
+        if let Some(item) = &item {
+            let item = item.as_object();
+            match self.heap.get(reference) {
+                Object::Iterator(Iter::Chars { .. }) => {
+                    let advanced = self.heap.str(item.unwrap()).unwrap().len();
+                    if let Object::Iterator(Iter::Chars { offset, .. }) = self.heap.get_mut(reference) {
+                        *offset += advanced;
+                    }
+                }
+                _ => match self.heap.get_mut(reference) {
+                    Object::Iterator(Iter::Sequence { index, .. }) => *index += 1,
+                    Object::Iterator(Iter::Range { next, .. }) => *next += 1,
+                    _ => unreachable!(),
+                },
+            }
+        }
+        Ok(item)
+    }
+
+    /// All the items of `value`, as `for` would iterate over them.
+    pub fn items(&mut self, value: &Value) -> VmResult<Vec<Value>> {
+        if let Some(Object::List(items) | Object::Tuple(items)) = value.as_object().map(|object| self.heap.get(object)) {
+            return Ok(items.clone());
+        }
+        let iterator = self.iter(value)?;
+        let mut items = vec![];
+        while let Some(item) = self.next(&iterator)? {
+            items.push(item);
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_ints_overflow_to_the_heap() {
+        let mut vm = Vm::new();
+        let max = vm.int(Value::MAX_INT);
+        let min = vm.int(Value::MIN_INT);
+        let one = vm.int(1);
+        let limit = BigInt::from(1) << 47u32;
+
+        let above = vm.binary_op(BinaryOperator::Add, &max, &one).unwrap();
+        assert_eq!(above.as_int(), None);
+        assert!(matches!(vm.heap.get(above.as_object().unwrap()), Object::BigInt(_)));
+        assert_eq!(vm.integer(&above), Some(limit.clone()));
+        let below = vm.binary_op(BinaryOperator::Sub, &min, &one).unwrap();
+        assert_eq!(vm.integer(&below), Some(-limit - 1));
+        // A result that fits again is never left on the heap.
+        let back = vm.binary_op(BinaryOperator::Sub, &above, &one).unwrap();
+        assert_eq!(back, max);
+        let back = vm.binary_op(BinaryOperator::Add, &below, &one).unwrap();
+        assert_eq!(back, min);
+
+        let negated = vm.unary_op(UnaryOperator::Minus, &min).unwrap();
+        assert_eq!(negated.as_int(), None);
+        assert!(vm.equals(&negated, &above));
+        let squared = vm.binary_op(BinaryOperator::Mult, &max, &max).unwrap();
+        assert_eq!(vm.integer(&squared), Some(BigInt::from(Value::MAX_INT).pow(2)));
+        assert_eq!(vm.int(Value::MAX_INT + 1).as_int(), None);
+        assert!(vm.compare(ComparisonOperator::Gt, &above, &max).unwrap());
+    }
+
+    #[test]
+    fn test_negative_zero_and_nan() {
+        let mut vm = Vm::new();
+        let negative = Value::from(-0.0);
+        let zero = vm.int(0);
+        assert!(vm.equals(&negative, &Value::from(0.0)));
+        assert!(vm.equals(&negative, &zero));
+        assert_eq!(vm.repr(&negative), "-0.0");
+        let three = vm.int(3);
+        let product = vm.binary_op(BinaryOperator::Mult, &negative, &three).unwrap();
+        assert_eq!(vm.repr(&product), "-0.0");
+        assert!(!vm.truthy(&negative));
+
+        let infinity = Value::from(f64::INFINITY);
+        let nan = vm.binary_op(BinaryOperator::Sub, &infinity, &infinity).unwrap();
+        // The same bits as any other NaN, but not equal to itself.
+        assert_eq!(nan, Value::from(f64::NAN));
+        assert!(!vm.equals(&nan, &nan));
+        assert!(!vm.compare(ComparisonOperator::Lt, &nan, &zero).unwrap());
+        assert!(vm.compare(ComparisonOperator::NotEq, &nan, &nan).unwrap());
+        assert_eq!(vm.repr(&nan), "nan");
+        assert!(vm.truthy(&nan));
+    }
+
+    #[test]
+    fn test_format_specs() {
+        let mut vm = Vm::new();
+        let price = Value::from(1.23456);
+        let n = vm.int(1234567);
+        let negative = vm.int(-42);
+        let name = vm.alloc_str("emerald".to_owned());
+        let cases = [
+            (price, ".2f", "1.23"),
+            (price, ">8.3f", "   1.235"),
+            (price, "*^9.1f", "***1.2***"),
+            (price, "+.2e", "+1.23e+00"),
+            (price, ".3", "1.23"),
+            (Value::from(1.0), ".3", "1.0"),
+            (Value::from(1234.5), ".2", "1.2e+03"),
+            (Value::from(0.00001), "g", "1e-05"),
+            (Value::from(0.25), ".0%", "25%"),
+            (Value::from(f64::INFINITY), "F", "INF"),
+            (n, ",", "1,234,567"),
+            (n, "_", "1_234_567"),
+            (n, ".1f", "1234567.0"),
+            (negative, "06", "-00042"),
+            (negative, "+", "-42"),
+            (Value::int(255).unwrap(), "#x", "0xff"),
+            (Value::int(5).unwrap(), "08b", "00000101"),
+            (Value::from(true), "d", "1"),
+            (Value::from(true), ">5", " True"),
+            (name, "<9", "emerald  "),
+            (name, ".3", "eme"),
+        ];
+        for (value, spec, expected) in cases {
+            assert_eq!(vm.format(&value, spec).unwrap(), expected, "{}", spec);
+        }
+        assert!(vm.format(&name, "d").is_err());
+        assert!(vm.format(&price, "x").is_err());
+        assert!(vm.format(&n, ".2d").is_err());
+        assert!(vm.format(&price, "5.q2").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Programs, and the modules they import.
+//!
+//! A program is the file it starts from, the module `__main__`, and the
+//! modules it imports, directly or through other modules. Module `a.b` is
+//! the file `a/b.em` in the directory of the program. A name that starts
+//! with dots is relative to the directory of the importing module: `.b` in
+//! `a.c` is `a.b`, and `..b` is `b`.
+//!
+//! Every module a program can reach is compiled before it runs. A module
+//! runs the first time it is imported, and its globals are the attributes
+//! of the module object the import gives.
+
+use crate::aot::code_objects;
+use crate::bytecode::{CodeObject, Instruction};
+use crate::compile_source;
+use crate::error::Error;
+use rustc_hash::FxHashSet;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The name of the module a program starts from.
+pub const MAIN: &str = "__main__";
+/// The extension of the files of modules.
+pub const EXTENSION: &str = "em";
+
+/// A compiled program.
+#[derive(Debug)]
+pub struct Program {
+    pub main: Rc<CodeObject>,
+    /// The modules it can import, by name, in the order they were found.
+    pub modules: Vec<(String, Rc<CodeObject>)>,
+}
+
+/// The name of the module `spec` names in module `importer`, or None if it
+/// goes beyond the directory of the program.
+pub fn resolve(importer: &str, spec: &str) -> Option<String> {
+    let relative = spec.trim_start_matches('.');
+    let level = spec.len() - relative.len();
+    if level == 0 {
+        return Some(spec.to_owned());
+    }
+    let mut package: Vec<&str> = importer.split('.').collect();
+    for _ in 0..level {
+        package.pop()?;
+    }
+    package.push(relative);
+    Some(package.join("."))
+}
+
+/// The file of module `name` of the program in `directory`.
+pub fn module_path(directory: &Path, name: &str) -> PathBuf {
+    let mut path = directory.join(name.replace('.', "/"));
+    path.set_extension(EXTENSION);
+    path
+}
+
+/// The names the code of a module imports modules by, as written.
+fn imports(module: &Rc<CodeObject>) -> Vec<String> {
+    let mut specs = vec![];
+    for code in code_objects(module) {
+        for instruction in &code.instructions {
+            if let Instruction::ImportModule(name) = *instruction {
+                specs.push(code.names[name as usize].clone());
+            }
+        }
+    }
+    specs
+}
+
+/// Compile the program in `source`, from `source_path`, and the modules it
+/// can reach. A module that can't be read is left out, and importing it
+/// raises `ImportError`; one that doesn't parse or compile is an error.
+pub fn load(source: &str, source_path: &str) -> Result<Program, Error> {
+    let main = compile_source(source, source_path)?;
+    let directory = Path::new(source_path).parent().unwrap_or(Path::new(""));
+    let mut modules = vec![];
+    let mut found: FxHashSet<String> = [MAIN.to_owned()].into_iter().collect();
+    let mut pending = vec![(MAIN.to_owned(), Rc::clone(&main))];
+    while let Some((importer, code)) = pending.pop() {
+        for spec in imports(&code) {
+            let name = match resolve(&importer, &spec) {
+                Some(name) => name,
+                None => continue,
+            };
+            if !found.insert(name.clone()) {
+                continue;
+            }
+            let path = module_path(directory, &name);
+            if let Ok(source) = std::fs::read_to_string(&path) {
+                let code = compile_source(&source, &path.to_string_lossy())?;
+                modules.push((name.clone(), Rc::clone(&code)));
+                pending.push((name, code));
+            }
+        }
+    }
+    Ok(Program { main, modules })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{tests::SharedBuffer, Vm};
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve(MAIN, "a.b").as_deref(), Some("a.b"));
+        assert_eq!(resolve(MAIN, ".b").as_deref(), Some("b"));
+        assert_eq!(resolve("a.c", ".b").as_deref(), Some("a.b"));
+        assert_eq!(resolve("a.c", "..b.d").as_deref(), Some("b.d"));
+        assert_eq!(resolve("a.c", "...b"), None);
+        assert_eq!(resolve(MAIN, "..b"), None);
+    }
+
+    #[test]
+    fn test_imports() {
+        let directory = std::env::temp_dir().join(format!("emerald-test-imports-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("shapes")).unwrap();
+        let modules = [
+            (
+                "geometry",
+                "\
+calls = 0
+factor = 2
+
+def area(w, h):
+    global calls
+    calls = calls + 1
+    return w * h * factor
+
+print('geometry runs once')
+",
+            ),
+            (
+                "shapes.circle",
+                "\
+from ..geometry import area
+import geometry
+
+class Circle:
+    def initialize(self, r):
+        self.r = r
+    def area(self):
+        return area(self.r, self.r) * 3 // 2
+
+def describe(circle):
+    return f'circle {circle.r} {circle.area()} {geometry.calls}'
+",
+            ),
+            ("broken", "def broken(:\n"),
+        ];
+        for (name, source) in modules {
+            std::fs::write(module_path(&directory, name), source).unwrap();
+        }
+        let source = "\
+import geometry
+from shapes.circle import Circle, describe as show
+import shapes.circle as circle
+
+factor = 10
+print(geometry.area(3, 4), geometry.calls, geometry.factor)
+print(show(Circle(2)), circle.Circle is Circle)
+try:
+    import missing
+except ImportError as error:
+    print(error.message)
+try:
+    from geometry import nothing
+except ImportError as error:
+    print(error.message)
+";
+        let path = directory.join("main.em");
+        let program = load(source, &path.to_string_lossy()).unwrap();
+        // `broken` is never imported, so it is never compiled.
+        let names: Vec<&str> = program.modules.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["geometry", "shapes.circle"]);
+
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.run_program(program).unwrap();
+        assert_eq!(
+            String::from_utf8(output.0.take()).unwrap(),
+            "\
+geometry runs once
+24 1 2
+circle 2 12 2 True
+no module named 'missing'
+can't import name 'nothing' from 'geometry'
+"
+        );
+
+        let error = load("import broken\n", &path.to_string_lossy()).unwrap_err();
+        assert!(matches!(error, Error::Parse(_)), "{}", error);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+//! Object shapes, also known as hidden classes.
+//!
+//! An instance keeps the values of its attributes in a vector of slots, and a
+//! shape that says which attribute is in which slot. Instances of a class that
+//! have the same attributes share a shape, so comparing `ShapeId`s is a cheap
+//! way to tell that two instances have the same class and keep an attribute
+//! in the same slot.
+//!
+//! The shapes of a class form a tree. Its root has no attributes, and setting
+//! a new attribute follows the transition for it to a shape with one more
+//! slot, which is made the first time.
+//!
+//! Shapes favour growing over changing, so instances don't oscillate between
+//! shapes as their attributes come and go:
+//!
+//! - Deleting an attribute leaves its slot empty instead of moving the instance
+//!   to a shape without it, and setting it again fills the slot again.
+//! - Setting an attribute that has no transition yet moves the instance to an
+//!   existing shape of its class with the same attributes in another order, if
+//!   there is one, instead of starting a new branch of the tree.
+//!
+//! A class that declares fields, or includes modules that do, starts its
+//! instances out at the shape with those fields, in inclusion order, with
+//! their slots empty until they are set.
+//!
+//! The shapes of a class are freed when the class is collected. Their ids
+//! aren't used again.
+
+use crate::heap::{Heap, ObjRef};
+use rustc_hash::FxHashMap;
+use std::fmt::Write;
+
+/// A handle to a shape. Native code compares the `u32` in place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct ShapeId(u32);
+
+impl ShapeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug)]
+pub struct Shape {
+    pub class: ObjRef,
+    /// The shape this one grew from, with one attribute less.
+    pub parent: Option<ShapeId>,
+    /// The attribute of each slot.
+    pub attributes: Vec<String>,
+    slots: FxHashMap<String, usize>,
+    /// The shape an instance moves to when it gets an attribute, in the order
+    /// the transitions were made.
+    transitions: Vec<(String, ShapeId)>,
+}
+
+impl Shape {
+    /// The slot of attribute `name`.
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn transition(&self, name: &str) -> Option<ShapeId> {
+        self.transitions
+            .iter()
+            .find_map(|(attribute, target)| (attribute == name).then_some(*target))
+    }
+}
+
+/// Where setting a new attribute takes an instance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// To a shape with the same slots and one more at the end, for the new
+    /// attribute.
+    Grow(ShapeId),
+    /// To a shape with the same attributes in another order, whose slots the
+    /// values have to be moved to.
+    Reorder(ShapeId),
+}
+
+#[derive(Debug, Default)]
+pub struct Shapes {
+    /// The shapes by id, with `None` for freed ones.
+    shapes: Vec<Option<Shape>>,
+    roots: FxHashMap<ObjRef, ShapeId>,
+    /// The first shape made for each set of attributes of a class, sorted by name.
+    by_attributes: FxHashMap<(ObjRef, Vec<String>), ShapeId>,
+    /// The fields each class or module declares itself.
+    fields: FxHashMap<ObjRef, Vec<String>>,
+    /// The shape the instances of each class start out with, once it has one.
+    initial: FxHashMap<ObjRef, ShapeId>,
+}
+
+impl Shapes {
+    pub fn get(&self, shape: ShapeId) -> &Shape {
+        self.shapes[shape.index()].as_ref().expect("use of a freed shape")
+    }
+
+    /// The number of shapes that weren't freed.
+    pub fn len(&self) -> usize {
+        self.shapes.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Free the shapes of the classes that aren't `alive` anymore. Returns
+    /// how many were freed.
+    pub fn collect(&mut self, alive: impl Fn(ObjRef) -> bool) -> usize {
+        let mut freed = 0;
+        for slot in &mut self.shapes {
+            if slot.as_ref().is_some_and(|shape| !alive(shape.class)) {
+                *slot = None;
+                freed += 1;
+            }
+        }
+        self.roots.retain(|class, _| alive(*class));
+        self.by_attributes.retain(|(class, _), _| alive(*class));
+        self.fields.retain(|class, _| alive(*class));
+        self.initial.retain(|class, _| alive(*class));
+        freed
+    }
+
+    /// The shape of the instances of `class` that have no attributes yet.
+    pub fn root(&mut self, class: ObjRef) -> ShapeId {
+        if let Some(&root) = self.roots.get(&class) {
+            return root;
+        }
+        let root = self.push(Shape {
+            class,
+            parent: None,
+            attributes: vec![],
+            slots: FxHashMap::default(),
+            transitions: vec![],
+        });
+        self.roots.insert(class, root);
+        self.by_attributes.insert((class, vec![]), root);
+        root
+    }
+
+    /// Add `fields` to those `class` declares. Instances made from now on
+    /// start out with them.
+    pub fn declare(&mut self, class: ObjRef, fields: &[String]) {
+        if fields.is_empty() {
+            return;
+        }
+        let declared = self.fields.entry(class).or_default();
+        for field in fields {
+            if !declared.contains(field) {
+                declared.push(field.clone());
+            }
+        }
+        // What includes the class starts out with them too.
+        self.initial.clear();
+    }
+
+    /// The shape new instances of `class`, which includes `mro`, start out
+    /// with: the one with the fields of everything it includes, from what it
+    /// included first to itself.
+    ///
+    /// This is the order of `emerald_lang_analysis::layout`, but the shape
+    /// isn't built from `module_layouts`: those are the layouts of the classes
+    /// of one source file by name, as declared there, while classes here are
+    /// values. A class can be reopened, or extend a module imported from
+    /// another file, after instances of it exist, which is why fields are
+    /// declared as class bodies run.
+    pub fn initial(&mut self, class: ObjRef, mro: &[ObjRef]) -> ShapeId {
+        if let Some(&shape) = self.initial.get(&class) {
+            return shape;
+        }
+        let mut shape = self.root(class);
+        let fields: Vec<String> = mro
+            .iter()
+            .rev()
+            .flat_map(|included| self.fields.get(included).into_iter().flatten())
+            .cloned()
+            .collect();
+        for field in fields {
+            if self.get(shape).slot(&field).is_none() {
+                shape = match self.add_attribute(shape, &field) {
+                    Transition::Grow(shape) | Transition::Reorder(shape) => shape,
+                };
+            }
+        }
+        self.initial.insert(class, shape);
+        shape
+    }
+
+    fn push(&mut self, shape: Shape) -> ShapeId {
+        let id = ShapeId(self.shapes.len().try_into().expect("over u32::MAX shapes"));
+        self.shapes.push(Some(shape));
+        id
+    }
+
+    /// The shape an instance of `shape` moves to when it gets attribute
+    /// `name`, which it doesn't have yet.
+    pub fn add_attribute(&mut self, shape: ShapeId, name: &str) -> Transition {
+        let target = match self.get(shape).transition(name) {
+            Some(target) => target,
+            None => self.make_transition(shape, name),
+        };
+        if self.get(target).parent == Some(shape) {
+            Transition::Grow(target)
+        } else {
+            Transition::Reorder(target)
+        }
+    }
+
+    fn make_transition(&mut self, shape: ShapeId, name: &str) -> ShapeId {
+        let source = self.get(shape);
+        let class = source.class;
+        let mut key = source.attributes.clone();
+        key.push(name.to_owned());
+        key.sort();
+        let target = match self.by_attributes.get(&(class, key.clone())) {
+            Some(&existing) => existing,
+            None => {
+                let mut attributes = source.attributes.clone();
+                attributes.push(name.to_owned());
+                let mut slots = source.slots.clone();
+                slots.insert(name.to_owned(), attributes.len() - 1);
+                let target = self.push(Shape {
+                    class,
+                    parent: Some(shape),
+                    attributes,
+                    slots,
+                    transitions: vec![],
+                });
+                self.by_attributes.insert((class, key), target);
+                target
+            }
+        };
+        self.shapes[shape.index()]
+            .as_mut()
+            .unwrap()
+            .transitions
+            .push((name.to_owned(), target));
+        target
+    }
+
+    /// The shape trees of all classes with instances, for debugging. Each
+    /// transition is shown as `+attribute`, followed by the shape it leads
+    /// to; a transition to a shape that grew from another one is shown with
+    /// `->` instead of the subtree.
+    pub fn dump(&self, heap: &Heap) -> String {
+        let mut roots: Vec<(ObjRef, ShapeId)> = self.roots.iter().map(|(&class, &root)| (class, root)).collect();
+        roots.sort_by_key(|&(_, root)| root);
+        let mut dump = String::new();
+        for (class, root) in roots {
+            let name = heap.class(class).map_or("?", |class| &class.name);
+            writeln!(dump, "{} #{}", name, root.index()).unwrap();
+            self.dump_transitions(&mut dump, root, 1);
+        }
+        dump
+    }
+
+    fn dump_transitions(&self, dump: &mut String, shape: ShapeId, depth: usize) {
+        for (name, target) in &self.get(shape).transitions {
+            let indent = "  ".repeat(depth);
+            let attributes = self.get(*target).attributes.join(", ");
+            if self.get(*target).parent == Some(shape) {
+                writeln!(dump, "{}+{} #{} ({})", indent, name, target.index(), attributes).unwrap();
+                self.dump_transitions(dump, *target, depth + 1);
+            } else {
+                writeln!(dump, "{}+{} -> #{} ({})", indent, name, target.index(), attributes).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::{Class, Object};
+
+    fn class(heap: &mut Heap, name: &str) -> ObjRef {
+        heap.alloc(Object::Class(Class {
+            name: name.to_owned(),
+            is_module: false,
+            bases: vec![],
+            mro: vec![],
+            namespace: FxHashMap::default(),
+        }))
+    }
+
+    #[test]
+    fn test_transitions_are_shared() {
+        let mut heap = Heap::default();
+        let point = class(&mut heap, "Point");
+        let mut shapes = Shapes::default();
+        let root = shapes.root(point);
+        assert_eq!(shapes.root(point), root);
+        let x = match shapes.add_attribute(root, "x") {
+            Transition::Grow(x) => x,
+            reorder => panic!("{:?}", reorder),
+        };
+        assert_eq!(shapes.add_attribute(root, "x"), Transition::Grow(x));
+        assert_eq!(shapes.get(x).slot("x"), Some(0));
+        assert_eq!(shapes.len(), 2);
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut heap = Heap::default();
+        let point = class(&mut heap, "Point");
+        let line = class(&mut heap, "Line");
+        let mut shapes = Shapes::default();
+        let root = shapes.root(point);
+        let x = match shapes.add_attribute(root, "x") {
+            Transition::Grow(x) => x,
+            reorder => panic!("{:?}", reorder),
+        };
+        let xy = match shapes.add_attribute(x, "y") {
+            Transition::Grow(xy) => xy,
+            reorder => panic!("{:?}", reorder),
+        };
+        let y = match shapes.add_attribute(root, "y") {
+            Transition::Grow(y) => y,
+            reorder => panic!("{:?}", reorder),
+        };
+        // The same attributes in another order end up in the same shape.
+        assert_eq!(shapes.add_attribute(y, "x"), Transition::Reorder(xy));
+        let line_root = shapes.root(line);
+        shapes.add_attribute(line_root, "start");
+        insta::assert_snapshot!(shapes.dump(&heap));
+    }
+}
@@ -0,0 +1,528 @@
+//! Specialisations of functions by the shapes of the values they work with.
+//!
+//! Every function and method keeps its generic code, and an indexed list of
+//! specialisations of it. A specialisation is keyed by the shapes of the
+//! arguments it was called with, told apart the way inline caches tell
+//! receivers apart, and made the first time the function is called with
+//! arguments of shapes it has no specialisation for yet. It has a copy of the
+//! code with inline caches of its own, which therefore only see the values of
+//! its shapes.
+//!
+//! Besides its arguments, a specialisation depends on the shapes of the
+//! values it returned, and of the globals the code refers to as they were
+//! when it was made. It is dropped when any of those changes: when a class
+//! any of its shapes belongs to, or a class or module that one includes, is
+//! reopened or has an attribute set or deleted, or when one of its globals is
+//! bound to a value of another shape. The next call with those shapes makes a
+//! new one.
+//!
+//! Specialisations are collectable: they are owned by the registry alone,
+//! besides the frames running them, and `collect` drops the ones that weren't
+//! called since the last collection. Dropping a specialisation invalidates
+//! its native code, so frames running it deoptimise.
+
+use crate::bytecode::{CodeObject, Instruction};
+use crate::cache::{CacheSite, Receiver};
+use crate::gc::Marker;
+use crate::heap::{Heap, ObjRef};
+use crate::jit::Compiled;
+use crate::shape::Shapes;
+use rustc_hash::FxHashMap;
+use std::cell::RefCell;
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+/// The most specialisations a function has. Calls with other shapes run the
+/// generic code.
+pub const SPECIALISATION_LIMIT: usize = 8;
+
+/// A handle to a specialisation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SpecialisationId(u32);
+
+impl SpecialisationId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug)]
+pub struct Specialisation {
+    /// The generic code this specialises.
+    pub generic: Rc<CodeObject>,
+    /// The shapes of the arguments, `self` first for methods.
+    pub arguments: Vec<Receiver>,
+    /// The globals the code refers to, with their shapes.
+    pub constants: Vec<(String, Receiver)>,
+    /// The shapes of the values it returned so far.
+    pub returns: Vec<Receiver>,
+    /// A copy of the generic code, with inline caches of its own.
+    pub code: Rc<CodeObject>,
+    pub calls: u64,
+    /// The backward jumps taken in calls of it that the interpreter ran.
+    pub loops: u64,
+    /// Its native code, once it is compiled.
+    pub compiled: Option<Rc<Compiled>>,
+    /// How often its native code was thrown away.
+    pub recompilations: u32,
+    /// Whether compiling it failed, or it was compiled too often, so it
+    /// stays interpreted.
+    pub uncompilable: bool,
+    /// The classes of its shapes, with everything they include.
+    classes: Vec<ObjRef>,
+    /// Whether it was called since the last collection.
+    used: bool,
+}
+
+/// The generic code of a function and its specialisations, in the order they
+/// were made.
+#[derive(Debug)]
+struct Method {
+    code: Rc<CodeObject>,
+    specialisations: Vec<SpecialisationId>,
+}
+
+/// How the specialisations were used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpecialisationStats {
+    pub created: u64,
+    /// Calls that found a specialisation for their shapes.
+    pub hits: u64,
+    /// Calls that ran the generic code, because their function had too many
+    /// specialisations already.
+    pub generic: u64,
+    /// Specialisations dropped because a shape they depend on changed.
+    pub dropped: u64,
+    /// Specialisations dropped because they weren't used.
+    pub collected: u64,
+}
+
+impl fmt::Display for SpecialisationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "specialisations created: {}", self.created)?;
+        writeln!(f, "specialisation hits: {}", self.hits)?;
+        writeln!(f, "generic calls: {}", self.generic)?;
+        writeln!(f, "specialisations dropped: {}", self.dropped)?;
+        write!(f, "specialisations collected: {}", self.collected)
+    }
+}
+
+/// The specialisations of all functions.
+#[derive(Debug, Default)]
+pub struct Specialisations {
+    /// By the address of their generic code.
+    methods: FxHashMap<usize, Method>,
+    specialisations: FxHashMap<SpecialisationId, Specialisation>,
+    /// The specialisations that depend on each global.
+    by_constant: FxHashMap<String, Vec<SpecialisationId>>,
+    next: u32,
+    pub stats: SpecialisationStats,
+}
+
+fn address(code: &Rc<CodeObject>) -> usize {
+    Rc::as_ptr(code) as usize
+}
+
+impl Specialisations {
+    pub fn get(&self, id: SpecialisationId) -> Option<&Specialisation> {
+        self.specialisations.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: SpecialisationId) -> Option<&mut Specialisation> {
+        self.specialisations.get_mut(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.specialisations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.specialisations.is_empty()
+    }
+
+    /// The specialisations of `code`, in the order they were made.
+    pub fn of(&self, code: &Rc<CodeObject>) -> &[SpecialisationId] {
+        self.methods
+            .get(&address(code))
+            .map_or(&[], |method| &method.specialisations)
+    }
+
+    /// The specialisation of `code` for `arguments`, counting a call of it.
+    pub fn find(&mut self, code: &Rc<CodeObject>, arguments: &[Receiver]) -> Option<SpecialisationId> {
+        let method = self.methods.get(&address(code))?;
+        let id = *method
+            .specialisations
+            .iter()
+            .find(|id| self.specialisations[id].arguments == arguments)?;
+        let specialisation = self.specialisations.get_mut(&id).unwrap();
+        specialisation.calls += 1;
+        specialisation.used = true;
+        self.stats.hits += 1;
+        Some(id)
+    }
+
+    /// Whether `code` can't get more specialisations.
+    pub fn is_full(&self, code: &Rc<CodeObject>) -> bool {
+        self.of(code).len() >= SPECIALISATION_LIMIT
+    }
+
+    /// Make a specialisation of `code` for `arguments`, counting a call of
+    /// it. `classes` are those of the shapes of the arguments and constants,
+    /// with everything they include.
+    pub fn insert(
+        &mut self,
+        code: &Rc<CodeObject>,
+        arguments: Vec<Receiver>,
+        constants: Vec<(String, Receiver)>,
+        classes: Vec<ObjRef>,
+    ) -> SpecialisationId {
+        let id = SpecialisationId(self.next);
+        self.next += 1;
+        let mut copy = CodeObject::clone(code);
+        copy.caches = RefCell::new(vec![CacheSite::default(); code.caches.borrow().len()]);
+        for (name, _) in &constants {
+            self.by_constant.entry(name.clone()).or_default().push(id);
+        }
+        self.specialisations.insert(
+            id,
+            Specialisation {
+                generic: Rc::clone(code),
+                arguments,
+                constants,
+                returns: vec![],
+                code: Rc::new(copy),
+                calls: 1,
+                loops: 0,
+                compiled: None,
+                recompilations: 0,
+                uncompilable: false,
+                classes,
+                used: true,
+            },
+        );
+        self.methods
+            .entry(address(code))
+            .or_insert_with(|| Method {
+                code: Rc::clone(code),
+                specialisations: vec![],
+            })
+            .specialisations
+            .push(id);
+        self.stats.created += 1;
+        id
+    }
+
+    /// Make specialisation `id` depend on `classes` too, and be dropped when
+    /// one of them changes.
+    pub fn add_dependencies(&mut self, id: SpecialisationId, classes: &[ObjRef]) {
+        if let Some(specialisation) = self.specialisations.get_mut(&id) {
+            for class in classes {
+                if !specialisation.classes.contains(class) {
+                    specialisation.classes.push(*class);
+                }
+            }
+        }
+    }
+
+    /// Remember that specialisation `id` returned a value of shape `receiver`,
+    /// whose classes it now depends on too. It may have been dropped while
+    /// it ran.
+    pub fn record_return(&mut self, id: SpecialisationId, receiver: Receiver, classes: &[ObjRef]) {
+        if let Some(specialisation) = self.specialisations.get_mut(&id) {
+            if !specialisation.returns.contains(&receiver) {
+                specialisation.returns.push(receiver);
+                for class in classes {
+                    if !specialisation.classes.contains(class) {
+                        specialisation.classes.push(*class);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drop the specialisations that depend on `class`, which changed.
+    pub fn class_changed(&mut self, class: ObjRef) {
+        let stale: Vec<SpecialisationId> = self
+            .specialisations
+            .iter()
+            .filter(|(_, specialisation)| specialisation.classes.contains(&class))
+            .map(|(&id, _)| id)
+            .collect();
+        self.stats.dropped += stale.len() as u64;
+        self.remove(stale);
+    }
+
+    /// Drop the specialisations that saw global `name` with another shape
+    /// than `receiver`, which it is bound to now.
+    pub fn constant_changed(&mut self, name: &str, receiver: Receiver) {
+        let stale: Vec<SpecialisationId> = self
+            .by_constant
+            .get(name)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|id| {
+                self.specialisations[id]
+                    .constants
+                    .iter()
+                    .any(|(constant, seen)| constant == name && *seen != receiver)
+            })
+            .collect();
+        self.stats.dropped += stale.len() as u64;
+        self.remove(stale);
+    }
+
+    /// Drop the specialisations that saw global `name`, which was deleted.
+    pub fn constant_deleted(&mut self, name: &str) {
+        let stale: Vec<SpecialisationId> = self.by_constant.get(name).into_iter().flatten().copied().collect();
+        self.stats.dropped += stale.len() as u64;
+        self.remove(stale);
+    }
+
+    /// Drop the specialisations that weren't called since the last
+    /// collection. Returns how many were dropped.
+    pub fn collect(&mut self) -> usize {
+        let unused: Vec<SpecialisationId> = self
+            .specialisations
+            .iter_mut()
+            .filter_map(|(&id, specialisation)| (!std::mem::take(&mut specialisation.used)).then_some(id))
+            .collect();
+        let count = unused.len();
+        self.stats.collected += count as u64;
+        self.remove(unused);
+        count
+    }
+
+    /// Keep the code of the specialisations alive, and the classes of their
+    /// shapes, which there are at most `SPECIALISATION_LIMIT` of per
+    /// function.
+    pub fn trace(&self, marker: &mut Marker) {
+        for method in self.methods.values() {
+            marker.code(&method.code);
+        }
+        for specialisation in self.specialisations.values() {
+            marker.code(&specialisation.code);
+            let receivers = specialisation
+                .arguments
+                .iter()
+                .chain(&specialisation.returns)
+                .chain(specialisation.constants.iter().map(|(_, receiver)| receiver));
+            for receiver in receivers {
+                marker.receiver(*receiver);
+            }
+            for class in &specialisation.classes {
+                marker.object(*class);
+            }
+            if let Some(compiled) = &specialisation.compiled {
+                marker.compiled(compiled);
+            }
+        }
+    }
+
+    fn remove(&mut self, ids: Vec<SpecialisationId>) {
+        for id in ids {
+            let specialisation = self.specialisations.remove(&id).unwrap();
+            // Frames running its native code deoptimise.
+            if let Some(compiled) = &specialisation.compiled {
+                compiled.invalidate();
+            }
+            let key = address(&specialisation.generic);
+            let method = self.methods.get_mut(&key).unwrap();
+            method.specialisations.retain(|&other| other != id);
+            if method.specialisations.is_empty() {
+                self.methods.remove(&key);
+            }
+            for (name, _) in &specialisation.constants {
+                if let Some(ids) = self.by_constant.get_mut(name) {
+                    ids.retain(|&other| other != id);
+                    if ids.is_empty() {
+                        self.by_constant.remove(name);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The specialisations of every function, for debugging, in the order
+    /// the functions got their first one.
+    pub fn dump(&self, heap: &Heap, shapes: &Shapes) -> String {
+        let mut methods: Vec<&Method> = self.methods.values().collect();
+        methods.sort_by_key(|method| method.specialisations[0]);
+        let mut dump = String::new();
+        for method in methods {
+            writeln!(dump, "{}", method.code.name).unwrap();
+            for id in &method.specialisations {
+                let specialisation = &self.specialisations[id];
+                let describe_all = |receivers: &[Receiver]| {
+                    receivers
+                        .iter()
+                        .map(|receiver| describe(heap, shapes, *receiver))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                write!(dump, "  #{} ({})", id.index(), describe_all(&specialisation.arguments)).unwrap();
+                if !specialisation.returns.is_empty() {
+                    write!(dump, " -> {}", describe_all(&specialisation.returns).replace(", ", " | ")).unwrap();
+                }
+                let calls = if specialisation.calls == 1 { "call" } else { "calls" };
+                write!(dump, ", {} {}", specialisation.calls, calls).unwrap();
+                if specialisation.compiled.is_some() {
+                    write!(dump, ", compiled").unwrap();
+                }
+                writeln!(dump).unwrap();
+                for (name, receiver) in &specialisation.constants {
+                    writeln!(dump, "    {}: {}", name, describe(heap, shapes, *receiver)).unwrap();
+                }
+            }
+        }
+        dump
+    }
+}
+
+/// A shape as `Shapes::dump` shows it, a class or module by its name, and a
+/// builtin type by the name of the type.
+fn describe(heap: &Heap, shapes: &Shapes, receiver: Receiver) -> String {
+    let name = |class| heap.class(class).map_or("?", |class| class.name.as_str());
+    match receiver {
+        Receiver::Shape(shape) => format!("{} #{}", name(shapes.get(shape).class), shape.index()),
+        Receiver::Class(class) => format!("class {}", name(class)),
+        Receiver::Type(name) => name.to_owned(),
+    }
+}
+
+/// The globals `code` refers to, in the order it first does.
+pub fn referenced_globals(code: &CodeObject) -> Vec<&str> {
+    let mut names: Vec<&str> = vec![];
+    for instruction in &code.instructions {
+        if let Instruction::LoadGlobal(index) = instruction {
+            let name = code.names[*index as usize].as_str();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Constant;
+    use crate::heap::{Class, Object};
+    use crate::shape::Shapes;
+
+    /// The code of the function `scale` of a module that refers to the
+    /// global `factor`.
+    fn scale() -> Rc<CodeObject> {
+        let module = crate::compile_source("def scale(point):\n    return point.x * factor\n", "<test>").unwrap();
+        module
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Constant::Code(code) if code.name == "scale" => Some(Rc::clone(code)),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn class(heap: &mut Heap, name: &str) -> ObjRef {
+        heap.alloc(Object::Class(Class {
+            name: name.to_owned(),
+            is_module: false,
+            bases: vec![],
+            mro: vec![],
+            namespace: FxHashMap::default(),
+        }))
+    }
+
+    #[test]
+    fn test_register_and_find() {
+        let mut heap = Heap::default();
+        let point = class(&mut heap, "Point");
+        let mut shapes = Shapes::default();
+        let shape = Receiver::Shape(shapes.root(point));
+        let code = scale();
+        assert_eq!(referenced_globals(&code), ["factor"]);
+
+        let mut specialisations = Specialisations::default();
+        assert_eq!(specialisations.find(&code, &[shape]), None);
+        let constants = vec![("factor".to_owned(), Receiver::Type("int"))];
+        let id = specialisations.insert(&code, vec![shape], constants, vec![point]);
+        assert_eq!(specialisations.of(&code), [id]);
+        assert_eq!(specialisations.find(&code, &[shape]), Some(id));
+        assert_eq!(specialisations.find(&code, &[Receiver::Type("int")]), None);
+
+        let specialisation = specialisations.get(id).unwrap();
+        assert_eq!(specialisation.calls, 2);
+        // The copy has inline caches of its own.
+        assert!(!Rc::ptr_eq(&specialisation.code, &code));
+        assert_eq!(specialisation.code.instructions, code.instructions);
+        assert_eq!(specialisations.stats.created, 1);
+        assert_eq!(specialisations.stats.hits, 1);
+
+        specialisations.record_return(id, Receiver::Type("float"), &[]);
+        insta::assert_snapshot!(specialisations.dump(&heap, &shapes), @r###"
+        scale
+          #0 (Point #0) -> float, 2 calls
+            factor: int
+        "###);
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut heap = Heap::default();
+        let point = class(&mut heap, "Point");
+        let line = class(&mut heap, "Line");
+        let vector = class(&mut heap, "Vector");
+        let mut shapes = Shapes::default();
+        let code = scale();
+
+        let mut specialisations = Specialisations::default();
+        let int = vec![("factor".to_owned(), Receiver::Type("int"))];
+        let point_shape = Receiver::Shape(shapes.root(point));
+        let of_point = specialisations.insert(&code, vec![point_shape], int.clone(), vec![point]);
+        let of_line = specialisations.insert(&code, vec![Receiver::Shape(shapes.root(line))], int, vec![line]);
+        // Returning a vector makes the specialisation depend on its class.
+        specialisations.record_return(of_line, Receiver::Shape(shapes.root(vector)), &[vector]);
+
+        specialisations.class_changed(vector);
+        assert!(specialisations.get(of_line).is_none());
+        assert_eq!(specialisations.of(&code), [of_point]);
+
+        // Rebinding a global to a value of the same shape keeps it.
+        specialisations.constant_changed("factor", Receiver::Type("int"));
+        assert_eq!(specialisations.of(&code), [of_point]);
+        specialisations.constant_changed("factor", Receiver::Type("float"));
+        assert!(specialisations.is_empty());
+        assert_eq!(specialisations.stats.dropped, 2);
+        assert_eq!(specialisations.find(&code, &[point_shape]), None);
+    }
+
+    #[test]
+    fn test_generic_fallback_and_collection() {
+        let mut heap = Heap::default();
+        let mut shapes = Shapes::default();
+        let code = scale();
+
+        let mut specialisations = Specialisations::default();
+        let receivers: Vec<Receiver> = (0..=SPECIALISATION_LIMIT)
+            .map(|i| Receiver::Shape(shapes.root(class(&mut heap, &format!("C{}", i)))))
+            .collect();
+        for &receiver in &receivers[..SPECIALISATION_LIMIT] {
+            assert!(!specialisations.is_full(&code));
+            specialisations.insert(&code, vec![receiver], vec![], vec![]);
+        }
+        // Calls with other shapes run the generic code from now on.
+        assert!(specialisations.is_full(&code));
+        assert_eq!(specialisations.find(&code, &receivers[SPECIALISATION_LIMIT..]), None);
+
+        // All of them were used since they were made; only the first is used
+        // after that, and the rest make room for new shapes again.
+        assert_eq!(specialisations.collect(), 0);
+        let first = specialisations.find(&code, &receivers[..1]).unwrap();
+        assert_eq!(specialisations.collect(), SPECIALISATION_LIMIT - 1);
+        assert_eq!(specialisations.of(&code), [first]);
+        assert!(!specialisations.is_full(&code));
+        assert_eq!(specialisations.stats.collected, SPECIALISATION_LIMIT as u64 - 1);
+    }
+}
@@ -0,0 +1,594 @@
+//! Scope analysis: for every variable of every scope, whether it is a local,
+//! a global, or a variable shared between a function and the closures in it.
+//!
+//! Functions follow Python: a name assigned anywhere in a function is local to
+//! it, unless it is declared `global` or `nonlocal`. `do` blocks and lambdas
+//! follow Ruby instead: a name assigned in a block that the enclosing code
+//! binds refers to that variable, so
+//!
+//! ```text
+//! total = 0
+//! xs.each() do x:
+//!     total += x
+//! ```
+//!
+//! adds up into the outer `total`. Names of the enclosing function that a
+//! nested function or block uses become cells, which the nested code closes
+//! over. Class bodies bind names in the namespace of the class, and aren't
+//! visible to the functions defined in them.
+//!
+//! Comprehensions and generator expressions are scopes of their own, like in
+//! Python 3: their loop variables don't leak out, and an assignment expression
+//! in them binds the variable of the code around them.
+
+use crate::ast::{
+    self,
+    visitor::{self, Visitor},
+    Location,
+};
+use crate::bytecode::CodeKind;
+use crate::error::{CompileError, CompileErrorType};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symbol {
+    Local,
+    /// A local that nested code closes over.
+    Cell,
+    /// A variable of enclosing code.
+    Free,
+    Global,
+    /// A name in the namespace of a class body.
+    Name,
+}
+
+#[derive(Debug)]
+pub struct Scope {
+    pub kind: CodeKind,
+    parent: Option<usize>,
+    /// The parameters, with `self` first for methods.
+    pub params: Vec<String>,
+    symbols: FxHashMap<String, Symbol>,
+    /// Every name of the scope, in order of appearance, parameters first.
+    names: Vec<String>,
+    bound: FxHashSet<String>,
+    globals: FxHashSet<String>,
+    nonlocals: Vec<(String, Location)>,
+}
+
+impl Scope {
+    fn new(kind: CodeKind, parent: Option<usize>, params: Vec<String>) -> Self {
+        let mut scope = Scope {
+            kind,
+            parent,
+            params: vec![],
+            symbols: FxHashMap::default(),
+            names: vec![],
+            bound: FxHashSet::default(),
+            globals: FxHashSet::default(),
+            nonlocals: vec![],
+        };
+        for param in &params {
+            scope.bind(param);
+        }
+        scope.params = params;
+        scope
+    }
+
+    fn mention(&mut self, name: &str) {
+        if !self.names.iter().any(|known| known == name) {
+            self.names.push(name.to_owned());
+        }
+    }
+
+    fn bind(&mut self, name: &str) {
+        self.mention(name);
+        self.bound.insert(name.to_owned());
+    }
+
+    pub fn symbol(&self, name: &str) -> Symbol {
+        self.symbols.get(name).copied().unwrap_or(Symbol::Global)
+    }
+
+    /// The local variables, parameters first. Parameters that are cells keep
+    /// their local too, for the arguments to arrive in.
+    pub fn locals(&self) -> Vec<String> {
+        let mut locals = self.params.clone();
+        locals.extend(
+            self.names
+                .iter()
+                .filter(|name| self.symbols.get(*name) == Some(&Symbol::Local) && !self.params.contains(name))
+                .cloned(),
+        );
+        locals
+    }
+
+    pub fn cells(&self) -> Vec<String> {
+        self.with_symbol(Symbol::Cell)
+    }
+
+    pub fn frees(&self) -> Vec<String> {
+        self.with_symbol(Symbol::Free)
+    }
+
+    fn with_symbol(&self, symbol: Symbol) -> Vec<String> {
+        self.names
+            .iter()
+            .filter(|name| self.symbols.get(*name) == Some(&symbol))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The scopes of a program, looked up by the statement or expression that
+/// opens them.
+#[derive(Debug)]
+pub struct SymbolTable {
+    scopes: Vec<Scope>,
+    by_node: FxHashMap<usize, usize>,
+}
+
+impl SymbolTable {
+    pub fn build(body: &[ast::Stmt]) -> Result<Self, CompileError> {
+        let mut collector = Collector {
+            table: SymbolTable {
+                scopes: vec![Scope::new(CodeKind::Module, None, vec![])],
+                by_node: FxHashMap::default(),
+            },
+            current: 0,
+        };
+        for stmt in body {
+            collector.visit_stmt(stmt);
+        }
+        let mut table = collector.table;
+        // Parents come before their children, so they are resolved first.
+        for index in 0..table.scopes.len() {
+            table.resolve(index)?;
+        }
+        Ok(table)
+    }
+
+    pub fn module(&self) -> &Scope {
+        &self.scopes[0]
+    }
+
+    /// The scope of a function, class or module statement.
+    pub fn stmt_scope(&self, stmt: &ast::Stmt) -> &Scope {
+        &self.scopes[self.by_node[&(stmt as *const ast::Stmt as usize)]]
+    }
+
+    /// The scope of a lambda, `do` block or comprehension.
+    pub fn expr_scope(&self, expr: &ast::Expr) -> &Scope {
+        &self.scopes[self.by_node[&(expr as *const ast::Expr as usize)]]
+    }
+
+    fn resolve(&mut self, index: usize) -> Result<(), CompileError> {
+        let kind = self.scopes[index].kind;
+        let names = self.scopes[index].names.clone();
+        for (name, location) in self.scopes[index].nonlocals.clone() {
+            if !self.capture(index, &name) {
+                return Err(CompileError {
+                    error: CompileErrorType::UnboundNonlocal(name),
+                    location,
+                });
+            }
+        }
+        for name in names {
+            if self.scopes[index].symbols.contains_key(&name) {
+                continue;
+            }
+            let scope = &self.scopes[index];
+            let bound = scope.bound.contains(&name);
+            let symbol = match kind {
+                CodeKind::Module => Symbol::Global,
+                CodeKind::Class => Symbol::Name,
+                _ if scope.globals.contains(&name) => Symbol::Global,
+                _ if scope.params.contains(&name) => Symbol::Local,
+                CodeKind::Function | CodeKind::Method | CodeKind::Comprehension if bound => Symbol::Local,
+                // A block shares the variables of the enclosing code.
+                CodeKind::Block if bound => match self.binder(index, &name) {
+                    Some(_) => {
+                        if !self.capture(index, &name) {
+                            Symbol::Global
+                        } else {
+                            continue;
+                        }
+                    }
+                    None => Symbol::Local,
+                },
+                _ => {
+                    if self.capture(index, &name) {
+                        continue;
+                    }
+                    Symbol::Global
+                }
+            };
+            self.scopes[index].symbols.insert(name, symbol);
+        }
+        Ok(())
+    }
+
+    /// The closest enclosing scope, skipping class bodies, that binds `name`.
+    fn binder(&self, index: usize, name: &str) -> Option<usize> {
+        let mut current = self.scopes[index].parent;
+        while let Some(ancestor) = current {
+            let scope = &self.scopes[ancestor];
+            match scope.kind {
+                CodeKind::Class => {}
+                CodeKind::Module => return scope.bound.contains(name).then_some(ancestor),
+                _ => {
+                    if matches!(scope.symbols.get(name), Some(Symbol::Local | Symbol::Cell | Symbol::Free)) {
+                        return Some(ancestor);
+                    }
+                    if scope.symbols.get(name) == Some(&Symbol::Global) {
+                        return None;
+                    }
+                }
+            }
+            current = scope.parent;
+        }
+        None
+    }
+
+    /// Make `name` a free variable of `index` if a function or block around it
+    /// binds it, turning it into a cell there. Returns whether it did.
+    fn capture(&mut self, index: usize, name: &str) -> bool {
+        let binder = match self.binder(index, name) {
+            Some(binder) if self.scopes[binder].kind != CodeKind::Module => binder,
+            _ => return false,
+        };
+        let symbol = self.scopes[binder].symbols.get_mut(name).unwrap();
+        if *symbol == Symbol::Local {
+            *symbol = Symbol::Cell;
+        }
+        // Every function and block in between passes the cell on.
+        let mut current = Some(index);
+        while let Some(scope) = current.filter(|&scope| scope != binder) {
+            let scope = &mut self.scopes[scope];
+            if scope.kind != CodeKind::Class {
+                scope.mention(name);
+                scope.symbols.insert(name.to_owned(), Symbol::Free);
+            }
+            current = scope.parent;
+        }
+        true
+    }
+}
+
+struct Collector {
+    table: SymbolTable,
+    current: usize,
+}
+
+impl Collector {
+    fn scope(&mut self) -> &mut Scope {
+        &mut self.table.scopes[self.current]
+    }
+
+    fn in_scope(&mut self, node: usize, kind: CodeKind, params: Vec<String>, f: impl FnOnce(&mut Self)) {
+        let index = self.table.scopes.len();
+        self.table.scopes.push(Scope::new(kind, Some(self.current), params));
+        self.table.by_node.insert(node, index);
+        let outer = std::mem::replace(&mut self.current, index);
+        f(self);
+        self.current = outer;
+    }
+
+    /// Visit a comprehension, whose first iterable is evaluated by the code
+    /// around it, and the rest in its own scope.
+    fn visit_comprehension_scope(
+        &mut self,
+        node: usize,
+        generators: &[ast::Comprehension],
+        elements: &[&ast::Expr],
+    ) {
+        let (first, rest) = match generators.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        self.visit_expr(&first.iter);
+        self.in_scope(node, CodeKind::Comprehension, vec![".0".to_owned()], |collector| {
+            collector.visit_expr(&first.target);
+            for expr in &first.ifs {
+                collector.visit_expr(expr);
+            }
+            for generator in rest {
+                collector.visit_comprehension(generator);
+            }
+            for element in elements {
+                collector.visit_expr(element);
+            }
+        });
+    }
+
+    fn visit_defaults(&mut self, args: &ast::Arguments) {
+        for default in args.defaults.iter().chain(&args.kw_defaults) {
+            self.visit_expr(default);
+        }
+    }
+}
+
+/// The names of the parameters of a function or block, in the order the
+/// arguments arrive: positional parameters, then the one collecting the rest.
+pub fn parameter_names(args: &ast::Arguments) -> Vec<String> {
+    args.posonlyargs
+        .iter()
+        .chain(&args.args)
+        .chain(args.vararg.as_deref())
+        .chain(&args.kwonlyargs)
+        .chain(args.kwarg.as_deref())
+        .map(|arg| arg.node.arg.clone())
+        .collect()
+}
+
+impl Visitor for Collector {
+    fn visit_stmt(&mut self, node: &ast::Stmt) {
+        let key = node as *const ast::Stmt as usize;
+        match &node.node {
+            ast::StmtKind::FunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                ..
+            }
+            | ast::StmtKind::AsyncFunctionDef {
+                name,
+                args,
+                body,
+                decorator_list,
+                ..
+            } => {
+                for decorator in decorator_list {
+                    self.visit_expr(decorator);
+                }
+                self.visit_defaults(args);
+                self.scope().bind(name);
+                let mut params = parameter_names(args);
+                let kind = if self.scope().kind == CodeKind::Class {
+                    // `self` is the first parameter of a method, spelled out or not.
+                    if params.first().map(String::as_str) != Some("self") {
+                        params.insert(0, "self".to_owned());
+                    }
+                    CodeKind::Method
+                } else {
+                    CodeKind::Function
+                };
+                self.in_scope(key, kind, params, |collector| {
+                    for stmt in body {
+                        collector.visit_stmt(stmt);
+                    }
+                });
+            }
+            ast::StmtKind::ClassDef {
+                name,
+                bases,
+                keywords,
+                body,
+                decorator_list,
+            } => {
+                for expr in decorator_list.iter().chain(bases) {
+                    self.visit_expr(expr);
+                }
+                for keyword in keywords {
+                    self.visit_keyword(keyword);
+                }
+                self.scope().bind(name);
+                self.in_scope(key, CodeKind::Class, vec![], |collector| {
+                    for stmt in body {
+                        collector.visit_stmt(stmt);
+                    }
+                });
+            }
+            ast::StmtKind::ModuleDef {
+                name,
+                bases,
+                body,
+                decorator_list,
+            } => {
+                for expr in decorator_list.iter().chain(bases) {
+                    self.visit_expr(expr);
+                }
+                self.scope().bind(name);
+                self.in_scope(key, CodeKind::Class, vec![], |collector| {
+                    for stmt in body {
+                        collector.visit_stmt(stmt);
+                    }
+                });
+            }
+            ast::StmtKind::Global { names } => {
+                for name in names {
+                    self.scope().mention(name);
+                    self.scope().globals.insert(name.clone());
+                }
+            }
+            ast::StmtKind::Nonlocal { names } => {
+                for name in names {
+                    self.scope().mention(name);
+                    self.scope().nonlocals.push((name.clone(), node.location));
+                }
+            }
+            // The types of the fields of variants aren't evaluated.
+            ast::StmtKind::EnumDef { name, decorator_list, .. } => {
+                for decorator in decorator_list {
+                    self.visit_expr(decorator);
+                }
+                self.scope().bind(name);
+            }
+            // Annotations aren't evaluated.
+            ast::StmtKind::AnnAssign { target, value, .. } => {
+                self.visit_expr(target);
+                if let Some(value) = value {
+                    self.visit_expr(value);
+                }
+            }
+            ast::StmtKind::Import { names } | ast::StmtKind::ImportFrom { names, .. } => {
+                for alias in names {
+                    let name = alias.node.asname.as_ref().unwrap_or(&alias.node.name);
+                    let name = name.split('.').next().unwrap_or(name).to_owned();
+                    self.scope().bind(&name);
+                }
+            }
+            _ => visitor::walk_stmt(self, node),
+        }
+    }
+
+    fn visit_expr(&mut self, node: &ast::Expr) {
+        let key = node as *const ast::Expr as usize;
+        match &node.node {
+            ast::ExprKind::Name { id, ctx } => match ctx {
+                ast::ExprContext::Load => {
+                    self.scope().mention(id);
+                    // `super()` calls the next method on `self`.
+                    if id == "super" {
+                        self.scope().mention("self");
+                    }
+                }
+                ast::ExprContext::Store | ast::ExprContext::Del => self.scope().bind(id),
+            },
+            ast::ExprKind::NamedExpr { target, value } if self.scope().kind == CodeKind::Comprehension => {
+                // The variable belongs to the first scope around that isn't a
+                // comprehension, and the comprehension closes over it.
+                if let ast::ExprKind::Name { id, .. } = &target.node {
+                    let mut index = self.current;
+                    while self.table.scopes[index].kind == CodeKind::Comprehension {
+                        self.table.scopes[index].mention(id);
+                        index = self.table.scopes[index].parent.unwrap();
+                    }
+                    self.table.scopes[index].bind(id);
+                }
+                self.visit_expr(value);
+            }
+            ast::ExprKind::ListComp { elt, generators }
+            | ast::ExprKind::SetComp { elt, generators }
+            | ast::ExprKind::GeneratorExp { elt, generators } => {
+                self.visit_comprehension_scope(key, generators, &[elt]);
+            }
+            ast::ExprKind::DictComp { key: k, value, generators } => {
+                self.visit_comprehension_scope(key, generators, &[k, value]);
+            }
+            ast::ExprKind::Lambda { args, body } => {
+                self.visit_defaults(args);
+                self.in_scope(key, CodeKind::Block, parameter_names(args), |collector| {
+                    collector.visit_expr(body)
+                });
+            }
+            ast::ExprKind::DoBlock { args, body, chain, .. } => {
+                self.visit_defaults(args);
+                self.in_scope(key, CodeKind::Block, parameter_names(args), |collector| {
+                    for stmt in body {
+                        collector.visit_stmt(stmt);
+                    }
+                });
+                if let Some(chain) = chain {
+                    self.visit_expr(chain);
+                }
+            }
+            _ => visitor::walk_expr(self, node),
+        }
+    }
+
+    fn visit_excepthandler(&mut self, node: &ast::Excepthandler) {
+        let ast::ExcepthandlerKind::ExceptHandler { name, .. } = &node.node;
+        if let Some(name) = name {
+            self.scope().bind(name);
+        }
+        visitor::walk_excepthandler(self, node);
+    }
+
+    fn visit_pattern(&mut self, node: &ast::Pattern) {
+        match &node.node {
+            ast::PatternKind::MatchAs { name: Some(name), .. }
+            | ast::PatternKind::MatchStar { name: Some(name) }
+            | ast::PatternKind::MatchMapping { rest: Some(name), .. } => self.scope().bind(name),
+            _ => {}
+        }
+        visitor::walk_pattern(self, node);
+    }
+
+    // Parameters are bound by the scope they belong to, and annotations aren't evaluated.
+    fn visit_arguments(&mut self, _node: &ast::Arguments) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emerald_lang_parser::parser::parse_program;
+
+    fn symbols(scope: &Scope) -> Vec<(String, Symbol)> {
+        let mut symbols: Vec<_> = scope.symbols.iter().map(|(name, symbol)| (name.clone(), *symbol)).collect();
+        symbols.sort_by(|a, b| a.0.cmp(&b.0));
+        symbols
+    }
+
+    #[test]
+    fn test_block_shares_enclosing_variables() {
+        let source = "\
+count = 0
+def total(xs):
+    sum = 0
+    xs.each() do x:
+        sum += x
+        step = x * 2
+        count += 1
+    return sum
+";
+        let body = parse_program(source, "<test>").unwrap();
+        let table = SymbolTable::build(&body).unwrap();
+        let function = table.stmt_scope(&body[1]);
+        assert_eq!(
+            symbols(function),
+            vec![("sum".to_owned(), Symbol::Cell), ("xs".to_owned(), Symbol::Local)]
+        );
+        let block = match &body[1].node {
+            ast::StmtKind::FunctionDef { body, .. } => match &body[1].node {
+                ast::StmtKind::Expr { value } => match &value.node {
+                    ast::ExprKind::Call { args, .. } => table.expr_scope(&args[0]),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            symbols(block),
+            vec![
+                ("count".to_owned(), Symbol::Global),
+                ("step".to_owned(), Symbol::Local),
+                ("sum".to_owned(), Symbol::Free),
+                ("x".to_owned(), Symbol::Local),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_method_scopes() {
+        let source = "\
+class Counter:
+    count = 0
+    def add(amounts):
+        amounts.each() do amount:
+            self.count += amount
+";
+        let body = parse_program(source, "<test>").unwrap();
+        let table = SymbolTable::build(&body).unwrap();
+        let class = table.stmt_scope(&body[0]);
+        assert_eq!(symbols(class), vec![("add".to_owned(), Symbol::Name), ("count".to_owned(), Symbol::Name)]);
+        let method = match &body[0].node {
+            ast::StmtKind::ClassDef { body, .. } => table.stmt_scope(&body[1]),
+            _ => unreachable!(),
+        };
+        assert_eq!(method.params, vec!["self", "amounts"]);
+        assert_eq!(method.cells(), vec!["self"]);
+    }
+
+    #[test]
+    fn test_unbound_nonlocal() {
+        let source = "\
+def f():
+    nonlocal x
+";
+        let error = SymbolTable::build(&parse_program(source, "<test>").unwrap()).unwrap_err();
+        assert_eq!(error.to_string(), "no binding for nonlocal 'x' found at line 2 column 4");
+    }
+}
@@ -0,0 +1,233 @@
+//! The values the virtual machine computes with.
+//!
+//! A value is 64 bits, NaN-boxed. A float is held as itself, with every NaN
+//! made the one quiet NaN the hardware produces. The other NaNs, with the
+//! sign and quiet bits set, are free to hold everything else: a tag in the
+//! three bits below the quiet bit, and 48 bits of payload under it. None,
+//! booleans, integers that fit in 48 bits and object handles are tagged.
+//! Integers that don't fit are `Object::BigInt`s on the heap, and an integer
+//! that fits is never one, so integers are equal exactly when their values
+//! are, and ints can be compared without looking at the heap.
+
+use crate::heap::ObjRef;
+use std::fmt;
+
+/// The bits every tagged value has set.
+const TAGGED: u64 = 0xFFF8_0000_0000_0000;
+/// The NaN every NaN float is held as, which isn't tagged.
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const PAYLOAD: u64 = (1 << TAG_SHIFT) - 1;
+
+const TAG_NONE: u64 = 1;
+const TAG_BOOL: u64 = 2;
+const TAG_INT: u64 = 3;
+const TAG_OBJECT: u64 = 4;
+
+/// A value. Numbers, booleans and None are held directly; everything else is
+/// an object on the heap. `unbox` tells them apart.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Value(u64);
+
+/// What a value holds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unboxed {
+    None,
+    Bool(bool),
+    /// An integer from `Value::MIN_INT` to `Value::MAX_INT`.
+    Int(i64),
+    Float(f64),
+    Object(ObjRef),
+}
+
+impl Value {
+    pub const NONE: Value = Value::tagged(TAG_NONE, 0);
+    pub const TRUE: Value = Value::tagged(TAG_BOOL, 1);
+    pub const FALSE: Value = Value::tagged(TAG_BOOL, 0);
+    /// The smallest and largest integers a value holds without the heap.
+    pub const MIN_INT: i64 = -(1 << (TAG_SHIFT - 1));
+    pub const MAX_INT: i64 = (1 << (TAG_SHIFT - 1)) - 1;
+    /// What the slot of a deleted attribute holds. A program never sees it.
+    pub(crate) const ABSENT: Value = Value(TAGGED);
+    /// The bits above the payload of every object handle, whose index is
+    /// the low 32 bits of the payload, for native code to check.
+    pub(crate) const OBJECT_TAG: u64 = (TAGGED | TAG_OBJECT << TAG_SHIFT) >> TAG_SHIFT;
+    pub(crate) const TAG_SHIFT: u32 = TAG_SHIFT;
+
+    const fn tagged(tag: u64, payload: u64) -> Value {
+        Value(TAGGED | tag << TAG_SHIFT | (payload & PAYLOAD))
+    }
+
+    fn tag(self) -> Option<u64> {
+        (self.0 & TAGGED == TAGGED).then_some((self.0 >> TAG_SHIFT) & 0b111)
+    }
+
+    /// The integer `i`, if it fits in a value. Bigger ones go on the heap,
+    /// which `Vm::int` takes care of.
+    pub fn int(i: i64) -> Option<Value> {
+        (Value::MIN_INT..=Value::MAX_INT)
+            .contains(&i)
+            .then(|| Value::tagged(TAG_INT, i as u64))
+    }
+
+    pub fn unbox(self) -> Unboxed {
+        match self.tag() {
+            None => Unboxed::Float(f64::from_bits(self.0)),
+            Some(TAG_NONE) => Unboxed::None,
+            Some(TAG_BOOL) => Unboxed::Bool(self.0 & 1 == 1),
+            // Shifting the payload up to the sign bit and back extends its sign.
+            Some(TAG_INT) => Unboxed::Int(((self.0 << (64 - TAG_SHIFT)) as i64) >> (64 - TAG_SHIFT)),
+            Some(TAG_OBJECT) => Unboxed::Object(ObjRef::from_index((self.0 & PAYLOAD) as u32)),
+            Some(tag) => unreachable!("value with tag {}", tag),
+        }
+    }
+
+    pub fn as_object(&self) -> Option<ObjRef> {
+        match self.unbox() {
+            Unboxed::Object(object) => Some(object),
+            _ => None,
+        }
+    }
+
+    /// The integer, unless it is on the heap or not an integer.
+    pub fn as_int(&self) -> Option<i64> {
+        match self.unbox() {
+            Unboxed::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Value::NONE
+    }
+
+    pub(crate) fn is_absent(&self) -> bool {
+        *self == Value::ABSENT
+    }
+
+    pub(crate) fn to_bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.unbox().fmt(f)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::tagged(TAG_BOOL, b as u64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        if x.is_nan() {
+            Value(CANONICAL_NAN)
+        } else {
+            Value(x.to_bits())
+        }
+    }
+}
+
+impl From<ObjRef> for Value {
+    fn from(object: ObjRef) -> Self {
+        Value::tagged(TAG_OBJECT, object.index() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boxing() {
+        let values = [
+            Value::NONE,
+            Value::TRUE,
+            Value::FALSE,
+            Value::int(0).unwrap(),
+            Value::int(-1).unwrap(),
+            Value::int(Value::MIN_INT).unwrap(),
+            Value::int(Value::MAX_INT).unwrap(),
+            Value::from(-0.0),
+            Value::from(f64::INFINITY),
+            Value::from(-f64::NAN),
+            Value::from(f64::from_bits(0xFFF9_0000_0000_0001)),
+            Value::from(ObjRef::from_index(u32::MAX)),
+        ];
+        let unboxed: Vec<String> = values.iter().map(|value| format!("{:?}", value)).collect();
+        insta::assert_snapshot!(unboxed.join("\n"));
+        assert_eq!(Value::int(Value::MAX_INT + 1), None);
+        assert_eq!(Value::int(Value::MIN_INT - 1), None);
+    }
+
+    #[test]
+    fn test_int_boundaries() {
+        assert_eq!(Value::MIN_INT, -(1 << 47));
+        assert_eq!(Value::MAX_INT, (1 << 47) - 1);
+        for i in [0, 1, -1, 1 << 46, -(1 << 46), Value::MIN_INT, Value::MAX_INT] {
+            let value = Value::int(i).unwrap();
+            assert_eq!(value.unbox(), Unboxed::Int(i));
+            assert_eq!(value.as_int(), Some(i));
+        }
+        // The payloads of the extremes differ only in the sign bit of the
+        // payload, which must not leak into the tag.
+        assert_ne!(Value::int(Value::MIN_INT), Value::int(Value::MAX_INT));
+        assert_ne!(Value::int(-1), Some(Value::NONE));
+        for i in [Value::MAX_INT + 1, Value::MIN_INT - 1, i64::MAX, i64::MIN] {
+            assert_eq!(Value::int(i), None);
+        }
+    }
+
+    #[test]
+    fn test_negative_zero() {
+        let negative = Value::from(-0.0);
+        let positive = Value::from(0.0);
+        // Held as floats of their own, not as the integer 0.
+        assert_ne!(negative, positive);
+        assert_ne!(positive, Value::int(0).unwrap());
+        match negative.unbox() {
+            Unboxed::Float(x) => assert!(x == 0.0 && x.is_sign_negative()),
+            unboxed => panic!("{:?}", unboxed),
+        }
+        assert_eq!(negative.as_int(), None);
+    }
+
+    #[test]
+    fn test_nan_canonicalisation() {
+        let nans = [
+            f64::NAN,
+            -f64::NAN,
+            f64::from_bits(0x7FF0_0000_0000_0001),
+            // These would read as tagged values if they were held as they are.
+            f64::from_bits(TAGGED | TAG_INT << TAG_SHIFT | 5),
+            f64::from_bits(TAGGED | TAG_OBJECT << TAG_SHIFT),
+            f64::from_bits(u64::MAX),
+        ];
+        for x in nans {
+            assert!(x.is_nan());
+            let value = Value::from(x);
+            assert_eq!(value, Value(CANONICAL_NAN));
+            assert!(matches!(value.unbox(), Unboxed::Float(x) if x.is_nan()));
+        }
+        assert!(matches!(Value::from(f64::INFINITY).unbox(), Unboxed::Float(x) if x == f64::INFINITY));
+        assert!(matches!(Value::from(f64::NEG_INFINITY).unbox(), Unboxed::Float(x) if x == f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn test_pointer_round_trip() {
+        for index in [0, 1, 0xFFFF, 0x8000_0000, u32::MAX] {
+            let object = ObjRef::from_index(index);
+            let value = Value::from(object);
+            assert_eq!(value.unbox(), Unboxed::Object(object));
+            assert_eq!(value.as_object(), Some(object));
+            assert_eq!(value.as_int(), None);
+        }
+        assert_eq!(Value::NONE.as_object(), None);
+        assert_eq!(Value::int(0).unwrap().as_object(), None);
+        assert_ne!(Value::from(ObjRef::from_index(1)), Value::TRUE);
+    }
+}
@@ -0,0 +1,3563 @@
+//! The virtual machine: frames, the dispatch loop, calls and exceptions.
+//!
+//! Frames share one value stack. A call of an Emerald function pushes a frame
+//! and carries on in the same dispatch loop, so deep Emerald recursion doesn't
+//! use the Rust stack. Builtins that call back into Emerald, like `map` calling
+//! a block, run a nested dispatch loop with `Vm::call` until the frame they
+//! pushed returns.
+//!
+//! An exception unwinds frame by frame, recording where each one was in its
+//! traceback, until it reaches a frame with an active `SetupExcept` block.
+//!
+//! Calling an `async def` or `async do` runs it as a coroutine, in a nested
+//! dispatch loop, until it returns or awaits a promise that hasn't settled.
+//! Then its frame and its part of the stack are moved to the event loop,
+//! and the call gives a promise of its result. The event loop pushes the
+//! frame again to resume it, wherever the stack is by then.
+//!
+//! Calling code that yields gives a generator, which holds the frame of the
+//! call the same way, and pushes it again to run it up to the next `yield`
+//! whenever it is asked for an item.
+//!
+//! The garbage collector runs at safe points: before an instruction, in the
+//! interpreter or in native code. Builtins that call back into Emerald pin
+//! the values they hold on to meanwhile.
+
+use crate::builtins;
+use crate::cache::{CacheStats, Caches, InlineCache, Lookup, Probe, Receiver};
+use crate::bytecode::{CodeKind, CodeObject, Constant, Instruction};
+use crate::error::{RuntimeError, TraceEntry};
+use crate::event_loop::{EventLoop, Outcome, Promise, Reaction};
+use crate::flat_vec::FlatVec;
+use crate::gc::{GcStats, Marker};
+use crate::heap::{Class, Function, Heap, Instance, Iter, ObjRef, Object};
+use crate::shape::{Shapes, Transition};
+use crate::jit::{self, Compiled, DeoptReason, Jit, JitMode, JitStats};
+use crate::program::{self, Program};
+use crate::specialise::{self, SpecialisationId, SpecialisationStats, Specialisations};
+use crate::value::{Unboxed, Value};
+use emerald_lang_analysis::mro;
+use num_traits::ToPrimitive;
+use rustc_hash::FxHashMap;
+use std::{io::Write, mem::offset_of, rc::Rc};
+
+pub type VmResult<T> = Result<T, Raised>;
+
+/// The most frames that can be active at once.
+const MAX_FRAMES: usize = 10_000;
+
+/// An exception on its way up the stack.
+#[derive(Clone, Debug)]
+pub struct Raised {
+    pub exception: ObjRef,
+    traceback: Vec<TraceEntry>,
+    /// Raised again by the frame that recorded the last entry of the traceback.
+    reraised: bool,
+}
+
+impl Raised {
+    pub fn new(exception: ObjRef) -> Self {
+        Raised {
+            exception,
+            traceback: vec![],
+            reraised: false,
+        }
+    }
+}
+
+/// The classes of the exceptions the runtime raises itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Exception,
+    AssertionError,
+    AttributeError,
+    IndexError,
+    KeyError,
+    ImportError,
+    NameError,
+    OSError,
+    OverflowError,
+    RuntimeError,
+    TypeError,
+    ValueError,
+    ZeroDivisionError,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 13] = [
+        ErrorKind::Exception,
+        ErrorKind::AssertionError,
+        ErrorKind::AttributeError,
+        ErrorKind::IndexError,
+        ErrorKind::KeyError,
+        ErrorKind::ImportError,
+        ErrorKind::NameError,
+        ErrorKind::OSError,
+        ErrorKind::OverflowError,
+        ErrorKind::RuntimeError,
+        ErrorKind::TypeError,
+        ErrorKind::ValueError,
+        ErrorKind::ZeroDivisionError,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Exception => "Exception",
+            ErrorKind::AssertionError => "AssertionError",
+            ErrorKind::AttributeError => "AttributeError",
+            ErrorKind::IndexError => "IndexError",
+            ErrorKind::KeyError => "KeyError",
+            ErrorKind::ImportError => "ImportError",
+            ErrorKind::NameError => "NameError",
+            ErrorKind::OSError => "OSError",
+            ErrorKind::OverflowError => "OverflowError",
+            ErrorKind::RuntimeError => "RuntimeError",
+            ErrorKind::TypeError => "TypeError",
+            ErrorKind::ValueError => "ValueError",
+            ErrorKind::ZeroDivisionError => "ZeroDivisionError",
+        }
+    }
+}
+
+/// What the result of a frame is when it returns.
+enum ReturnAction {
+    Value,
+    /// The frame initializes a new instance, which is the result.
+    Instance(ObjRef),
+    /// The frame runs a class body, and the class built from its namespace is the result.
+    Class {
+        name: String,
+        bases: Vec<ObjRef>,
+        is_module: bool,
+    },
+    /// The frame runs the body of a class that is reopened, and adds its
+    /// namespace to that class, which is the result.
+    Reopen(ObjRef),
+    /// The frame runs the code of an imported module, which is the result.
+    Module(ObjRef),
+}
+
+/// An active `SetupExcept`.
+struct Block {
+    handler: usize,
+    stack_level: usize,
+    handled_level: usize,
+}
+
+struct Frame {
+    code: Rc<CodeObject>,
+    function: Option<ObjRef>,
+    /// The imported module whose attributes are the globals of the code, or
+    /// None for code of the program itself.
+    module: Option<ObjRef>,
+    /// The next instruction.
+    pc: usize,
+    locals: Vec<Value>,
+    /// The cells of the frame, followed by the ones its function closes over.
+    cells: Vec<ObjRef>,
+    /// Where the stack of the frame starts.
+    stack_base: usize,
+    blocks: Vec<Block>,
+    /// The exceptions being handled, innermost last.
+    handled: Vec<Raised>,
+    /// The namespace of a class body.
+    namespace: Option<FxHashMap<String, Value>>,
+    on_return: ReturnAction,
+    /// The specialisation the frame runs, if any.
+    specialisation: Option<SpecialisationId>,
+    /// The native code of the specialisation, while the frame runs it.
+    compiled: Option<Rc<Compiled>>,
+}
+
+enum Flow {
+    Continue,
+    Return(Value),
+    /// The coroutine awaits the value.
+    Await(Value),
+    /// The generator yields the value.
+    Yield(Value),
+}
+
+/// Why a dispatch loop stopped.
+enum Stop {
+    Returned(Value),
+    /// The coroutine at the depth of the loop awaits the value.
+    Awaiting(Value),
+    /// The generator at the depth of the loop yields the value.
+    Yielded(Value),
+}
+
+/// A coroutine suspended at an `await`, waiting for the event loop to
+/// resume it.
+pub(crate) struct Coroutine {
+    frame: Frame,
+    /// The part of the stack that belongs to the frame. The stack levels of
+    /// its blocks are relative to the start of it.
+    stack: Vec<Value>,
+    /// The promise of the result of the coroutine.
+    promise: ObjRef,
+}
+
+/// A call of code that yields, which runs up to the next `yield` whenever
+/// it is asked for an item.
+pub struct Generator {
+    /// The frame, suspended before it starts or at a `yield`, and its part of
+    /// the stack, as for a `Coroutine`. None while the generator runs, and
+    /// once it returned.
+    suspended: Option<(Frame, Vec<Value>)>,
+    /// Whether the frame is at a `yield`, rather than at its start.
+    started: bool,
+    running: bool,
+}
+
+impl Generator {
+    pub(crate) fn trace(&self, marker: &mut Marker) {
+        if let Some((frame, stack)) = &self.suspended {
+            trace_frame(marker, frame);
+            for value in stack {
+                marker.value(value);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Generator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.suspended {
+            Some((frame, _)) => write!(f, "<generator {}>", frame.code.name),
+            None => write!(f, "<generator>"),
+        }
+    }
+}
+
+/// The outcome of calling something: either it was done right away, or a
+/// frame was pushed that still has to run.
+enum Invoked {
+    Value(Value),
+    Frame,
+}
+
+pub struct Vm {
+    pub heap: Heap,
+    pub shapes: Shapes,
+    caches: Caches,
+    specialisations: Specialisations,
+    /// Made when the first specialisation is compiled.
+    jit: Option<Jit>,
+    jit_mode: JitMode,
+    pub(crate) jit_stats: JitStats,
+    /// The locals of the frame native code is entered with, for it to check
+    /// the shapes of the arguments against those of its specialisation.
+    jit_locals: *const Value,
+    /// What native code returned or raised.
+    compiled_result: Option<VmResult<Value>>,
+    /// Native code compiled ahead of time, by the address of its code
+    /// object, which it keeps alive.
+    precompiled: FxHashMap<usize, (Rc<CodeObject>, Rc<Compiled>)>,
+    stack: FlatVec<Value>,
+    frames: Vec<Frame>,
+    /// Values builtins hold on to while they call back into Emerald.
+    pinned: Vec<Value>,
+    pub globals: FxHashMap<String, Value>,
+    builtins: FxHashMap<String, Value>,
+    /// The code of the modules the program can import, by name.
+    module_code: FxHashMap<String, Rc<CodeObject>>,
+    /// The modules imported so far, by name.
+    modules: FxHashMap<String, ObjRef>,
+    /// The classes of `ErrorKind::ALL`, in order.
+    errors: Vec<ObjRef>,
+    /// String and big integer constants, allocated once per code object
+    /// and index.
+    constants: FxHashMap<(usize, u32), ObjRef>,
+    pub(crate) event_loop: EventLoop,
+    output: Box<dyn Write>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    // Where native code finds what it reads and writes in place.
+    pub(crate) const STACK_OFFSET: i32 = offset_of!(Vm, stack) as i32 + FlatVec::<Value>::PTR_OFFSET;
+    pub(crate) const STACK_LEN_OFFSET: i32 = offset_of!(Vm, stack) as i32 + FlatVec::<Value>::LEN_OFFSET;
+    pub(crate) const INSTANCES_OFFSET: i32 = offset_of!(Vm, heap) as i32 + Heap::INSTANCES_OFFSET;
+    pub(crate) const LOCALS_OFFSET: i32 = offset_of!(Vm, jit_locals) as i32;
+    pub(crate) const INLINED_OFFSET: i32 = (offset_of!(Vm, jit_stats) + offset_of!(JitStats, inlined)) as i32;
+
+    pub fn new() -> Self {
+        Vm::with_output(Box::new(std::io::stdout()))
+    }
+
+    /// A machine that writes what `print` prints to `output`.
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        let mut vm = Vm {
+            heap: Heap::default(),
+            shapes: Shapes::default(),
+            caches: Caches::default(),
+            specialisations: Specialisations::default(),
+            jit: None,
+            jit_mode: JitMode::default(),
+            jit_stats: JitStats::default(),
+            jit_locals: std::ptr::null(),
+            compiled_result: None,
+            precompiled: FxHashMap::default(),
+            stack: FlatVec::new(),
+            frames: vec![],
+            pinned: vec![],
+            globals: FxHashMap::default(),
+            builtins: FxHashMap::default(),
+            module_code: FxHashMap::default(),
+            modules: FxHashMap::default(),
+            errors: vec![],
+            constants: FxHashMap::default(),
+            event_loop: EventLoop::default(),
+            output,
+        };
+        builtins::install(&mut vm);
+        vm
+    }
+
+    /// Run the code of a program, and then the event loop until nothing is
+    /// left for it to do, keeping its globals for later runs.
+    pub fn run(&mut self, code: Rc<CodeObject>) -> Result<Value, RuntimeError> {
+        let module = self.heap.alloc(Object::Function(Function {
+            code,
+            defaults: vec![],
+            closure: vec![],
+            owner: None,
+            module: None,
+        }));
+        self.call(Value::from(module), vec![])
+            .and_then(|value| self.run_event_loop().map(|()| value))
+            .map_err(|raised| self.runtime_error(raised))
+    }
+
+    /// Run `program` like `run`, with the modules it imports.
+    pub fn run_program(&mut self, program: Program) -> Result<Value, RuntimeError> {
+        for (name, code) in program.modules {
+            self.add_module(name, code);
+        }
+        self.run(program.main)
+    }
+
+    /// Make module `name`, with `code`, importable.
+    pub fn add_module(&mut self, name: String, code: Rc<CodeObject>) {
+        self.module_code.insert(name, code);
+    }
+
+    /// Call `callee` with `args`, running it to completion.
+    pub fn call(&mut self, callee: Value, args: Vec<Value>) -> VmResult<Value> {
+        match self.invoke(callee, args)? {
+            Invoked::Value(value) => Ok(value),
+            Invoked::Frame => self.execute(self.frames.len() - 1),
+        }
+    }
+
+    pub(crate) fn define_builtin(&mut self, name: &str, value: Value) {
+        self.builtins.insert(name.to_owned(), value);
+    }
+
+    pub(crate) fn register_error(&mut self, class: ObjRef) {
+        self.errors.push(class);
+    }
+
+    pub fn error_class(&self, kind: ErrorKind) -> ObjRef {
+        self.errors[kind as usize]
+    }
+
+    /// An exception of `kind` with `message`, ready to be returned as an error.
+    pub fn error(&mut self, kind: ErrorKind, message: impl Into<String>) -> Raised {
+        let message = self.alloc_str(message.into());
+        let exception = self.new_instance(self.error_class(kind));
+        self.set_attr(&Value::from(exception), "message", message).unwrap();
+        Raised::new(exception)
+    }
+
+    /// A new instance of `class`, without attributes.
+    pub fn new_instance(&mut self, class: ObjRef) -> ObjRef {
+        let shape = self.shapes.initial(class, &self.heap.class(class).unwrap().mro);
+        let slots = vec![Value::ABSENT; self.shapes.get(shape).attributes.len()];
+        self.heap.alloc(Object::Instance(Box::new(Instance {
+            shape,
+            class,
+            slots: slots.into(),
+        })))
+    }
+
+    /// The attribute `name` of `instance`, if it is set.
+    pub fn attribute<'a>(&self, instance: &'a Instance, name: &str) -> Option<&'a Value> {
+        let slot = self.shapes.get(instance.shape).slot(name)?;
+        instance.slot(slot)
+    }
+
+    /// How well the inline caches of attribute access and method calls did.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.caches.stats
+    }
+
+    /// The shape trees of the classes that have instances, as `Shapes::dump`.
+    pub fn dump_shapes(&self) -> String {
+        self.shapes.dump(&self.heap)
+    }
+
+    /// How the specialisations of functions were used.
+    pub fn specialisation_stats(&self) -> SpecialisationStats {
+        self.specialisations.stats
+    }
+
+    /// The specialisations of every function, as `Specialisations::dump`.
+    pub fn dump_specialisations(&self) -> String {
+        self.specialisations.dump(&self.heap, &self.shapes)
+    }
+
+    /// Drop the specialisations that weren't called since the last
+    /// collection. Returns how many were dropped.
+    pub fn collect_specialisations(&mut self) -> usize {
+        self.specialisations.collect()
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats
+    }
+
+    /// Collect at every safe point after an allocation, for testing.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    /// Collect the objects the program can't reach anymore, and call the
+    /// finalisers of the ones that had them. Returns how many were freed.
+    pub fn collect_garbage(&mut self) -> u64 {
+        let marks = {
+            let mut marker = Marker::new(&self.heap, &self.shapes);
+            self.trace_roots(&mut marker);
+            marker.finish()
+        };
+        let freed = self.heap.stats.freed;
+        let finalisers = self.heap.sweep(&marks);
+        let freed = self.heap.stats.freed - freed;
+        let shapes = &self.shapes;
+        let alive = |receiver| marks.is_receiver_alive(shapes, receiver);
+        self.caches.prune(alive);
+        for code in &marks.codes {
+            for site in code.caches.borrow_mut().iter_mut() {
+                site.prune(alive);
+            }
+        }
+        // Constants are allocated again when they are needed again.
+        self.constants
+            .retain(|&(code, _), constant| marks.is_code_alive(code) && marks.is_marked(*constant));
+        self.shapes.collect(|class| marks.is_marked(class));
+        for finaliser in finalisers {
+            self.heap.stats.finalisers_run += 1;
+            // Nothing is there to handle what a finaliser raises.
+            if let Err(raised) = self.call(finaliser, vec![]) {
+                let error = self.runtime_error(raised);
+                eprintln!("Exception ignored in finaliser:\n{}", error);
+            }
+        }
+        freed
+    }
+
+    /// Mark everything the machine holds on to.
+    fn trace_roots(&self, marker: &mut Marker) {
+        let values = self
+            .stack
+            .iter()
+            .chain(&self.pinned)
+            .chain(self.globals.values())
+            .chain(self.builtins.values())
+            .chain(self.heap.finalisers());
+        for value in values {
+            marker.value(value);
+        }
+        for object in self.errors.iter().chain(self.modules.values()) {
+            marker.object(*object);
+        }
+        for frame in &self.frames {
+            trace_frame(marker, frame);
+        }
+        for coroutine in self.event_loop.coroutines() {
+            trace_frame(marker, &coroutine.frame);
+            for value in &coroutine.stack {
+                marker.value(value);
+            }
+            marker.object(coroutine.promise);
+        }
+        self.event_loop.trace(marker);
+        match &self.compiled_result {
+            Some(Ok(value)) => marker.value(value),
+            Some(Err(raised)) => marker.object(raised.exception),
+            None => {}
+        }
+        self.caches.trace(marker);
+        self.specialisations.trace(marker);
+        for (code, compiled) in self.precompiled.values() {
+            marker.code(code);
+            marker.compiled(compiled);
+        }
+    }
+
+    /// Run `f` with `values` pinned, so they stay alive while it calls back
+    /// into Emerald.
+    pub(crate) fn with_pinned<T>(&mut self, values: &[Value], f: impl FnOnce(&mut Vm) -> T) -> T {
+        let base = self.pinned.len();
+        self.pinned.extend_from_slice(values);
+        let result = f(self);
+        self.pinned.truncate(base);
+        result
+    }
+
+    /// Pin `value` until the `with_pinned` this runs in returns.
+    pub(crate) fn pin(&mut self, value: Value) {
+        self.pinned.push(value);
+    }
+
+    /// Run frames of `code` in `compiled`, native code compiled ahead of
+    /// time, unless their specialisation is compiled.
+    pub fn add_precompiled(&mut self, code: &Rc<CodeObject>, compiled: Compiled) {
+        let key = Rc::as_ptr(code) as usize;
+        self.precompiled.insert(key, (Rc::clone(code), Rc::new(compiled)));
+    }
+
+    /// When specialisations are compiled to native code from now on.
+    pub fn set_jit_mode(&mut self, mode: JitMode) {
+        self.jit_mode = mode;
+    }
+
+    pub fn jit_mode(&self) -> JitMode {
+        self.jit_mode
+    }
+
+    /// How often specialisations were compiled, and their native code run.
+    pub fn jit_stats(&self) -> JitStats {
+        self.jit_stats
+    }
+
+    pub fn alloc_str(&mut self, s: impl Into<String>) -> Value {
+        Value::from(self.heap.alloc(Object::Str(s.into())))
+    }
+
+    pub fn alloc_list(&mut self, items: Vec<Value>) -> Value {
+        Value::from(self.heap.alloc(Object::List(items)))
+    }
+
+    pub(crate) fn write_output(&mut self, text: &str) -> VmResult<()> {
+        let written = self.output.write_all(text.as_bytes()).and_then(|_| self.output.flush());
+        written.map_err(|error| self.error(ErrorKind::RuntimeError, error.to_string()))
+    }
+
+    fn runtime_error(&mut self, raised: Raised) -> RuntimeError {
+        let class = match self.heap.get(raised.exception) {
+            Object::Instance(instance) => self.heap.class(instance.class).map(|class| class.name.clone()),
+            _ => None,
+        };
+        RuntimeError {
+            class: class.unwrap_or_else(|| "Exception".to_owned()),
+            message: self.to_str(&Value::from(raised.exception)),
+            traceback: raised.traceback,
+        }
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn top(&self) -> &Value {
+        self.stack.last().unwrap()
+    }
+
+    fn pop_n(&mut self, n: usize) -> Vec<Value> {
+        let start = self.stack.len() - n;
+        self.stack.split_off(start)
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.frame_mut().pc = target as usize;
+    }
+
+    /// Run until the frame at `depth` returns.
+    fn execute(&mut self, depth: usize) -> VmResult<Value> {
+        match self.dispatch(depth)? {
+            Stop::Returned(value) => Ok(value),
+            Stop::Awaiting(_) | Stop::Yielded(_) => unreachable!("only coroutines and generators suspend"),
+        }
+    }
+
+    /// Run until the frame at `depth` returns, or awaits if it is a coroutine.
+    fn dispatch(&mut self, depth: usize) -> VmResult<Stop> {
+        loop {
+            if self.heap.collection_due() {
+                self.collect_garbage();
+            }
+            let frame = self.frames.last_mut().unwrap();
+            let flow = match &frame.compiled {
+                Some(compiled) => {
+                    let compiled = Rc::clone(compiled);
+                    self.run_compiled(compiled)
+                }
+                None => {
+                    let instruction = frame.code.instructions[frame.pc];
+                    frame.pc += 1;
+                    self.step(instruction)
+                }
+            };
+            match flow {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Return(value)) => {
+                    if let Some(value) = self.return_from_frame(value, depth) {
+                        return Ok(Stop::Returned(value));
+                    }
+                }
+                Ok(Flow::Await(value)) => {
+                    // Coroutines and generators only run in a loop of their own.
+                    debug_assert_eq!(self.frames.len() - 1, depth);
+                    return Ok(Stop::Awaiting(value));
+                }
+                Ok(Flow::Yield(value)) => {
+                    debug_assert_eq!(self.frames.len() - 1, depth);
+                    return Ok(Stop::Yielded(value));
+                }
+                Err(raised) => self.unwind(raised, depth)?,
+            }
+        }
+    }
+
+    /// Run the coroutine whose frame is on top, raising `raised` in it
+    /// first if there is one, until it finishes and settles `promise`, or
+    /// awaits and is suspended.
+    fn run_coroutine(&mut self, promise: ObjRef, raised: Option<Raised>) {
+        let depth = self.frames.len() - 1;
+        let stop = self.with_pinned(&[Value::from(promise)], |vm| match raised {
+            Some(raised) => vm.unwind(raised, depth).and_then(|()| vm.dispatch(depth)),
+            None => vm.dispatch(depth),
+        });
+        match stop {
+            Ok(Stop::Returned(value)) => self.resolve(promise, value),
+            Ok(Stop::Awaiting(awaited)) => {
+                let (frame, stack) = self.suspend_frame();
+                let id = self.event_loop.suspend(Coroutine { frame, stack, promise });
+                self.await_value(awaited, Reaction::Resume(id));
+            }
+            Ok(Stop::Yielded(_)) => unreachable!("coroutines don't yield"),
+            Err(raised) => self.reject(promise, raised),
+        }
+    }
+
+    /// Go on with a suspended coroutine, with the outcome of what it awaited.
+    pub(crate) fn resume(&mut self, coroutine: Coroutine, outcome: Outcome) {
+        let Coroutine { frame, stack, promise } = coroutine;
+        self.restore_frame(frame, stack);
+        match outcome {
+            Ok(value) => {
+                self.push(value);
+                self.run_coroutine(promise, None);
+            }
+            Err(raised) => self.run_coroutine(promise, Some(raised)),
+        }
+    }
+
+    /// Run the generator `generator` up to its next `yield`, giving the item
+    /// it yields, or None once it returned.
+    pub(crate) fn resume_generator(&mut self, generator: ObjRef) -> VmResult<Option<Value>> {
+        let state = match self.heap.get_mut(generator) {
+            Object::Iterator(Iter::Generator(state)) => state,
+            _ => unreachable!(),
+        };
+        if state.running {
+            return Err(self.error(ErrorKind::ValueError, "generator already executing"));
+        }
+        let (frame, stack) = match state.suspended.take() {
+            Some(suspended) => suspended,
+            None => return Ok(None),
+        };
+        state.running = true;
+        let started = state.started;
+        self.restore_frame(frame, stack);
+        if started {
+            self.push(Value::NONE);
+        }
+        let depth = self.frames.len() - 1;
+        let stop = self.with_pinned(&[Value::from(generator)], |vm| vm.dispatch(depth));
+        let (suspended, result) = match stop {
+            Ok(Stop::Yielded(item)) => (Some(self.suspend_frame()), Ok(Some(item))),
+            Ok(Stop::Returned(_)) => (None, Ok(None)),
+            Ok(Stop::Awaiting(_)) => unreachable!("generators don't await"),
+            Err(raised) => (None, Err(raised)),
+        };
+        if let Object::Iterator(Iter::Generator(state)) = self.heap.get_mut(generator) {
+            state.suspended = suspended;
+            state.started = true;
+            state.running = false;
+        }
+        result
+    }
+
+    /// Pop the running frame, with its part of the stack, to push it again
+    /// with `restore_frame` later.
+    fn suspend_frame(&mut self) -> (Frame, Vec<Value>) {
+        let mut frame = self.frames.pop().unwrap();
+        let stack = self.stack.split_off(frame.stack_base);
+        for block in &mut frame.blocks {
+            block.stack_level -= frame.stack_base;
+        }
+        (frame, stack)
+    }
+
+    /// Push a frame `suspend_frame` popped again, wherever the stack is by now.
+    fn restore_frame(&mut self, mut frame: Frame, stack: Vec<Value>) {
+        frame.stack_base = self.stack.len();
+        for block in &mut frame.blocks {
+            block.stack_level += frame.stack_base;
+        }
+        self.stack.extend(stack);
+        self.frames.push(frame);
+    }
+
+    /// Pop the running frame, and hand its result to the caller. Returns the
+    /// result if the frame was the one at `depth`.
+    fn return_from_frame(&mut self, value: Value, depth: usize) -> Option<Value> {
+        let mut frame = self.frames.pop().unwrap();
+        self.stack.truncate(frame.stack_base);
+        if let Some(specialisation) = frame.specialisation {
+            let receiver = self.receiver(&value);
+            let classes = self.classes_of(receiver);
+            self.specialisations.record_return(specialisation, receiver, &classes);
+        }
+        let result = match frame.on_return {
+            ReturnAction::Value => value,
+            ReturnAction::Instance(instance) => Value::from(instance),
+            ReturnAction::Class { name, bases, is_module } => {
+                let namespace = frame.namespace.take().unwrap_or_default();
+                let class = self.build_class(name, bases, is_module, namespace);
+                self.shapes.declare(class, &frame.code.fields);
+                Value::from(class)
+            }
+            ReturnAction::Reopen(class) => {
+                let namespace = frame.namespace.take().unwrap_or_default();
+                self.reopen_class(class, namespace);
+                self.shapes.declare(class, &frame.code.fields);
+                Value::from(class)
+            }
+            ReturnAction::Module(module) => Value::from(module),
+        };
+        if self.frames.len() == depth {
+            return Some(result);
+        }
+        self.push(result);
+        None
+    }
+
+    /// Find a handler for `raised`, popping frames down to `depth`.
+    fn unwind(&mut self, mut raised: Raised, depth: usize) -> VmResult<()> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            if std::mem::take(&mut raised.reraised) {
+                // The traceback already passes through this frame.
+            } else {
+                raised.traceback.push(TraceEntry {
+                    function: frame.code.name.clone(),
+                    source_path: frame.code.source_path.clone(),
+                    location: frame.code.locations[frame.pc.saturating_sub(1)],
+                });
+            }
+            if let Some(block) = frame.blocks.pop() {
+                self.stack.truncate(block.stack_level);
+                frame.handled.truncate(block.handled_level);
+                frame.pc = block.handler;
+                self.stack.push(Value::from(raised.exception));
+                frame.handled.push(raised);
+                return Ok(());
+            }
+            let frame = self.frames.pop().unwrap();
+            self.stack.truncate(frame.stack_base);
+            if self.frames.len() == depth {
+                return Err(raised);
+            }
+        }
+    }
+
+    fn constant(&mut self, index: u32) -> Value {
+        let code = Rc::clone(&self.frame().code);
+        match &code.constants[index as usize] {
+            Constant::None => Value::NONE,
+            Constant::Bool(b) => Value::from(*b),
+            Constant::Int(i) => match i.to_i64().and_then(Value::int) {
+                Some(value) => value,
+                None => self.heap_constant(index, || Object::BigInt(i.clone())),
+            },
+            Constant::Float(x) => Value::from(*x),
+            Constant::Str(s) => self.heap_constant(index, || Object::Str(s.clone())),
+            Constant::Code(_) => unreachable!("code is only loaded by MakeFunction"),
+        }
+    }
+
+    /// The object of constant `index` of the running frame, allocated with
+    /// `object` the first time.
+    fn heap_constant(&mut self, index: u32, object: impl FnOnce() -> Object) -> Value {
+        let key = (Rc::as_ptr(&self.frame().code) as usize, index);
+        if let Some(&constant) = self.constants.get(&key) {
+            return Value::from(constant);
+        }
+        let constant = self.heap.alloc(object());
+        self.constants.insert(key, constant);
+        Value::from(constant)
+    }
+
+    /// The code of the running frame, to look names up in while the frame
+    /// is borrowed mutably.
+    fn code(&self) -> Rc<CodeObject> {
+        Rc::clone(&self.frame().code)
+    }
+
+    /// The globals of the code of `module`, or of the program.
+    fn globals(&self, module: Option<ObjRef>) -> &FxHashMap<String, Value> {
+        match module {
+            Some(module) => &self.heap.class(module).unwrap().namespace,
+            None => &self.globals,
+        }
+    }
+
+    fn store_global(&mut self, name: String, value: Value) {
+        let receiver = self.receiver(&value);
+        self.specialisations.constant_changed(&name, receiver);
+        match self.frame().module {
+            // Other modules may have cached the attribute.
+            Some(module) => {
+                if let Object::Class(module) = self.heap.get_mut(module) {
+                    if module.namespace.insert(name, value) != Some(value) {
+                        self.caches.invalidate();
+                    }
+                }
+            }
+            None => {
+                self.globals.insert(name, value);
+            }
+        }
+    }
+
+    fn delete_global(&mut self, name: &str) -> VmResult<()> {
+        let deleted = match self.frame().module {
+            Some(module) => match self.heap.get_mut(module) {
+                Object::Class(module) => module.namespace.remove(name).is_some(),
+                _ => false,
+            },
+            None => self.globals.remove(name).is_some(),
+        };
+        if !deleted {
+            return Err(self.error(ErrorKind::NameError, format!("name '{}' is not defined", name)));
+        }
+        self.caches.invalidate();
+        self.specialisations.constant_deleted(name);
+        Ok(())
+    }
+
+    fn load_global(&mut self, name: &str) -> VmResult<Value> {
+        let globals = self.globals(self.frame().module);
+        match globals.get(name).or_else(|| self.builtins.get(name)) {
+            Some(value) => Ok(*value),
+            None => Err(self.error(ErrorKind::NameError, format!("name '{}' is not defined", name))),
+        }
+    }
+
+    fn cell(&self, index: u32) -> ObjRef {
+        self.frame().cells[index as usize]
+    }
+
+    fn step(&mut self, instruction: Instruction) -> VmResult<Flow> {
+        match instruction {
+            Instruction::LoadConst(index) => {
+                let value = self.constant(index);
+                self.push(value);
+            }
+            Instruction::Pop => {
+                self.pop();
+            }
+            Instruction::Dup => {
+                let value = *self.top();
+                self.push(value);
+            }
+            Instruction::DupTwo => {
+                let n = self.stack.len();
+                self.stack.extend_from_within(n - 2..);
+            }
+            Instruction::RotTwo => {
+                let n = self.stack.len();
+                self.stack.swap(n - 1, n - 2);
+            }
+            Instruction::RotThree => {
+                let top = self.pop();
+                let n = self.stack.len();
+                self.stack.insert(n - 2, top);
+            }
+            Instruction::LoadLocal(index) => {
+                let value = self.frame().locals[index as usize];
+                self.push(value);
+            }
+            Instruction::StoreLocal(index) => {
+                let value = self.pop();
+                self.frame_mut().locals[index as usize] = value;
+            }
+            Instruction::LoadCell(index) => {
+                let value = match self.heap.get(self.cell(index)) {
+                    Object::Cell(value) => *value,
+                    _ => unreachable!(),
+                };
+                self.push(value);
+            }
+            Instruction::StoreCell(index) => {
+                let value = self.pop();
+                *self.heap.get_mut(self.cell(index)) = Object::Cell(value);
+            }
+            Instruction::LoadClosure(index) => {
+                let cell = self.cell(index);
+                self.push(Value::from(cell));
+            }
+            Instruction::LoadGlobal(index) => {
+                let code = self.code();
+                let value = self.load_global(&code.names[index as usize])?;
+                self.push(value);
+            }
+            Instruction::StoreGlobal(index) => {
+                let code = self.code();
+                let value = self.pop();
+                self.store_global(code.names[index as usize].clone(), value);
+            }
+            Instruction::DeleteGlobal(index) => {
+                let code = self.code();
+                self.delete_global(&code.names[index as usize])?;
+            }
+            Instruction::LoadName(index) => {
+                let code = self.code();
+                let name = &code.names[index as usize];
+                let value = match self.frame().namespace.as_ref().and_then(|namespace| namespace.get(name)) {
+                    Some(value) => *value,
+                    None => self.load_global(name)?,
+                };
+                self.push(value);
+            }
+            Instruction::StoreName(index) => {
+                let code = self.code();
+                let value = self.pop();
+                let name = code.names[index as usize].clone();
+                match &mut self.frame_mut().namespace {
+                    Some(namespace) => {
+                        namespace.insert(name, value);
+                    }
+                    None => self.store_global(name, value),
+                }
+            }
+            Instruction::DeleteName(index) => {
+                let code = self.code();
+                let name = &code.names[index as usize];
+                let deleted = match &mut self.frame_mut().namespace {
+                    Some(namespace) => namespace.remove(name).is_some(),
+                    None => false,
+                };
+                if !deleted {
+                    self.delete_global(name)?;
+                }
+            }
+            Instruction::LoadAttr { name, cache } => {
+                let code = self.code();
+                let object = self.pop();
+                let lookup = self.cached_lookup(cache, &object, &code.names[name as usize])?;
+                let value = self.attribute_value(&object, lookup);
+                self.push(value);
+            }
+            Instruction::StoreAttr { name, cache } => {
+                let code = self.code();
+                let object = self.pop();
+                let value = self.pop();
+                self.cached_store(cache, &object, &code.names[name as usize], value)?;
+            }
+            Instruction::DeleteAttr(index) => {
+                let code = self.code();
+                let object = self.pop();
+                self.delete_attr(&object, &code.names[index as usize])?;
+            }
+            Instruction::LoadSubscript => {
+                let index = self.pop();
+                let object = self.pop();
+                let item = self.get_item(&object, &index)?;
+                self.push(item);
+            }
+            Instruction::StoreSubscript => {
+                let index = self.pop();
+                let object = self.pop();
+                let value = self.pop();
+                self.set_item(&object, index, value)?;
+            }
+            Instruction::DeleteSubscript => {
+                let index = self.pop();
+                let object = self.pop();
+                self.delete_item(&object, &index)?;
+            }
+            Instruction::BinaryOp(op) => {
+                let right = self.pop();
+                let left = self.pop();
+                let result = self.binary_op(op, &left, &right)?;
+                self.push(result);
+            }
+            Instruction::UnaryOp(op) => {
+                let operand = self.pop();
+                let result = self.unary_op(op, &operand)?;
+                self.push(result);
+            }
+            Instruction::Compare(op) => {
+                let right = self.pop();
+                let left = self.pop();
+                let result = self.compare(op, &left, &right)?;
+                self.push(Value::from(result));
+            }
+            Instruction::Jump(target) => {
+                let backward = (target as usize) < self.frame().pc;
+                self.jump(target);
+                if backward {
+                    self.count_loop();
+                }
+            }
+            Instruction::JumpIfFalse(target) => {
+                let value = self.pop();
+                if !self.truthy(&value) {
+                    self.jump(target);
+                }
+            }
+            Instruction::JumpIfTrue(target) => {
+                let value = self.pop();
+                if self.truthy(&value) {
+                    self.jump(target);
+                }
+            }
+            Instruction::JumpIfFalseOrPop(target) => {
+                if self.truthy(self.top()) {
+                    self.pop();
+                } else {
+                    self.jump(target);
+                }
+            }
+            Instruction::JumpIfTrueOrPop(target) => {
+                if self.truthy(self.top()) {
+                    self.jump(target);
+                } else {
+                    self.pop();
+                }
+            }
+            Instruction::JumpIfNotNoneOrPop(target) => {
+                if self.top().is_none() {
+                    self.pop();
+                } else {
+                    self.jump(target);
+                }
+            }
+            Instruction::JumpIfNone(target) => {
+                if self.top().is_none() {
+                    self.jump(target);
+                }
+            }
+            Instruction::GetIter => {
+                let value = self.pop();
+                let iterator = self.iter(&value)?;
+                self.push(iterator);
+            }
+            Instruction::ForIter(target) => {
+                let iterator = *self.top();
+                match self.next(&iterator)? {
+                    Some(item) => self.push(item),
+                    None => {
+                        self.pop();
+                        self.jump(target);
+                    }
+                }
+            }
+            Instruction::BuildList(n) => {
+                let items = self.pop_n(n as usize);
+                let list = self.alloc_list(items);
+                self.push(list);
+            }
+            Instruction::BuildTuple(n) => {
+                let items = self.pop_n(n as usize);
+                let tuple = self.heap.alloc(Object::Tuple(items));
+                self.push(Value::from(tuple));
+            }
+            Instruction::BuildDict(n) => {
+                let items = self.pop_n(2 * n as usize);
+                let dict = Value::from(self.heap.alloc(Object::Dict(vec![])));
+                let mut items = items.into_iter();
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    self.set_item(&dict, key, value)?;
+                }
+                self.push(dict);
+            }
+            Instruction::BuildSet(n) => {
+                let items = self.pop_n(n as usize);
+                let set = self.heap.alloc(Object::Set(vec![]));
+                for item in items {
+                    self.add_to_set(set, item);
+                }
+                self.push(Value::from(set));
+            }
+            Instruction::BuildString(n) => {
+                let parts = self.pop_n(n as usize);
+                let string: String = parts.iter().map(|part| self.to_str(part)).collect();
+                let string = self.alloc_str(string);
+                self.push(string);
+            }
+            Instruction::FormatValue(conversion) => {
+                let value = self.pop();
+                let string = match conversion {
+                    crate::ast::ConversionFlag::Repr | crate::ast::ConversionFlag::Ascii => self.repr(&value),
+                    _ => self.to_str(&value),
+                };
+                let string = self.alloc_str(string);
+                self.push(string);
+            }
+            Instruction::FormatWithSpec(conversion) => {
+                let spec = self.pop();
+                let value = self.pop();
+                let spec = self.to_str(&spec);
+                let value = match conversion {
+                    crate::ast::ConversionFlag::None => value,
+                    crate::ast::ConversionFlag::Repr | crate::ast::ConversionFlag::Ascii => {
+                        let string = self.repr(&value);
+                        self.alloc_str(string)
+                    }
+                    crate::ast::ConversionFlag::Str => {
+                        let string = self.to_str(&value);
+                        self.alloc_str(string)
+                    }
+                };
+                let string = self.format(&value, &spec)?;
+                let string = self.alloc_str(string);
+                self.push(string);
+            }
+            Instruction::BuildRange { inclusive } => {
+                let upper = self.pop();
+                let lower = self.pop();
+                let start = self.to_i64(&lower)?;
+                let stop = self.to_i64(&upper)?;
+                let stop = if inclusive { stop.saturating_add(1) } else { stop };
+                let range = self.heap.alloc(Object::Range { start, stop });
+                self.push(Value::from(range));
+            }
+            Instruction::BuildSlice => {
+                let step = self.pop();
+                let upper = self.pop();
+                let lower = self.pop();
+                let slice = self.heap.alloc(Object::Slice { lower, upper, step });
+                self.push(Value::from(slice));
+            }
+            Instruction::UnpackSequence(n) => {
+                let value = self.pop();
+                let items = self.items(&value)?;
+                if items.len() != n as usize {
+                    return Err(self.error(
+                        ErrorKind::ValueError,
+                        format!("expected {} values to unpack, got {}", n, items.len()),
+                    ));
+                }
+                self.stack.extend(items.into_iter().rev());
+            }
+            Instruction::UnpackStarred { before, after } => {
+                let value = self.pop();
+                let mut items = self.items(&value)?;
+                let (before, after) = (before as usize, after as usize);
+                if items.len() < before + after {
+                    return Err(self.error(
+                        ErrorKind::ValueError,
+                        format!("expected at least {} values to unpack, got {}", before + after, items.len()),
+                    ));
+                }
+                let last = items.split_off(items.len() - after);
+                let middle = items.split_off(before);
+                let middle = self.alloc_list(middle);
+                self.stack.extend(last.into_iter().rev());
+                self.push(middle);
+                self.stack.extend(items.into_iter().rev());
+            }
+            Instruction::MatchSequence { len, star } => {
+                let count = match self.top().as_object().map(|object| self.heap.get(object)) {
+                    Some(Object::List(items) | Object::Tuple(items)) => Some(items.len()),
+                    _ => None,
+                };
+                let len = len as usize;
+                let matched = count.is_some_and(|count| if star { count >= len } else { count == len });
+                self.push(Value::from(matched));
+            }
+            Instruction::MatchKeys => {
+                let keys = self.pop();
+                let keys = match self.heap.get(keys.as_object().unwrap()) {
+                    Object::Tuple(keys) => keys.clone(),
+                    _ => unreachable!("the keys of a mapping pattern are a tuple"),
+                };
+                let values = match self.top().as_object().map(|object| self.heap.get(object)) {
+                    Some(Object::Dict(entries)) => keys
+                        .iter()
+                        .map(|key| entries.iter().find(|(known, _)| self.equals(known, key)).map(|(_, value)| *value))
+                        .collect(),
+                    _ => None,
+                };
+                let values = match values {
+                    Some(values) => Value::from(self.heap.alloc(Object::Tuple(values))),
+                    None => Value::NONE,
+                };
+                self.push(values);
+            }
+            Instruction::MatchClass(npos) => {
+                let names = self.pop();
+                let class = self.pop();
+                let names = match self.heap.get(names.as_object().unwrap()) {
+                    Object::Tuple(names) => names.clone(),
+                    _ => unreachable!("the attributes of a class pattern are a tuple"),
+                };
+                let value = *self.top();
+                let attributes = match self.match_class(&value, &class, npos as usize, &names)? {
+                    Some(attributes) => Value::from(self.heap.alloc(Object::Tuple(attributes))),
+                    None => Value::NONE,
+                };
+                self.push(attributes);
+            }
+            Instruction::ListAppend(n) => {
+                let item = self.pop();
+                let list = self.stack[self.stack.len() - n as usize].as_object().unwrap();
+                if let Object::List(items) = self.heap.get_mut(list) {
+                    items.push(item);
+                }
+            }
+            Instruction::SetAdd(n) => {
+                let item = self.pop();
+                let set = self.stack[self.stack.len() - n as usize].as_object().unwrap();
+                self.add_to_set(set, item);
+            }
+            Instruction::MapAdd(n) => {
+                let value = self.pop();
+                let key = self.pop();
+                let dict = self.stack[self.stack.len() - n as usize];
+                self.set_item(&dict, key, value)?;
+            }
+            Instruction::MakeFunction { code, defaults } => {
+                let code = match &self.frame().code.constants[code as usize] {
+                    Constant::Code(code) => Rc::clone(code),
+                    _ => unreachable!(),
+                };
+                let closure = self
+                    .pop_n(code.frees.len())
+                    .into_iter()
+                    .map(|cell| cell.as_object().unwrap())
+                    .collect();
+                let defaults = self.pop_n(defaults as usize);
+                // A block or comprehension calls `super()` for the method it is made in.
+                let owner = match code.kind {
+                    CodeKind::Block | CodeKind::Comprehension => self.frame().function.and_then(|function| match self.heap.get(function) {
+                        Object::Function(function) => function.owner,
+                        _ => None,
+                    }),
+                    _ => None,
+                };
+                let module = self.frame().module;
+                let function = self.heap.alloc(Object::Function(Function {
+                    code,
+                    defaults,
+                    closure,
+                    owner,
+                    module,
+                }));
+                self.push(Value::from(function));
+            }
+            Instruction::Call(argc) => {
+                let args = self.pop_n(argc as usize);
+                let callee = self.pop();
+                self.call_from_frame(callee, args)?;
+            }
+            Instruction::CallKw(argc) => {
+                let names = self.pop();
+                let args = self.pop_n(argc as usize);
+                let callee = self.pop();
+                let args = self.bind_keywords(&callee, args, &names)?;
+                self.call_from_frame(callee, args)?;
+            }
+            Instruction::CallMethod { name, argc, cache } => {
+                let code = self.code();
+                let mut args = self.pop_n(argc as usize);
+                let receiver = self.pop();
+                match self.cached_lookup(cache, &receiver, &code.names[name as usize])? {
+                    Lookup::Method(function) => {
+                        args.insert(0, receiver);
+                        self.call_from_frame(function, args)?;
+                    }
+                    lookup => {
+                        let callee = self.attribute_value(&receiver, lookup);
+                        self.call_from_frame(callee, args)?;
+                    }
+                }
+            }
+            Instruction::CallSuper { name, argc } => {
+                let code = self.code();
+                let mut args = self.pop_n(argc as usize);
+                let receiver = self.pop();
+                let method = self.super_method(&receiver, &code.names[name as usize])?;
+                args.insert(0, receiver);
+                self.call_from_frame(method, args)?;
+            }
+            Instruction::LoadSuper(name) => {
+                let code = self.code();
+                let receiver = self.pop();
+                let method = self.super_method(&receiver, &code.names[name as usize])?;
+                let bound = self.heap.alloc(Object::BoundMethod { receiver, method });
+                self.push(Value::from(bound));
+            }
+            Instruction::Return => {
+                let value = self.pop();
+                return Ok(Flow::Return(value));
+            }
+            Instruction::Await => {
+                let value = self.pop();
+                return Ok(Flow::Await(value));
+            }
+            Instruction::Yield => {
+                let value = self.pop();
+                return Ok(Flow::Yield(value));
+            }
+            Instruction::BuildClass { name, bases, module } => {
+                let code = self.code();
+                let bases = self.pop_n(bases as usize);
+                let body = self.pop();
+                let mut classes = vec![];
+                for base in bases {
+                    match base.as_object().filter(|&base| self.heap.class(base).is_some()) {
+                        Some(base) => classes.push(base),
+                        None => {
+                            let message = format!("can't extend '{}', which is not a class or module", self.type_name(&base));
+                            return Err(self.error(ErrorKind::TypeError, message));
+                        }
+                    }
+                }
+                let name = &code.names[name as usize];
+                let action = match self.class_to_reopen(name, module) {
+                    Some(class) => {
+                        let existing = &self.heap.class(class).unwrap().bases;
+                        if !classes.is_empty() && classes != *existing {
+                            let message = format!("can't reopen '{}' with other classes or modules to extend", name);
+                            return Err(self.error(ErrorKind::TypeError, message));
+                        }
+                        ReturnAction::Reopen(class)
+                    }
+                    None => ReturnAction::Class {
+                        name: name.clone(),
+                        bases: classes,
+                        is_module: module,
+                    },
+                };
+                let function = body.as_object().unwrap();
+                self.push_frame(function, vec![], action)?;
+                self.frame_mut().namespace = Some(FxHashMap::default());
+            }
+            Instruction::BuildEnum { name, variants } => {
+                let code = self.code();
+                let name = &code.names[name as usize];
+                let variants = self.pop_n(2 * variants as usize);
+                let enumeration = self.build_class(name.clone(), vec![], false, FxHashMap::default());
+                let initialize = Value::from(self.heap.alloc(Object::Builtin(builtins::VARIANT_INITIALIZE)));
+                for variant in variants.chunks(2) {
+                    let fields: Vec<String> = match self.heap.get(variant[1].as_object().unwrap()) {
+                        Object::Tuple(fields) => fields.iter().map(|field| self.to_str(field)).collect(),
+                        _ => unreachable!("the fields of a variant are a tuple"),
+                    };
+                    let variant_name = self.to_str(&variant[0]);
+                    let mut namespace = FxHashMap::default();
+                    namespace.insert("match_args".to_owned(), variant[1]);
+                    if !fields.is_empty() {
+                        namespace.insert("initialize".to_owned(), initialize);
+                    }
+                    let qualified = format!("{}.{}", name, variant_name);
+                    let class = self.build_class(qualified, vec![enumeration], false, namespace);
+                    self.shapes.declare(class, &fields);
+                    // A variant without fields is its only instance.
+                    let value = match fields.is_empty() {
+                        true => Value::from(self.new_instance(class)),
+                        false => Value::from(class),
+                    };
+                    if let Object::Class(enumeration) = self.heap.get_mut(enumeration) {
+                        enumeration.namespace.insert(variant_name, value);
+                    }
+                }
+                self.push(Value::from(enumeration));
+            }
+            Instruction::ImportModule(index) => {
+                let code = self.code();
+                let module = self.import(&code.names[index as usize])?;
+                if let Some(module) = module {
+                    self.push(Value::from(module));
+                }
+            }
+            Instruction::ImportName(index) => {
+                let code = self.code();
+                let name = &code.names[index as usize];
+                let module = self.top().as_object().unwrap();
+                let module = self.heap.class(module).unwrap();
+                match module.namespace.get(name) {
+                    Some(&value) => self.push(value),
+                    None => {
+                        let message = format!("can't import name '{}' from '{}'", name, module.name);
+                        return Err(self.error(ErrorKind::ImportError, message));
+                    }
+                }
+            }
+            Instruction::SetupExcept(handler) => {
+                let stack_level = self.stack.len();
+                let frame = self.frame_mut();
+                let handled_level = frame.handled.len();
+                frame.blocks.push(Block {
+                    handler: handler as usize,
+                    stack_level,
+                    handled_level,
+                });
+            }
+            Instruction::PopBlock => {
+                self.frame_mut().blocks.pop();
+            }
+            Instruction::ExceptionMatch => {
+                let class = self.pop();
+                let exception = *self.top();
+                let matches = self.exception_matches(&exception, &class)?;
+                self.push(Value::from(matches));
+            }
+            Instruction::PopExcept => {
+                self.frame_mut().handled.pop();
+            }
+            Instruction::Raise => {
+                let value = self.pop();
+                let exception = self.make_exception(value)?;
+                return Err(Raised::new(exception));
+            }
+            Instruction::Reraise => {
+                return Err(match self.frame_mut().handled.pop() {
+                    Some(mut raised) => {
+                        raised.reraised = true;
+                        raised
+                    }
+                    None => self.error(ErrorKind::RuntimeError, "no active exception to reraise"),
+                });
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Call `callee` for the running frame: the result is pushed, or the
+    /// frame of the callee runs next.
+    fn call_from_frame(&mut self, callee: Value, args: Vec<Value>) -> VmResult<()> {
+        if let Invoked::Value(value) = self.invoke(callee, args)? {
+            self.push(value);
+        }
+        Ok(())
+    }
+
+    fn invoke(&mut self, callee: Value, mut args: Vec<Value>) -> VmResult<Invoked> {
+        let object = match callee.as_object() {
+            Some(object) => object,
+            None => return Err(self.not_callable(&callee)),
+        };
+        match self.heap.get(object) {
+            Object::Function(function) if function.code.is_async => {
+                self.push_frame(object, args, ReturnAction::Value)?;
+                let promise = self.heap.alloc(Object::Promise(Promise::Pending(vec![])));
+                self.run_coroutine(promise, None);
+                Ok(Invoked::Value(Value::from(promise)))
+            }
+            Object::Function(function) if function.code.is_generator => {
+                self.push_frame(object, args, ReturnAction::Value)?;
+                let (frame, stack) = self.suspend_frame();
+                let generator = Generator {
+                    suspended: Some((frame, stack)),
+                    started: false,
+                    running: false,
+                };
+                let generator = self.heap.alloc(Object::Iterator(Iter::Generator(Box::new(generator))));
+                Ok(Invoked::Value(Value::from(generator)))
+            }
+            Object::Function(_) => {
+                self.push_frame(object, args, ReturnAction::Value)?;
+                Ok(Invoked::Frame)
+            }
+            Object::Builtin(builtin) => {
+                let function = builtin.function;
+                Ok(Invoked::Value(function(self, args)?))
+            }
+            Object::BoundMethod { receiver, method } => {
+                let method = *method;
+                args.insert(0, *receiver);
+                self.invoke(method, args)
+            }
+            Object::Class(class) if !class.is_module => self.instantiate(object, args),
+            _ => Err(self.not_callable(&callee)),
+        }
+    }
+
+    fn not_callable(&mut self, value: &Value) -> Raised {
+        let message = format!("'{}' object is not callable", self.type_name(value));
+        self.error(ErrorKind::TypeError, message)
+    }
+
+    fn instantiate(&mut self, class: ObjRef, mut args: Vec<Value>) -> VmResult<Invoked> {
+        let instance = self.new_instance(class);
+        match self.lookup_in_class(class, "initialize") {
+            Some(initialize) => {
+                args.insert(0, Value::from(instance));
+                match self.invoke(initialize, args)? {
+                    Invoked::Frame => self.frame_mut().on_return = ReturnAction::Instance(instance),
+                    Invoked::Value(_) => return Ok(Invoked::Value(Value::from(instance))),
+                }
+                Ok(Invoked::Frame)
+            }
+            None if args.is_empty() => Ok(Invoked::Value(Value::from(instance))),
+            None => {
+                let name = self.heap.class(class).unwrap().name.clone();
+                Err(self.error(ErrorKind::TypeError, format!("{}() takes no arguments", name)))
+            }
+        }
+    }
+
+    /// The arguments of a call of `callee` with `args`, the last of which
+    /// are the keyword arguments the tuple `names` names, with every one in
+    /// the position of its parameter. The parameters left out before the
+    /// last one given get their defaults.
+    fn bind_keywords(&mut self, callee: &Value, mut args: Vec<Value>, names: &Value) -> VmResult<Vec<Value>> {
+        let names = match names.as_object().map(|names| self.heap.get(names)) {
+            Some(Object::Tuple(names)) => names.clone(),
+            _ => unreachable!("keyword names are a tuple"),
+        };
+        let (function, implicit) = self.keyword_target(callee)?;
+        let (code, defaults) = match self.heap.get(function) {
+            Object::Function(function) => (Rc::clone(&function.code), function.defaults.clone()),
+            _ => unreachable!(),
+        };
+        let arg_count = code.arg_count as usize;
+        let required = arg_count - defaults.len();
+        let values = args.split_off(args.len() - names.len());
+        let mut bound: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+        let params = &code.locals[implicit.min(arg_count)..arg_count];
+        for (name, value) in names.iter().zip(values) {
+            let name = self.to_str(name);
+            let position = match params.iter().position(|param| *param == name) {
+                Some(position) => position,
+                None => {
+                    let message = format!("{}() got an unexpected keyword argument '{}'", code.name, name);
+                    return Err(self.error(ErrorKind::TypeError, message));
+                }
+            };
+            if bound.len() <= position {
+                bound.resize(position + 1, None);
+            }
+            if bound[position].replace(value).is_some() {
+                let message = format!("{}() got multiple values for argument '{}'", code.name, name);
+                return Err(self.error(ErrorKind::TypeError, message));
+            }
+        }
+        let mut args = Vec::with_capacity(bound.len());
+        for (i, value) in bound.into_iter().enumerate() {
+            let param = implicit + i;
+            let value = match value {
+                Some(value) => value,
+                None if param >= required => defaults[param - required],
+                // Blocks are lenient about their arguments.
+                None if code.kind == CodeKind::Block => Value::NONE,
+                None => {
+                    let message = format!("{}() missing argument '{}'", code.name, code.locals[param]);
+                    return Err(self.error(ErrorKind::TypeError, message));
+                }
+            };
+            args.push(value);
+        }
+        Ok(args)
+    }
+
+    /// The function a call of `callee` runs, and how many arguments it
+    /// passes it before the ones it is given, for keyword arguments to be
+    /// bound to its parameters.
+    fn keyword_target(&mut self, callee: &Value) -> VmResult<(ObjRef, usize)> {
+        let mut callee = *callee;
+        let mut implicit = 0;
+        loop {
+            let object = match callee.as_object() {
+                Some(object) => object,
+                None => return Err(self.not_callable(&callee)),
+            };
+            let name = match self.heap.get(object) {
+                Object::Function(_) => return Ok((object, implicit)),
+                Object::BoundMethod { method, .. } => {
+                    callee = *method;
+                    implicit += 1;
+                    continue;
+                }
+                Object::Class(class) if !class.is_module => match self.lookup_in_class(object, "initialize") {
+                    Some(initialize) => {
+                        callee = initialize;
+                        implicit += 1;
+                        continue;
+                    }
+                    None => class.name.clone(),
+                },
+                Object::Builtin(builtin) => builtin.name.to_owned(),
+                _ => return Err(self.not_callable(&callee)),
+            };
+            return Err(self.error(ErrorKind::TypeError, format!("{}() takes no keyword arguments", name)));
+        }
+    }
+
+    /// Push a frame that runs `function` with `args`.
+    fn push_frame(&mut self, function: ObjRef, args: Vec<Value>, on_return: ReturnAction) -> VmResult<()> {
+        if self.frames.len() >= MAX_FRAMES {
+            return Err(self.error(ErrorKind::RuntimeError, "maximum recursion depth exceeded"));
+        }
+        let (code, defaults, closure, module) = match self.heap.get(function) {
+            Object::Function(function) => (
+                Rc::clone(&function.code),
+                function.defaults.clone(),
+                function.closure.clone(),
+                function.module,
+            ),
+            _ => unreachable!(),
+        };
+        let mut locals = vec![Value::NONE; code.locals.len()];
+        let arg_count = code.arg_count as usize;
+        let required = arg_count - defaults.len();
+        // Blocks are lenient about their arguments, like Ruby blocks.
+        if code.kind != CodeKind::Block && (args.len() < required || (args.len() > arg_count && !code.varargs)) {
+            // `self` is passed implicitly, so it doesn't count.
+            let implicit = (code.kind == CodeKind::Method) as usize;
+            let expected = if defaults.is_empty() || code.varargs {
+                format!("{}", required - implicit)
+            } else {
+                format!("from {} to {}", required - implicit, arg_count - implicit)
+            };
+            let message = format!(
+                "{}() takes {} arguments but {} were given",
+                code.name,
+                expected,
+                args.len() - implicit.min(args.len())
+            );
+            return Err(self.error(ErrorKind::TypeError, message));
+        }
+        // Specialisations run the same instructions, so they can run the
+        // native code of their generic code too. Coroutines and generators
+        // always run in the interpreter, as native frames can't be suspended.
+        let precompiled = self
+            .precompiled
+            .get(&(Rc::as_ptr(&code) as usize))
+            .filter(|_| !code.is_async && !code.is_generator)
+            .map(|(_, compiled)| Rc::clone(compiled));
+        let (code, specialisation) = self.specialise(code, module, &args);
+        let compiled = specialisation.and_then(|id| self.tier_up(id)).or(precompiled);
+        let mut args = args.into_iter();
+        for (i, local) in locals.iter_mut().enumerate().take(arg_count) {
+            match args.next() {
+                Some(arg) => *local = arg,
+                None if i >= required => *local = defaults[i - required],
+                None => {}
+            }
+        }
+        if code.varargs {
+            let rest = args.collect();
+            locals[arg_count] = Value::from(self.heap.alloc(Object::Tuple(rest)));
+        }
+        let mut cells: Vec<ObjRef> = code
+            .cell_args
+            .iter()
+            .map(|arg| {
+                let value = arg.map_or(Value::NONE, |arg| locals[arg as usize]);
+                self.heap.alloc(Object::Cell(value))
+            })
+            .collect();
+        cells.extend(closure);
+        self.frames.push(Frame {
+            code,
+            function: Some(function),
+            module,
+            pc: 0,
+            locals,
+            cells,
+            stack_base: self.stack.len(),
+            blocks: vec![],
+            handled: vec![],
+            namespace: None,
+            on_return,
+            specialisation,
+            compiled,
+        });
+        Ok(())
+    }
+
+    /// The code to run for a call of `code` with `args`: that of its
+    /// specialisation for the shapes of the arguments, made if there is none
+    /// yet, or the generic code if it can't have more specialisations. Only
+    /// functions and methods are specialised, not blocks, coroutines or
+    /// generators. The globals the code refers to are those of `module`.
+    fn specialise(
+        &mut self,
+        code: Rc<CodeObject>,
+        module: Option<ObjRef>,
+        args: &[Value],
+    ) -> (Rc<CodeObject>, Option<SpecialisationId>) {
+        if !matches!(code.kind, CodeKind::Function | CodeKind::Method) || code.is_async || code.is_generator {
+            return (code, None);
+        }
+        let arguments: Vec<Receiver> = args.iter().map(|arg| self.receiver(arg)).collect();
+        if let Some(id) = self.specialisations.find(&code, &arguments) {
+            let specialised = Rc::clone(&self.specialisations.get(id).unwrap().code);
+            return (specialised, Some(id));
+        }
+        if self.specialisations.is_full(&code) {
+            self.specialisations.stats.generic += 1;
+            return (code, None);
+        }
+        let constants: Vec<(String, Receiver)> = specialise::referenced_globals(&code)
+            .into_iter()
+            .map(|name| {
+                let receiver = match self.globals(module).get(name).or_else(|| self.builtins.get(name)) {
+                    Some(value) => self.receiver(value),
+                    None => Receiver::Type("undefined"),
+                };
+                (name.to_owned(), receiver)
+            })
+            .collect();
+        let mut classes = vec![];
+        for receiver in arguments.iter().chain(constants.iter().map(|(_, receiver)| receiver)) {
+            for class in self.classes_of(*receiver) {
+                if !classes.contains(&class) {
+                    classes.push(class);
+                }
+            }
+        }
+        let id = self.specialisations.insert(&code, arguments, constants, classes);
+        (Rc::clone(&self.specialisations.get(id).unwrap().code), Some(id))
+    }
+
+    /// The classes and modules whose changes change what values of shape
+    /// `receiver` do: their class, with everything it includes.
+    fn classes_of(&self, receiver: Receiver) -> Vec<ObjRef> {
+        let class = match receiver {
+            Receiver::Shape(shape) => self.shapes.get(shape).class,
+            Receiver::Class(class) => class,
+            Receiver::Type(_) => return vec![],
+        };
+        self.heap.class(class).map_or_else(|| vec![class], |class| class.mro.clone())
+    }
+
+    /// The native code of specialisation `id`, compiling it if the JIT mode
+    /// says it is time to.
+    fn tier_up(&mut self, id: SpecialisationId) -> Option<Rc<Compiled>> {
+        let specialisation = self.specialisations.get(id)?;
+        if specialisation.compiled.is_some() || specialisation.uncompilable {
+            return specialisation.compiled.clone();
+        }
+        // Code that was thrown away has to get hot again.
+        let heat = specialisation.recompilations as u64 + 1;
+        let due = match self.jit_mode {
+            JitMode::Interpreter => false,
+            JitMode::Tiered => {
+                specialisation.calls >= jit::CALL_THRESHOLD * heat
+                    || specialisation.loops >= jit::LOOP_THRESHOLD * heat
+            }
+            JitMode::Force => true,
+        };
+        if !due {
+            return None;
+        }
+        let code = Rc::clone(&specialisation.code);
+        let arguments = specialisation.arguments.clone();
+        let compiled = match self.jit.take().map_or_else(Jit::new, Ok) {
+            Ok(mut jit) => {
+                let compiled = jit.compile(&code, &arguments, self.caches.epoch());
+                self.jit = Some(jit);
+                compiled
+            }
+            Err(error) => Err(error),
+        };
+        match compiled {
+            Ok(compiled) => {
+                // It calls these methods directly, so it depends on the
+                // classes they were found in.
+                let mut classes = vec![];
+                for shape in &compiled.method_shapes {
+                    classes.extend(self.classes_of(Receiver::Shape(*shape)));
+                }
+                self.specialisations.add_dependencies(id, &classes);
+                let compiled = Rc::new(compiled);
+                self.specialisations.get_mut(id).unwrap().compiled = Some(Rc::clone(&compiled));
+                self.jit_stats.compiled += 1;
+                Some(compiled)
+            }
+            Err(_) => {
+                self.specialisations.get_mut(id).unwrap().uncompilable = true;
+                self.jit_stats.failed += 1;
+                None
+            }
+        }
+    }
+
+    /// Throw away native code that failed too many guards, so its
+    /// specialisation is compiled again once it is hot again, with what its
+    /// inline caches saw since.
+    fn discard_compiled(&mut self, id: Option<SpecialisationId>, compiled: &Rc<Compiled>) {
+        compiled.invalidate();
+        self.jit_stats.discarded += 1;
+        let specialisation = match id.and_then(|id| self.specialisations.get_mut(id)) {
+            Some(specialisation) => specialisation,
+            None => return,
+        };
+        if specialisation.compiled.as_ref().is_some_and(|own| Rc::ptr_eq(own, compiled)) {
+            specialisation.compiled = None;
+            specialisation.recompilations += 1;
+            specialisation.uncompilable = specialisation.recompilations >= jit::RECOMPILATION_LIMIT;
+        }
+    }
+
+    /// Count a backward jump of the running frame, and switch it to native
+    /// code from the loop header if that makes its specialisation compiled.
+    fn count_loop(&mut self) {
+        let id = match self.frame().specialisation {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(specialisation) = self.specialisations.get_mut(id) {
+            specialisation.loops += 1;
+        }
+        if let Some(compiled) = self.tier_up(id) {
+            self.frame_mut().compiled = Some(compiled);
+            self.jit_stats.loop_entries += 1;
+        }
+    }
+
+    /// Run the running frame in its native code, from its `pc`, until it
+    /// returns, raises an exception or deoptimises.
+    fn run_compiled(&mut self, compiled: Rc<Compiled>) -> VmResult<Flow> {
+        if compiled.is_invalidated() {
+            self.frame_mut().compiled = None;
+            return Ok(Flow::Continue);
+        }
+        self.jit_stats.entries += 1;
+        let pc = self.frame().pc as u32;
+        self.jit_locals = self.frame().locals.as_ptr();
+        let vm: *mut Vm = self;
+        // Native code reads and writes the stack and instances in place, at
+        // the offsets above, and does everything else through the
+        // `emerald_jit_*` entry points, which take the pointer it is given.
+        let status = unsafe { (compiled.entry)(vm, pc) };
+        if status == jit::EXITED {
+            return Ok(Flow::Continue);
+        }
+        let result = self.compiled_result.take().expect("native code stopped without a result");
+        debug_assert!(status == jit::RETURNED || status == jit::RAISED);
+        result.map(Flow::Return)
+    }
+
+    /// Hand the running frame from native code back to the interpreter,
+    /// which goes on at `pc`. Returns `jit::EXITED`, for native code to
+    /// return.
+    pub(crate) fn jit_deopt(&mut self, pc: usize, reason: DeoptReason) -> i32 {
+        let frame = self.frames.last_mut().unwrap();
+        let compiled = frame.compiled.take().expect("deoptimised a frame without native code");
+        // Native code keeps the instruction it is at to itself; the rest of
+        // the frame is where the interpreter expects it.
+        frame.pc = pc;
+        let depth = compiled.frame_map[pc].expect("deoptimised at an unreachable instruction");
+        debug_assert_eq!(self.stack.len() - frame.stack_base, depth as usize);
+        let specialisation = frame.specialisation;
+        self.jit_stats.deopts += 1;
+        if reason == DeoptReason::GuardFailed {
+            self.jit_stats.guard_failures += 1;
+            if compiled.guard_failed() {
+                self.discard_compiled(specialisation, &compiled);
+            }
+        }
+        jit::EXITED
+    }
+
+    /// Run the instruction at `pc` of the running frame for native code, and
+    /// the frame it pushes, if any, to completion. Returns the instruction
+    /// to run next, or `jit::RETURNED` or `jit::RAISED`.
+    pub(crate) fn jit_step(&mut self, pc: usize) -> i32 {
+        if self.heap.collection_due() {
+            self.collect_garbage();
+        }
+        let frame = self.frame_mut();
+        let instruction = frame.code.instructions[pc];
+        frame.pc = pc + 1;
+        let depth = self.frames.len();
+        let flow = self.step(instruction);
+        self.jit_continue(flow, depth)
+    }
+
+    /// What native code does next after an instruction of the frame at
+    /// `depth - 1` had `flow`.
+    fn jit_continue(&mut self, flow: VmResult<Flow>, depth: usize) -> i32 {
+        match flow {
+            Ok(Flow::Continue) => {
+                if self.frames.len() > depth {
+                    match self.execute(depth) {
+                        Ok(value) => self.push(value),
+                        Err(raised) => {
+                            self.compiled_result = Some(Err(raised));
+                            return jit::RAISED;
+                        }
+                    }
+                }
+                self.frame().pc as i32
+            }
+            Ok(Flow::Return(value)) => {
+                self.compiled_result = Some(Ok(value));
+                jit::RETURNED
+            }
+            Ok(Flow::Await(_) | Flow::Yield(_)) => unreachable!("coroutines and generators don't run native code"),
+            Err(raised) => {
+                self.compiled_result = Some(Err(raised));
+                jit::RAISED
+            }
+        }
+    }
+
+    /// `CallMethod` of `function` on the instance below `argc` arguments,
+    /// whose shape native code checked.
+    pub(crate) fn jit_call_method(&mut self, pc: usize, function: ObjRef, argc: usize) -> i32 {
+        self.frame_mut().pc = pc + 1;
+        let mut args = self.pop_n(argc);
+        args.insert(0, self.pop());
+        let depth = self.frames.len();
+        let flow = self.call_from_frame(Value::from(function), args).map(|()| Flow::Continue);
+        self.jit_continue(flow, depth)
+    }
+
+    fn build_class(
+        &mut self,
+        name: String,
+        bases: Vec<ObjRef>,
+        is_module: bool,
+        namespace: FxHashMap<String, Value>,
+    ) -> ObjRef {
+        let methods: Vec<ObjRef> = namespace.values().filter_map(Value::as_object).collect();
+        let class = self.heap.alloc(Object::Class(Class {
+            name,
+            is_module,
+            bases: bases.clone(),
+            mro: vec![],
+            namespace,
+        }));
+        let base_mros: Vec<Vec<ObjRef>> = bases
+            .iter()
+            .map(|&base| self.heap.class(base).unwrap().mro.clone())
+            .collect();
+        let resolution_order = mro::resolution_order(class, base_mros);
+        if let Object::Class(built) = self.heap.get_mut(class) {
+            built.mro = resolution_order;
+        }
+        for method in methods {
+            if let Object::Function(function) = self.heap.get_mut(method) {
+                function.owner.get_or_insert(class);
+            }
+        }
+        class
+    }
+
+    /// The module `spec` names, relative to the module of the running code,
+    /// if it was imported before. Otherwise push a frame that runs its code,
+    /// and returns it.
+    fn import(&mut self, spec: &str) -> VmResult<Option<ObjRef>> {
+        let importer = match self.frame().module {
+            Some(module) => &self.heap.class(module).unwrap().name,
+            None => program::MAIN,
+        };
+        let name = match program::resolve(importer, spec) {
+            Some(name) => name,
+            None => {
+                let message = format!("'{}' goes beyond the directory of the program", spec);
+                return Err(self.error(ErrorKind::ImportError, message));
+            }
+        };
+        if let Some(&module) = self.modules.get(&name) {
+            return Ok(Some(module));
+        }
+        let code = match self.module_code.get(&name) {
+            Some(code) => Rc::clone(code),
+            None => return Err(self.error(ErrorKind::ImportError, format!("no module named '{}'", name))),
+        };
+        // It is imported as soon as it starts running, so modules it
+        // imports can import it in turn.
+        let module = self.build_class(name.clone(), vec![], true, FxHashMap::default());
+        self.modules.insert(name, module);
+        let function = self.heap.alloc(Object::Function(Function {
+            code,
+            defaults: vec![],
+            closure: vec![],
+            owner: None,
+            module: Some(module),
+        }));
+        self.push_frame(function, vec![], ReturnAction::Module(module))?;
+        Ok(None)
+    }
+
+    /// The attributes a class pattern of `class` with `npos` positional
+    /// sub-patterns and the keyword ones `names` matches against `value`, in
+    /// that order, or None if it doesn't match.
+    fn match_class(
+        &mut self,
+        value: &Value,
+        class: &Value,
+        npos: usize,
+        names: &[Value],
+    ) -> VmResult<Option<Vec<Value>>> {
+        let class = match class.as_object().filter(|&class| self.heap.class(class).is_some()) {
+            Some(class) => class,
+            None if npos == 0 && names.is_empty() => return Ok((value == class).then(Vec::new)),
+            None => {
+                let message = format!("called match pattern must be a class, not '{}'", self.type_name(class));
+                return Err(self.error(ErrorKind::TypeError, message));
+            }
+        };
+        let instance = match value.as_object().map(|object| self.heap.get(object)) {
+            Some(Object::Instance(instance)) => instance,
+            _ => return Ok(None),
+        };
+        if !self.heap.class(instance.class).unwrap().mro.contains(&class) {
+            return Ok(None);
+        }
+        let positional = match self.lookup_in_class(class, "match_args").and_then(|names| names.as_object()) {
+            Some(names) => match self.heap.get(names) {
+                Object::Tuple(names) => names.clone(),
+                _ => vec![],
+            },
+            None => vec![],
+        };
+        if positional.len() < npos {
+            let message = format!(
+                "{}() accepts {} positional sub-patterns ({} given)",
+                self.heap.class(class).unwrap().name,
+                positional.len(),
+                npos
+            );
+            return Err(self.error(ErrorKind::TypeError, message));
+        }
+        let attributes = positional[..npos]
+            .iter()
+            .chain(names)
+            .map(|name| self.attribute(instance, &self.to_str(name)).copied())
+            .collect();
+        Ok(attributes)
+    }
+
+    /// Look `name` up in the namespaces of the method resolution order of `class`.
+    pub fn lookup_in_class(&self, class: ObjRef, name: &str) -> Option<Value> {
+        let class = self.heap.class(class)?;
+        class
+            .mro
+            .iter()
+            .find_map(|&included| self.heap.class(included).unwrap().namespace.get(name).cloned())
+    }
+
+    fn is_function(&self, value: &Value) -> bool {
+        value
+            .as_object()
+            .is_some_and(|object| matches!(self.heap.get(object), Object::Function(_) | Object::Builtin(_)))
+    }
+
+    /// The class or module bound to `name` where the running class statement
+    /// binds it, if there is one of the same kind, for the statement to reopen.
+    fn class_to_reopen(&self, name: &str, module: bool) -> Option<ObjRef> {
+        let frame = self.frame();
+        let existing = match (&frame.namespace, frame.code.kind) {
+            (Some(namespace), _) => namespace.get(name),
+            (None, CodeKind::Module) => self.globals(frame.module).get(name),
+            _ => None,
+        };
+        existing
+            .and_then(Value::as_object)
+            .filter(|&class| self.heap.class(class).is_some_and(|class| class.is_module == module))
+    }
+
+    fn reopen_class(&mut self, class: ObjRef, namespace: FxHashMap<String, Value>) {
+        for value in namespace.values() {
+            if let Some(Object::Function(function)) = value.as_object().map(|object| self.heap.get_mut(object)) {
+                function.owner.get_or_insert(class);
+            }
+        }
+        if let Object::Class(reopened) = self.heap.get_mut(class) {
+            reopened.namespace.extend(namespace);
+        }
+        self.caches.invalidate();
+        self.specialisations.class_changed(class);
+    }
+
+    /// What inline caches tell `value` apart by.
+    fn receiver(&self, value: &Value) -> Receiver {
+        match value.unbox() {
+            Unboxed::Object(object) => match self.heap.get(object) {
+                Object::Instance(instance) => Receiver::Shape(instance.shape),
+                Object::Class(_) => Receiver::Class(object),
+                object => Receiver::Type(object.type_name()),
+            },
+            Unboxed::None => Receiver::Type("NoneType"),
+            Unboxed::Bool(_) => Receiver::Type("bool"),
+            Unboxed::Int(_) => Receiver::Type("int"),
+            Unboxed::Float(_) => Receiver::Type("float"),
+        }
+    }
+
+    /// What attribute `name` of `receiver` is, without the inline caches.
+    fn lookup(&mut self, receiver: &Value, name: &str) -> VmResult<Lookup> {
+        if let Some(object) = receiver.as_object() {
+            match self.heap.get(object) {
+                Object::Instance(instance) => {
+                    if let Some(slot) = self.shapes.get(instance.shape).slot(name) {
+                        if instance.slot(slot).is_some() {
+                            return Ok(Lookup::Slot(slot));
+                        }
+                    }
+                    if let Some(value) = self.lookup_in_class(instance.class, name) {
+                        return Ok(if self.is_function(&value) {
+                            Lookup::Method(value)
+                        } else {
+                            Lookup::Value(value)
+                        });
+                    }
+                }
+                Object::Class(_) => {
+                    if let Some(value) = self.lookup_in_class(object, name) {
+                        return Ok(Lookup::Value(value));
+                    }
+                }
+                _ => {}
+            }
+        }
+        match builtins::method(self, receiver, name) {
+            Some(builtin) => Ok(Lookup::Method(Value::from(self.heap.alloc(Object::Builtin(builtin))))),
+            None => Err(self.no_attribute(receiver, name)),
+        }
+    }
+
+    /// What attribute `name` of `receiver` is, through the inline cache
+    /// `cache` of the running code.
+    fn cached_lookup(&mut self, cache: u32, receiver: &Value, name: &str) -> VmResult<Lookup> {
+        let key = self.receiver(receiver);
+        let code = self.code();
+        let mut sites = code.caches.borrow_mut();
+        let site = &mut sites[cache as usize];
+        if site.revalidate(self.caches.epoch()) {
+            self.caches.stats.invalidations += 1;
+        }
+        let (cached, megamorphic) = match site.probe(key) {
+            Probe::Hit(lookup) => (Some(lookup.clone()), false),
+            Probe::Miss => (None, false),
+            Probe::Megamorphic => (self.caches.megamorphic(key, name).cloned(), true),
+        };
+        // A deleted attribute leaves its slot in the shape, empty.
+        if let Some(lookup) = cached.filter(|lookup| self.is_set(receiver, lookup)) {
+            let stats = &mut self.caches.stats;
+            match site.cache {
+                InlineCache::Monomorphic(..) => stats.monomorphic_hits += 1,
+                InlineCache::Polymorphic(..) => stats.polymorphic_hits += 1,
+                _ => stats.megamorphic_hits += 1,
+            }
+            return Ok(lookup);
+        }
+        self.caches.stats.misses += 1;
+        let lookup = self.lookup(receiver, name)?;
+        // Whether an instance has an attribute in a slot of its shape doesn't
+        // follow from the shape once attributes have been deleted.
+        let has_empty_slot = match receiver.as_object().map(|object| self.heap.get(object)) {
+            Some(Object::Instance(instance)) => {
+                !matches!(lookup, Lookup::Slot(_)) && self.shapes.get(instance.shape).slot(name).is_some()
+            }
+            _ => false,
+        };
+        if !has_empty_slot {
+            if megamorphic {
+                self.caches.insert_megamorphic(key, name, lookup.clone());
+            } else {
+                site.insert(key, lookup.clone());
+            }
+        }
+        Ok(lookup)
+    }
+
+    fn is_set(&self, receiver: &Value, lookup: &Lookup) -> bool {
+        match (lookup, receiver.as_object().map(|object| self.heap.get(object))) {
+            (Lookup::Slot(slot), Some(Object::Instance(instance))) => instance.slot(*slot).is_some(),
+            (Lookup::Slot(_), _) => false,
+            _ => true,
+        }
+    }
+
+    /// The value of an attribute that `lookup` found on `receiver`. Methods
+    /// are bound to the receiver.
+    fn attribute_value(&mut self, receiver: &Value, lookup: Lookup) -> Value {
+        match lookup {
+            Lookup::Slot(slot) => match receiver.as_object().map(|object| self.heap.get(object)) {
+                Some(Object::Instance(instance)) => *instance.slot(slot).unwrap(),
+                _ => unreachable!(),
+            },
+            Lookup::Method(method) => Value::from(self.heap.alloc(Object::BoundMethod {
+                receiver: *receiver,
+                method,
+            })),
+            Lookup::Value(value) => value,
+            Lookup::Grow(_) => unreachable!("only stores grow shapes"),
+        }
+    }
+
+    /// Set attribute `name` of `object`, through the inline cache `cache` of
+    /// the running code. Stores into a slot the instance already has, and
+    /// stores that grow its shape by the attribute, are cached.
+    fn cached_store(&mut self, cache: u32, object: &Value, name: &str, value: Value) -> VmResult<()> {
+        let reference = object.as_object().filter(|&object| matches!(self.heap.get(object), Object::Instance(_)));
+        let reference = match reference {
+            Some(reference) => reference,
+            None => return self.set_attr(object, name, value),
+        };
+        let key = self.receiver(object);
+        let code = self.code();
+        let mut sites = code.caches.borrow_mut();
+        let site = &mut sites[cache as usize];
+        if site.revalidate(self.caches.epoch()) {
+            self.caches.stats.invalidations += 1;
+        }
+        let cached = match site.probe(key) {
+            Probe::Hit(lookup) => Some(lookup.clone()),
+            _ => None,
+        };
+        if let (Some(lookup), Object::Instance(instance)) = (cached, self.heap.get_mut(reference)) {
+            match lookup {
+                Lookup::Slot(slot) => instance.slots[slot] = value,
+                Lookup::Grow(shape) => {
+                    instance.shape = shape;
+                    instance.slots.push(value);
+                }
+                _ => unreachable!(),
+            }
+            let stats = &mut self.caches.stats;
+            match site.cache {
+                InlineCache::Monomorphic(..) => stats.monomorphic_hits += 1,
+                _ => stats.polymorphic_hits += 1,
+            }
+            return Ok(());
+        }
+        self.caches.stats.misses += 1;
+        let shape = match key {
+            Receiver::Shape(shape) => shape,
+            _ => unreachable!(),
+        };
+        let slot = self.shapes.get(shape).slot(name);
+        self.set_attr(object, name, value)?;
+        let lookup = match (slot, self.heap.get(reference)) {
+            (Some(slot), _) => Some(Lookup::Slot(slot)),
+            (None, Object::Instance(instance)) => {
+                (self.shapes.get(instance.shape).parent == Some(shape)).then_some(Lookup::Grow(instance.shape))
+            }
+            _ => None,
+        };
+        if let Some(lookup) = lookup {
+            if !matches!(site.cache, InlineCache::Megamorphic) {
+                site.insert(key, lookup);
+            }
+        }
+        Ok(())
+    }
+
+    fn no_attribute(&mut self, value: &Value, name: &str) -> Raised {
+        let message = format!("'{}' object has no attribute '{}'", self.type_name(value), name);
+        self.error(ErrorKind::AttributeError, message)
+    }
+
+    pub fn get_attr(&mut self, object: &Value, name: &str) -> VmResult<Value> {
+        let lookup = self.lookup(object, name)?;
+        Ok(self.attribute_value(object, lookup))
+    }
+
+    pub fn set_attr(&mut self, object: &Value, name: &str, value: Value) -> VmResult<()> {
+        if let Some(reference) = object.as_object() {
+            match self.heap.get_mut(reference) {
+                Object::Instance(instance) => {
+                    match self.shapes.get(instance.shape).slot(name) {
+                        Some(slot) => instance.slots[slot] = value,
+                        None => match self.shapes.add_attribute(instance.shape, name) {
+                            Transition::Grow(shape) => {
+                                instance.shape = shape;
+                                instance.slots.push(value);
+                            }
+                            Transition::Reorder(shape) => {
+                                let old = self.shapes.get(instance.shape);
+                                let slots: Vec<Value> = self
+                                    .shapes
+                                    .get(shape)
+                                    .attributes
+                                    .iter()
+                                    .map(|attribute| match old.slot(attribute) {
+                                        Some(slot) => instance.slots[slot],
+                                        None => value,
+                                    })
+                                    .collect();
+                                instance.slots = slots.into();
+                                instance.shape = shape;
+                            }
+                        },
+                    }
+                    return Ok(());
+                }
+                Object::Class(class) => {
+                    class.namespace.insert(name.to_owned(), value);
+                    self.caches.invalidate();
+                    self.specialisations.class_changed(reference);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        let message = format!("can't set attribute '{}' of '{}' object", name, self.type_name(object));
+        Err(self.error(ErrorKind::AttributeError, message))
+    }
+
+    /// Delete attribute `name` of an instance, or of a class or module. The
+    /// slot of an instance stays in its shape, empty.
+    pub fn delete_attr(&mut self, object: &Value, name: &str) -> VmResult<()> {
+        if let Some(reference) = object.as_object() {
+            let deleted = match self.heap.get_mut(reference) {
+                Object::Instance(instance) => match self.shapes.get(instance.shape).slot(name) {
+                    Some(slot) => instance.take_slot(slot).is_some(),
+                    None => false,
+                },
+                Object::Class(class) => {
+                    self.caches.invalidate();
+                    self.specialisations.class_changed(reference);
+                    class.namespace.remove(name).is_some()
+                }
+                _ => false,
+            };
+            if deleted {
+                return Ok(());
+            }
+        }
+        Err(self.no_attribute(object, name))
+    }
+
+    /// The definition of `name` after the owner of the running method in the
+    /// method resolution order of the class of `receiver`.
+    fn super_method(&mut self, receiver: &Value, name: &str) -> VmResult<Value> {
+        let owner = self.frame().function.and_then(|function| match self.heap.get(function) {
+            Object::Function(function) => function.owner,
+            _ => None,
+        });
+        let class = match receiver.as_object().map(|object| self.heap.get(object)) {
+            Some(Object::Instance(instance)) => Some(instance.class),
+            _ => None,
+        };
+        if let (Some(owner), Some(class)) = (owner, class) {
+            let mro = &self.heap.class(class).unwrap().mro;
+            if let Some(after) = mro::super_order(mro, &owner) {
+                let found = after
+                    .iter()
+                    .find_map(|&included| self.heap.class(included).unwrap().namespace.get(name).cloned());
+                if let Some(method) = found {
+                    return Ok(method);
+                }
+            }
+        }
+        Err(self.error(
+            ErrorKind::AttributeError,
+            format!("'super' object has no attribute '{}'", name),
+        ))
+    }
+
+    /// The exception that raising `value` raises: an instance of a subclass of
+    /// `Exception`, or a new instance of such a class.
+    fn make_exception(&mut self, value: Value) -> VmResult<ObjRef> {
+        let value = match value.as_object().map(|object| self.heap.get(object)) {
+            Some(Object::Class(_)) => self.call(value, vec![])?,
+            _ => value,
+        };
+        let exception = self.error_class(ErrorKind::Exception);
+        if let Some(object) = value.as_object() {
+            if let Object::Instance(instance) = self.heap.get(object) {
+                if self.heap.class(instance.class).unwrap().mro.contains(&exception) {
+                    return Ok(object);
+                }
+            }
+        }
+        Err(self.error(ErrorKind::TypeError, "exceptions must derive from Exception"))
+    }
+
+    fn exception_matches(&mut self, exception: &Value, class: &Value) -> VmResult<bool> {
+        let class = class.as_object();
+        let instance_class = match exception.as_object().map(|object| self.heap.get(object)) {
+            Some(Object::Instance(instance)) => instance.class,
+            _ => return Ok(false),
+        };
+        let mro = &self.heap.class(instance_class).unwrap().mro;
+        match class.map(|class| (class, self.heap.get(class))) {
+            Some((class, Object::Class(_))) => Ok(mro.contains(&class)),
+            Some((_, Object::Tuple(classes))) => Ok(classes
+                .iter()
+                .any(|class| class.as_object().is_some_and(|class| mro.contains(&class)))),
+            _ => Err(self.error(ErrorKind::TypeError, "catching classes that don't derive from Exception")),
+        }
+    }
+}
+
+/// Mark what `frame` holds on to, apart from its part of the stack.
+fn trace_frame(marker: &mut Marker, frame: &Frame) {
+    marker.code(&frame.code);
+    if let Some(function) = frame.function {
+        marker.object(function);
+    }
+    for value in frame.locals.iter().chain(frame.namespace.iter().flat_map(|namespace| namespace.values())) {
+        marker.value(value);
+    }
+    for cell in &frame.cells {
+        marker.object(*cell);
+    }
+    for raised in &frame.handled {
+        marker.object(raised.exception);
+    }
+    match &frame.on_return {
+        ReturnAction::Value => {}
+        ReturnAction::Instance(object) | ReturnAction::Reopen(object) | ReturnAction::Module(object) => {
+            marker.object(*object)
+        }
+        ReturnAction::Class { bases, .. } => {
+            for base in bases {
+                marker.object(*base);
+            }
+        }
+    }
+    if let Some(compiled) = &frame.compiled {
+        marker.compiled(compiled);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::cell::RefCell;
+
+    /// Output that stays readable after the machine writing it is gone.
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run `source`, returning what it printed and how it ended.
+    pub(crate) fn run(source: &str) -> (String, Result<Value, Error>) {
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        let result = crate::run_source(&mut vm, source, "<test>");
+        let printed = String::from_utf8(output.0.take()).unwrap();
+        (printed, result)
+    }
+
+    pub(crate) fn output(source: &str) -> String {
+        let (printed, result) = run(source);
+        if let Err(error) = result {
+            panic!("{}", error);
+        }
+        printed
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let source = "\
+print(1 + 2 * 3, 7 // -2, 7 % -3, -7.5 % 2, 2 ** 100, 2 ** -1, 10 / 4)
+print(1 < 2 <= 2, 3 in [1, 2, 3], 'b' in 'abc', [1, 2] + [3], 'ab' * 2)
+print(None ?? 'default', 0 ?? 'default', 1 and 2, 0 or 'other', not [])
+";
+        assert_eq!(
+            output(source),
+            "\
+7 -4 -2 0.5 1267650600228229401496703205376 0.5 2.5
+True True True [1, 2, 3] abab
+default 0 2 other True
+"
+        );
+    }
+
+    #[test]
+    fn test_optional_chaining() {
+        let source = "\
+class Node:
+    def initialize(self, child):
+        self.child = child
+        self.items = [self]
+
+    def name(self):
+        return 'node'
+
+none = None
+leaf = Node(None)
+root = Node(leaf)
+print(none?.child.child, none?[0].child, none?.child.name().upper(), none?.items[0].child.child)
+print(root?.child.child, root?.items[0].child.child, root?.child?.name().upper(), leaf.child?.name())
+print([none?.x.y, none?[0][1] ?? 'default'], 1 |> none?.child.add())
+";
+        assert_eq!(
+            output(source),
+            "\
+None None None None
+None None NODE None
+[None, 'default'] None
+"
+        );
+    }
+
+    #[test]
+    fn test_integer_promotion() {
+        let source = "\
+big = 140737488355327
+print(big + 1, big + 1 - 1 == big, -big - 1, -(-big - 1), big * big)
+print(3037000499 * 3037000499, 2 ** 64 // 2 ** 32, 2 ** 64 - 2 ** 64 + 5, -2 ** 70 // 3)
+print(2 ** 64 == 18446744073709551616, {2 ** 64: 'a'}[18446744073709551616], 2 ** 64 > big, 1.5 < 2 ** 64)
+print(int('123456789012345678901234567890') % 7, abs(-2 ** 70), float(2 ** 60), range(big + 3)[big + 1])
+";
+        assert_eq!(
+            output(source),
+            "\
+140737488355328 True -140737488355328 140737488355328 19807040628565802923409276929
+9223372030926249001 4294967296 5 -393530540239137101142
+True a True True
+0 1180591620717411303424 1.152921504606847e18 140737488355328
+"
+        );
+    }
+
+    #[test]
+    fn test_control_flow_and_recursion() {
+        let source = "\
+def fib(n):
+    if n < 2:
+        return n
+    return fib(n - 1) + fib(n - 2)
+
+total = 0
+for i in 1..10:
+    if i % 2 == 0:
+        continue
+    if i > 7:
+        break
+    total += i
+n = 0
+while n < 5:
+    n += 1
+print(fib(20), total, n)
+";
+        assert_eq!(output(source), "6765 16 5\n");
+    }
+
+    #[test]
+    fn test_closures_and_blocks() {
+        let source = "\
+def counter():
+    count = 0
+    def increment(step=1):
+        nonlocal count
+        count += step
+        return count
+    return increment
+
+tick = counter()
+tick()
+tick(10)
+total = 0
+doubled = [1, 2, 3, 4].map() do x:
+    total += x
+    x * 2
+    |.select() do x:
+        x > 2
+product = [1, 2, 3, 4].reduce() do a, b:
+    a * b
+print(tick(), total, doubled, product)
+";
+        assert_eq!(output(source), "12 10 [4, 6, 8] 24\n");
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        let source = "\
+def describe(name, greeting='hello', punctuation='!'):
+    return f'{greeting} {name}{punctuation}'
+
+class Point:
+    def initialize(self, x=0, y=0):
+        self.x = x
+        self.y = y
+    def moved(self, dx=0, dy=0):
+        return Point(y=self.y + dy, x=self.x + dx)
+
+class Point3 extends Point:
+    def moved(self, dx=0, dy=0):
+        return super().moved(dy=dy, dx=dx)
+
+p = Point3(1, y=2).moved(dy=10)
+print(describe('ann', punctuation='?'), describe(greeting='hi', name='bob'), p.x, p.y)
+for call in [lambda: describe(nickname='c'), lambda: describe('c', name='d'), lambda: describe(greeting='e')]:
+    try:
+        call()
+    except TypeError as error:
+        print(error)
+try:
+    print('x', sep='')
+except TypeError as error:
+    print(error)
+";
+        assert_eq!(
+            output(source),
+            "\
+hello ann? hi bob! 1 12
+describe() got an unexpected keyword argument 'nickname'
+describe() got multiple values for argument 'name'
+describe() missing argument 'name'
+print() takes no keyword arguments
+"
+        );
+    }
+
+    #[test]
+    fn test_generators() {
+        let source = "\
+def countdown(n):
+    while n > 0:
+        yield n
+        n -= 1
+    return 'done'
+
+def fibonacci():
+    a, b = 0, 1
+    while True:
+        yield a
+        a, b = b, a + b
+
+def take(xs, n):
+    for x in xs:
+        if n == 0:
+            return
+        n -= 1
+        yield x
+
+def divide(xs):
+    try:
+        for x in xs:
+            print('got', (yield 12 // x))
+    except ZeroDivisionError:
+        yield 'caught'
+    finally:
+        print('finally')
+
+numbers = countdown(3)
+print(numbers, list(numbers), list(numbers), list(take(fibonacci(), 10)))
+print(list(divide([4, 0, 6])))
+square = lambda n: (yield n * n)
+counts = [3, 1].map() do x:
+    countdown(x).to_list()
+print(list(square(7)), counts)
+";
+        assert_eq!(
+            output(source),
+            "\
+<iterator> [3, 2, 1] [] [0, 1, 1, 2, 3, 5, 8, 13, 21, 34]
+got None
+finally
+[3, 'caught']
+[49] [[3, 2, 1], [1]]
+"
+        );
+    }
+
+    #[test]
+    fn test_with_statements() {
+        let source = "\
+class Resource:
+    def initialize(self, name, swallow=False):
+        self.name = name
+        self.swallow = swallow
+    def enter(self):
+        print('enter', self.name)
+        return self.name.upper()
+    def exit(self, error):
+        print('exit', self.name, repr(error))
+        return self.swallow
+
+with Resource('a') as a, Resource('b') as b:
+    print(a, b)
+with Resource('quiet', True):
+    raise ValueError('hidden')
+try:
+    with Resource('loud'):
+        raise ValueError('shown')
+except ValueError as e:
+    print('caught', e)
+
+def find(xs):
+    with Resource('search'):
+        for x in xs:
+            with Resource(x):
+                if x == 'c':
+                    return x
+                if x == 'a':
+                    continue
+                break
+
+print(find(['a', 'c']))
+for x in [1, 2]:
+    with Resource('loop'):
+        break
+";
+        assert_eq!(
+            output(source),
+            "\
+enter a
+enter b
+A B
+exit b None
+exit a None
+enter quiet
+exit quiet ValueError('hidden')
+enter loud
+exit loud ValueError('shown')
+caught shown
+enter search
+enter a
+exit a None
+enter c
+exit c None
+exit search None
+c
+enter loop
+exit loop None
+"
+        );
+    }
+
+    #[test]
+    fn test_del() {
+        let source = "\
+def scratch():
+    x = 1
+    del x
+    return x
+
+total = 1
+items = [1, 2, 3, 4]
+table = {'a': 1, 'b': 2}
+del total, items[0], table['a']
+del items[-1]
+print(scratch(), items, table)
+try:
+    print(total)
+except NameError as e:
+    print(e)
+
+class Config:
+    debug = True
+    del debug
+
+try:
+    Config.debug
+except AttributeError as e:
+    print('deleted')
+try:
+    del table['z']
+except KeyError as e:
+    print('missing', e)
+";
+        assert_eq!(
+            output(source),
+            "None [2, 3] {'b': 2}\nname 'total' is not defined\ndeleted\nmissing 'z'\n"
+        );
+    }
+
+    #[test]
+    fn test_match_statements() {
+        let source = "\
+enum Shape:
+    Circle(float)
+    Rect(width: float, height: float)
+    Empty
+
+class Point:
+    match_args = ('x', 'y')
+    def initialize(self, x, y):
+        self.x = x
+        self.y = y
+
+def area(shape):
+    match shape:
+        case Shape.Circle(r):
+            return 3 * r * r
+        case Shape.Rect(width=w, height=h) if w == h:
+            return f'square {w * h}'
+        case Shape.Rect(w, h):
+            return w * h
+        case Shape.Empty:
+            return 0
+
+def describe(value):
+    match value:
+        case None | True:
+            return 'singleton'
+        case 0 | 1 as small:
+            return f'small {small}'
+        case [x, y] | (x, y, _):
+            return f'pair {x} {y}'
+        case [first, *rest] if rest:
+            return f'first {first} rest {rest}'
+        case {'name': name, 'tags': [*tags]}:
+            return f'{name} {tags}'
+        case Point(0, y):
+            return f'on the y axis at {y}'
+        case Point(x=x):
+            return f'point at x {x}'
+        case _:
+            return 'other'
+
+print(area(Shape.Circle(2)), area(Shape.Rect(2, 2)), area(Shape.Rect(2, 3)), area(Shape.Empty))
+print(describe(None), describe(True), describe(1), describe([1, 2]), describe((1, 2, 3)))
+print(describe([1, 2, 3, 4]), describe([1]), describe({'name': 'a', 'tags': ['b'], 'extra': 1}))
+print(describe(Point(0, 5)), describe(Point(3, 4)), describe('text'))
+head, *middle, tail = range(5)
+print(head, middle, tail, Shape.Rect(1, 2).height, isinstance(Shape.Empty, Shape), Shape.Empty)
+";
+        assert_eq!(
+            output(source),
+            "\
+12 square 4 6 0
+singleton singleton small 1 pair 1 2 pair 1 2
+first 1 rest [2, 3, 4] other a ['b']
+on the y axis at 5 point at x 3 other
+0 [1, 2, 3] 4 2 True <Shape.Empty object>
+"
+        );
+        let (_, result) = run("enum Shape:\n    Circle(float)\nShape.Circle()\n");
+        let error = result.unwrap_err().to_string();
+        assert!(error.ends_with("TypeError: Shape.Circle() takes 1 arguments but 0 were given"), "{}", error);
+    }
+
+    #[test]
+    fn test_comprehensions() {
+        let source = "\
+x = 'outer'
+pairs = [(x, y) for x in range(3) if x != 1 for y in 'ab']
+print(pairs, x)
+print({n % 3 for n in range(10)}, {n: n * n for n in [1, 2, 3] if n > 1}, set())
+
+def first(xs, n):
+    taken = []
+    for x in xs:
+        if taken.length() == n:
+            break
+        taken.append(x)
+    return taken
+
+class Grid:
+    def initialize(self, size):
+        self.size = size
+    def cells(self):
+        return [[(row, col) for col in range(self.size)] for row in range(self.size)]
+
+squares = (n * n for n in range(1000000000))
+print(first(squares, 3), (n for n in range(5)).sum(), Grid(2).cells())
+found = [hit for n in [1, 5, 9] if (hit := n * 2) > 4]
+print(found, hit, {1, 2} == {2, 1, 2}, 3 in {1, 2, 3})
+";
+        assert_eq!(
+            output(source),
+            "\
+[(0, 'a'), (0, 'b'), (2, 'a'), (2, 'b')] outer
+{0, 1, 2} {2: 4, 3: 9} set()
+[0, 1, 4] 10 [[(0, 0), (0, 1)], [(1, 0), (1, 1)]]
+[10, 18] 18 True True
+"
+        );
+    }
+
+    #[test]
+    fn test_classes_modules_and_super() {
+        let source = "\
+class Animal:
+    def initialize(self, name):
+        self.name = name
+    def describe(self):
+        return f'{self.name} the {self.kind()}'
+    def kind(self):
+        return 'animal'
+
+module Loud:
+    def describe(self):
+        return super().describe().upper()
+
+class Dog extends Animal, Loud:
+    def kind(self):
+        return 'dog'
+
+dog = Dog('rex')
+print(dog.describe(), isinstance(dog, Animal), isinstance(dog, Loud))
+";
+        assert_eq!(output(source), "REX THE DOG True True\n");
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let source = "\
+class Empty extends ValueError:
+    pass
+
+def check(xs):
+    if not xs:
+        raise Empty('nothing to check')
+    return xs[0]
+
+for xs in [[1], []]:
+    try:
+        print(check(xs))
+    except ValueError as error:
+        print('caught', repr(error))
+    finally:
+        print('checked')
+try:
+    {}['missing']
+except (IndexError, KeyError) as error:
+    print(error)
+";
+        assert_eq!(
+            output(source),
+            "1\nchecked\ncaught Empty('nothing to check')\nchecked\n'missing'\n"
+        );
+    }
+
+    #[test]
+    fn test_traceback() {
+        let source = "\
+def outer(x):
+    return inner(x)
+
+def inner(x):
+    return x.missing
+
+outer(1)
+";
+        let (_, result) = run(source);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "\
+Traceback (most recent call last):
+  File \"<test>\", line 7 column 0, in <module>
+  File \"<test>\", line 2 column 11, in outer
+  File \"<test>\", line 5 column 11, in inner
+AttributeError: 'int' object has no attribute 'missing'"
+        );
+    }
+
+    #[test]
+    fn test_shapes() {
+        let source = "\
+class Point:
+    def initialize(self, x, y):
+        self.x = x
+        self.y = y
+
+class Label:
+    pass
+
+a = Point(1, 2)
+b = Point(3, 4)
+c = Label()
+c.text = 'c'
+c.color = 'red'
+d = Label()
+d.color = 'blue'
+d.text = 'd'
+del d.text
+d.text = 'again'
+print(a.x + b.y, c.text, d.text, d.color)
+";
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        crate::run_source(&mut vm, source, "<test>").unwrap();
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "5 c again blue\n");
+        insta::assert_snapshot!(vm.dump_shapes());
+    }
+
+    #[test]
+    fn test_inline_caches() {
+        let source = "\
+class Circle:
+    def initialize(self, r):
+        self.r = r
+    def area(self):
+        return 3 * self.r * self.r
+
+class Square:
+    def initialize(self, side):
+        self.side = side
+    def area(self):
+        return self.side * self.side
+
+shapes = [Circle(1), Square(2), Circle(3)]
+def total():
+    return shapes.map() do shape:
+        shape.area()
+        |.sum()
+print(total())
+
+class Square:
+    def area(self):
+        return 0
+
+print(total())
+Circle.area = Square.area
+print(total())
+";
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        crate::run_source(&mut vm, source, "<test>").unwrap();
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "34\n30\n0\n");
+        let stats = vm.cache_stats();
+        assert!(stats.invalidations > 0, "{:?}", stats);
+        assert!(stats.polymorphic_hits > 0, "{:?}", stats);
+        assert!(stats.hits() + stats.misses > 0 && stats.hit_rate() < 1.0);
+    }
+
+    /// Run `source` in a machine with JIT mode `mode`, returning what it
+    /// printed, or its error, and the JIT stats.
+    fn run_with_jit(source: &str, mode: JitMode) -> (String, JitStats) {
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_jit_mode(mode);
+        let result = crate::run_source(&mut vm, source, "<test>");
+        let mut printed = String::from_utf8(output.0.take()).unwrap();
+        if let Err(error) = result {
+            printed.push_str(&error.to_string());
+        }
+        (printed, vm.jit_stats())
+    }
+
+    /// Run `source` like `run_with_jit`, collecting at every safe point after
+    /// an allocation, which frees anything the roots miss.
+    fn run_stressed(source: &str, mode: JitMode) -> String {
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_jit_mode(mode);
+        vm.set_gc_stress(true);
+        let result = crate::run_source(&mut vm, source, "<test>");
+        let mut printed = String::from_utf8(output.0.take()).unwrap();
+        if let Err(error) = result {
+            printed.push_str(&error.to_string());
+        }
+        assert!(vm.gc_stats().collections > 0);
+        printed
+    }
+
+    #[test]
+    fn test_jit() {
+        let source = "\
+class Vector:
+    def initialize(self, x, y):
+        self.x = x
+        self.y = y
+    def dot(self, other):
+        return self.x * other.x + self.y * other.y
+    def scale(self, factor):
+        self.x = self.x * factor
+        self.y = self.y * factor
+        return self
+
+def sum_dots(vectors):
+    total = 0
+    for v in vectors:
+        total = total + v.dot(v)
+    return total
+
+def fib(n):
+    if n < 2:
+        return n
+    return fib(n - 1) + fib(n - 2)
+
+def checked(items, i):
+    try:
+        return items[i]
+    except IndexError as error:
+        return error.message
+
+vectors = []
+for i in range(2000):
+    vectors.append(Vector(i % 10, 1).scale(2))
+print(sum_dots(vectors))
+odd = Vector(1, 2)
+odd.z = 3
+vectors.append(odd)
+print(sum_dots(vectors), fib(20))
+for i in range(200):
+    found = checked([1, 2, 3], i % 5)
+print(found, checked([1, 2, 3], 1))
+fib(None)
+";
+        let (interpreted, stats) = run_with_jit(source, JitMode::Interpreter);
+        assert_eq!(
+            interpreted,
+            "\
+236000
+236005 6765
+list index out of range 2
+Traceback (most recent call last):
+  File \"<test>\", line 40 column 0, in <module>
+  File \"<test>\", line 19 column 7, in fib
+TypeError: '<' not supported between instances of 'NoneType' and 'int'"
+        );
+        assert_eq!(stats, JitStats::default());
+
+        let (tiered, stats) = run_with_jit(source, JitMode::Tiered);
+        assert_eq!(tiered, interpreted);
+        assert!(stats.compiled > 0 && stats.failed == 0, "{:?}", stats);
+        // `sum_dots` is compiled in its loop, and its guard fails for `odd`.
+        assert!(stats.loop_entries > 0 && stats.guard_failures > 0, "{:?}", stats);
+
+        let (forced, stats) = run_with_jit(source, JitMode::Force);
+        assert_eq!(forced, interpreted);
+        assert!(stats.compiled > 0 && stats.failed == 0, "{:?}", stats);
+
+        assert_eq!(run_stressed(source, JitMode::Interpreter), interpreted);
+        assert_eq!(run_stressed(source, JitMode::Tiered), interpreted);
+    }
+
+    #[test]
+    fn test_deoptimization() {
+        let source = "\
+class Counter:
+    def step(self):
+        return 1
+
+def patched(self):
+    return 10
+
+def run(counters, n):
+    total = 0
+    i = 0
+    while i < n:
+        if i == 1500:
+            Counter.step = patched
+        total = total + counters[0].step()
+        i = i + 1
+    return total
+
+class Odd:
+    def step(self):
+        return 100
+
+def mixed(counters, n):
+    total = 0
+    i = 0
+    while i < n:
+        counter = counters[0]
+        if i >= 1200:
+            counter = counters[1]
+        total = total + counter.step()
+        i = i + 1
+    return total
+
+print(run([Counter()], 2000))
+print(mixed([Odd(), Counter()], 1300))
+";
+        let (interpreted, _) = run_with_jit(source, JitMode::Interpreter);
+        assert_eq!(interpreted, "6500\n121000\n");
+
+        let (tiered, stats) = run_with_jit(source, JitMode::Tiered);
+        assert_eq!(tiered, interpreted);
+        // Patching `Counter.step` invalidates the native code of `run` in the
+        // middle of its loop, which goes on in the interpreter.
+        assert!(stats.deopts > stats.guard_failures, "{:?}", stats);
+        // `mixed` is compiled in its loop for `Odd`, and the guard fails for
+        // `Counter` until the code is thrown away.
+        assert!(stats.guard_failures > 0 && stats.discarded > 0, "{:?}", stats);
+
+        let (forced, _) = run_with_jit(source, JitMode::Force);
+        assert_eq!(forced, interpreted);
+        assert_eq!(run_stressed(source, JitMode::Tiered), interpreted);
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        let source = "\
+class Node:
+    def initialize(self, value):
+        self.value = value
+        self.next = None
+
+def chain(n):
+    head = Node(0)
+    node = head
+    for i in range(1, n):
+        node.next = Node(i)
+        node = node.next
+    node.next = head
+    return head
+
+log = []
+def collected():
+    log.append('cycle')
+
+cycle = chain(100)
+finalize(cycle, collected)
+ref = weakref(cycle)
+print(ref.get().next.value, ref)
+cycle = None
+freed = collect_garbage()
+print(freed >= 100, ref.get(), ref, log)
+
+kept = [Node(1)]
+ref = weakref(kept[0])
+total = range(5000).map() do i:
+    Node(i).value
+print(total.sum(), ref.get().value)
+finalize(1, print)
+";
+        let (printed, result) = run(source);
+        let error = result.unwrap_err().to_string();
+        assert_eq!(
+            printed,
+            "\
+1 <weakref to <Node object>>
+True None <weakref dead> ['cycle']
+12497500 1
+"
+        );
+        assert!(error.ends_with("TypeError: finalize() takes an object, not 'int'"), "{}", error);
+        assert_eq!(run_stressed(source, JitMode::Tiered), printed + &error);
+    }
+
+    #[test]
+    fn test_inline_attribute_access() {
+        let source = "\
+class Point:
+    x = 'class'
+    def initialize(self, x, y):
+        self.x = x
+        self.y = y
+
+def walk(point, n):
+    i = 0
+    while i < n:
+        point.x = point.x + point.y
+        i = i + 1
+    return point.x
+
+def first(point):
+    return point.x
+
+point = Point(0, 2)
+print(walk(point, 5000))
+for i in range(200):
+    first(point)
+del point.x
+print(first(point), first(Point(1, 2)))
+";
+        let (interpreted, _) = run_with_jit(source, JitMode::Interpreter);
+        assert_eq!(interpreted, "10000\nclass 1\n");
+
+        // The loop of `walk` is compiled once its caches saw `Point`, and
+        // every iteration after loads and stores `x` and loads `y` in place.
+        let (tiered, stats) = run_with_jit(source, JitMode::Tiered);
+        assert_eq!(tiered, interpreted);
+        assert!(stats.inlined >= 3 * (5000 - jit::LOOP_THRESHOLD), "{:?}", stats);
+        // Only loading the deleted `x` hands a frame to the interpreter.
+        assert_eq!(stats.deopts, 1, "{:?}", stats);
+        assert_eq!(run_stressed(source, JitMode::Tiered), interpreted);
+    }
+
+    #[test]
+    fn test_specialisations() {
+        let source = "\
+class Point:
+    def initialize(self, x, y):
+        self.x = x
+        self.y = y
+    def scale(self, factor):
+        return Point(self.x * factor, self.y * factor)
+
+def norm(p):
+    return p.x * p.x + p.y * p.y
+
+factor = 2
+def grow(p):
+    return p.scale(factor)
+
+a = Point(1, 2)
+print(norm(a), norm(a.scale(2)), norm(a.scale(0.5)))
+print(norm(grow(a)))
+factor = 3
+print(norm(grow(a)))
+factor = 0.5
+print(norm(grow(a)))
+";
+        let output = SharedBuffer::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        crate::run_source(&mut vm, source, "<test>").unwrap();
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "5 20 1.25\n20\n45\n1.25\n");
+        // Binding `factor` to a float dropped the specialisation of `grow`
+        // that saw an int.
+        insta::assert_snapshot!(vm.dump_specialisations());
+        let stats = vm.specialisation_stats();
+        assert_eq!(stats.dropped, 1, "{:?}", stats);
+
+        // Changing a class drops everything that depends on its shapes.
+        crate::run_source(&mut vm, "Point.origin = 0\nprint(norm(a.scale(2)))", "<test>").unwrap();
+        assert_eq!(vm.specialisation_stats().dropped, 7);
+        assert_eq!(vm.specialisations.len(), 3);
+
+        // Collecting drops what wasn't called since the last collection.
+        assert_eq!(vm.collect_specialisations(), 0);
+        crate::run_source(&mut vm, "print(norm(a))", "<test>").unwrap();
+        assert_eq!(vm.collect_specialisations(), 2);
+        assert_eq!(vm.specialisations.len(), 1);
+    }
+
+    #[test]
+    fn test_coroutines() {
+        let source = "\
+log = []
+async def step(name, delay):
+    log.append(name + ' start')
+    await sleep(delay)
+    log.append(name + ' end')
+    return name
+
+async def guarded(n):
+    try:
+        await sleep(0)
+        if n > 1:
+            raise ValueError('too big')
+        return n
+    except ValueError as e:
+        return e.message
+    finally:
+        log.append('finally ' + str(n))
+
+async def inner():
+    await sleep(0)
+    return 'adopted'
+
+async def outer():
+    return inner()
+
+async def main():
+    a = step('a', 0.02)
+    b = step('b', 0.01)
+    log.append('awaiting')
+    log.append(await a)
+    log.append(await b)
+    log.append(await 42)
+    print(log)
+    results = [1, 2].map() async do n:
+        await guarded(n)
+    for result in results:
+        print(await result)
+    print(await outer())
+
+print('started', main())
+set_timeout(0.03) do:
+    print('timeout')
+outer().then() do value:
+    print('then', value)
+";
+        let printed = output(source);
+        assert_eq!(
+            printed,
+            "\
+started <promise pending>
+then adopted
+['a start', 'b start', 'awaiting', 'b end', 'a end', 'a', 'b', 42]
+1
+too big
+adopted
+timeout
+"
+        );
+        assert_eq!(run_stressed(source, JitMode::Force), printed);
+
+        // A rejection that nothing awaits stops the program.
+        let (printed, result) = run("\
+async def fail():
+    await sleep(0)
+    raise ValueError('nobody awaits')
+fail()
+print('after')
+");
+        assert_eq!(printed, "after\n");
+        let error = result.unwrap_err().to_string();
+        assert!(error.ends_with("line 3 column 4, in fail\nValueError: nobody awaits"), "{}", error);
+    }
+
+    #[test]
+    fn test_async_for_and_async_with() {
+        let source = "\
+class Countdown:
+    def initialize(self, n):
+        self.n = n
+    async def next(self):
+        await sleep(0)
+        if self.n == 0:
+            return None
+        self.n -= 1
+        return self.n + 1
+
+class Lock:
+    def initialize(self, name, swallow=False):
+        self.name = name
+        self.swallow = swallow
+    async def enter(self):
+        await sleep(0)
+        print('acquire', self.name)
+        return self.name
+    async def exit(self, error):
+        await sleep(0)
+        print('release', self.name, repr(error))
+        return self.swallow
+
+async def first(n):
+    async with Lock('search'):
+        async for i in Countdown(4):
+            async with Lock(i):
+                if i == n:
+                    return i
+
+async def main():
+    async for i in Countdown(2):
+        print('tick', i)
+    else:
+        print('done')
+    async for i in Countdown(5):
+        if i == 4:
+            break
+    async with Lock('a') as a, Lock('b') as b:
+        print('holding', a, b)
+    async with Lock('quiet', True):
+        raise ValueError('hidden')
+    try:
+        async with Lock('loud'):
+            raise ValueError('shown')
+    except ValueError as e:
+        print('caught', e)
+    print(await first(3))
+
+main()
+";
+        let printed = output(source);
+        assert_eq!(
+            printed,
+            "\
+tick 2
+tick 1
+done
+acquire a
+acquire b
+holding a b
+release b None
+release a None
+acquire quiet
+release quiet ValueError('hidden')
+acquire loud
+release loud ValueError('shown')
+caught shown
+acquire search
+acquire 4
+release 4 None
+acquire 3
+release 3 None
+release search None
+3
+"
+        );
+        assert_eq!(run_stressed(source, JitMode::Force), printed);
+    }
+
+    #[test]
+    fn test_file_io() {
+        let path = std::env::temp_dir().join(format!("emerald-test-{}.txt", std::process::id()));
+        let source = format!(
+            "\
+async def main(path):
+    await write_file(path, 'some text')
+    print(await read_file(path))
+    try:
+        await read_file(path + '.missing')
+    except OSError as e:
+        print('OSError', e.message.startswith(\"can't read\"))
+main({:?})
+",
+            path.display().to_string()
+        );
+        let printed = output(&source);
+        assert_eq!(printed, "some text\nOSError True\n");
+        assert_eq!(run_stressed(&source, JitMode::Tiered), printed);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_loopback() {
+        let source = "\
+server = listen('127.0.0.1', 0)
+
+async def serve():
+    connection = await server.accept()
+    while True:
+        text = await connection.read()
+        if text == '':
+            break
+        await connection.write(text.upper())
+    connection.close()
+    server.close()
+    print('served')
+
+async def client(words):
+    connection = await connect('127.0.0.1', server.port())
+    for word in words:
+        await connection.write(word)
+        print(await connection.read())
+    connection.close()
+
+serve()
+client(['hello', 'world'])
+print(server.port() > 0)
+";
+        let printed = output(source);
+        assert_eq!(printed, "True\nHELLO\nWORLD\nserved\n");
+        assert_eq!(run_stressed(source, JitMode::Tiered), printed);
+
+        let (_, result) = run("\
+async def refused():
+    server = listen('127.0.0.1', 0)
+    port = server.port()
+    server.close()
+    await connect('127.0.0.1', port)
+refused()
+");
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("OSError: Connection refused"), "{}", error);
+    }
+}
@@ -0,0 +1,79 @@
+//! Build executables with `emerald build`, which needs the runtime library
+//! and a C compiler to link with, and run them.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A fresh directory for the programs of `test`.
+fn directory(test: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("emerald-test-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Build `main.em` in `directory` into the executable `main`, and run it
+/// from another directory, where the source of the program isn't.
+fn build_and_run(directory: &Path) -> Output {
+    let built = Command::new(env!("CARGO_BIN_EXE_emerald"))
+        .args(["build", "main.em"])
+        .current_dir(directory)
+        .output()
+        .unwrap();
+    assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+    std::fs::remove_file(directory.join("main.em")).unwrap();
+    Command::new(directory.join("main"))
+        .current_dir(std::env::temp_dir())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_build() {
+    let directory = directory("build");
+    std::fs::write(
+        directory.join("geometry.em"),
+        "\
+class Point:
+    x: int
+    y: int
+    def initialize(self, x, y):
+        self.x = x
+        self.y = y
+    def length(self):
+        return self.x * self.x + self.y * self.y
+",
+    )
+    .unwrap();
+    std::fs::write(
+        directory.join("main.em"),
+        "\
+from geometry import Point
+
+points = [1, 2, 3].map() do i:
+    Point(i, i + 1)
+lengths = points.map() do point:
+    point.length()
+print(lengths)
+",
+    )
+    .unwrap();
+    let output = build_and_run(&directory);
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "[5, 13, 25]\n");
+    assert_eq!(output.status.code(), Some(0));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_build_error() {
+    let directory = directory("build-error");
+    std::fs::write(directory.join("main.em"), "print(1)\nprint(1 + None)\n").unwrap();
+    let output = build_and_run(&directory);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("TypeError"), "{}", stderr);
+    assert!(stderr.contains("main.em"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(1));
+    std::fs::remove_dir_all(&directory).unwrap();
+}